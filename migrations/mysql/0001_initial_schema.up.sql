@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS users (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    username VARCHAR(255) NOT NULL UNIQUE,
+    email VARCHAR(255) NOT NULL UNIQUE,
+    password VARCHAR(255) NOT NULL,
+    verification_token VARCHAR(255) NOT NULL,
+    verified BOOLEAN DEFAULT FALSE,
+    verification_attempts INT DEFAULT 0,
+    token_expiry TIMESTAMP NULL,
+    reset_password_token VARCHAR(255),
+    reset_token_expiry TIMESTAMP NULL,
+    has_2fa BOOLEAN DEFAULT FALSE,
+    2fa_code VARCHAR(6),
+    2fa_expiry TIMESTAMP NULL,
+    temp_2fa_code VARCHAR(6),
+    temp_token VARCHAR(36),
+    temp_token_expiry TIMESTAMP NULL,
+    INDEX idx_username (username),
+    INDEX idx_email (email),
+    INDEX idx_verification_token (verification_token),
+    INDEX idx_2fa_code (2fa_code)
+);
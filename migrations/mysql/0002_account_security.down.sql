@@ -0,0 +1,22 @@
+DROP TABLE IF EXISTS ip_login_failures;
+DROP TABLE IF EXISTS magic_link_tokens;
+DROP TABLE IF EXISTS webauthn_challenges;
+DROP TABLE IF EXISTS webauthn_credentials;
+DROP TABLE IF EXISTS social_identities;
+DROP TABLE IF EXISTS social_login_states;
+DROP TABLE IF EXISTS oauth_codes;
+DROP TABLE IF EXISTS oauth_authorization_requests;
+DROP TABLE IF EXISTS oauth_clients;
+DROP TABLE IF EXISTS refresh_tokens;
+DROP TABLE IF EXISTS sessions;
+DROP TABLE IF EXISTS recovery_codes;
+
+ALTER TABLE users
+    DROP COLUMN 2fa_method,
+    DROP COLUMN totp_secret,
+    DROP COLUMN temp_totp_secret,
+    DROP COLUMN totp_last_step,
+    DROP COLUMN failed_login_attempts,
+    DROP COLUMN locked_until,
+    DROP COLUMN unlock_token_hash,
+    DROP COLUMN 2fa_failed_attempts
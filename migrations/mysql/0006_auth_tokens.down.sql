@@ -0,0 +1,11 @@
+ALTER TABLE users
+    ADD COLUMN verification_token VARCHAR(255) NOT NULL DEFAULT '',
+    ADD COLUMN verification_attempts INT DEFAULT 0,
+    ADD COLUMN token_expiry TIMESTAMP NULL,
+    ADD COLUMN reset_password_token VARCHAR(255),
+    ADD COLUMN reset_token_expiry TIMESTAMP NULL,
+    ADD COLUMN temp_token VARCHAR(36),
+    ADD COLUMN temp_token_expiry TIMESTAMP NULL,
+    ADD INDEX idx_verification_token (verification_token);
+
+DROP TABLE IF EXISTS auth_tokens
@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS auth_tokens (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    user_id INT NOT NULL,
+    purpose VARCHAR(32) NOT NULL,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    expires_at DATETIME NOT NULL,
+    consumed_at DATETIME NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    INDEX idx_auth_tokens_user (user_id, purpose),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT IGNORE INTO auth_tokens (user_id, purpose, token_hash, expires_at)
+    SELECT id, 'email_verification', SHA2(verification_token, 256), token_expiry FROM users
+    WHERE verified = FALSE AND verification_token <> '' AND token_expiry > UTC_TIMESTAMP();
+
+INSERT IGNORE INTO auth_tokens (user_id, purpose, token_hash, expires_at)
+    SELECT id, 'password_reset', SHA2(reset_password_token, 256), reset_token_expiry FROM users
+    WHERE reset_password_token IS NOT NULL AND reset_password_token <> '' AND reset_token_expiry > UTC_TIMESTAMP();
+
+ALTER TABLE users
+    DROP COLUMN verification_token,
+    DROP COLUMN verification_attempts,
+    DROP COLUMN token_expiry,
+    DROP COLUMN reset_password_token,
+    DROP COLUMN reset_token_expiry,
+    DROP COLUMN temp_token,
+    DROP COLUMN temp_token_expiry
@@ -0,0 +1 @@
+ALTER TABLE oauth_codes ADD COLUMN session_jti VARCHAR(36) NULL;
@@ -0,0 +1,28 @@
+DROP TABLE IF EXISTS ip_login_failures;
+DROP TABLE IF EXISTS magic_link_tokens;
+DROP TABLE IF EXISTS webauthn_challenges;
+DROP TABLE IF EXISTS webauthn_credentials;
+DROP TABLE IF EXISTS social_identities;
+DROP TABLE IF EXISTS social_login_states;
+DROP TABLE IF EXISTS oauth_codes;
+DROP TABLE IF EXISTS oauth_authorization_requests;
+DROP TABLE IF EXISTS oauth_clients;
+DROP TABLE IF EXISTS refresh_tokens;
+DROP TABLE IF EXISTS sessions;
+DROP TABLE IF EXISTS recovery_codes;
+
+ALTER TABLE users DROP COLUMN two_fa_method;
+
+ALTER TABLE users DROP COLUMN totp_secret;
+
+ALTER TABLE users DROP COLUMN temp_totp_secret;
+
+ALTER TABLE users DROP COLUMN totp_last_step;
+
+ALTER TABLE users DROP COLUMN failed_login_attempts;
+
+ALTER TABLE users DROP COLUMN locked_until;
+
+ALTER TABLE users DROP COLUMN unlock_token_hash;
+
+ALTER TABLE users DROP COLUMN two_fa_failed_attempts
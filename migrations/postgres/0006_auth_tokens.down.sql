@@ -0,0 +1,17 @@
+ALTER TABLE users ADD COLUMN verification_token VARCHAR(255) NOT NULL DEFAULT '';
+
+ALTER TABLE users ADD COLUMN verification_attempts INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE users ADD COLUMN token_expiry TIMESTAMP NULL;
+
+ALTER TABLE users ADD COLUMN reset_password_token VARCHAR(255);
+
+ALTER TABLE users ADD COLUMN reset_token_expiry TIMESTAMP NULL;
+
+ALTER TABLE users ADD COLUMN temp_token VARCHAR(36);
+
+ALTER TABLE users ADD COLUMN temp_token_expiry TIMESTAMP NULL;
+
+CREATE INDEX IF NOT EXISTS idx_verification_token ON users(verification_token);
+
+DROP TABLE IF EXISTS auth_tokens
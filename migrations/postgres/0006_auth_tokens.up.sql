@@ -0,0 +1,37 @@
+CREATE TABLE IF NOT EXISTS auth_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    purpose VARCHAR(32) NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMP NOT NULL,
+    consumed_at TIMESTAMP NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_tokens_user ON auth_tokens(user_id, purpose);
+
+INSERT INTO auth_tokens (user_id, purpose, token_hash, expires_at)
+    SELECT id, 'email_verification', encode(sha256(convert_to(verification_token, 'UTF8')), 'hex'), token_expiry FROM users
+    WHERE verified = FALSE AND verification_token <> '' AND token_expiry > timezone('UTC', now())
+    ON CONFLICT DO NOTHING;
+
+INSERT INTO auth_tokens (user_id, purpose, token_hash, expires_at)
+    SELECT id, 'password_reset', encode(sha256(convert_to(reset_password_token, 'UTF8')), 'hex'), reset_token_expiry FROM users
+    WHERE reset_password_token IS NOT NULL AND reset_password_token <> '' AND reset_token_expiry > timezone('UTC', now())
+    ON CONFLICT DO NOTHING;
+
+DROP INDEX IF EXISTS idx_verification_token;
+
+ALTER TABLE users DROP COLUMN verification_token;
+
+ALTER TABLE users DROP COLUMN verification_attempts;
+
+ALTER TABLE users DROP COLUMN token_expiry;
+
+ALTER TABLE users DROP COLUMN reset_password_token;
+
+ALTER TABLE users DROP COLUMN reset_token_expiry;
+
+ALTER TABLE users DROP COLUMN temp_token;
+
+ALTER TABLE users DROP COLUMN temp_token_expiry
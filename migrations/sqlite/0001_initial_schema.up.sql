@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username VARCHAR(255) NOT NULL UNIQUE,
+    email VARCHAR(255) NOT NULL UNIQUE,
+    password VARCHAR(255) NOT NULL,
+    verification_token VARCHAR(255) NOT NULL,
+    verified BOOLEAN NOT NULL DEFAULT FALSE,
+    verification_attempts BIGINT NOT NULL DEFAULT 0,
+    token_expiry TIMESTAMP NULL,
+    reset_password_token VARCHAR(255),
+    reset_token_expiry TIMESTAMP NULL,
+    has_2fa BOOLEAN NOT NULL DEFAULT FALSE,
+    two_fa_code VARCHAR(6),
+    two_fa_expiry TIMESTAMP NULL,
+    temp_2fa_code VARCHAR(6),
+    temp_token VARCHAR(36),
+    temp_token_expiry TIMESTAMP NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_username ON users(username);
+
+CREATE INDEX IF NOT EXISTS idx_email ON users(email);
+
+CREATE INDEX IF NOT EXISTS idx_verification_token ON users(verification_token);
+
+CREATE INDEX IF NOT EXISTS idx_2fa_code ON users(two_fa_code);
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS security_alert_tokens;
+DROP TABLE IF EXISTS known_devices;
+ALTER TABLE users DROP COLUMN password_reset_required;
@@ -0,0 +1,29 @@
+-- SQLite has no SHA-256 function, so unlike MySQL and PostgreSQL the outstanding verification and reset links
+-- are not carried over into auth_tokens: they stop working and users request new ones
+CREATE TABLE IF NOT EXISTS auth_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    purpose VARCHAR(32) NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMP NOT NULL,
+    consumed_at TIMESTAMP NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_tokens_user ON auth_tokens(user_id, purpose);
+
+DROP INDEX IF EXISTS idx_verification_token;
+
+ALTER TABLE users DROP COLUMN verification_token;
+
+ALTER TABLE users DROP COLUMN verification_attempts;
+
+ALTER TABLE users DROP COLUMN token_expiry;
+
+ALTER TABLE users DROP COLUMN reset_password_token;
+
+ALTER TABLE users DROP COLUMN reset_token_expiry;
+
+ALTER TABLE users DROP COLUMN temp_token;
+
+ALTER TABLE users DROP COLUMN temp_token_expiry
@@ -0,0 +1,2 @@
+ALTER TABLE sessions ADD COLUMN client_id VARCHAR(36) NULL;
+ALTER TABLE sessions ADD COLUMN scope VARCHAR(255) NULL;
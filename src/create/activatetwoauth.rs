@@ -1,28 +1,25 @@
 //activatetwoauth.rs
 
 use crate::create::common::*;
+use crate::create::authtokens;
+use crate::create::store::TokenPurpose;
 use crate::create::twoauth;
 
 #[post("/activate_2fa")]
 async fn activate_2fa(
-    pool: Data<Pool>,
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
     req: HttpRequest,
 ) -> Result<HttpResponse, ServiceError> {
-    let (email_addr, user_from_token) = extract_user_email_from_token(&req, &pool).await?;  // <-- Destructure the tuple
+    let user = extract_user_from_token(&req, store.get_ref()).await?;
     let code = twoauth::generate_2fa_code();
-    let temp_token = Uuid::new_v4().to_string();
+    let temp_token = authtokens::issue(store.get_ref(), config.get_ref(), &user.username, TokenPurpose::TwoFactorChange).await?;
 
-    send_2fa_email(&email_addr, "Your 2FA activation code", &format!("Here is your 2FA activation code: {}", code)).await?;
+    let locale = emails.locale(user.locale.as_deref(), Some(&req));
+    emails.send(&user.email, "2fa_activation", &locale, &[("code", &code)]).await?;
 
-    let mut conn = pool.get_conn().await.map_err(|e| {
-        error!("Error getting DB connection: {:?}", e);
-        ServiceError::InternalServerError
-    })?;
-
-    conn.exec_drop(
-        "UPDATE users SET temp_2fa_code = ?, temp_token = ? WHERE username = ?",
-        (&code, &temp_token, &user_from_token),
-    ).await.map_err(|_| ServiceError::InternalServerError)?;
+    store.start_2fa_change(&user.username, &code).await?;
 
     Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "2FA activation code sent. Check your email and submit the code to finalize activation.", "token": temp_token })))
 }
\ No newline at end of file
@@ -0,0 +1,180 @@
+// authorize.rs
+
+use crate::create::common::*;
+use crate::create::login;
+use crate::create::notifications;
+use crate::create::oauthclients;
+use crate::create::oidc;
+use crate::create::store::{AuthorizationCode, AuthorizationRequest as PendingAuthorization};
+use crate::create::tokens;
+#[cfg(feature = "two-factor")]
+use crate::create::twoauth;
+
+const AUTHORIZATION_REQUEST_TTL_MINUTES: i64 = 10;
+const AUTHORIZATION_CODE_TTL_SECONDS: i64 = 60;
+
+fn redirect_with_params(redirect_uri: &str, params: &[(&str, &str)]) -> Result<String, ServiceError> {
+    let mut url = url::Url::parse(redirect_uri).map_err(|_| ServiceError::InternalServerError)?;
+    {
+        let mut query = url.query_pairs_mut();
+        for (key, value) in params {
+            query.append_pair(key, value);
+        }
+    }
+    Ok(url.to_string())
+}
+
+fn redirect_error(redirect_uri: &str, error: &str, state: Option<&str>) -> Result<HttpResponse, ServiceError> {
+    let mut params = vec![("error", error)];
+    if let Some(state) = state {
+        params.push(("state", state));
+    }
+    let location = redirect_with_params(redirect_uri, &params)?;
+    Ok(HttpResponse::Found().insert_header((http::header::LOCATION, location)).finish())
+}
+
+async fn load_pending_authorization(
+    store: &dyn UserStore,
+    request_id: &str,
+) -> Result<PendingAuthorization, ServiceError> {
+    store
+        .find_authorization_request(request_id)
+        .await?
+        .ok_or(ServiceError::BadRequest("Unknown or expired authorization request.".to_string()))
+}
+
+// Turns an authenticated authorization request into a single-use code bound to the PKCE challenge
+async fn complete_authorization(
+    store: &dyn UserStore,
+    request_id: &str,
+    pending: PendingAuthorization,
+    username: &str,
+) -> Result<HttpResponse, ServiceError> {
+    let code: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(43)
+        .map(char::from)
+        .collect();
+
+    let expiry = Utc::now()
+        .checked_add_signed(Duration::seconds(AUTHORIZATION_CODE_TTL_SECONDS))
+        .ok_or(ServiceError::InternalServerError)?;
+
+    let authorization_code = AuthorizationCode {
+        client_id: pending.client_id.clone(),
+        username: username.to_string(),
+        redirect_uri: pending.redirect_uri.clone(),
+        scope: pending.scope.clone(),
+        nonce: pending.nonce.clone(),
+        code_challenge: pending.code_challenge.clone(),
+        auth_time: Utc::now().timestamp(),
+    };
+    store.insert_authorization_code(&tokens::hash_token(&code), &authorization_code, expiry.naive_utc()).await?;
+
+    store.delete_authorization_request(request_id).await?;
+
+    let mut params = vec![("code", code.as_str())];
+    if let Some(state) = &pending.state {
+        params.push(("state", state));
+    }
+    let redirect_to = redirect_with_params(&pending.redirect_uri, &params)?;
+    info!("Issued authorization code for user {} to client {}", username, pending.client_id);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "redirect_to": redirect_to})))
+}
+
+// Validates the request and sends the browser to the login page at OIDC_LOGIN_URL
+#[get("/authorize")]
+async fn authorize(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    query: Query<AuthorizeQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    let login_url = config.oidc.login_url.as_deref().ok_or_else(|| {
+        error!("OIDC_LOGIN_URL is not configured");
+        ServiceError::InternalServerError
+    })?;
+
+    // Never redirect to an unverified redirect_uri, answer directly instead
+    let client = oauthclients::find_client(store.get_ref(), &query.client_id).await?
+        .ok_or(ServiceError::BadRequest("Unknown client_id".to_string()))?;
+    if !client.allows_redirect_uri(&query.redirect_uri) {
+        return Err(ServiceError::BadRequest("redirect_uri is not registered for this client".to_string()));
+    }
+
+    let state = query.state.as_deref();
+    if query.response_type != "code" {
+        return redirect_error(&query.redirect_uri, "unsupported_response_type", state);
+    }
+    if !oidc::has_scope(&query.scope, "openid") {
+        return redirect_error(&query.redirect_uri, "invalid_scope", state);
+    }
+    let code_challenge = match (&query.code_challenge, query.code_challenge_method.as_deref()) {
+        (Some(challenge), Some("S256")) if !challenge.is_empty() => challenge,
+        _ => return redirect_error(&query.redirect_uri, "invalid_request", state),
+    };
+
+    let request_id = Uuid::new_v4().to_string();
+    let expiry = Utc::now()
+        .checked_add_signed(Duration::minutes(AUTHORIZATION_REQUEST_TTL_MINUTES))
+        .ok_or(ServiceError::InternalServerError)?;
+
+    let authorization_request = PendingAuthorization {
+        client_id: client.client_id.clone(),
+        redirect_uri: query.redirect_uri.clone(),
+        scope: query.scope.clone(),
+        state: query.state.clone(),
+        nonce: query.nonce.clone(),
+        code_challenge: code_challenge.clone(),
+        pending_username: None,
+    };
+    store.insert_authorization_request(&request_id, &authorization_request, expiry.naive_utc()).await?;
+
+    let location = redirect_with_params(login_url, &[("request_id", &request_id)])?;
+    Ok(HttpResponse::Found().insert_header((http::header::LOCATION, location)).finish())
+}
+
+#[post("/authorize/login")]
+async fn authorize_login(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<AuthorizeLoginRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let pending = load_pending_authorization(store.get_ref(), &info.request_id).await?;
+    let has_2fa = login::verify_credentials(store.get_ref(), emails.get_ref(), config.get_ref(), &info.username, &info.password, &client_ip(&req)).await?;
+
+    if has_2fa {
+        // Remember who passed the password step so the 2FA step cannot switch users
+        store.set_authorization_request_user(&info.request_id, &info.username).await?;
+
+        return login::second_factor_challenge(store.get_ref(), emails.get_ref(), config.get_ref(), &info.username).await;
+    }
+
+    notifications::check_login_device(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &info.username).await?;
+    complete_authorization(store.get_ref(), &info.request_id, pending, &info.username).await
+}
+
+#[cfg(feature = "two-factor")]
+#[post("/authorize/verify_2fa")]
+async fn authorize_verify_2fa(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<AuthorizeVerify2FARequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let pending = load_pending_authorization(store.get_ref(), &info.request_id).await?;
+    let pending_username = pending.pending_username.clone()
+        .ok_or(ServiceError::BadRequest("Authorization request is not waiting for 2FA.".to_string()))?;
+
+    // Checked before check_2fa, which burns the temp_token and counts failures against its owner
+    if twoauth::pending_login(store.get_ref(), &info.verification.temp_token).await?.username != pending_username {
+        return Err(ServiceError::BadRequest("Invalid temporary token.".to_string()));
+    }
+    let outcome = twoauth::check_2fa(store.get_ref(), config.get_ref(), &info.verification).await?;
+
+    notifications::check_login_device(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &outcome.username).await?;
+    complete_authorization(store.get_ref(), &info.request_id, pending, &outcome.username).await
+}
@@ -0,0 +1,52 @@
+// authtokens.rs
+
+use crate::create::common::*;
+use crate::create::store::{AuthToken, TokenPurpose};
+use crate::create::tokens;
+use subtle::ConstantTimeEq;
+
+// Sends back the plaintext token; only its SHA-256 digest is stored, valid for the TTL of its purpose
+pub async fn issue(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    purpose: TokenPurpose,
+) -> Result<String, ServiceError> {
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(43)
+        .map(char::from)
+        .collect();
+
+    let expiry = Utc::now()
+        .checked_add_signed(config.tokens.ttl(purpose))
+        .ok_or(ServiceError::InternalServerError)?;
+
+    store.insert_auth_token(username, purpose, &tokens::hash_token(&token), expiry.naive_utc()).await?;
+    Ok(token)
+}
+
+// For links and temp_tokens, where the token is all the request carries
+pub async fn find(store: &dyn UserStore, purpose: TokenPurpose, token: &str) -> Result<Option<AuthToken>, ServiceError> {
+    store.find_auth_token(purpose, &tokens::hash_token(token)).await
+}
+
+// When the request names the user, the digests are compared here in constant time instead of by the database
+#[cfg(any(feature = "two-factor", feature = "password-reset"))]
+pub fn matches(stored: &AuthToken, token: &str) -> bool {
+    same_secret(Some(&stored.token_hash), &tokens::hash_token(token))
+}
+
+// Also used for the emailed 2FA codes and OAuth client secrets
+pub fn same_secret(stored: Option<&str>, submitted: &str) -> bool {
+    stored.is_some_and(|stored| bool::from(stored.as_bytes().ct_eq(submitted.as_bytes())))
+}
+
+pub fn is_expired(token: &AuthToken) -> bool {
+    Utc::now().naive_utc() > token.expires_at
+}
+
+// Single use: false when the token was consumed already, possibly by a concurrent request
+pub async fn consume(store: &dyn UserStore, token: &AuthToken) -> Result<bool, ServiceError> {
+    store.consume_auth_token(token.id).await
+}
@@ -0,0 +1,103 @@
+// changeemail.rs
+
+use crate::create::common::*;
+use crate::create::authtokens;
+use crate::create::changepassword;
+use crate::create::store::TokenPurpose;
+
+const EMAIL_IN_USE: &str = "This email address is already in use.";
+
+// The new address gets a confirmation link and the current one a notice with a cancel link.
+// users.email only changes once the new address is confirmed.
+#[post("/change_email")]
+async fn change_email(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<ChangeEmailRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    info.validate().map_err(|e| {
+        error!("Validation error: {:?}", e);
+        ServiceError::BadRequest("Invalid input data.".to_string())
+    })?;
+    let user = extract_user_from_token(&req, store.get_ref()).await?;
+    changepassword::verify_current_password(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &user, &info.current_password).await?;
+
+    let new_email = info.new_email.trim();
+    if new_email.eq_ignore_ascii_case(&user.email) {
+        return Err(ServiceError::BadRequest("This is already your email address.".to_string()));
+    }
+    if store.find_user_by_email(new_email).await?.is_some() {
+        return Err(ServiceError::BadRequest(EMAIL_IN_USE.to_string()));
+    }
+
+    // A new request replaces the pending one and its links
+    store.start_email_change(&user.username, new_email).await?;
+    let confirm_token = authtokens::issue(store.get_ref(), config.get_ref(), &user.username, TokenPurpose::EmailChange).await?;
+    let cancel_token = authtokens::issue(store.get_ref(), config.get_ref(), &user.username, TokenPurpose::EmailChangeCancel).await?;
+
+    let locale = emails.locale(user.locale.as_deref(), Some(&req));
+    let confirm_link = format!("{}/confirm_email_change?token={}", config.links.email_change_base_url, confirm_token);
+    emails.send(new_email, "email_change_confirm", &locale, &[("link", &confirm_link)]).await?;
+    let cancel_link = format!("{}/cancel_email_change?token={}", config.links.email_change_base_url, cancel_token);
+    emails.send(&user.email, "email_change_notice", &locale, &[("new_email", new_email), ("link", &cancel_link)]).await?;
+    info!("Email change requested for user: {}", user.username);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "Check the new address for the confirmation link."})))
+}
+
+#[get("/confirm_email_change")]
+async fn confirm_email_change(
+    store: Data<dyn UserStore>,
+    query: Query<VerifyQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    let token = authtokens::find(store.get_ref(), TokenPurpose::EmailChange, &query.token)
+        .await?
+        .filter(|token| token.consumed_at.is_none())
+        .ok_or(ServiceError::BadRequest("Invalid confirmation token".to_string()))?;
+    if authtokens::is_expired(&token) {
+        return Err(ServiceError::BadRequest("Confirmation token has expired".to_string()));
+    }
+    let new_email = store
+        .find_email_change(&token.username)
+        .await?
+        .ok_or(ServiceError::BadRequest("No pending email change".to_string()))?;
+    if !authtokens::consume(store.get_ref(), &token).await? {
+        return Err(ServiceError::BadRequest("Invalid confirmation token".to_string()));
+    }
+
+    // Someone may have registered with the address since it was requested
+    let applied = store.find_user_by_email(&new_email).await?.is_none()
+        && store.apply_email_change(&token.username, &new_email).await?;
+    store.clear_email_change(&token.username).await?;
+    if !applied {
+        error!("Email change of user {} failed, the address is taken", token.username);
+        return Err(ServiceError::BadRequest(EMAIL_IN_USE.to_string()));
+    }
+    info!("Email changed for user: {}", token.username);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "Email changed successfully"})))
+}
+
+#[get("/cancel_email_change")]
+async fn cancel_email_change(
+    store: Data<dyn UserStore>,
+    query: Query<VerifyQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    let token = authtokens::find(store.get_ref(), TokenPurpose::EmailChangeCancel, &query.token)
+        .await?
+        .filter(|token| token.consumed_at.is_none())
+        .ok_or(ServiceError::BadRequest("Invalid cancel token".to_string()))?;
+    if authtokens::is_expired(&token) {
+        return Err(ServiceError::BadRequest("Cancel token has expired".to_string()));
+    }
+    if !authtokens::consume(store.get_ref(), &token).await? {
+        return Err(ServiceError::BadRequest("Invalid cancel token".to_string()));
+    }
+
+    store.clear_email_change(&token.username).await?;
+    info!("Email change cancelled for user: {}", token.username);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "Email change cancelled"})))
+}
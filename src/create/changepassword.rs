@@ -0,0 +1,151 @@
+// changepassword.rs
+
+use crate::create::common::*;
+use crate::create::lockout;
+use crate::create::notifications::{self, SecurityEvent};
+use crate::create::passwordhash::PasswordHasher;
+use crate::create::sessions;
+use crate::create::store::User;
+#[cfg(feature = "two-factor")]
+use crate::create::{authtokens, store::TokenPurpose, totp, twoauth};
+#[cfg(not(feature = "two-factor"))]
+use crate::create::login;
+
+// Guesses at the current password count towards the login lockout
+pub async fn verify_current_password(
+    store: &dyn UserStore,
+    emails: &Emails,
+    config: &AppConfig,
+    req: &HttpRequest,
+    user: &User,
+    password: &str,
+) -> Result<(), ServiceError> {
+    let ip = client_ip(req);
+    lockout::check_login_allowed(store, &user.username, &ip).await?;
+    if !PasswordHasher::new(&config.hashing).verify(password, &user.password_hash).await?.valid {
+        error!("Current password mismatch for user: {}", user.username);
+        lockout::record_login_failure(store, emails, config, &user.username, &ip).await?;
+        return Err(ServiceError::BadRequest("Current password is incorrect.".to_string()));
+    }
+    Ok(())
+}
+
+// TOTP users send their current code along. Email users first get a code, then repeat the request with it
+// and the returned token. Returns the response to send when a code had to be emailed first.
+#[cfg(feature = "two-factor")]
+async fn check_second_factor(
+    store: &dyn UserStore,
+    emails: &Emails,
+    config: &AppConfig,
+    req: &HttpRequest,
+    user: &User,
+    info: &ChangePasswordRequest,
+) -> Result<Option<HttpResponse>, ServiceError> {
+    let verification = if user.two_fa_method == "totp" {
+        let code = info.code.as_deref().ok_or(ServiceError::BadRequest("2FA code required.".to_string()))?;
+        totp::verify_user_code(store, config, &user.username, code).await
+    } else {
+        match (&info.code, &info.token) {
+            (Some(code), Some(token)) => confirm_emailed_code(store, &user.username, code, token).await,
+            _ => return send_2fa_code(store, emails, config, req, user).await.map(Some),
+        }
+    };
+
+    // Wrong codes count towards login.two_fa_max_attempts, as on /verify_2fa
+    match verification {
+        Ok(()) => Ok(None),
+        Err(ServiceError::BadRequest(message)) => {
+            lockout::record_2fa_failure(store, config, &user.username).await?;
+            Err(ServiceError::BadRequest(message))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(feature = "two-factor"))]
+async fn check_second_factor(
+    _store: &dyn UserStore,
+    _emails: &Emails,
+    _config: &AppConfig,
+    _req: &HttpRequest,
+    user: &User,
+    _info: &ChangePasswordRequest,
+) -> Result<Option<HttpResponse>, ServiceError> {
+    Err(login::second_factor_unavailable(&user.username))
+}
+
+// The token and code are scoped to password changes, so they can't complete a 2FA activation or deactivation
+#[cfg(feature = "two-factor")]
+async fn confirm_emailed_code(store: &dyn UserStore, username: &str, code: &str, token: &str) -> Result<(), ServiceError> {
+    let stored_token = store
+        .find_user_auth_token(username, TokenPurpose::PasswordChange)
+        .await?
+        .ok_or(ServiceError::BadRequest("No pending 2FA code. Send the request without a code to get one.".to_string()))?;
+    let stored_code = store.find_password_change(username).await?;
+
+    if !authtokens::matches(&stored_token, token) || !authtokens::same_secret(stored_code.as_deref(), code) {
+        return Err(ServiceError::BadRequest("Invalid code or token".to_string()));
+    }
+    if authtokens::is_expired(&stored_token) {
+        return Err(ServiceError::BadRequest("Token has expired".to_string()));
+    }
+    if !authtokens::consume(store, &stored_token).await? {
+        return Err(ServiceError::BadRequest("Invalid code or token".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "two-factor")]
+async fn send_2fa_code(store: &dyn UserStore, emails: &Emails, config: &AppConfig, req: &HttpRequest, user: &User) -> Result<HttpResponse, ServiceError> {
+    let code = twoauth::generate_2fa_code();
+    let temp_token = authtokens::issue(store, config, &user.username, TokenPurpose::PasswordChange).await?;
+
+    let locale = emails.locale(user.locale.as_deref(), Some(req));
+    emails.send(&user.email, "2fa_code", &locale, &[("code", &code)]).await?;
+
+    store.start_password_change(&user.username, &code).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "status": "2fa_required",
+        "message": "2FA code sent. Repeat the request with the code and this token.",
+        "token": temp_token
+    })))
+}
+
+
+#[post("/change_password")]
+async fn change_password(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    policy: Data<PasswordPolicy>,
+    req: HttpRequest,
+    info: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    // The session making the request stays signed in
+    let claims = extract_claims_from_token(&req, store.get_ref()).await?;
+    let user = store.find_user(&claims.sub).await?.ok_or(ServiceError::BadRequest("User not found".to_string()))?;
+    let username = user.username.clone();
+
+    verify_current_password(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &user, &info.current_password).await?;
+
+    // Before the 2FA step, so a rejected password does not cost a code
+    policy.check(&info.new_password, &username, &user.email)?;
+
+    if user.has_2fa {
+        if let Some(response) = check_second_factor(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &user, &info).await? {
+            return Ok(response);
+        }
+    }
+
+    let hashed_password = PasswordHasher::new(&config.hashing).hash(&info.new_password).await?;
+    if !store.update_password_hash(&username, &user.password_hash, &hashed_password).await? {
+        return Err(ServiceError::BadRequest("The password was changed by another request. Try again.".to_string()));
+    }
+    info!("Password changed for user: {}", username);
+
+    sessions::revoke_other_sessions(store.get_ref(), &username, &claims.jti).await?;
+    notifications::notify(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &username, SecurityEvent::PasswordChanged).await;
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "Password changed. Other sessions were signed out."})))
+}
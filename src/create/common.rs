@@ -15,9 +15,6 @@ pub use serde_json::json;
 // chrono
 pub use chrono::{Utc, Duration, NaiveDateTime};
 
-// bcrypt
-pub use bcrypt::{DEFAULT_COST, hash};
-
 // jsonwebtoken
 pub use jsonwebtoken::{encode, EncodingKey, Header, Validation, decode, DecodingKey};
 
@@ -25,11 +22,20 @@ pub use jsonwebtoken::{encode, EncodingKey, Header, Validation, decode, Decoding
 pub use thiserror::Error;
 pub use rand::{Rng, distributions::Alphanumeric};
 pub use validator::Validate;
-pub use lettre::{Message, SmtpTransport, Transport, transport::smtp::authentication::Credentials};
-pub use log::{info, error, debug};
+pub use lettre::{Message, transport::smtp::authentication::Credentials};
+pub use log::{info, error, warn};
 pub use std::env;
 pub use uuid::Uuid;
 
+pub use crate::create::store::UserStore;
+pub use crate::create::mailer::{Emails, Mailer};
+pub use crate::create::config::AppConfig;
+pub use crate::create::passwordpolicy::{PasswordPolicy, PolicyFailure};
+
+use crate::create::sessions;
+use crate::create::jwks;
+use crate::create::store::User;
+
 
 impl ResponseError for ServiceError {
     fn error_response(&self) -> HttpResponse {
@@ -37,6 +43,11 @@ impl ResponseError for ServiceError {
             ServiceError::InternalServerError => HttpResponse::InternalServerError().json("Internal Server Error"),
             ServiceError::BadRequest(ref message) => HttpResponse::BadRequest().json(message),
             ServiceError::Unauthorized(ref message) => HttpResponse::Unauthorized().json(message),
+            ServiceError::TooManyRequests(retry_after) => HttpResponse::TooManyRequests()
+                .insert_header((http::header::RETRY_AFTER, retry_after.to_string()))
+                .json(format!("Too many attempts, try again in {} seconds", retry_after)),
+            ServiceError::PasswordRejected(ref failures) => HttpResponse::BadRequest()
+                .json(json!({"error": "Password does not meet the policy", "reasons": failures})),
         }
     }
 }
@@ -47,20 +58,26 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+#[cfg(feature = "two-factor")]
 #[derive(Deserialize)]
 pub struct Verify2FARequest {
     pub temp_token: String,
+    #[serde(default)]
     pub code: String,
+    pub recovery_code: Option<String>,
 }
 
+#[cfg(feature = "registration")]
 #[derive(Deserialize, Validate)]
 pub struct RegisterRequest {
     #[validate(length(min = 3))]
     pub username: String,
     #[validate(email)]
     pub email: String,
-    #[validate(length(min = 6))]
+    // Checked against the password policy instead
     pub password: String,
+    // Language of the emails; Accept-Language decides when it is missing
+    pub locale: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -71,6 +88,10 @@ pub enum ServiceError {
     BadRequest(String),
     #[error("Unauthorized: {0}")]
     Unauthorized(String),
+    #[error("Too Many Requests, retry after {0}s")]
+    TooManyRequests(i64),
+    #[error("Password rejected by the policy")]
+    PasswordRejected(Vec<PolicyFailure>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -78,8 +99,15 @@ pub struct Claims {
     pub sub: String,
     pub exp: usize,
     pub has_2fa: bool,
+    pub jti: String,
+    // Only on tokens issued to OAuth clients: the client id and the granted scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
 }
 
+#[cfg(feature = "email-verification")]
 #[derive(Deserialize)]
 pub struct ResendVerificationRequest {
     pub email: String,
@@ -90,11 +118,13 @@ pub struct VerifyQuery {
     pub token: String,
 }
 
+#[cfg(feature = "password-reset")]
 #[derive(Deserialize)]
 pub struct ForgotPasswordRequest {
     pub email: String,
 }
 
+#[cfg(feature = "password-reset")]
 #[derive(Deserialize)]
 pub struct ResetPasswordRequest {
     pub email: String,
@@ -102,6 +132,30 @@ pub struct ResetPasswordRequest {
     pub new_password: String,
 }
 
+#[derive(Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ChangeEmailRequest {
+    #[validate(email)]
+    pub new_email: String,
+    pub current_password: String,
+}
+
+// `code` is the TOTP code, or the emailed code together with the `token` of the first request
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+    #[cfg(feature = "two-factor")]
+    pub code: Option<String>,
+    #[cfg(feature = "two-factor")]
+    pub token: Option<String>,
+}
+
+#[cfg(feature = "two-factor")]
 #[derive(Deserialize)]
 pub struct TwoFAVerificationRequest {
     pub username: String,
@@ -109,77 +163,185 @@ pub struct TwoFAVerificationRequest {
     pub token: String,
 }
 
-pub async fn send_2fa_email(
-    email_addr: &str,
-    subject: &str,
-    body: &str,
-) -> Result<(), ServiceError> {
-    let smtp_email = env::var("SMTP_EMAIL").expect("SMTP_EMAIL is not set in .env");
-    let smtp_password = env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD is not set in .env");
-    let smtp_server = env::var("SMTP_SERVER").expect("SMTP_SERVER is not set in .env");
+#[derive(Deserialize)]
+pub struct AuthorizeQuery {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub response_type: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AuthorizeLoginRequest {
+    pub request_id: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[cfg(feature = "two-factor")]
+#[derive(Deserialize)]
+pub struct AuthorizeVerify2FARequest {
+    pub request_id: String,
+    #[serde(flatten)]
+    pub verification: Verify2FARequest,
+}
 
-    let email = Message::builder()
-        .to(email_addr.parse().unwrap())
-        .from(smtp_email.parse().unwrap())
-        .subject(subject)
-        .body(body.to_string()) 
-        .map_err(|_| ServiceError::InternalServerError)?;
+#[derive(Deserialize)]
+pub struct OAuthTokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub refresh_token: Option<String>,
+}
 
-    let credentials = Credentials::new(
-        smtp_email,
-        smtp_password
-    );
+#[derive(Deserialize)]
+pub struct ClientRegistrationRequest {
+    pub client_name: String,
+    pub redirect_uris: Vec<String>,
+    pub token_endpoint_auth_method: Option<String>,
+}
 
-    let mailer = SmtpTransport::relay(&smtp_server)
-        .unwrap()
-        .credentials(credentials)
-        .build();
+#[derive(Deserialize)]
+pub struct SocialCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
 
-    mailer.send(&email)
-        .map_err(|_| ServiceError::InternalServerError)?;
-    
-    Ok(()) 
+#[derive(Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+    pub device_fingerprint: Option<String>,
 }
 
-pub async fn extract_user_email_from_token(
-    req: &HttpRequest,
-    pool: &Data<Pool>,
-) -> Result<(String, String), ServiceError> {
-    let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET is not set in .env");
-    let auth_header = req.headers().get(http::header::AUTHORIZATION);
+#[derive(Deserialize)]
+pub struct MagicLinkLoginRequest {
+    pub token: String,
+    pub device_fingerprint: Option<String>,
+}
 
-    if auth_header.is_none() {
-        return Err(ServiceError::Unauthorized("No authorization header".to_string()));
+#[derive(Deserialize)]
+pub struct WebAuthnCredential {
+    pub id: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: Option<String>,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: Option<String>,
+    pub signature: Option<String>,
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WebAuthnRegisterFinishRequest {
+    pub challenge_id: String,
+    pub name: Option<String>,
+    pub credential: WebAuthnCredential,
+}
+
+#[derive(Deserialize)]
+pub struct WebAuthnLoginStartRequest {
+    pub username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WebAuthnLoginFinishRequest {
+    pub challenge_id: String,
+    pub credential: WebAuthnCredential,
+}
+
+#[cfg(feature = "two-factor")]
+#[derive(Deserialize)]
+pub struct WebAuthn2FAStartRequest {
+    pub temp_token: String,
+}
+
+#[cfg(feature = "two-factor")]
+#[derive(Deserialize)]
+pub struct WebAuthn2FAFinishRequest {
+    pub temp_token: String,
+    pub challenge_id: String,
+    pub credential: WebAuthnCredential,
+}
+
+// X-Forwarded-For is only honoured behind a proxy we control, otherwise anyone could pick their IP
+pub fn client_ip(req: &HttpRequest) -> String {
+    let trust_proxy = req.app_data::<Data<AppConfig>>().is_some_and(|config| config.server.trust_proxy_headers);
+    if trust_proxy {
+        if let Some(ip) = req.connection_info().realip_remote_addr() {
+            return ip.to_string();
+        }
     }
+    req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+}
 
-    let token_str_full = auth_header.unwrap().to_str().unwrap();
+// Any valid access token of an active session, including those issued to OAuth clients
+pub async fn extract_access_token_claims(
+    req: &HttpRequest,
+    store: &dyn UserStore,
+) -> Result<Claims, ServiceError> {
+    let Some(auth_header) = req.headers().get(http::header::AUTHORIZATION) else {
+        return Err(ServiceError::Unauthorized("No authorization header".to_string()));
+    };
+
+    let token_str_full = auth_header
+        .to_str()
+        .map_err(|_| ServiceError::Unauthorized("Invalid authorization header format".to_string()))?;
     let token_parts: Vec<&str> = token_str_full.split_whitespace().collect();
     if token_parts.len() != 2 || token_parts[0] != "Bearer" {
         return Err(ServiceError::Unauthorized("Invalid authorization header format".to_string()));
     }
     let token_str = token_parts[1];
 
-    let token_data = decode::<Claims>(&token_str, &DecodingKey::from_secret(jwt_secret.as_ref()), &Validation::default())
-    .map_err(|e| {
-        error!("Error decoding JWT: {:?}", e);
-        ServiceError::Unauthorized("Invalid token".to_string())
+    let config = req.app_data::<Data<AppConfig>>().ok_or_else(|| {
+        error!("AppConfig is missing from the app data");
+        ServiceError::InternalServerError
     })?;
+    let claims: Claims = jwks::verify(&config.jwt, token_str)?;
 
-    let user_from_token = token_data.claims.sub;
+    if !sessions::is_session_active(store, &claims.jti).await? {
+        return Err(ServiceError::Unauthorized("Session has been revoked".to_string()));
+    }
 
-    let mut conn = pool.get_conn().await.map_err(|e| {
-        error!("Error getting DB connection: {:?}", e);
-        ServiceError::InternalServerError
-    })?;
+    Ok(claims)
+}
+
+// The API's own endpoints: tokens issued to OAuth clients only work at /userinfo
+pub async fn extract_claims_from_token(
+    req: &HttpRequest,
+    store: &dyn UserStore,
+) -> Result<Claims, ServiceError> {
+    let claims = extract_access_token_claims(req, store).await?;
+    if claims.aud.is_some() {
+        return Err(ServiceError::Unauthorized("This token was issued to a client application".to_string()));
+    }
 
-    let user_email: Option<String> = conn
-        .exec_first("SELECT email FROM users WHERE username = ?", (&user_from_token,))
-        .await
-        .map_err(|e| {
-            error!("Error executing DB query: {:?}", e);
-            ServiceError::InternalServerError
-        })?;
+    Ok(claims)
+}
+
+pub async fn extract_user_from_token(
+    req: &HttpRequest,
+    store: &dyn UserStore,
+) -> Result<User, ServiceError> {
+    let user_from_token = extract_claims_from_token(req, store).await?.sub;
 
-    Ok((user_email.ok_or(ServiceError::BadRequest("User not found".to_string()))?, user_from_token))
+    store.find_user(&user_from_token).await?.ok_or(ServiceError::BadRequest("User not found".to_string()))
 }
 
+pub async fn extract_user_email_from_token(
+    req: &HttpRequest,
+    store: &dyn UserStore,
+) -> Result<(String, String), ServiceError> {
+    let user = extract_user_from_token(req, store).await?;
+
+    Ok((user.email, user.username))
+}
@@ -0,0 +1,719 @@
+// config.rs
+
+use crate::create::common::*;
+use crate::create::jwks::{self, KeyRing};
+use crate::create::outbox::OutboxSettings;
+use crate::create::ratelimit::{self, RouteRule};
+use crate::create::socialidp::SocialProvider;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use crate::create::store::TokenPurpose;
+use std::path::Path;
+use std::str::FromStr;
+
+// Everything the server needs to run, read and checked once at startup and shared as app data.
+// Each setting comes from its environment variable, else from the TOML file, else a default.
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub links: LinksConfig,
+    pub features: FeaturesConfig,
+    pub login: LoginConfig,
+    pub tokens: TokensConfig,
+    pub jwt: JwtConfig,
+    #[cfg(feature = "two-factor")]
+    pub totp: TotpConfig,
+    pub webauthn: WebAuthnConfig,
+    pub oidc: OidcConfig,
+    pub social: SocialConfig,
+    pub password: PasswordConfig,
+    pub hashing: HashingConfig,
+    pub mail: MailConfig,
+    pub templates: TemplatesConfig,
+    pub outbox: OutboxSettings,
+    pub rate_limits: RateLimitConfig,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsBackend {
+    Rustls,
+    Openssl,
+    // Plain HTTP, for running behind a TLS-terminating proxy
+    None,
+}
+
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub tls: TlsBackend,
+    pub cors_origins: Vec<String>,
+    #[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+    pub tls_cert_file: String,
+    #[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+    pub tls_key_file: String,
+    pub trust_proxy_headers: bool,
+}
+
+pub struct DatabaseConfig {
+    pub url: String,
+    // Overrides the database of the URL
+    pub name: Option<String>,
+    pub migrate_on_startup: bool,
+}
+
+// Where the links sent by email point to
+pub struct LinksConfig {
+    #[cfg(any(feature = "registration", feature = "email-verification"))]
+    pub verification_base_url: String,
+    #[cfg(feature = "password-reset")]
+    pub reset_password_base_url: String,
+    pub unlock_account_base_url: String,
+    pub magic_link_base_url: String,
+    pub security_alert_base_url: String,
+    pub email_change_base_url: String,
+}
+
+// Subsystems that can be turned off; a subsystem left out of the build is always off
+#[derive(Clone, Copy)]
+pub struct FeaturesConfig {
+    pub registration: bool,
+    pub email_verification: bool,
+    pub password_reset: bool,
+    pub two_factor: bool,
+    pub magic_link: bool,
+}
+
+pub struct LoginConfig {
+    pub free_attempts: i64,
+    pub ip_free_attempts: i64,
+    pub lockout_threshold: i64,
+    pub lockout_minutes: i64,
+    #[cfg(feature = "two-factor")]
+    pub two_fa_max_attempts: i64,
+    pub magic_link_ttl_minutes: i64,
+}
+
+// How long each kind of auth token stays valid, in minutes unless named otherwise
+pub struct TokensConfig {
+    pub access_token_ttl_minutes: i64,
+    pub refresh_token_ttl_days: i64,
+    #[cfg(any(feature = "registration", feature = "email-verification"))]
+    pub email_verification_ttl_minutes: i64,
+    #[cfg(feature = "password-reset")]
+    pub password_reset_ttl_minutes: i64,
+    #[cfg(feature = "two-factor")]
+    pub two_factor_ttl_minutes: i64,
+    #[cfg(feature = "two-factor")]
+    pub two_factor_change_ttl_minutes: i64,
+    #[cfg(feature = "two-factor")]
+    pub password_change_ttl_minutes: i64,
+    #[cfg(feature = "two-factor")]
+    pub totp_enrollment_ttl_minutes: i64,
+    // Both the confirmation link and the cancel link
+    pub email_change_ttl_minutes: i64,
+}
+
+// What /create_account, /reset_password and /change_password accept as a new password
+#[derive(Clone)]
+pub struct PasswordConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    // 0 (guessable in a few tries) to 4 (very unguessable), like zxcvbn
+    pub min_strength: u8,
+    // Rejects passwords containing the username or the local part of the email
+    pub reject_personal_info: bool,
+    // SHA1:COUNT lines, as in the Have I Been Pwned downloads
+    pub breached_passwords_file: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashAlgorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+// How new password hashes are made; older hashes are upgraded at the next successful login
+#[derive(Clone, Copy)]
+pub struct HashingConfig {
+    pub algorithm: HashAlgorithm,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub bcrypt_cost: u32,
+}
+
+// With no keys listed every token is HS256 with the secret and carries no `kid`
+pub struct JwtConfig {
+    pub secret: Option<String>,
+    pub keys: KeyRing,
+}
+
+#[cfg(feature = "two-factor")]
+pub struct TotpConfig {
+    pub issuer: String,
+    // Accepted clock skew, in 30-second time steps
+    pub skew_back: i64,
+    pub skew_forward: i64,
+    // AES-256-GCM key for the stored secrets
+    pub encryption_key: Vec<u8>,
+    // HMAC-SHA256 key for the stored recovery code digests
+    pub recovery_code_key: Vec<u8>,
+}
+
+// Passkeys answer with an error until the relying party is configured
+pub struct WebAuthnConfig {
+    pub rp_id: Option<String>,
+    pub rp_name: String,
+    // Without a trailing slash, as browsers report them
+    pub origins: Vec<String>,
+}
+
+// The OpenID Connect provider endpoints answer with an error while the issuer is unset
+pub struct OidcConfig {
+    // Without a trailing slash
+    pub issuer: Option<String>,
+    pub login_url: Option<String>,
+    // Client registration is disabled while unset
+    pub registration_token: Option<String>,
+}
+
+// The defaults of ratelimit::route_limits(), with the configured rules laid over them
+pub struct RateLimitConfig {
+    pub rules: Vec<RouteRule>,
+}
+
+pub struct SocialConfig {
+    pub providers: Vec<SocialProvider>,
+}
+
+impl TokensConfig {
+    pub fn access_token_ttl(&self) -> Duration {
+        Duration::minutes(self.access_token_ttl_minutes)
+    }
+
+    pub fn refresh_token_ttl(&self) -> Duration {
+        Duration::days(self.refresh_token_ttl_days)
+    }
+
+    pub fn ttl(&self, purpose: TokenPurpose) -> Duration {
+        Duration::minutes(match purpose {
+            #[cfg(any(feature = "registration", feature = "email-verification"))]
+            TokenPurpose::EmailVerification => self.email_verification_ttl_minutes,
+            #[cfg(feature = "password-reset")]
+            TokenPurpose::PasswordReset => self.password_reset_ttl_minutes,
+            #[cfg(feature = "two-factor")]
+            TokenPurpose::TwoFactorLogin => self.two_factor_ttl_minutes,
+            #[cfg(feature = "two-factor")]
+            TokenPurpose::TwoFactorChange => self.two_factor_change_ttl_minutes,
+            #[cfg(feature = "two-factor")]
+            TokenPurpose::PasswordChange => self.password_change_ttl_minutes,
+            #[cfg(feature = "two-factor")]
+            TokenPurpose::TotpEnrollment => self.totp_enrollment_ttl_minutes,
+            TokenPurpose::EmailChange | TokenPurpose::EmailChangeCancel => self.email_change_ttl_minutes,
+        })
+    }
+}
+
+#[derive(PartialEq, Eq)]
+pub enum MailTransport {
+    Smtp,
+    File,
+    Stdout,
+}
+
+pub struct MailConfig {
+    pub transport: MailTransport,
+    pub from: String,
+    pub dir: String,
+    pub smtp: SmtpConfig,
+}
+
+pub struct SmtpConfig {
+    pub server: String,
+    // tls, starttls or plain
+    pub security: String,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub pool_size: u32,
+}
+
+pub struct TemplatesConfig {
+    pub dir: String,
+    pub default_locale: String,
+    pub product_name: String,
+    pub logo_url: Option<String>,
+}
+
+impl AppConfig {
+    pub fn load() -> Result<Self, Vec<String>> {
+        Self::from_sources(config_file()?)
+    }
+
+    pub fn from_sources(file: toml::Table) -> Result<Self, Vec<String>> {
+        let mut source = Source { file, errors: Vec::new() };
+
+        let default_tls = if cfg!(feature = "tls-rustls") {
+            "rustls"
+        } else if cfg!(feature = "tls-openssl") {
+            "openssl"
+        } else {
+            "none"
+        };
+        let tls = match source.string("TLS_BACKEND", "server.tls", default_tls).as_str() {
+            "rustls" if cfg!(feature = "tls-rustls") => TlsBackend::Rustls,
+            "openssl" if cfg!(feature = "tls-openssl") => TlsBackend::Openssl,
+            "none" => TlsBackend::None,
+            other @ ("rustls" | "openssl") => {
+                source.errors.push(format!("TLS_BACKEND (server.tls) is {} but the server was built without the tls-{} feature", other, other));
+                TlsBackend::None
+            },
+            other => {
+                source.errors.push(format!("TLS_BACKEND (server.tls) must be rustls, openssl or none, got {}", other));
+                TlsBackend::None
+            },
+        };
+        let server = ServerConfig {
+            bind_address: source.string("BIND_ADDRESS", "server.bind_address", "0.0.0.0:8084"),
+            tls,
+            cors_origins: source.list("CORS_ALLOWED_ORIGINS", "server.cors_origins", &["https://192.168.0.39:8084"]),
+            #[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+            tls_cert_file: source.string("TLS_CERT_FILE", "server.tls_cert_file", "cert.pem"),
+            #[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+            tls_key_file: source.string("TLS_KEY_FILE", "server.tls_key_file", "key.pem"),
+            trust_proxy_headers: source.parse("TRUST_PROXY_HEADERS", "server.trust_proxy_headers", false),
+        };
+        if server.bind_address.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err()) {
+            source.errors.push(format!("BIND_ADDRESS (server.bind_address) needs a host and a port, got {}", server.bind_address));
+        }
+        for origin in &server.cors_origins {
+            source.check_url("CORS_ALLOWED_ORIGINS (server.cors_origins)", origin);
+        }
+
+        let database = DatabaseConfig::from_source(&mut source);
+
+        let features = FeaturesConfig {
+            registration: source.feature("REGISTRATION_ENABLED", "features.registration", "registration", cfg!(feature = "registration"), true),
+            email_verification: source.feature(
+                "EMAIL_VERIFICATION_ENABLED",
+                "features.email_verification",
+                "email-verification",
+                cfg!(feature = "email-verification"),
+                false,
+            ),
+            password_reset: source.feature("PASSWORD_RESET_ENABLED", "features.password_reset", "password-reset", cfg!(feature = "password-reset"), true),
+            two_factor: source.feature("TWO_FACTOR_ENABLED", "features.two_factor", "two-factor", cfg!(feature = "two-factor"), true),
+            magic_link: source.parse("MAGIC_LINK_ENABLED", "features.magic_link", true),
+        };
+
+        // Each link is only needed while the subsystem that sends it is on
+        let links = LinksConfig {
+            #[cfg(any(feature = "registration", feature = "email-verification"))]
+            verification_base_url: source.url_if(features.email_verification, "VERIFICATION_BASE_URL", "links.verification_base_url"),
+            #[cfg(feature = "password-reset")]
+            reset_password_base_url: source.url_if(features.password_reset, "RESET_PASSWORD_BASE_URL", "links.reset_password_base_url"),
+            unlock_account_base_url: source.url("UNLOCK_ACCOUNT_BASE_URL", "links.unlock_account_base_url"),
+            magic_link_base_url: source.url_if(features.magic_link, "MAGIC_LINK_BASE_URL", "links.magic_link_base_url"),
+            security_alert_base_url: source.url("SECURITY_ALERT_BASE_URL", "links.security_alert_base_url"),
+            email_change_base_url: source.url("EMAIL_CHANGE_BASE_URL", "links.email_change_base_url"),
+        };
+
+        let login = LoginConfig {
+            free_attempts: source.parse("LOGIN_FREE_ATTEMPTS", "login.free_attempts", 3),
+            ip_free_attempts: source.parse("LOGIN_IP_FREE_ATTEMPTS", "login.ip_free_attempts", 20),
+            lockout_threshold: source.parse("LOGIN_LOCKOUT_THRESHOLD", "login.lockout_threshold", 10),
+            lockout_minutes: source.parse("LOGIN_LOCKOUT_MINUTES", "login.lockout_minutes", 30),
+            #[cfg(feature = "two-factor")]
+            two_fa_max_attempts: source.parse("TWO_FA_MAX_ATTEMPTS", "login.two_fa_max_attempts", 5),
+            magic_link_ttl_minutes: source.parse("MAGIC_LINK_TTL_MINUTES", "login.magic_link_ttl_minutes", 15),
+        };
+
+        let tokens = TokensConfig {
+            access_token_ttl_minutes: source.parse("ACCESS_TOKEN_TTL_MINUTES", "tokens.access_token_ttl_minutes", 15),
+            refresh_token_ttl_days: source.parse("REFRESH_TOKEN_TTL_DAYS", "tokens.refresh_token_ttl_days", 30),
+            #[cfg(any(feature = "registration", feature = "email-verification"))]
+            email_verification_ttl_minutes: source.parse("EMAIL_VERIFICATION_TTL_MINUTES", "tokens.email_verification_ttl_minutes", 1440),
+            #[cfg(feature = "password-reset")]
+            password_reset_ttl_minutes: source.parse("PASSWORD_RESET_TTL_MINUTES", "tokens.password_reset_ttl_minutes", 60),
+            #[cfg(feature = "two-factor")]
+            two_factor_ttl_minutes: source.parse("TWO_FACTOR_TTL_MINUTES", "tokens.two_factor_ttl_minutes", 10),
+            #[cfg(feature = "two-factor")]
+            two_factor_change_ttl_minutes: source.parse("TWO_FACTOR_CHANGE_TTL_MINUTES", "tokens.two_factor_change_ttl_minutes", 15),
+            #[cfg(feature = "two-factor")]
+            password_change_ttl_minutes: source.parse("PASSWORD_CHANGE_TTL_MINUTES", "tokens.password_change_ttl_minutes", 15),
+            #[cfg(feature = "two-factor")]
+            totp_enrollment_ttl_minutes: source.parse("TOTP_ENROLLMENT_TTL_MINUTES", "tokens.totp_enrollment_ttl_minutes", 15),
+            email_change_ttl_minutes: source.parse("EMAIL_CHANGE_TTL_MINUTES", "tokens.email_change_ttl_minutes", 1440),
+        };
+        if tokens.access_token_ttl_minutes <= 0 || tokens.refresh_token_ttl_days <= 0 {
+            source.errors.push(format!(
+                "ACCESS_TOKEN_TTL_MINUTES (tokens.access_token_ttl_minutes) and REFRESH_TOKEN_TTL_DAYS (tokens.refresh_token_ttl_days) must be positive, got {} and {}",
+                tokens.access_token_ttl_minutes, tokens.refresh_token_ttl_days
+            ));
+        }
+
+        // Key files are read here, so a missing or mismatched PEM stops the server before it signs anything
+        let key_entries = source.list("JWT_KEYS", "jwt.keys", &[]);
+        let signing_kid = source.optional("JWT_SIGNING_KID", "jwt.signing_kid");
+        let jwt = JwtConfig {
+            secret: source.optional("JWT_SECRET", "jwt.secret"),
+            keys: jwks::load_key_ring(&key_entries, signing_kid).unwrap_or_else(|message| {
+                source.errors.push(format!("JWT_KEYS (jwt.keys) is invalid: {}", message));
+                KeyRing { keys: Vec::new(), signing_kid: None }
+            }),
+        };
+        if key_entries.is_empty() && jwt.secret.is_none() {
+            source.errors.push("JWT_SECRET (jwt.secret) is required unless JWT_KEYS (jwt.keys) lists signing keys".to_string());
+        }
+
+        #[cfg(feature = "two-factor")]
+        let totp = TotpConfig {
+            issuer: source.string("TOTP_ISSUER", "totp.issuer", "LoginAPI"),
+            skew_back: source.parse("TOTP_SKEW_BACK", "totp.skew_back", 1),
+            skew_forward: source.parse("TOTP_SKEW_FORWARD", "totp.skew_forward", 1),
+            // Enrolled accounts still use their TOTP secrets after 2FA is turned off
+            encryption_key: match source.optional("TOTP_ENCRYPTION_KEY", "totp.encryption_key") {
+                Some(key) => source.encryption_key("TOTP_ENCRYPTION_KEY (totp.encryption_key)", &key).unwrap_or_default(),
+                None => {
+                    source.errors.push("TOTP_ENCRYPTION_KEY (totp.encryption_key) is required in builds with the two-factor feature".to_string());
+                    Vec::new()
+                },
+            },
+            recovery_code_key: match source.optional("RECOVERY_CODE_KEY", "totp.recovery_code_key") {
+                Some(key) => source.encryption_key("RECOVERY_CODE_KEY (totp.recovery_code_key)", &key).unwrap_or_default(),
+                None => {
+                    source.errors.push("RECOVERY_CODE_KEY (totp.recovery_code_key) is required in builds with the two-factor feature".to_string());
+                    Vec::new()
+                },
+            },
+        };
+        #[cfg(feature = "two-factor")]
+        if totp.skew_back < 0 || totp.skew_forward < 0 {
+            source.errors.push(format!(
+                "TOTP_SKEW_BACK (totp.skew_back) and TOTP_SKEW_FORWARD (totp.skew_forward) cannot be negative, got {} and {}",
+                totp.skew_back, totp.skew_forward
+            ));
+        }
+
+        let webauthn = WebAuthnConfig {
+            rp_id: source.optional("WEBAUTHN_RP_ID", "webauthn.rp_id"),
+            rp_name: source.string("WEBAUTHN_RP_NAME", "webauthn.rp_name", "LoginAPI"),
+            origins: source.list("WEBAUTHN_ORIGINS", "webauthn.origins", &[])
+                .into_iter()
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect(),
+        };
+        if webauthn.rp_id.is_some() && webauthn.origins.is_empty() {
+            source.errors.push("WEBAUTHN_ORIGINS (webauthn.origins) is required with WEBAUTHN_RP_ID (webauthn.rp_id)".to_string());
+        }
+        for origin in &webauthn.origins {
+            source.check_url("WEBAUTHN_ORIGINS (webauthn.origins)", origin);
+        }
+
+        let oidc = OidcConfig {
+            issuer: source.optional("OIDC_ISSUER", "oidc.issuer").map(|issuer| issuer.trim_end_matches('/').to_string()),
+            login_url: source.optional("OIDC_LOGIN_URL", "oidc.login_url"),
+            registration_token: source.optional("OIDC_REGISTRATION_TOKEN", "oidc.registration_token"),
+        };
+        if let Some(issuer) = &oidc.issuer {
+            source.check_url("OIDC_ISSUER (oidc.issuer)", issuer);
+            match &oidc.login_url {
+                Some(login_url) => source.check_url("OIDC_LOGIN_URL (oidc.login_url)", login_url),
+                None => source.errors.push("OIDC_LOGIN_URL (oidc.login_url) is required with OIDC_ISSUER (oidc.issuer)".to_string()),
+            }
+            // Relying parties verify ID tokens against the JWKS, which has nothing to offer for an HS256 secret
+            if jwt.keys.signing_kid.is_none() {
+                source.errors.push("OIDC_ISSUER (oidc.issuer) needs JWT_KEYS (jwt.keys): ID tokens must be signed with an asymmetric key".to_string());
+            }
+        }
+
+        // Each provider NAME listed is configured by SOCIAL_<NAME>_* or its own [social.NAME] table
+        let provider_names = source.list("SOCIAL_PROVIDERS", "social.providers", &[]);
+        let redirect_base_url = match provider_names.is_empty() {
+            true => String::new(),
+            false => source.url("SOCIAL_REDIRECT_BASE_URL", "social.redirect_base_url"),
+        };
+        let providers = provider_names.iter().map(|name| {
+            let env_name = |key: &str| format!("SOCIAL_{}_{}", name.to_uppercase(), key);
+            let path = |key: &str| format!("social.{}.{}", name, key);
+            SocialProvider {
+                name: name.clone(),
+                issuer: source.url(&env_name("ISSUER"), &path("issuer")).trim_end_matches('/').to_string(),
+                client_id: source.required(&env_name("CLIENT_ID"), &path("client_id")),
+                client_secret: source.required(&env_name("CLIENT_SECRET"), &path("client_secret")),
+                scopes: source.string(&env_name("SCOPES"), &path("scopes"), "openid email profile"),
+                redirect_uri: format!("{}/social/{}/callback", redirect_base_url.trim_end_matches('/'), name),
+            }
+        }).collect();
+        let social = SocialConfig { providers };
+
+        let password = PasswordConfig {
+            min_length: source.parse("PASSWORD_MIN_LENGTH", "password.min_length", 8),
+            max_length: source.parse("PASSWORD_MAX_LENGTH", "password.max_length", 128),
+            require_lowercase: source.parse("PASSWORD_REQUIRE_LOWERCASE", "password.require_lowercase", false),
+            require_uppercase: source.parse("PASSWORD_REQUIRE_UPPERCASE", "password.require_uppercase", false),
+            require_digit: source.parse("PASSWORD_REQUIRE_DIGIT", "password.require_digit", false),
+            require_symbol: source.parse("PASSWORD_REQUIRE_SYMBOL", "password.require_symbol", false),
+            min_strength: source.parse("PASSWORD_MIN_STRENGTH", "password.min_strength", 2),
+            reject_personal_info: source.parse("PASSWORD_REJECT_PERSONAL_INFO", "password.reject_personal_info", true),
+            breached_passwords_file: source.optional("BREACHED_PASSWORDS_FILE", "password.breached_passwords_file"),
+        };
+        if password.min_length == 0 || password.max_length < password.min_length {
+            source.errors.push(format!(
+                "PASSWORD_MAX_LENGTH (password.max_length) must be at least PASSWORD_MIN_LENGTH (password.min_length), which must be positive, got {} and {}",
+                password.max_length, password.min_length
+            ));
+        }
+        if password.min_strength > 4 {
+            source.errors.push(format!("PASSWORD_MIN_STRENGTH (password.min_strength) must be between 0 and 4, got {}", password.min_strength));
+        }
+
+        // OWASP's minimum recommendation for Argon2id
+        let hashing = HashingConfig {
+            algorithm: match source.string("PASSWORD_HASH_ALGORITHM", "hashing.algorithm", "argon2id").as_str() {
+                "argon2id" => HashAlgorithm::Argon2id,
+                "bcrypt" => HashAlgorithm::Bcrypt,
+                other => {
+                    source.errors.push(format!("PASSWORD_HASH_ALGORITHM (hashing.algorithm) must be argon2id or bcrypt, got {}", other));
+                    HashAlgorithm::Argon2id
+                },
+            },
+            argon2_memory_kib: source.parse("ARGON2_MEMORY_KIB", "hashing.argon2_memory_kib", 19456),
+            argon2_iterations: source.parse("ARGON2_ITERATIONS", "hashing.argon2_iterations", 2),
+            argon2_parallelism: source.parse("ARGON2_PARALLELISM", "hashing.argon2_parallelism", 1),
+            bcrypt_cost: source.parse("BCRYPT_COST", "hashing.bcrypt_cost", bcrypt::DEFAULT_COST),
+        };
+        if let Err(e) = argon2::Params::new(hashing.argon2_memory_kib, hashing.argon2_iterations, hashing.argon2_parallelism, None) {
+            source.errors.push(format!("ARGON2_MEMORY_KIB, ARGON2_ITERATIONS and ARGON2_PARALLELISM (hashing.argon2_*) are invalid: {}", e));
+        }
+        if !(4..=31).contains(&hashing.bcrypt_cost) {
+            source.errors.push(format!("BCRYPT_COST (hashing.bcrypt_cost) must be between 4 and 31, got {}", hashing.bcrypt_cost));
+        }
+
+        let transport = match source.string("MAIL_TRANSPORT", "mail.transport", "smtp").as_str() {
+            "smtp" => MailTransport::Smtp,
+            "file" => MailTransport::File,
+            "stdout" => MailTransport::Stdout,
+            other => {
+                source.errors.push(format!("MAIL_TRANSPORT (mail.transport) must be smtp, file or stdout, got {}", other));
+                MailTransport::Stdout
+            },
+        };
+        // The SMTP account doubles as the sender and the login, as before MAIL_FROM and SMTP_USERNAME existed
+        let smtp_email = source.optional("SMTP_EMAIL", "mail.smtp.email");
+        let smtp = SmtpConfig {
+            server: match transport {
+                MailTransport::Smtp => source.required("SMTP_SERVER", "mail.smtp.server"),
+                _ => source.string("SMTP_SERVER", "mail.smtp.server", ""),
+            },
+            security: source.string("SMTP_SECURITY", "mail.smtp.security", "tls"),
+            port: source.optional_parse("SMTP_PORT", "mail.smtp.port"),
+            username: source.optional("SMTP_USERNAME", "mail.smtp.username").or_else(|| smtp_email.clone()),
+            password: source.optional("SMTP_PASSWORD", "mail.smtp.password"),
+            pool_size: source.parse("SMTP_POOL_SIZE", "mail.smtp.pool_size", 10),
+        };
+        if !["tls", "starttls", "plain"].contains(&smtp.security.as_str()) {
+            source.errors.push(format!("SMTP_SECURITY (mail.smtp.security) must be tls, starttls or plain, got {}", smtp.security));
+        }
+        if smtp.password.is_some() && smtp.username.is_none() {
+            source.errors.push("SMTP_PASSWORD is set without SMTP_USERNAME or SMTP_EMAIL".to_string());
+        }
+        let mail = MailConfig {
+            transport,
+            from: source.optional("MAIL_FROM", "mail.from").or(smtp_email).unwrap_or_else(|| "no-reply@localhost".to_string()),
+            dir: source.string("MAIL_DIR", "mail.dir", "mail"),
+            smtp,
+        };
+
+        let templates = TemplatesConfig {
+            dir: source.string("EMAIL_TEMPLATE_DIR", "templates.dir", "templates/email"),
+            default_locale: source.string("EMAIL_DEFAULT_LOCALE", "templates.default_locale", "en").to_lowercase(),
+            product_name: source.string("PRODUCT_NAME", "templates.product_name", "LoginAPI"),
+            logo_url: source.optional("PRODUCT_LOGO_URL", "templates.logo_url"),
+        };
+
+        let base_delay_seconds = source.parse("OUTBOX_BASE_DELAY_SECONDS", "outbox.base_delay_seconds", 30).max(1);
+        let outbox = OutboxSettings {
+            batch_size: source.parse("OUTBOX_BATCH_SIZE", "outbox.batch_size", 20usize).max(1),
+            max_attempts: source.parse("OUTBOX_MAX_ATTEMPTS", "outbox.max_attempts", 8).max(1),
+            base_delay_seconds,
+            max_delay_seconds: source.parse("OUTBOX_MAX_DELAY_SECONDS", "outbox.max_delay_seconds", 3600).max(base_delay_seconds),
+            poll_interval_seconds: source.parse("OUTBOX_POLL_SECONDS", "outbox.poll_seconds", 5u64).max(1),
+            // Queued bodies hold codes and links, so they are encrypted until sent
+            encryption_key: match source.optional("OUTBOX_ENCRYPTION_KEY", "outbox.encryption_key") {
+                Some(key) => source.encryption_key("OUTBOX_ENCRYPTION_KEY (outbox.encryption_key)", &key).unwrap_or_default(),
+                None => {
+                    source.errors.push("OUTBOX_ENCRYPTION_KEY (outbox.encryption_key) is required".to_string());
+                    Vec::new()
+                },
+            },
+        };
+
+        let rate_limits = RateLimitConfig {
+            rules: ratelimit::load_rules(&source.list("RATE_LIMITS", "rate_limits.rules", &[])).unwrap_or_else(|message| {
+                source.errors.push(format!("RATE_LIMITS (rate_limits.rules) is invalid: {}", message));
+                Vec::new()
+            }),
+        };
+
+        if !source.errors.is_empty() {
+            return Err(source.errors);
+        }
+        Ok(AppConfig { server, database, links, features, login, tokens, jwt, #[cfg(feature = "two-factor")] totp, webauthn, oidc, social, password, hashing, mail, templates, outbox, rate_limits })
+    }
+}
+
+// CONFIG_FILE names the TOML file; without it config.toml is used when present
+fn config_file() -> Result<toml::Table, Vec<String>> {
+    let file = match env::var("CONFIG_FILE").ok().filter(|path| !path.is_empty()) {
+        Some(path) => Some(path),
+        None => Path::new("config.toml").exists().then(|| "config.toml".to_string()),
+    };
+    match file {
+        Some(path) => {
+            let content = std::fs::read_to_string(&path).map_err(|e| vec![format!("{}: {}", path, e)])?;
+            content.parse::<toml::Table>().map_err(|e| vec![format!("{}: {}", path, e)])
+        },
+        None => Ok(toml::Table::new()),
+    }
+}
+
+impl DatabaseConfig {
+    // The migrate and outbox commands only need the database, so nothing else has to be configured for them
+    pub fn load() -> Result<Self, Vec<String>> {
+        let mut source = Source { file: config_file()?, errors: Vec::new() };
+        let database = Self::from_source(&mut source);
+        if !source.errors.is_empty() {
+            return Err(source.errors);
+        }
+        Ok(database)
+    }
+
+    fn from_source(source: &mut Source) -> Self {
+        let database = DatabaseConfig {
+            url: source.required("DATABASE_URL", "database.url"),
+            name: source.optional("DATABASE_NAME", "database.name"),
+            migrate_on_startup: source.parse("MIGRATE_ON_STARTUP", "database.migrate_on_startup", true),
+        };
+        // The name ends up inside SQL, so only plain names are accepted
+        if let Some(name) = &database.name {
+            if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$') {
+                source.errors.push(format!("DATABASE_NAME (database.name) may only hold letters, digits, _ and $, got {}", name));
+            }
+        }
+        database
+    }
+}
+
+// Looks a setting up and records what is wrong with it, so every problem is reported at once
+struct Source {
+    file: toml::Table,
+    errors: Vec<String>,
+}
+
+impl Source {
+    // Empty environment values count as unset
+    fn raw(&self, env_name: &str, path: &str) -> Option<String> {
+        if let Some(value) = env::var(env_name).ok().filter(|value| !value.trim().is_empty()) {
+            return Some(value);
+        }
+
+        let mut keys = path.split('.');
+        let mut value = self.file.get(keys.next()?)?;
+        for key in keys {
+            value = value.get(key)?;
+        }
+        match value {
+            toml::Value::String(value) => Some(value.clone()),
+            toml::Value::Array(items) => Some(
+                items.iter()
+                    .map(|item| item.as_str().map(str::to_string).unwrap_or_else(|| item.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            other => Some(other.to_string()),
+        }
+    }
+
+    fn optional(&self, env_name: &str, path: &str) -> Option<String> {
+        self.raw(env_name, path)
+    }
+
+    fn string(&self, env_name: &str, path: &str, default: &str) -> String {
+        self.raw(env_name, path).unwrap_or_else(|| default.to_string())
+    }
+
+    fn required(&mut self, env_name: &str, path: &str) -> String {
+        self.raw(env_name, path).unwrap_or_else(|| {
+            self.errors.push(format!("{} ({}) is required", env_name, path));
+            String::new()
+        })
+    }
+
+    fn url(&mut self, env_name: &str, path: &str) -> String {
+        let value = self.required(env_name, path);
+        if !value.is_empty() {
+            self.check_url(&format!("{} ({})", env_name, path), &value);
+        }
+        value
+    }
+
+    // An empty link stands in for one whose subsystem is off
+    fn url_if(&mut self, enabled: bool, env_name: &str, path: &str) -> String {
+        match enabled {
+            true => self.url(env_name, path),
+            false => String::new(),
+        }
+    }
+
+    fn check_url(&mut self, name: &str, value: &str) {
+        if url::Url::parse(value).map(|url| url.host().is_none()).unwrap_or(true) {
+            self.errors.push(format!("{} must be an absolute URL, got {}", name, value));
+        }
+    }
+
+    fn list(&self, env_name: &str, path: &str, default: &[&str]) -> Vec<String> {
+        match self.raw(env_name, path) {
+            Some(value) => value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::to_string).collect(),
+            None => default.iter().map(|item| item.to_string()).collect(),
+        }
+    }
+
+    // AES-256-GCM keys are given as the base64 of 32 bytes
+    fn encryption_key(&mut self, name: &str, value: &str) -> Option<Vec<u8>> {
+        match STANDARD.decode(value.trim()) {
+            Ok(key) if key.len() == 32 => Some(key),
+            _ => {
+                self.errors.push(format!("{} must be the base64 of 32 bytes", name));
+                None
+            },
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, env_name: &str, path: &str, default: T) -> T {
+        self.optional_parse(env_name, path).unwrap_or(default)
+    }
+
+    // On by `default` when compiled in; asking for a subsystem the build left out is an error
+    fn feature(&mut self, env_name: &str, path: &str, cargo_feature: &str, compiled: bool, default: bool) -> bool {
+        let enabled = self.parse(env_name, path, default && compiled);
+        if enabled && !compiled {
+            self.errors.push(format!("{} ({}) needs the {} cargo feature", env_name, path, cargo_feature));
+            return false;
+        }
+        enabled
+    }
+
+    fn optional_parse<T: FromStr>(&mut self, env_name: &str, path: &str) -> Option<T> {
+        let value = self.raw(env_name, path)?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.errors.push(format!("{} ({}) has an invalid value: {}", env_name, path, value));
+                None
+            },
+        }
+    }
+}
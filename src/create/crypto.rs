@@ -0,0 +1,37 @@
+// crypto.rs
+
+use crate::create::common::*;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+const NONCE_LEN: usize = 12;
+
+// AES-256-GCM under a fresh random nonce, stored as the base64 of nonce | ciphertext
+pub fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<String, ServiceError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce);
+
+    let ciphertext = cipher(key)?
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| ServiceError::InternalServerError)?;
+
+    let mut stored = nonce.to_vec();
+    stored.extend_from_slice(&ciphertext);
+    Ok(STANDARD.encode(stored))
+}
+
+pub fn decrypt(key: &[u8], stored: &str) -> Result<Vec<u8>, ServiceError> {
+    let raw = STANDARD.decode(stored).map_err(|_| ServiceError::InternalServerError)?;
+    if raw.len() <= NONCE_LEN {
+        return Err(ServiceError::InternalServerError);
+    }
+    let (nonce, ciphertext) = raw.split_at(NONCE_LEN);
+
+    cipher(key)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| ServiceError::InternalServerError)
+}
+
+fn cipher(key: &[u8]) -> Result<Aes256Gcm, ServiceError> {
+    Aes256Gcm::new_from_slice(key).map_err(|_| ServiceError::InternalServerError)
+}
@@ -0,0 +1,72 @@
+// der.rs
+
+// Minimal DER reader, enough to pull key material out of SubjectPublicKeyInfo and X.509 certificates
+
+pub const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+pub const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+pub const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+pub fn read_tlv(input: &[u8]) -> Result<(u8, &[u8], &[u8]), String> {
+    let malformed = || "malformed DER".to_string();
+    if input.len() < 2 {
+        return Err(malformed());
+    }
+
+    let (len, header) = match input[1] {
+        len if len < 0x80 => (len as usize, 2),
+        len => {
+            let count = (len & 0x7f) as usize;
+            if count == 0 || count > 4 || input.len() < 2 + count {
+                return Err(malformed());
+            }
+            let len = input[2..2 + count].iter().fold(0usize, |acc, byte| (acc << 8) | *byte as usize);
+            (len, 2 + count)
+        },
+    };
+
+    if input.len() < header + len {
+        return Err(malformed());
+    }
+    Ok((input[0], &input[header..header + len], &input[header + len..]))
+}
+
+pub fn read_der(input: &[u8], expected_tag: u8) -> Result<(&[u8], &[u8]), String> {
+    match read_tlv(input)? {
+        (tag, value, rest) if tag == expected_tag => Ok((value, rest)),
+        _ => Err("unexpected DER tag".to_string()),
+    }
+}
+
+pub fn strip_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(bytes.len().saturating_sub(1));
+    &bytes[start..]
+}
+
+// Returns the algorithm OID and the raw subjectPublicKey bits
+pub fn parse_spki(spki: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    let (spki, _) = read_der(spki, 0x30)?;
+    let (algorithm_identifier, rest) = read_der(spki, 0x30)?;
+    let (oid, _) = read_der(algorithm_identifier, 0x06)?;
+    let (bit_string, _) = read_der(rest, 0x03)?;
+    let key_bytes = bit_string.get(1..).ok_or("empty public key")?;
+
+    Ok((oid, key_bytes))
+}
+
+// Walks tbsCertificate up to subjectPublicKeyInfo and returns it with its header
+pub fn certificate_spki(certificate: &[u8]) -> Result<&[u8], String> {
+    let (certificate, _) = read_der(certificate, 0x30)?;
+    let (mut tbs, _) = read_der(certificate, 0x30)?;
+
+    // Optional explicit [0] version
+    if tbs.first() == Some(&0xa0) {
+        tbs = read_tlv(tbs)?.2;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = read_tlv(tbs)?.2;
+    }
+
+    let (_, _, rest) = read_tlv(tbs)?;
+    Ok(&tbs[..tbs.len() - rest.len()])
+}
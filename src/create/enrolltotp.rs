@@ -0,0 +1,64 @@
+// enrolltotp.rs
+
+use crate::create::common::*;
+use crate::create::authtokens;
+use crate::create::store::TokenPurpose;
+use crate::create::totp;
+use crate::create::recoverycodes;
+
+#[post("/enroll_totp")]
+async fn enroll_totp(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServiceError> {
+    let (email_addr, user_from_token) = extract_user_email_from_token(&req, store.get_ref()).await?;
+    let secret = totp::generate_secret();
+    let encrypted_secret = totp::encrypt_secret(&config.totp, &secret)?;
+    store.start_totp_enrollment(&user_from_token, &encrypted_secret).await?;
+    let temp_token = authtokens::issue(store.get_ref(), config.get_ref(), &user_from_token, TokenPurpose::TotpEnrollment).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "status": "success",
+        "message": "Add the secret to your authenticator app and submit the first code to finalize activation.",
+        "secret": totp::encode_secret(&secret),
+        "otpauth_uri": totp::provisioning_uri(&config.totp, &secret, &email_addr),
+        "token": temp_token
+    })))
+}
+
+#[post("/verify_totp_enrollment")]
+async fn verify_totp_enrollment(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    verification_data: web::Json<TwoFAVerificationRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let token = store
+        .find_user_auth_token(&verification_data.0.username, TokenPurpose::TotpEnrollment)
+        .await?
+        .ok_or(ServiceError::BadRequest("User not found or no pending TOTP enrollment".to_string()))?;
+    let stored_secret = store
+        .find_totp_enrollment(&verification_data.0.username)
+        .await?
+        .ok_or(ServiceError::BadRequest("User not found or no pending TOTP enrollment".to_string()))?;
+
+    if !authtokens::matches(&token, &verification_data.0.token) {
+        return Err(ServiceError::BadRequest("Invalid code or token".to_string()));
+    }
+    if authtokens::is_expired(&token) {
+        return Err(ServiceError::BadRequest("Token has expired".to_string()));
+    }
+
+    let secret = totp::decrypt_secret(&config.totp, &stored_secret)?;
+    let step = totp::verify_code(&config.totp, &secret, &verification_data.0.code, None)
+        .ok_or(ServiceError::BadRequest("Invalid code or token".to_string()))?;
+
+    if !authtokens::consume(store.get_ref(), &token).await? {
+        return Err(ServiceError::BadRequest("Invalid code or token".to_string()));
+    }
+    store.enable_totp(&verification_data.0.username, step).await?;
+
+    let recovery_codes = recoverycodes::generate_recovery_codes(store.get_ref(), config.get_ref(), &verification_data.0.username).await?;
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "TOTP 2FA activated. Store your recovery codes somewhere safe.", "recovery_codes": recovery_codes })))
+}
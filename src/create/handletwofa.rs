@@ -1,48 +1,47 @@
 // handletwofa.rs
 
 use crate::create::common::*;
+use crate::create::authtokens;
+use crate::create::store::TokenPurpose;
 use crate::create::twoauth;
 
 pub async fn handle_2fa(
-    conn: &mut Conn,
+    store: &dyn UserStore,
+    emails: &Emails,
+    config: &AppConfig,
     username: &str
 ) -> Result<HttpResponse, ServiceError> {
-    let code = twoauth::generate_2fa_code();
-
-    let email_addr: String = conn
-        .exec_first("SELECT email FROM users WHERE username = ?", (username,))
-        .await
-        .map_err(|e| {
-            error!("Error executing DB query: {:?}", e);
-            ServiceError::InternalServerError
-        })?
+    let user = store
+        .find_user(username)
+        .await?
         .ok_or(ServiceError::BadRequest("User not found".to_string()))?;
+    let method = user.two_fa_method.clone();
 
-    send_2fa_email(&email_addr, "Your 2FA code", &format!("Here is your 2FA code: {}", code)).await?;
+    // A new challenge replaces the previous one and its failure count
+    store.reset_2fa_challenge(username).await?;
 
-    let expiry = Utc::now()
-        .checked_add_signed(Duration::minutes(3))
-        .expect("Failed to calculate 2FA code expiry");
-    let expiry_naive = expiry.naive_utc();
-    let expiry_string = expiry_naive.to_string();
+    // TOTP users read the code from their authenticator app, nothing to send
+    if method != "totp" {
+        let code = twoauth::generate_2fa_code();
 
-    conn.exec_drop(
-        "UPDATE users SET 2fa_code = ?, 2fa_expiry = ? WHERE username = ?",
-        (&code, &expiry_string, username),
-    ).await.map_err(|_| ServiceError::InternalServerError)?;
+        let locale = emails.locale(user.locale.as_deref(), None);
+        emails.send(&user.email, "2fa_code", &locale, &[("code", &code)]).await?;
 
-    let temp_token = Uuid::new_v4().to_string();
+        let expiry = Utc::now()
+            .checked_add_signed(Duration::minutes(3))
+            .expect("Failed to calculate 2FA code expiry");
 
-    let token_expiry = Utc::now()
-        .checked_add_signed(Duration::minutes(10))
-        .expect("Failed to calculate temp token expiry");
-    let token_expiry_naive = token_expiry.naive_utc();
-    let token_expiry_string = token_expiry_naive.to_string();
+        store.set_2fa_code(username, &code, expiry.naive_utc()).await?;
+    }
 
-    conn.exec_drop(
-        "UPDATE users SET temp_token = ?, temp_token_expiry = ? WHERE username = ?",
-        (&temp_token, &token_expiry_string, username),
-    ).await.map_err(|_| ServiceError::InternalServerError)?;
+    let temp_token = authtokens::issue(store, config, username, TokenPurpose::TwoFactorLogin).await?;
 
-    Ok(HttpResponse::Ok().json(json!({"status": "2fa_required", "temp_token": temp_token})))
+    let webauthn_available = !store.list_webauthn_credential_ids(username).await?.is_empty();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "status": "2fa_required",
+        "method": method,
+        "webauthn_available": webauthn_available,
+        "temp_token": temp_token
+    })))
 }
@@ -0,0 +1,187 @@
+// jwks.rs
+
+use crate::create::common::*;
+use crate::create::der;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use crate::create::config::JwtConfig;
+use jsonwebtoken::Algorithm;
+
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub encoding: Option<EncodingKey>,
+    pub decoding: DecodingKey,
+    pub jwk: serde_json::Value,
+}
+
+pub struct KeyRing {
+    pub keys: Vec<SigningKey>,
+    pub signing_kid: Option<String>,
+}
+
+fn parse_algorithm(name: &str) -> Result<Algorithm, String> {
+    match name {
+        "RS256" => Ok(Algorithm::RS256),
+        "ES256" => Ok(Algorithm::ES256),
+        "EdDSA" => Ok(Algorithm::EdDSA),
+        other => Err(format!("unsupported algorithm {} (expected RS256, ES256 or EdDSA)", other)),
+    }
+}
+
+// Each entry is kid:ALG:public.pem[:private.pem]; keys without a private PEM only verify
+pub fn load_key_ring(entries: &[String], signing_kid: Option<String>) -> Result<KeyRing, String> {
+    if entries.is_empty() {
+        return Ok(KeyRing { keys: Vec::new(), signing_kid: None });
+    }
+
+    let mut keys = Vec::new();
+    for entry in entries {
+        let parts: Vec<&str> = entry.split(':').collect();
+        if parts.len() != 3 && parts.len() != 4 {
+            return Err(format!("entry '{}' must be kid:ALG:public.pem[:private.pem]", entry));
+        }
+        let (kid, algorithm) = (parts[0].to_string(), parse_algorithm(parts[1])?);
+
+        let public_pem = std::fs::read(parts[2]).map_err(|e| format!("{}: {}", parts[2], e))?;
+        let (decoding, jwk) = load_public_key(&kid, algorithm, &public_pem)?;
+
+        let encoding = match parts.get(3) {
+            Some(path) => {
+                let private_pem = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+                let key = match algorithm {
+                    Algorithm::RS256 => EncodingKey::from_rsa_pem(&private_pem),
+                    Algorithm::ES256 => EncodingKey::from_ec_pem(&private_pem),
+                    _ => EncodingKey::from_ed_pem(&private_pem),
+                };
+                Some(key.map_err(|e| format!("{}: {}", path, e))?)
+            },
+            None => None,
+        };
+
+        keys.push(SigningKey { kid, algorithm, encoding, decoding, jwk });
+    }
+
+    let signing_kid = signing_kid.unwrap_or_else(|| keys.first().map(|key| key.kid.clone()).unwrap_or_default());
+    match keys.iter().find(|key| key.kid == signing_kid) {
+        Some(key) if key.encoding.is_some() => {},
+        Some(_) => return Err(format!("signing key '{}' has no private PEM", signing_kid)),
+        None => return Err(format!("signing key '{}' is not listed in JWT_KEYS", signing_kid)),
+    }
+
+    Ok(KeyRing { keys, signing_kid: Some(signing_kid) })
+}
+
+fn load_public_key(kid: &str, algorithm: Algorithm, pem_bytes: &[u8]) -> Result<(DecodingKey, serde_json::Value), String> {
+    let parsed = pem::parse(pem_bytes).map_err(|e| format!("key '{}': {}", kid, e))?;
+    if parsed.tag() != "PUBLIC KEY" {
+        return Err(format!("key '{}': expected a PUBLIC KEY (SubjectPublicKeyInfo) PEM", kid));
+    }
+
+    let (oid, key_bytes) = der::parse_spki(parsed.contents())?;
+
+    match (algorithm, oid) {
+        (Algorithm::RS256, der::OID_RSA_ENCRYPTION) => {
+            let (rsa_key, _) = der::read_der(key_bytes, 0x30)?;
+            let (modulus, rest) = der::read_der(rsa_key, 0x02)?;
+            let (exponent, _) = der::read_der(rest, 0x02)?;
+            let n = URL_SAFE_NO_PAD.encode(der::strip_leading_zeros(modulus));
+            let e = URL_SAFE_NO_PAD.encode(der::strip_leading_zeros(exponent));
+
+            let decoding = DecodingKey::from_rsa_components(&n, &e).map_err(|e| e.to_string())?;
+            Ok((decoding, json!({"kty": "RSA", "kid": kid, "use": "sig", "alg": "RS256", "n": n, "e": e})))
+        },
+        (Algorithm::ES256, der::OID_EC_PUBLIC_KEY) => {
+            if key_bytes.len() != 65 || key_bytes[0] != 0x04 {
+                return Err(format!("key '{}': expected an uncompressed P-256 point", kid));
+            }
+            let x = URL_SAFE_NO_PAD.encode(&key_bytes[1..33]);
+            let y = URL_SAFE_NO_PAD.encode(&key_bytes[33..]);
+
+            let decoding = DecodingKey::from_ec_components(&x, &y).map_err(|e| e.to_string())?;
+            Ok((decoding, json!({"kty": "EC", "kid": kid, "use": "sig", "alg": "ES256", "crv": "P-256", "x": x, "y": y})))
+        },
+        (Algorithm::EdDSA, der::OID_ED25519) => {
+            let x = URL_SAFE_NO_PAD.encode(key_bytes);
+
+            let decoding = DecodingKey::from_ed_components(&x).map_err(|e| e.to_string())?;
+            Ok((decoding, json!({"kty": "OKP", "kid": kid, "use": "sig", "alg": "EdDSA", "crv": "Ed25519", "x": x})))
+        },
+        _ => Err(format!("key '{}': public key type does not match {:?}", kid, algorithm)),
+    }
+}
+
+pub fn signing_algorithm(config: &JwtConfig) -> Algorithm {
+    let ring = &config.keys;
+    ring.keys.iter()
+        .find(|key| Some(&key.kid) == ring.signing_kid.as_ref())
+        .map(|key| key.algorithm)
+        .unwrap_or(Algorithm::HS256)
+}
+
+// AppConfig only accepts a missing secret when JWT_KEYS lists a signing key
+fn secret(config: &JwtConfig) -> Result<&[u8], ServiceError> {
+    config.secret.as_deref().map(str::as_bytes).ok_or_else(|| {
+        error!("Neither JWT_KEYS nor JWT_SECRET is configured");
+        ServiceError::InternalServerError
+    })
+}
+
+pub fn sign<T: Serialize>(config: &JwtConfig, claims: &T) -> Result<String, ServiceError> {
+    let ring = &config.keys;
+
+    let token = match &ring.signing_kid {
+        Some(kid) => {
+            let key = ring.keys.iter().find(|key| &key.kid == kid).ok_or(ServiceError::InternalServerError)?;
+            let mut header = Header::new(key.algorithm);
+            header.kid = Some(key.kid.clone());
+            encode(&header, claims, key.encoding.as_ref().ok_or(ServiceError::InternalServerError)?)
+        },
+        None => encode(&Header::default(), claims, &EncodingKey::from_secret(secret(config)?)),
+    };
+
+    token.map_err(|e| {
+        error!("Error encoding JWT: {:?}", e);
+        ServiceError::InternalServerError
+    })
+}
+
+// Any listed key verifies, so tokens signed before a rotation stay valid until they expire
+pub fn verify<T: serde::de::DeserializeOwned>(config: &JwtConfig, token: &str) -> Result<T, ServiceError> {
+    let invalid = |e: jsonwebtoken::errors::Error| {
+        error!("Error decoding JWT: {:?}", e);
+        ServiceError::Unauthorized("Invalid token".to_string())
+    };
+    let ring = &config.keys;
+    let header = jsonwebtoken::decode_header(token).map_err(invalid)?;
+    // Client tokens name their client in `aud`; extract_claims_from_token decides where they are accepted
+    let validation = |algorithm| {
+        let mut validation = Validation::new(algorithm);
+        validation.validate_aud = false;
+        validation
+    };
+
+    let token_data = match &header.kid {
+        Some(kid) if ring.signing_kid.is_some() => {
+            let key = ring.keys.iter().find(|key| &key.kid == kid)
+                .ok_or(ServiceError::Unauthorized("Unknown signing key".to_string()))?;
+            decode::<T>(token, &key.decoding, &validation(key.algorithm)).map_err(invalid)?
+        },
+        // HS256 tokens from before JWT_KEYS was set stay valid for as long as JWT_SECRET is kept
+        None if ring.signing_kid.is_none() || config.secret.is_some() => {
+            decode::<T>(token, &DecodingKey::from_secret(secret(config)?), &validation(Algorithm::HS256)).map_err(invalid)?
+        },
+        _ => return Err(ServiceError::Unauthorized("Invalid token".to_string())),
+    };
+
+    Ok(token_data.claims)
+}
+
+#[get("/.well-known/jwks.json")]
+async fn jwks(config: Data<AppConfig>) -> HttpResponse {
+    let keys: Vec<&serde_json::Value> = config.jwt.keys.keys.iter().map(|key| &key.jwk).collect();
+
+    HttpResponse::Ok()
+        .insert_header((actix_web::http::header::CACHE_CONTROL, "public, max-age=300"))
+        .json(json!({ "keys": keys }))
+}
+
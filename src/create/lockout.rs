@@ -0,0 +1,119 @@
+// lockout.rs
+
+use crate::create::common::*;
+use crate::create::tokens;
+
+const MAX_BACKOFF_SECONDS: i64 = 900;
+
+// 1s, 2s, 4s, ... once the free attempts are used up
+fn backoff_seconds(failures: i64, free_attempts: i64) -> i64 {
+    if failures <= free_attempts {
+        return 0;
+    }
+    let exponent = (failures - free_attempts - 1).min(20) as u32;
+    2i64.pow(exponent).min(MAX_BACKOFF_SECONDS)
+}
+
+pub async fn check_login_allowed(store: &dyn UserStore, username: &str, ip: &str) -> Result<(), ServiceError> {
+    let now = Utc::now().naive_utc();
+    let wait = |until: Option<NaiveDateTime>| until.filter(|until| *until > now).map(|until| (until - now).num_seconds());
+
+    let ip_wait = wait(store.ip_blocked_until(ip).await?);
+    let account_wait = wait(store.account_locked_until(username).await?);
+
+    match ip_wait.max(account_wait) {
+        Some(wait) => {
+            info!("Login for {} from {} refused for another {}s", username, ip, wait);
+            Err(ServiceError::TooManyRequests(wait.max(1)))
+        },
+        None => Ok(()),
+    }
+}
+
+async fn lock_account(store: &dyn UserStore, emails: &Emails, config: &AppConfig, username: &str, email_addr: &str) -> Result<(), ServiceError> {
+    let lockout_minutes = config.login.lockout_minutes;
+
+    let unlock_token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(43)
+        .map(char::from)
+        .collect();
+
+    let locked_until = Utc::now()
+        .checked_add_signed(Duration::minutes(lockout_minutes))
+        .ok_or(ServiceError::InternalServerError)?;
+
+    store.lock_account(username, locked_until.naive_utc(), Some(&tokens::hash_token(&unlock_token))).await?;
+    info!("Locked account {} for {} minutes", username, lockout_minutes);
+
+    let unlock_link = format!("{}/unlock_account?token={}", config.links.unlock_account_base_url, unlock_token);
+    // The lock stands even if the mail can't be sent, it expires on its own
+    let stored_locale = store.find_user(username).await?.and_then(|user| user.locale);
+    let locale = emails.locale(stored_locale.as_deref(), None);
+    if emails.send(
+        email_addr,
+        "account_locked",
+        &locale,
+        &[("minutes", &lockout_minutes.to_string()), ("link", &unlock_link)],
+    ).await.is_err() {
+        error!("Failed to send unlock email to user {}", username);
+    }
+
+    Ok(())
+}
+
+fn seconds_from_now(seconds: i64) -> NaiveDateTime {
+    Utc::now().naive_utc() + Duration::seconds(seconds)
+}
+
+pub async fn record_login_failure(store: &dyn UserStore, emails: &Emails, config: &AppConfig, username: &str, ip: &str) -> Result<(), ServiceError> {
+    let ip_failures = store.record_ip_failure(ip).await?;
+    let ip_backoff = backoff_seconds(ip_failures, config.login.ip_free_attempts);
+    if ip_backoff > 0 {
+        store.block_ip(ip, seconds_from_now(ip_backoff)).await?;
+    }
+
+    if let Some((failures, email_addr)) = store.increment_failed_logins(username).await? {
+        if failures >= config.login.lockout_threshold {
+            lock_account(store, emails, config, username, &email_addr).await?;
+        } else {
+            let backoff = backoff_seconds(failures, config.login.free_attempts);
+            if backoff > 0 {
+                store.lock_account(username, seconds_from_now(backoff), None).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn record_login_success(store: &dyn UserStore, username: &str) -> Result<(), ServiceError> {
+    store.reset_failed_logins(username).await
+}
+
+// Burns the temp_token and any pending 2FA change once the limit is reached, so the user has to pass the password step again
+#[cfg(feature = "two-factor")]
+pub async fn record_2fa_failure(store: &dyn UserStore, config: &AppConfig, username: &str) -> Result<(), ServiceError> {
+    let failures = store.increment_2fa_failures(username).await?;
+
+    if failures >= config.login.two_fa_max_attempts {
+        store.reset_2fa_challenge(username).await?;
+        info!("Invalidated temp_token of user {} after {} failed 2FA attempts", username, failures);
+
+        return Err(ServiceError::BadRequest("Too many invalid codes. Please log in again.".to_string()));
+    }
+
+    Ok(())
+}
+
+#[get("/unlock_account")]
+async fn unlock_account(
+    store: Data<dyn UserStore>,
+    query: Query<VerifyQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    if !store.unlock_account(&tokens::hash_token(&query.token)).await? {
+        return Err(ServiceError::BadRequest("Invalid or already used unlock link".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(json!({"status": "Account unlocked successfully"})))
+}
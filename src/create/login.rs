@@ -1,69 +1,100 @@
 // login.rs
 
 use crate::create::common::*;
+#[cfg(feature = "two-factor")]
 use crate::create::handletwofa;
+use crate::create::lockout;
+use crate::create::notifications;
+use crate::create::passwordhash::PasswordHasher;
+use crate::create::store::User;
+use crate::create::tokens;
 
-#[post("/login")]
-async fn login(
-    pool: Data<Pool>,
-    info: web::Json<LoginRequest>,
-) -> Result<HttpResponse, ServiceError> {
-    let mut conn = pool.get_conn().await.map_err(|e| {
-        error!("Error getting DB connection: {:?}", e);
-        ServiceError::InternalServerError
-    })?;
-
-    let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET is not set in .env");
+// Returns whether the login needs a second factor; every failure gets the same message to avoid username probing
+pub async fn verify_credentials(
+    store: &dyn UserStore,
+    emails: &Emails,
+    config: &AppConfig,
+    username: &str,
+    password: &str,
+    ip: &str,
+) -> Result<bool, ServiceError> {
+    lockout::check_login_allowed(store, username, ip).await?;
 
-    let row: Option<(String, bool, bool)> = conn
-        .exec_first(
-            "SELECT password, verified, has_2fa FROM users WHERE username = ?",
-            (&info.0.username,),
-        )
-        .await.map_err(|e| {
-            error!("Error executing DB query: {:?}", e);
-            ServiceError::InternalServerError
-        })?;
+    let user = store.find_user(username).await?;
 
-    match row {
-        Some((hashed_password, is_verified, has_2fa)) => {
-            if bcrypt::verify(&info.0.password, &hashed_password).is_err() {
-                error!("Password verification failed for user: {}", info.0.username);
+    match user {
+        Some(User { password_hash: hashed_password, verified: is_verified, has_2fa, password_reset_required, .. }) => {
+            let verification = PasswordHasher::new(&config.hashing).verify(password, &hashed_password).await?;
+            if !verification.valid {
+                error!("Password verification failed for user: {}", username);
+                lockout::record_login_failure(store, emails, config, username, ip).await?;
                 return Err(ServiceError::BadRequest("Invalid username or password. If you haven't verified your email, please do so.".to_string()));
             }
-            info!("Password verified for user: {}", info.0.username);
+            info!("Password verified for user: {}", username);
+
+            if password_reset_required {
+                error!("Password login refused until reset for user: {}", username);
+                return Err(ServiceError::Unauthorized("This password can no longer be used. Check your email for the reset link.".to_string()));
+            }
 
             if !is_verified {
-                error!("User not verified: {}", info.0.username);
+                error!("User not verified: {}", username);
                 return Err(ServiceError::BadRequest("Invalid username or password. If you haven't verified your email, please do so.".to_string()));
             }
-            info!("User is verified: {}", info.0.username);
+            info!("User is verified: {}", username);
+            lockout::record_login_success(store, username).await?;
 
-            if has_2fa {
-                return handletwofa::handle_2fa(&mut conn, &info.0.username).await;
+            // Hashes made with an older algorithm or weaker parameters are upgraded while the password is at hand
+            if let Some(rehashed) = verification.rehashed {
+                store.update_password_hash(username, &hashed_password, &rehashed).await?;
+                info!("Password hash upgraded for user: {}", username);
             }
 
-            let expiration = Utc::now()
-                .checked_add_signed(Duration::days(1))
-                .expect("Failed to calculate JWT expiration")
-                .timestamp() as usize;
+            // Turning 2FA off only stops new enrolments, accounts that enrolled keep their second factor
+            Ok(has_2fa)
+        },
+        None => {
+            lockout::record_login_failure(store, emails, config, username, ip).await?;
+            Err(ServiceError::BadRequest("Invalid username or password. If you haven't verified your email, please do so.".to_string()))
+        }
+    }
+}
+
+// The answer to a login that passed the password step but still needs its second factor
+#[cfg(feature = "two-factor")]
+pub async fn second_factor_challenge(store: &dyn UserStore, emails: &Emails, config: &AppConfig, username: &str) -> Result<HttpResponse, ServiceError> {
+    handletwofa::handle_2fa(store, emails, config, username).await
+}
 
-            let claims = Claims {
-                sub: info.0.username.clone(),
-                exp: expiration,
-                has_2fa: has_2fa,  
-            };
+// Builds without 2FA refuse to start while any account has it, so this only catches accounts enrolled since
+#[cfg(not(feature = "two-factor"))]
+pub async fn second_factor_challenge(_store: &dyn UserStore, _emails: &Emails, _config: &AppConfig, username: &str) -> Result<HttpResponse, ServiceError> {
+    Err(second_factor_unavailable(username))
+}
 
-            let secret = &jwt_secret;
-            let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref()))
-                .map_err(|e| {
-                    error!("Error encoding JWT: {:?}", e);
-                    ServiceError::InternalServerError
-                })?;
-            info!("Generated JWT for user: {}", info.0.username);
+#[cfg(not(feature = "two-factor"))]
+pub fn second_factor_unavailable(username: &str) -> ServiceError {
+    error!("2FA is compiled out, refusing user: {}", username);
+    ServiceError::Unauthorized("This account uses two-factor authentication, which this server does not support.".to_string())
+}
 
-            Ok(HttpResponse::Ok().json(json!({"status": "success", "token": token })))
-        },
-        None => Err(ServiceError::BadRequest("Invalid username or password. If you haven't verified your email, please do so.".to_string()))
+#[post("/login")]
+async fn login(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<LoginRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let has_2fa = verify_credentials(store.get_ref(), emails.get_ref(), config.get_ref(), &info.0.username, &info.0.password, &client_ip(&req)).await?;
+
+    if has_2fa {
+        return second_factor_challenge(store.get_ref(), emails.get_ref(), config.get_ref(), &info.0.username).await;
     }
-}
\ No newline at end of file
+
+    notifications::check_login_device(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &info.0.username).await?;
+    let (token, refresh_token) = tokens::issue_token_pair(store.get_ref(), config.get_ref(), &info.0.username, has_2fa).await?;
+    info!("Generated JWT for user: {}", info.0.username);
+
+    Ok(HttpResponse::Ok().json(tokens::token_pair_response(config.get_ref(), &token, &refresh_token)))
+}
@@ -0,0 +1,95 @@
+// magiclink.rs
+
+use crate::create::common::*;
+use crate::create::login;
+use crate::create::notifications;
+use crate::create::store::MagicLinkRecord;
+use crate::create::tokens;
+
+// The same answer whether or not the email belongs to an account
+#[post("/login/magic_link")]
+async fn request_magic_link(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<MagicLinkRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let user = store
+        .find_user_by_email(&info.email)
+        .await?
+        .map(|user| (user.username, user.verified, user.locale));
+
+    let (username, locale) = match user {
+        Some((username, true, locale)) => (username, locale),
+        _ => return Ok(HttpResponse::Ok().json(json!({"status": "success"}))),
+    };
+
+    let magic_token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(43)
+        .map(char::from)
+        .collect();
+    let fingerprint_hash = info.device_fingerprint.as_deref()
+        .filter(|fingerprint| !fingerprint.is_empty())
+        .map(tokens::hash_token);
+
+    let ttl_minutes = config.login.magic_link_ttl_minutes;
+    let expiry = Utc::now()
+        .checked_add_signed(Duration::minutes(ttl_minutes))
+        .ok_or(ServiceError::InternalServerError)?;
+
+    // Only the latest link works
+    store.replace_magic_link(&username, &tokens::hash_token(&magic_token), fingerprint_hash.as_deref(), expiry.naive_utc()).await?;
+
+    let magic_link = format!("{}/magic_login?token={}", config.links.magic_link_base_url, magic_token);
+    let locale = emails.locale(locale.as_deref(), Some(&req));
+    emails.send(
+        &info.email,
+        "magic_link",
+        &locale,
+        &[("link", &magic_link), ("minutes", &ttl_minutes.to_string())],
+    ).await?;
+    info!("Sent magic link to user {}", username);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success"})))
+}
+
+// Answers like /login: a token pair, or the usual 2FA challenge for accounts with 2FA
+#[post("/login/magic_link/verify")]
+async fn consume_magic_link(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<MagicLinkLoginRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let token_hash = tokens::hash_token(&info.token);
+    let MagicLinkRecord { username, has_2fa, fingerprint_hash } = store
+        .find_magic_link(&token_hash)
+        .await?
+        .ok_or(ServiceError::Unauthorized("Invalid or expired login link".to_string()))?;
+
+    // A link requested with a device fingerprint only works on that device
+    if let Some(fingerprint_hash) = fingerprint_hash {
+        let presented = info.device_fingerprint.as_deref().map(tokens::hash_token);
+        if presented.as_deref() != Some(fingerprint_hash.as_str()) {
+            error!("Magic link for user {} used from another device", username);
+            return Err(ServiceError::Unauthorized("This login link was requested from another device".to_string()));
+        }
+    }
+
+    if !store.mark_magic_link_used(&token_hash).await? {
+        return Err(ServiceError::Unauthorized("Invalid or expired login link".to_string()));
+    }
+
+    if has_2fa {
+        return login::second_factor_challenge(store.get_ref(), emails.get_ref(), config.get_ref(), &username).await;
+    }
+
+    notifications::check_login_device(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &username).await?;
+    let (token, refresh_token) = tokens::issue_token_pair(store.get_ref(), config.get_ref(), &username, has_2fa).await?;
+    info!("Generated JWT for user {} via magic link", username);
+
+    Ok(HttpResponse::Ok().json(tokens::token_pair_response(config.get_ref(), &token, &refresh_token)))
+}
@@ -0,0 +1,150 @@
+// mailer.rs
+
+use crate::create::common::*;
+use crate::create::config::{MailConfig, MailTransport, SmtpConfig};
+use crate::create::outbox::Outbox;
+use crate::create::templates::{EmailTemplates, RenderedEmail};
+use async_trait::async_trait;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use std::sync::Arc;
+
+// Delivers rendered emails; only the outbox worker calls it
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, to: &str, email: &RenderedEmail) -> Result<(), ServiceError>;
+}
+
+// Renders a template in the recipient's language and queues it in the outbox. Registered as app data.
+pub struct Emails {
+    outbox: Arc<Outbox>,
+    templates: EmailTemplates,
+}
+
+impl Emails {
+    pub fn new(outbox: Arc<Outbox>, templates: EmailTemplates) -> Self {
+        Emails { outbox, templates }
+    }
+
+    pub fn locale(&self, stored: Option<&str>, req: Option<&HttpRequest>) -> String {
+        self.templates.locale(stored, req)
+    }
+
+    pub async fn send(&self, to: &str, template: &str, locale: &str, vars: &[(&str, &str)]) -> Result<(), ServiceError> {
+        let email = self.templates.render(template, locale, vars)?;
+        self.outbox.enqueue(to, &email).await?;
+        Ok(())
+    }
+}
+
+// Built once at startup from the validated [mail] settings
+pub fn from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>, String> {
+    let from: Mailbox = config.from.parse().map_err(|e| format!("invalid sender address {}: {}", config.from, e))?;
+    let mailer: Arc<dyn Mailer> = match config.transport {
+        MailTransport::Smtp => Arc::new(SmtpMailer::from_config(from, &config.smtp)?),
+        MailTransport::File => {
+            std::fs::create_dir_all(&config.dir).map_err(|e| format!("MAIL_DIR {}: {}", config.dir, e))?;
+            Arc::new(FileMailer::new(from, &config.dir))
+        },
+        MailTransport::Stdout => Arc::new(StdoutMailer { from }),
+    };
+    Ok(mailer)
+}
+
+fn build_message(from: &Mailbox, to: &str, email: &RenderedEmail) -> Result<Message, ServiceError> {
+    Message::builder()
+        .to(to.parse().map_err(|_| {
+            error!("Failed to parse email {}", to);
+            ServiceError::InternalServerError
+        })?)
+        .from(from.clone())
+        .subject(&email.subject)
+        .multipart(MultiPart::alternative_plain_html(email.text.clone(), email.html.clone()))
+        .map_err(|e| {
+            error!("Failed to create email message: {:?}", e);
+            ServiceError::InternalServerError
+        })
+}
+
+// Pooled async SMTP. SMTP_SECURITY is tls (implicit TLS, the default), starttls or plain
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    pub fn from_config(from: Mailbox, config: &SmtpConfig) -> Result<Self, String> {
+        let server = &config.server;
+        let mut builder = match config.security.as_str() {
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(server),
+            "plain" => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(server)),
+            _ => AsyncSmtpTransport::<Tokio1Executor>::relay(server),
+        }
+        .map_err(|e| format!("SMTP_SERVER {}: {}", server, e))?;
+
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+
+        // Without SMTP_PASSWORD the relay is used unauthenticated
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let transport = builder.pool_config(PoolConfig::new().max_size(config.pool_size)).build();
+
+        Ok(SmtpMailer { from, transport })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, to: &str, email: &RenderedEmail) -> Result<(), ServiceError> {
+        let email = build_message(&self.from, to, email)?;
+        self.transport.send(email).await.map_err(|e| {
+            error!("Failed to send email: {:?}", e);
+            ServiceError::InternalServerError
+        })?;
+        Ok(())
+    }
+}
+
+// Writes each message to MAIL_DIR as <uuid>.eml, for local development
+pub struct FileMailer {
+    from: Mailbox,
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileMailer {
+    pub fn new(from: Mailbox, dir: &str) -> Self {
+        FileMailer { from, transport: AsyncFileTransport::new(dir) }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, to: &str, email: &RenderedEmail) -> Result<(), ServiceError> {
+        let email = build_message(&self.from, to, email)?;
+        let id = self.transport.send(email).await.map_err(|e| {
+            error!("Failed to write email: {:?}", e);
+            ServiceError::InternalServerError
+        })?;
+        info!("Wrote email {}.eml for {}", id, to);
+        Ok(())
+    }
+}
+
+// Prints the raw message
+pub struct StdoutMailer {
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, to: &str, email: &RenderedEmail) -> Result<(), ServiceError> {
+        let email = build_message(&self.from, to, email)?;
+        println!("{}", String::from_utf8_lossy(&email.formatted()));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+// migrations.rs
+
+use crate::create::common::*;
+
+// Scripts live in migrations/<dialect>/<version>_<name>.{up,down}.sql and are embedded at build time
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    MySql,
+    Postgres,
+    Sqlite,
+}
+
+macro_rules! migration {
+    ($dialect:literal, $version:literal, $file:literal) => {
+        Migration {
+            version: $version,
+            name: $file,
+            up: include_str!(concat!("../../migrations/", $dialect, "/", $file, ".up.sql")),
+            down: include_str!(concat!("../../migrations/", $dialect, "/", $file, ".down.sql")),
+        }
+    };
+}
+
+// New migrations are appended here, with both scripts written for every dialect
+macro_rules! all_migrations {
+    ($dialect:literal) => {
+        vec![
+            migration!($dialect, 1, "0001_initial_schema"),
+            migration!($dialect, 2, "0002_account_security"),
+            migration!($dialect, 3, "0003_user_locale"),
+            migration!($dialect, 4, "0004_email_outbox"),
+            migration!($dialect, 5, "0005_security_notifications"),
+            migration!($dialect, 6, "0006_auth_tokens"),
+            migration!($dialect, 7, "0007_pending_email"),
+            migration!($dialect, 8, "0008_session_client"),
+            migration!($dialect, 9, "0009_password_change_code"),
+            migration!($dialect, 10, "0010_code_session"),
+        ]
+    };
+}
+
+pub fn migrations(dialect: Dialect) -> Vec<Migration> {
+    match dialect {
+        Dialect::MySql => all_migrations!("mysql"),
+        Dialect::Postgres => all_migrations!("postgres"),
+        Dialect::Sqlite => all_migrations!("sqlite"),
+    }
+}
+
+// Scripts are run statement by statement, so they must not contain ';' inside literals
+pub fn statements(script: &str) -> impl Iterator<Item = &str> {
+    script.split(';').map(str::trim).filter(|statement| !statement.is_empty())
+}
+
+pub async fn pending(store: &dyn UserStore) -> Result<Vec<Migration>, ServiceError> {
+    let applied = store.applied_migrations().await?;
+    Ok(migrations(store.dialect())
+        .into_iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+// Applies pending migrations in order, up to and including `target` when given
+pub async fn migrate_up(store: &dyn UserStore, target: Option<i64>) -> Result<Vec<i64>, ServiceError> {
+    let mut applied = Vec::new();
+
+    for migration in pending(store).await? {
+        if target.is_some_and(|target| migration.version > target) {
+            break;
+        }
+        info!("Applying migration {}", migration.name);
+        store.apply_migration(&migration).await?;
+        applied.push(migration.version);
+    }
+
+    Ok(applied)
+}
+
+// Reverts the last `steps` applied migrations, newest first
+pub async fn migrate_down(store: &dyn UserStore, steps: usize) -> Result<Vec<i64>, ServiceError> {
+    let mut applied = store.applied_migrations().await?;
+    applied.sort_unstable_by(|a, b| b.cmp(a));
+
+    let known = migrations(store.dialect());
+    let mut reverted = Vec::new();
+
+    for version in applied.into_iter().take(steps) {
+        let Some(migration) = known.iter().find(|migration| migration.version == version) else {
+            error!("Applied migration {} is unknown to this build", version);
+            return Err(ServiceError::InternalServerError);
+        };
+        info!("Reverting migration {}", migration.name);
+        store.revert_migration(migration).await?;
+        reverted.push(version);
+    }
+
+    Ok(reverted)
+}
+
+// `migrate [up [VERSION] | down [STEPS] | status]`
+pub async fn run_command(store: &dyn UserStore, args: &[String]) -> Result<(), String> {
+    let parse_arg = |arg: Option<&String>| -> Result<Option<i64>, String> {
+        arg.map(|value| value.parse::<i64>().map_err(|_| format!("Not a number: {}", value))).transpose()
+    };
+    let failed = |e: ServiceError| format!("Migration failed: {:?}", e);
+
+    match args.first().map(String::as_str).unwrap_or("up") {
+        "up" => {
+            let applied = migrate_up(store, parse_arg(args.get(1))?).await.map_err(failed)?;
+            println!("Applied {} migration(s): {:?}", applied.len(), applied);
+        },
+        "down" => {
+            let steps = parse_arg(args.get(1))?.unwrap_or(1).max(0) as usize;
+            let reverted = migrate_down(store, steps).await.map_err(failed)?;
+            println!("Reverted {} migration(s): {:?}", reverted.len(), reverted);
+        },
+        "status" => {
+            let applied = store.applied_migrations().await.map_err(failed)?;
+            for migration in migrations(store.dialect()) {
+                let state = if applied.contains(&migration.version) { "applied" } else { "pending" };
+                println!("{:<8} {}", state, migration.name);
+            }
+        },
+        other => return Err(format!("Unknown migrate command: {} (expected up, down or status)", other)),
+    }
+
+    Ok(())
+}
@@ -1,15 +1,63 @@
 // mod.rs
 
+#[cfg(feature = "email-verification")]
 pub mod sbverification;
+#[cfg(feature = "email-verification")]
 pub mod verify;
+#[cfg(feature = "registration")]
 pub mod register;
 pub mod login;
 pub mod common;
+pub mod config;
+#[cfg(feature = "password-reset")]
 pub mod forgot;
+#[cfg(feature = "password-reset")]
 pub mod reset;
+#[cfg(feature = "two-factor")]
 pub mod twoauth;
+#[cfg(feature = "two-factor")]
 pub mod handletwofa;
+#[cfg(feature = "two-factor")]
 pub mod deactivatetwoauth;
+#[cfg(feature = "two-factor")]
 pub mod activatetwoauth;
+#[cfg(feature = "two-factor")]
 pub mod verifyactivatetwoauth;
+#[cfg(feature = "registration")]
 pub mod registertwo;
+pub mod crypto;
+#[cfg(feature = "two-factor")]
+pub mod totp;
+#[cfg(feature = "two-factor")]
+pub mod enrolltotp;
+#[cfg(feature = "two-factor")]
+pub mod recoverycodes;
+pub mod tokens;
+pub mod authtokens;
+pub mod passwordpolicy;
+pub mod passwordhash;
+pub mod changepassword;
+pub mod changeemail;
+pub mod refresh;
+pub mod sessions;
+pub mod der;
+pub mod jwks;
+pub mod oauthclients;
+pub mod oidc;
+pub mod authorize;
+pub mod oidctoken;
+pub mod socialidp;
+pub mod social;
+pub mod webauthn;
+pub mod passkeys;
+pub mod magiclink;
+pub mod lockout;
+pub mod ratelimit;
+pub mod store;
+pub mod mysqlstore;
+pub mod sqlxstore;
+pub mod migrations;
+pub mod mailer;
+pub mod templates;
+pub mod outbox;
+pub mod notifications;
@@ -0,0 +1,1065 @@
+// mysqlstore.rs
+
+use crate::create::common::*;
+use crate::create::store::*;
+use async_trait::async_trait;
+use crate::create::migrations::{self, Dialect, Migration};
+use mysql_async::{Opts, OptsBuilder};
+
+pub struct MySqlStore {
+    pool: Pool,
+}
+
+impl MySqlStore {
+    // Creates the database first when it does not exist yet
+    pub async fn connect(database_url: &str, database_name: Option<&str>) -> Result<Self, ServiceError> {
+        let opts = Opts::from_url(database_url).map_err(|e| {
+            error!("Failed to parse database URL: {:?}", e);
+            ServiceError::InternalServerError
+        })?;
+        let db_name = database_name.or(opts.db_name()).map(str::to_string);
+
+        if let Some(db_name) = &db_name {
+            let mut conn = Conn::new(OptsBuilder::from_opts(opts.clone()).db_name(None::<String>)).await.map_err(|e| {
+                error!("Failed to connect to MySQL: {:?}", e);
+                ServiceError::InternalServerError
+            })?;
+            conn.query_drop(format!("CREATE DATABASE IF NOT EXISTS `{}`", db_name)).await.map_err(db_error)?;
+            conn.disconnect().await.map_err(db_error)?;
+        }
+
+        Ok(MySqlStore { pool: Pool::new(OptsBuilder::from_opts(opts).db_name(db_name)) })
+    }
+
+    async fn conn(&self) -> Result<Conn, ServiceError> {
+        self.pool.get_conn().await.map_err(|e| {
+            error!("Error getting DB connection: {:?}", e);
+            ServiceError::InternalServerError
+        })
+    }
+}
+
+fn ts(value: NaiveDateTime) -> String {
+    value.to_string()
+}
+
+// Date columns are read through DATE_FORMAT(..., '%Y-%m-%d %H:%i:%s')
+fn parse_datetime(value: Option<String>) -> Option<NaiveDateTime> {
+    value.and_then(|value| NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S").ok())
+}
+
+const USER_COLUMNS: &str = "username, email, password, verified, has_2fa, 2fa_method, locale, password_reset_required";
+
+type UserRow = (String, String, String, bool, bool, String, Option<String>, bool);
+
+fn user_from_row((username, email, password_hash, verified, has_2fa, two_fa_method, locale, password_reset_required): UserRow) -> User {
+    User { username, email, password_hash, verified, has_2fa, two_fa_method, locale, password_reset_required }
+}
+
+const AUTH_TOKEN_COLUMNS: &str = r"t.id, u.username, t.token_hash, DATE_FORMAT(t.expires_at, '%Y-%m-%d %H:%i:%s'),
+    DATE_FORMAT(t.consumed_at, '%Y-%m-%d %H:%i:%s')";
+
+type AuthTokenRow = (i64, String, String, Option<String>, Option<String>);
+
+fn auth_token_from_row((id, username, token_hash, expires_at, consumed_at): AuthTokenRow) -> AuthToken {
+    // An unreadable expiry counts as expired
+    let expires_at = parse_datetime(expires_at).unwrap_or_default();
+    AuthToken { id, username, token_hash, expires_at, consumed_at: parse_datetime(consumed_at) }
+}
+
+// Table exists, duplicate column, duplicate key name, can't drop a missing column or key
+const ALREADY_APPLIED: [u16; 4] = [1050, 1060, 1061, 1091];
+
+// A single ALTER TABLE is applied whole or not at all, so a statement failing with one of ALREADY_APPLIED
+// ran before the script failed the last time and is skipped
+async fn run_script(conn: &mut Conn, script: &str) -> Result<(), ServiceError> {
+    for statement in migrations::statements(script) {
+        match conn.query_drop(statement).await {
+            Err(mysql_async::Error::Server(e)) if ALREADY_APPLIED.contains(&e.code) => {
+                info!("Skipping migration statement that was already applied: {}", e.message);
+            },
+            result => result.map_err(db_error)?,
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl UserStore for MySqlStore {
+    fn dialect(&self) -> Dialect {
+        Dialect::MySql
+    }
+
+    async fn applied_migrations(&self) -> Result<Vec<i64>, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.query_drop(SCHEMA_MIGRATIONS_TABLE).await.map_err(db_error)?;
+        conn.query("SELECT version FROM schema_migrations ORDER BY version").await.map_err(db_error)
+    }
+
+    // MySQL commits every DDL statement on its own, so a script that fails halfway keeps what ran before it.
+    // Running it again is safe, see run_script
+    async fn apply_migration(&self, migration: &Migration) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        run_script(&mut conn, migration.up).await?;
+        conn.exec_drop(
+            "INSERT INTO schema_migrations (version, name) VALUES (?, ?)",
+            (migration.version, migration.name),
+        ).await.map_err(db_error)
+    }
+
+    async fn revert_migration(&self, migration: &Migration) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        run_script(&mut conn, migration.down).await?;
+        conn.exec_drop("DELETE FROM schema_migrations WHERE version = ?", (migration.version,)).await.map_err(db_error)
+    }
+
+    async fn create_user(&self, user: NewUser<'_>) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"INSERT INTO users (username, email, password, verified, locale)
+               VALUES (?, ?, ?, ?, ?)",
+            (user.username, user.email, user.password_hash, user.verified, user.locale),
+        ).await.map_err(db_error)
+    }
+
+    async fn find_user(&self, username: &str) -> Result<Option<User>, ServiceError> {
+        let row: Option<UserRow> = self.conn().await?
+            .exec_first(format!("SELECT {} FROM users WHERE username = ?", USER_COLUMNS), (username,))
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(user_from_row))
+    }
+
+    async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, ServiceError> {
+        let row: Option<UserRow> = self.conn().await?
+            .exec_first(format!("SELECT {} FROM users WHERE email = ?", USER_COLUMNS), (email,))
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(user_from_row))
+    }
+
+    async fn insert_auth_token(&self, username: &str, purpose: TokenPurpose, token_hash: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"DELETE FROM auth_tokens WHERE purpose = ? AND consumed_at IS NULL
+               AND user_id = (SELECT id FROM users WHERE username = ?)",
+            (purpose.as_str(), username),
+        ).await.map_err(db_error)?;
+
+        conn.exec_drop(
+            r"INSERT INTO auth_tokens (user_id, purpose, token_hash, expires_at)
+               SELECT id, ?, ?, ? FROM users WHERE username = ?",
+            (purpose.as_str(), token_hash, ts(expires_at), username),
+        ).await.map_err(db_error)
+    }
+
+    async fn find_auth_token(&self, purpose: TokenPurpose, token_hash: &str) -> Result<Option<AuthToken>, ServiceError> {
+        let row: Option<AuthTokenRow> = self.conn().await?
+            .exec_first(
+                format!(
+                    "SELECT {} FROM auth_tokens t JOIN users u ON u.id = t.user_id WHERE t.purpose = ? AND t.token_hash = ?",
+                    AUTH_TOKEN_COLUMNS
+                ),
+                (purpose.as_str(), token_hash),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(auth_token_from_row))
+    }
+
+    #[cfg(any(feature = "two-factor", feature = "password-reset"))]
+    async fn find_user_auth_token(&self, username: &str, purpose: TokenPurpose) -> Result<Option<AuthToken>, ServiceError> {
+        let row: Option<AuthTokenRow> = self.conn().await?
+            .exec_first(
+                format!(
+                    r"SELECT {} FROM auth_tokens t JOIN users u ON u.id = t.user_id
+                       WHERE u.username = ? AND t.purpose = ? AND t.consumed_at IS NULL ORDER BY t.id DESC LIMIT 1",
+                    AUTH_TOKEN_COLUMNS
+                ),
+                (username, purpose.as_str()),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(auth_token_from_row))
+    }
+
+    async fn consume_auth_token(&self, id: i64) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE auth_tokens SET consumed_at = UTC_TIMESTAMP() WHERE id = ? AND consumed_at IS NULL",
+            (id,),
+        ).await.map_err(db_error)?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    async fn mark_email_verified(&self, username: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET verified = true WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)
+    }
+
+    async fn update_password_hash(&self, username: &str, current_hash: &str, new_hash: &str) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE users SET password = ? WHERE username = ? AND password = ?",
+            (new_hash, username, current_hash),
+        ).await.map_err(db_error)?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    async fn reset_password(&self, email: &str, password_hash: &str) -> Result<Option<String>, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"UPDATE users SET password = ?, failed_login_attempts = 0, locked_until = NULL, unlock_token_hash = NULL, password_reset_required = FALSE
+               WHERE email = ?",
+            (password_hash, email),
+        ).await.map_err(db_error)?;
+
+        conn.exec_first("SELECT username FROM users WHERE email = ?", (email,))
+            .await
+            .map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn set_2fa_code(&self, username: &str, code: &str, expiry: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET 2fa_code = ?, 2fa_expiry = ? WHERE username = ?",
+            (code, ts(expiry), username),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn find_pending_2fa(&self, username: &str) -> Result<Option<PendingTwoFactor>, ServiceError> {
+        let row: Option<Row> = self.conn().await?
+            .exec_first(
+                r"SELECT 2fa_code, DATE_FORMAT(2fa_expiry, '%Y-%m-%d %H:%i:%s') AS 2fa_expiry, username, has_2fa, 2fa_method
+                   FROM users WHERE username = ?",
+                (username,),
+            )
+            .await
+            .map_err(db_error)?;
+
+        Ok(row.map(|mut row| PendingTwoFactor {
+            username: row.take("username").unwrap(),
+            has_2fa: row.take("has_2fa").unwrap_or(false),
+            two_fa_method: row.take("2fa_method").unwrap_or_else(|| "email".to_string()),
+            code: row.take("2fa_code").unwrap_or(None),
+            code_expiry: parse_datetime(row.take("2fa_expiry").unwrap_or(None)),
+        }))
+    }
+
+    async fn start_email_change(&self, username: &str, new_email: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET pending_email = ? WHERE username = ?",
+            (new_email, username),
+        ).await.map_err(db_error)
+    }
+
+    async fn find_email_change(&self, username: &str) -> Result<Option<String>, ServiceError> {
+        let email: Option<Option<String>> = self.conn().await?
+            .exec_first("SELECT pending_email FROM users WHERE username = ?", (username,))
+            .await
+            .map_err(db_error)?;
+        Ok(email.flatten())
+    }
+
+    async fn apply_email_change(&self, username: &str, new_email: &str) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        let result = conn.exec_drop(
+            "UPDATE users SET email = ?, pending_email = NULL, verified = TRUE WHERE username = ? AND pending_email = ?",
+            (new_email, username, new_email),
+        ).await;
+        match result {
+            Ok(()) => Ok(conn.affected_rows() > 0),
+            // ER_DUP_ENTRY
+            Err(mysql_async::Error::Server(ref e)) if e.code == 1062 => Ok(false),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    async fn clear_email_change(&self, username: &str) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE users SET pending_email = NULL WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)?;
+
+        conn.exec_drop(
+            r"DELETE FROM auth_tokens WHERE purpose IN (?, ?) AND consumed_at IS NULL
+               AND user_id = (SELECT id FROM users WHERE username = ?)",
+            (TokenPurpose::EmailChange.as_str(), TokenPurpose::EmailChangeCancel.as_str(), username),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn start_2fa_change(&self, username: &str, code: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET temp_2fa_code = ? WHERE username = ?",
+            (code, username),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn find_2fa_change(&self, username: &str) -> Result<Option<String>, ServiceError> {
+        let code: Option<Option<String>> = self.conn().await?
+            .exec_first("SELECT temp_2fa_code FROM users WHERE username = ?", (username,))
+            .await
+            .map_err(db_error)?;
+        Ok(code.flatten())
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn start_password_change(&self, username: &str, code: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET password_change_code = ? WHERE username = ?",
+            (code, username),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn find_password_change(&self, username: &str) -> Result<Option<String>, ServiceError> {
+        let code: Option<Option<String>> = self.conn().await?
+            .exec_first("SELECT password_change_code FROM users WHERE username = ?", (username,))
+            .await
+            .map_err(db_error)?;
+        Ok(code.flatten())
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn enable_email_2fa(&self, username: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET has_2fa = 1, 2fa_method = 'email', totp_secret = NULL, totp_last_step = NULL, temp_2fa_code = NULL WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)
+    }
+
+    async fn count_2fa_users(&self) -> Result<i64, ServiceError> {
+        let count: Option<i64> = self.conn().await?
+            .query_first("SELECT COUNT(*) FROM users WHERE has_2fa = 1")
+            .await
+            .map_err(db_error)?;
+        Ok(count.unwrap_or(0))
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn disable_2fa(&self, username: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET has_2fa = false, 2fa_method = 'email', totp_secret = NULL, totp_last_step = NULL, 2fa_code = NULL, 2fa_expiry = NULL, temp_2fa_code = NULL WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn start_totp_enrollment(&self, username: &str, encrypted_secret: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET temp_totp_secret = ? WHERE username = ?",
+            (encrypted_secret, username),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn find_totp_enrollment(&self, username: &str) -> Result<Option<String>, ServiceError> {
+        let secret: Option<Option<String>> = self.conn().await?
+            .exec_first("SELECT temp_totp_secret FROM users WHERE username = ?", (username,))
+            .await
+            .map_err(db_error)?;
+        Ok(secret.flatten())
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn enable_totp(&self, username: &str, last_step: i64) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"UPDATE users SET has_2fa = 1, 2fa_method = 'totp', totp_secret = temp_totp_secret, totp_last_step = ?,
+               temp_totp_secret = NULL, 2fa_code = NULL, 2fa_expiry = NULL WHERE username = ?",
+            (last_step, username),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn find_totp_secret(&self, username: &str) -> Result<Option<(Option<String>, Option<i64>)>, ServiceError> {
+        self.conn().await?
+            .exec_first("SELECT totp_secret, totp_last_step FROM users WHERE username = ?", (username,))
+            .await
+            .map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn set_totp_last_step(&self, username: &str, last_step: i64) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET totp_last_step = ? WHERE username = ?",
+            (last_step, username),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn replace_recovery_codes(&self, username: &str, code_hashes: &[String]) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "DELETE FROM recovery_codes WHERE user_id = (SELECT id FROM users WHERE username = ?)",
+            (username,),
+        ).await.map_err(db_error)?;
+
+        conn.exec_batch(
+            "INSERT INTO recovery_codes (user_id, code_hash) SELECT id, ? FROM users WHERE username = ?",
+            code_hashes.iter().map(|code_hash| (code_hash, username)),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn delete_recovery_codes(&self, username: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "DELETE FROM recovery_codes WHERE user_id = (SELECT id FROM users WHERE username = ?)",
+            (username,),
+        ).await.map_err(db_error)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn consume_recovery_code(&self, username: &str, code_hash: &str) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"UPDATE recovery_codes SET used_at = UTC_TIMESTAMP()
+               WHERE user_id = (SELECT id FROM users WHERE username = ?) AND code_hash = ? AND used_at IS NULL",
+            (username, code_hash),
+        ).await.map_err(db_error)?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn count_recovery_codes(&self, username: &str) -> Result<usize, ServiceError> {
+        let remaining: Option<usize> = self.conn().await?
+            .exec_first(
+                r"SELECT COUNT(*) FROM recovery_codes
+                   WHERE user_id = (SELECT id FROM users WHERE username = ?) AND used_at IS NULL",
+                (username,),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(remaining.unwrap_or(0))
+    }
+
+    async fn create_session(&self, jti: &str, username: &str, client: Option<&SessionClient>, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "INSERT INTO sessions (jti, user_id, client_id, scope, expires_at) SELECT ?, id, ?, ?, ? FROM users WHERE username = ?",
+            (jti, client.map(|client| client.client_id.as_str()), client.map(|client| client.scope.as_str()), ts(expires_at), username),
+        ).await.map_err(db_error)
+    }
+
+    async fn is_session_active(&self, jti: &str) -> Result<bool, ServiceError> {
+        let active: Option<bool> = self.conn().await?
+            .exec_first(
+                "SELECT revoked_at IS NULL AND expires_at > UTC_TIMESTAMP() FROM sessions WHERE jti = ?",
+                (jti,),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(active.unwrap_or(false))
+    }
+
+    async fn extend_session(&self, jti: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE sessions SET expires_at = ? WHERE jti = ? AND revoked_at IS NULL",
+            (ts(expires_at), jti),
+        ).await.map_err(db_error)
+    }
+
+    async fn revoke_session(&self, jti: &str) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE sessions SET revoked_at = UTC_TIMESTAMP() WHERE jti = ? AND revoked_at IS NULL",
+            (jti,),
+        ).await.map_err(db_error)?;
+
+        conn.exec_drop(
+            "UPDATE refresh_tokens SET revoked_at = UTC_TIMESTAMP() WHERE family_id = ? AND revoked_at IS NULL",
+            (jti,),
+        ).await.map_err(db_error)
+    }
+
+    async fn revoke_other_sessions(&self, username: &str, keep_jti: &str) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"UPDATE sessions SET revoked_at = UTC_TIMESTAMP()
+               WHERE user_id = (SELECT id FROM users WHERE username = ?) AND jti <> ? AND revoked_at IS NULL",
+            (username, keep_jti),
+        ).await.map_err(db_error)?;
+
+        conn.exec_drop(
+            r"UPDATE refresh_tokens SET revoked_at = UTC_TIMESTAMP()
+               WHERE user_id = (SELECT id FROM users WHERE username = ?) AND family_id <> ? AND revoked_at IS NULL",
+            (username, keep_jti),
+        ).await.map_err(db_error)
+    }
+
+    async fn revoke_all_sessions(&self, username: &str) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"UPDATE sessions SET revoked_at = UTC_TIMESTAMP()
+               WHERE user_id = (SELECT id FROM users WHERE username = ?) AND revoked_at IS NULL",
+            (username,),
+        ).await.map_err(db_error)?;
+
+        conn.exec_drop(
+            r"UPDATE refresh_tokens SET revoked_at = UTC_TIMESTAMP()
+               WHERE user_id = (SELECT id FROM users WHERE username = ?) AND revoked_at IS NULL",
+            (username,),
+        ).await.map_err(db_error)
+    }
+
+    async fn insert_refresh_token(&self, username: &str, token_hash: &str, family_id: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"INSERT INTO refresh_tokens (user_id, token_hash, family_id, expires_at)
+               SELECT id, ?, ?, ? FROM users WHERE username = ?",
+            (token_hash, family_id, ts(expires_at), username),
+        ).await.map_err(db_error)
+    }
+
+    async fn find_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRecord>, ServiceError> {
+        let row: Option<(u64, String, Option<String>, bool, String, bool, bool, Option<String>, Option<String>)> = self.conn().await?
+            .exec_first(
+                r"SELECT rt.id, rt.family_id, DATE_FORMAT(rt.expires_at, '%Y-%m-%d %H:%i:%s'),
+                         rt.used_at IS NOT NULL, u.username, u.has_2fa, rt.revoked_at IS NOT NULL, s.client_id, s.scope
+                   FROM refresh_tokens rt JOIN users u ON u.id = rt.user_id
+                   LEFT JOIN sessions s ON s.jti = rt.family_id
+                   WHERE rt.token_hash = ?",
+                (token_hash,),
+            )
+            .await
+            .map_err(db_error)?;
+
+        Ok(row.map(|(id, family_id, expires_at, used, username, has_2fa, revoked, client_id, scope)| RefreshTokenRecord {
+            id: id as i64,
+            family_id,
+            expires_at: parse_datetime(expires_at).unwrap_or_default(),
+            used,
+            revoked,
+            username,
+            has_2fa,
+            client: client_id.map(|client_id| SessionClient { client_id, scope: scope.unwrap_or_default() }),
+        }))
+    }
+
+    async fn mark_refresh_token_used(&self, id: i64) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE refresh_tokens SET used_at = UTC_TIMESTAMP() WHERE id = ? AND used_at IS NULL",
+            (id,),
+        ).await.map_err(db_error)?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    async fn insert_oauth_client(&self, client_id: &str, client_secret_hash: Option<&str>, client_name: &str, redirect_uris: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "INSERT INTO oauth_clients (client_id, client_secret_hash, client_name, redirect_uris) VALUES (?, ?, ?, ?)",
+            (client_id, client_secret_hash, client_name, redirect_uris),
+        ).await.map_err(db_error)
+    }
+
+    async fn find_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClientRecord>, ServiceError> {
+        let row: Option<(String, Option<String>, String)> = self.conn().await?
+            .exec_first(
+                "SELECT client_id, client_secret_hash, redirect_uris FROM oauth_clients WHERE client_id = ?",
+                (client_id,),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(|(client_id, client_secret_hash, redirect_uris)| OAuthClientRecord { client_id, client_secret_hash, redirect_uris }))
+    }
+
+    async fn insert_authorization_request(&self, id: &str, request: &AuthorizationRequest, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"INSERT INTO oauth_authorization_requests (id, client_id, redirect_uri, scope, state, nonce, code_challenge, expires_at)
+               VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            (id, &request.client_id, &request.redirect_uri, &request.scope, &request.state, &request.nonce, &request.code_challenge, ts(expires_at)),
+        ).await.map_err(db_error)
+    }
+
+    async fn find_authorization_request(&self, id: &str) -> Result<Option<AuthorizationRequest>, ServiceError> {
+        let row: Option<Row> = self.conn().await?
+            .exec_first(
+                r"SELECT ar.client_id, ar.redirect_uri, ar.scope, ar.state, ar.nonce, ar.code_challenge, u.username
+                   FROM oauth_authorization_requests ar LEFT JOIN users u ON u.id = ar.user_id
+                   WHERE ar.id = ? AND ar.expires_at > UTC_TIMESTAMP()",
+                (id,),
+            )
+            .await
+            .map_err(db_error)?;
+
+        Ok(row.map(|mut row| AuthorizationRequest {
+            client_id: row.take("client_id").unwrap(),
+            redirect_uri: row.take("redirect_uri").unwrap(),
+            scope: row.take("scope").unwrap(),
+            state: row.take("state").unwrap_or(None),
+            nonce: row.take("nonce").unwrap_or(None),
+            code_challenge: row.take("code_challenge").unwrap(),
+            pending_username: row.take("username").unwrap_or(None),
+        }))
+    }
+
+    async fn set_authorization_request_user(&self, id: &str, username: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE oauth_authorization_requests SET user_id = (SELECT id FROM users WHERE username = ?) WHERE id = ?",
+            (username, id),
+        ).await.map_err(db_error)
+    }
+
+    async fn delete_authorization_request(&self, id: &str) -> Result<(), ServiceError> {
+        self.conn().await?
+            .exec_drop("DELETE FROM oauth_authorization_requests WHERE id = ?", (id,))
+            .await
+            .map_err(db_error)
+    }
+
+    async fn insert_authorization_code(&self, code_hash: &str, code: &AuthorizationCode, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"INSERT INTO oauth_codes (code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time, expires_at)
+               SELECT ?, ?, id, ?, ?, ?, ?, ?, ? FROM users WHERE username = ?",
+            (
+                code_hash, &code.client_id, &code.redirect_uri, &code.scope,
+                &code.nonce, &code.code_challenge, code.auth_time, ts(expires_at), &code.username,
+            ),
+        ).await.map_err(db_error)
+    }
+
+    async fn find_authorization_code(&self, code_hash: &str) -> Result<Option<(AuthorizationCode, bool)>, ServiceError> {
+        let row: Option<Row> = self.conn().await?
+            .exec_first(
+                r"SELECT oc.client_id, oc.redirect_uri, oc.scope, oc.code_challenge, oc.nonce, oc.auth_time, u.username, u.has_2fa
+                   FROM oauth_codes oc JOIN users u ON u.id = oc.user_id
+                   WHERE oc.code_hash = ? AND oc.used_at IS NULL AND oc.expires_at > UTC_TIMESTAMP()",
+                (code_hash,),
+            )
+            .await
+            .map_err(db_error)?;
+
+        Ok(row.map(|mut row| {
+            let code = AuthorizationCode {
+                client_id: row.take("client_id").unwrap(),
+                username: row.take("username").unwrap(),
+                redirect_uri: row.take("redirect_uri").unwrap(),
+                scope: row.take("scope").unwrap(),
+                nonce: row.take("nonce").unwrap_or(None),
+                code_challenge: row.take("code_challenge").unwrap(),
+                auth_time: row.take("auth_time").unwrap(),
+            };
+            (code, row.take("has_2fa").unwrap_or(false))
+        }))
+    }
+
+    async fn mark_authorization_code_used(&self, code_hash: &str, session_jti: Option<&str>) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE oauth_codes SET used_at = UTC_TIMESTAMP(), session_jti = ? WHERE code_hash = ? AND used_at IS NULL",
+            (session_jti, code_hash),
+        ).await.map_err(db_error)?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    async fn find_authorization_code_session(&self, code_hash: &str) -> Result<Option<String>, ServiceError> {
+        let jti: Option<Option<String>> = self.conn().await?
+            .exec_first("SELECT session_jti FROM oauth_codes WHERE code_hash = ? AND used_at IS NOT NULL", (code_hash,))
+            .await
+            .map_err(db_error)?;
+        Ok(jti.flatten())
+    }
+
+    async fn insert_social_state(&self, state: &str, provider: &str, nonce: &str, code_verifier: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "INSERT INTO social_login_states (state, provider, nonce, code_verifier, expires_at) VALUES (?, ?, ?, ?, ?)",
+            (state, provider, nonce, code_verifier, ts(expires_at)),
+        ).await.map_err(db_error)
+    }
+
+    async fn take_social_state(&self, state: &str, provider: &str) -> Result<Option<(String, String)>, ServiceError> {
+        let mut conn = self.conn().await?;
+        let pending: Option<(String, String)> = conn
+            .exec_first(
+                r"SELECT nonce, code_verifier FROM social_login_states
+                   WHERE state = ? AND provider = ? AND expires_at > UTC_TIMESTAMP()",
+                (state, provider),
+            )
+            .await
+            .map_err(db_error)?;
+
+        conn.exec_drop("DELETE FROM social_login_states WHERE state = ?", (state,))
+            .await
+            .map_err(db_error)?;
+
+        Ok(pending)
+    }
+
+    async fn find_social_identity(&self, provider: &str, subject: &str) -> Result<Option<String>, ServiceError> {
+        self.conn().await?
+            .exec_first(
+                r"SELECT u.username FROM social_identities si JOIN users u ON u.id = si.user_id
+                   WHERE si.provider = ? AND si.subject = ?",
+                (provider, subject),
+            )
+            .await
+            .map_err(db_error)
+    }
+
+    async fn link_social_identity(&self, username: &str, provider: &str, subject: &str, email: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"INSERT INTO social_identities (user_id, provider, subject, email)
+               SELECT id, ?, ?, ? FROM users WHERE username = ?",
+            (provider, subject, email, username),
+        ).await.map_err(db_error)
+    }
+
+    async fn insert_webauthn_challenge(&self, id: &str, username: Option<&str>, purpose: &str, challenge: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"INSERT INTO webauthn_challenges (id, user_id, purpose, challenge, expires_at)
+               VALUES (?, (SELECT id FROM users WHERE username = ?), ?, ?, ?)",
+            (id, username, purpose, challenge, ts(expires_at)),
+        ).await.map_err(db_error)
+    }
+
+    async fn take_webauthn_challenge(&self, id: &str, purpose: &str) -> Result<Option<(String, Option<String>)>, ServiceError> {
+        let mut conn = self.conn().await?;
+        let row: Option<(String, Option<String>)> = conn
+            .exec_first(
+                r"SELECT wc.challenge, u.username FROM webauthn_challenges wc LEFT JOIN users u ON u.id = wc.user_id
+                   WHERE wc.id = ? AND wc.purpose = ? AND wc.expires_at > UTC_TIMESTAMP()",
+                (id, purpose),
+            )
+            .await
+            .map_err(db_error)?;
+
+        conn.exec_drop("DELETE FROM webauthn_challenges WHERE id = ?", (id,))
+            .await
+            .map_err(db_error)?;
+
+        Ok(row)
+    }
+
+    async fn list_webauthn_credential_ids(&self, username: &str) -> Result<Vec<String>, ServiceError> {
+        self.conn().await?
+            .exec(
+                r"SELECT wc.credential_id FROM webauthn_credentials wc JOIN users u ON u.id = wc.user_id
+                   WHERE u.username = ?",
+                (username,),
+            )
+            .await
+            .map_err(db_error)
+    }
+
+    async fn find_webauthn_credential(&self, credential_id: &str) -> Result<Option<WebAuthnCredentialRecord>, ServiceError> {
+        let row: Option<(String, String, u32)> = self.conn().await?
+            .exec_first(
+                r"SELECT u.username, wc.public_key, wc.sign_count FROM webauthn_credentials wc JOIN users u ON u.id = wc.user_id
+                   WHERE wc.credential_id = ?",
+                (credential_id,),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(|(username, public_key, sign_count)| WebAuthnCredentialRecord { username, public_key, sign_count }))
+    }
+
+    async fn insert_webauthn_credential(&self, credential: NewWebAuthnCredential<'_>) -> Result<bool, ServiceError> {
+        let inserted = self.conn().await?.exec_drop(
+            r"INSERT INTO webauthn_credentials (user_id, credential_id, public_key, alg, sign_count, name)
+               SELECT id, ?, ?, ?, ?, ? FROM users WHERE username = ?",
+            (credential.credential_id, credential.public_key, credential.alg, credential.sign_count, credential.name, credential.username),
+        ).await;
+
+        match inserted {
+            Ok(()) => Ok(true),
+            Err(mysql_async::Error::Server(ref server_error)) if server_error.code == 1062 => Ok(false),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    async fn update_webauthn_sign_count(&self, credential_id: &str, sign_count: u32) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE webauthn_credentials SET sign_count = ?, last_used_at = UTC_TIMESTAMP() WHERE credential_id = ?",
+            (sign_count, credential_id),
+        ).await.map_err(db_error)
+    }
+
+    async fn replace_magic_link(&self, username: &str, token_hash: &str, fingerprint_hash: Option<&str>, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "DELETE FROM magic_link_tokens WHERE used_at IS NULL AND user_id = (SELECT id FROM users WHERE username = ?)",
+            (username,),
+        ).await.map_err(db_error)?;
+
+        conn.exec_drop(
+            r"INSERT INTO magic_link_tokens (token_hash, user_id, fingerprint_hash, expires_at)
+               SELECT ?, id, ?, ? FROM users WHERE username = ?",
+            (token_hash, fingerprint_hash, ts(expires_at), username),
+        ).await.map_err(db_error)
+    }
+
+    async fn find_magic_link(&self, token_hash: &str) -> Result<Option<MagicLinkRecord>, ServiceError> {
+        let row: Option<(String, bool, Option<String>)> = self.conn().await?
+            .exec_first(
+                r"SELECT u.username, u.has_2fa, ml.fingerprint_hash FROM magic_link_tokens ml JOIN users u ON u.id = ml.user_id
+                   WHERE ml.token_hash = ? AND ml.used_at IS NULL AND ml.expires_at > UTC_TIMESTAMP()",
+                (token_hash,),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(|(username, has_2fa, fingerprint_hash)| MagicLinkRecord { username, has_2fa, fingerprint_hash }))
+    }
+
+    async fn mark_magic_link_used(&self, token_hash: &str) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE magic_link_tokens SET used_at = UTC_TIMESTAMP() WHERE token_hash = ? AND used_at IS NULL",
+            (token_hash,),
+        ).await.map_err(db_error)?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    async fn ip_blocked_until(&self, ip: &str) -> Result<Option<NaiveDateTime>, ServiceError> {
+        let until: Option<Option<String>> = self.conn().await?
+            .exec_first(
+                "SELECT DATE_FORMAT(blocked_until, '%Y-%m-%d %H:%i:%s') FROM ip_login_failures WHERE ip = ?",
+                (ip,),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(parse_datetime(until.flatten()))
+    }
+
+    async fn account_locked_until(&self, username: &str) -> Result<Option<NaiveDateTime>, ServiceError> {
+        let until: Option<Option<String>> = self.conn().await?
+            .exec_first(
+                "SELECT DATE_FORMAT(locked_until, '%Y-%m-%d %H:%i:%s') FROM users WHERE username = ?",
+                (username,),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(parse_datetime(until.flatten()))
+    }
+
+    async fn record_ip_failure(&self, ip: &str) -> Result<i64, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"INSERT INTO ip_login_failures (ip, failures, last_failure_at) VALUES (?, 1, UTC_TIMESTAMP())
+               ON DUPLICATE KEY UPDATE
+                 failures = IF(last_failure_at < UTC_TIMESTAMP() - INTERVAL 1 DAY, 1, failures + 1),
+                 last_failure_at = UTC_TIMESTAMP()",
+            (ip,),
+        ).await.map_err(db_error)?;
+
+        let failures: Option<i64> = conn
+            .exec_first("SELECT failures FROM ip_login_failures WHERE ip = ?", (ip,))
+            .await
+            .map_err(db_error)?;
+        Ok(failures.unwrap_or(0))
+    }
+
+    async fn block_ip(&self, ip: &str, until: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE ip_login_failures SET blocked_until = ? WHERE ip = ?",
+            (ts(until), ip),
+        ).await.map_err(db_error)
+    }
+
+    async fn increment_failed_logins(&self, username: &str) -> Result<Option<(i64, String)>, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE users SET failed_login_attempts = failed_login_attempts + 1 WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)?;
+
+        conn.exec_first("SELECT failed_login_attempts, email FROM users WHERE username = ?", (username,))
+            .await
+            .map_err(db_error)
+    }
+
+    async fn lock_account(&self, username: &str, until: NaiveDateTime, unlock_token_hash: Option<&str>) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET locked_until = ?, unlock_token_hash = COALESCE(?, unlock_token_hash) WHERE username = ?",
+            (ts(until), unlock_token_hash, username),
+        ).await.map_err(db_error)
+    }
+
+    async fn reset_failed_logins(&self, username: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET failed_login_attempts = 0, locked_until = NULL, unlock_token_hash = NULL WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)
+    }
+
+    async fn unlock_account(&self, unlock_token_hash: &str) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"UPDATE users SET failed_login_attempts = 0, locked_until = NULL, unlock_token_hash = NULL
+               WHERE unlock_token_hash = ?",
+            (unlock_token_hash,),
+        ).await.map_err(db_error)?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn increment_2fa_failures(&self, username: &str) -> Result<i64, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE users SET 2fa_failed_attempts = 2fa_failed_attempts + 1 WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)?;
+
+        let failures: Option<i64> = conn
+            .exec_first("SELECT 2fa_failed_attempts FROM users WHERE username = ?", (username,))
+            .await
+            .map_err(db_error)?;
+        Ok(failures.unwrap_or(0))
+    }
+
+    #[cfg(feature = "two-factor")]
+    async fn reset_2fa_challenge(&self, username: &str) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"DELETE FROM auth_tokens WHERE purpose IN (?, ?, ?) AND consumed_at IS NULL
+               AND user_id = (SELECT id FROM users WHERE username = ?)",
+            (TokenPurpose::TwoFactorLogin.as_str(), TokenPurpose::TwoFactorChange.as_str(), TokenPurpose::PasswordChange.as_str(), username),
+        ).await.map_err(db_error)?;
+
+        conn.exec_drop(
+            "UPDATE users SET 2fa_code = NULL, temp_2fa_code = NULL, password_change_code = NULL, 2fa_failed_attempts = 0 WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)
+    }
+
+    async fn enqueue_email(&self, recipient: &str, subject: &str, text_body: &str, html_body: &str) -> Result<i64, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"INSERT INTO email_outbox (recipient, subject, text_body, html_body, next_attempt_at)
+               VALUES (?, ?, ?, ?, UTC_TIMESTAMP())",
+            (recipient, subject, text_body, html_body),
+        ).await.map_err(db_error)?;
+        Ok(conn.last_insert_id().unwrap_or_default() as i64)
+    }
+
+    async fn claim_due_emails(&self, limit: usize, lease_until: NaiveDateTime) -> Result<Vec<OutboxEmail>, ServiceError> {
+        let mut conn = self.conn().await?;
+        let rows: Vec<(u64, String, String, String, String, i64)> = conn
+            .exec(
+                r"SELECT id, recipient, subject, text_body, html_body, attempts FROM email_outbox
+                   WHERE status = 'pending' AND next_attempt_at <= UTC_TIMESTAMP()
+                   ORDER BY next_attempt_at LIMIT ?",
+                (limit as u64,),
+            )
+            .await
+            .map_err(db_error)?;
+
+        let mut claimed = Vec::new();
+        for (id, recipient, subject, text_body, html_body, attempts) in rows {
+            conn.exec_drop(
+                r"UPDATE email_outbox SET next_attempt_at = ?
+                   WHERE id = ? AND status = 'pending' AND next_attempt_at <= UTC_TIMESTAMP()",
+                (ts(lease_until), id),
+            ).await.map_err(db_error)?;
+            if conn.affected_rows() > 0 {
+                claimed.push(OutboxEmail { id: id as i64, recipient, subject, text_body, html_body, attempts });
+            }
+        }
+        Ok(claimed)
+    }
+
+    async fn mark_email_sent(&self, id: i64) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"UPDATE email_outbox SET status = 'sent', attempts = attempts + 1, sent_at = UTC_TIMESTAMP(),
+                   last_error = NULL, text_body = '', html_body = ''
+               WHERE id = ?",
+            (id,),
+        ).await.map_err(db_error)
+    }
+
+    async fn mark_email_failed(&self, id: i64, error: &str, retry_at: Option<NaiveDateTime>) -> Result<(), ServiceError> {
+        let mut conn = self.conn().await?;
+        match retry_at {
+            Some(retry_at) => conn.exec_drop(
+                "UPDATE email_outbox SET attempts = attempts + 1, last_error = ?, next_attempt_at = ? WHERE id = ?",
+                (error, ts(retry_at), id),
+            ).await,
+            None => conn.exec_drop(
+                "UPDATE email_outbox SET status = 'dead', attempts = attempts + 1, last_error = ?, text_body = '', html_body = '' WHERE id = ?",
+                (error, id),
+            ).await,
+        }
+        .map_err(db_error)
+    }
+
+    async fn find_outbox_status(&self, id: i64) -> Result<Option<OutboxStatus>, ServiceError> {
+        let row: Option<(String, i64, Option<String>)> = self.conn().await?
+            .exec_first("SELECT status, attempts, last_error FROM email_outbox WHERE id = ?", (id,))
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(|(status, attempts, last_error)| OutboxStatus { status, attempts, last_error }))
+    }
+
+    async fn count_outbox_emails(&self) -> Result<Vec<(String, i64)>, ServiceError> {
+        self.conn().await?
+            .query("SELECT status, COUNT(*) FROM email_outbox GROUP BY status ORDER BY status")
+            .await
+            .map_err(db_error)
+    }
+
+    async fn count_known_devices(&self, username: &str) -> Result<i64, ServiceError> {
+        let count: Option<i64> = self.conn().await?
+            .exec_first(
+                "SELECT COUNT(*) FROM known_devices WHERE user_id = (SELECT id FROM users WHERE username = ?)",
+                (username,),
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn remember_device(&self, username: &str, device_hash: &str) -> Result<bool, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"INSERT IGNORE INTO known_devices (user_id, device_hash, first_seen_at, last_seen_at)
+               SELECT id, ?, UTC_TIMESTAMP(), UTC_TIMESTAMP() FROM users WHERE username = ?",
+            (device_hash, username),
+        ).await.map_err(db_error)?;
+        if conn.affected_rows() > 0 {
+            return Ok(true);
+        }
+
+        conn.exec_drop(
+            r"UPDATE known_devices SET last_seen_at = UTC_TIMESTAMP()
+               WHERE device_hash = ? AND user_id = (SELECT id FROM users WHERE username = ?)",
+            (device_hash, username),
+        ).await.map_err(db_error)?;
+        Ok(false)
+    }
+
+    async fn insert_security_alert_token(&self, username: &str, token_hash: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            r"INSERT INTO security_alert_tokens (token_hash, user_id, expires_at)
+               SELECT ?, id, ? FROM users WHERE username = ?",
+            (token_hash, ts(expires_at), username),
+        ).await.map_err(db_error)
+    }
+
+    async fn take_security_alert_token(&self, token_hash: &str) -> Result<Option<String>, ServiceError> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            r"UPDATE security_alert_tokens SET used_at = UTC_TIMESTAMP()
+               WHERE token_hash = ? AND used_at IS NULL AND expires_at > UTC_TIMESTAMP()",
+            (token_hash,),
+        ).await.map_err(db_error)?;
+        if conn.affected_rows() == 0 {
+            return Ok(None);
+        }
+
+        conn.exec_first(
+            "SELECT u.username FROM security_alert_tokens s JOIN users u ON u.id = s.user_id WHERE s.token_hash = ?",
+            (token_hash,),
+        )
+        .await
+        .map_err(db_error)
+    }
+
+    #[cfg(feature = "password-reset")]
+    async fn require_password_reset(&self, username: &str) -> Result<(), ServiceError> {
+        self.conn().await?.exec_drop(
+            "UPDATE users SET password_reset_required = TRUE WHERE username = ?",
+            (username,),
+        ).await.map_err(db_error)
+    }
+}
@@ -0,0 +1,154 @@
+// notifications.rs
+
+use crate::create::common::*;
+#[cfg(feature = "password-reset")]
+use crate::create::forgot;
+use crate::create::sessions;
+use crate::create::templates;
+use crate::create::tokens;
+use actix_web::Either;
+
+// "This wasn't me" links stay valid for a week
+const ALERT_LINK_TTL_DAYS: i64 = 7;
+
+pub enum SecurityEvent {
+    #[cfg(feature = "password-reset")]
+    PasswordReset,
+    #[cfg(feature = "two-factor")]
+    TwoFactorDisabled,
+    PasswordChanged,
+    NewDeviceLogin,
+}
+
+impl SecurityEvent {
+    fn template(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "password-reset")]
+            SecurityEvent::PasswordReset => "password_changed",
+            #[cfg(feature = "two-factor")]
+            SecurityEvent::TwoFactorDisabled => "2fa_disabled",
+            SecurityEvent::PasswordChanged => "password_changed",
+            SecurityEvent::NewDeviceLogin => "new_device_login",
+        }
+    }
+}
+
+fn user_agent(req: &HttpRequest) -> &str {
+    req.headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .unwrap_or("unknown")
+}
+
+// Emails the user about `event` with where and when it happened. The event has already
+// happened by then, so a failure is logged rather than returned.
+pub async fn notify(store: &dyn UserStore, emails: &Emails, config: &AppConfig, req: &HttpRequest, username: &str, event: SecurityEvent) {
+    if let Err(e) = send_alert(store, emails, config, req, username, &event).await {
+        error!("Failed to send {} alert to user {}: {:?}", event.template(), username, e);
+    }
+}
+
+async fn send_alert(store: &dyn UserStore, emails: &Emails, config: &AppConfig, req: &HttpRequest, username: &str, event: &SecurityEvent) -> Result<(), ServiceError> {
+    let Some(user) = store.find_user(username).await? else {
+        return Ok(());
+    };
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(43)
+        .map(char::from)
+        .collect();
+    let expiry = Utc::now()
+        .checked_add_signed(Duration::days(ALERT_LINK_TTL_DAYS))
+        .ok_or(ServiceError::InternalServerError)?;
+    store.insert_security_alert_token(username, &tokens::hash_token(&token), expiry.naive_utc()).await?;
+
+    let link = format!("{}/not_me?token={}", config.links.security_alert_base_url, token);
+    let time = Utc::now().format("%Y-%m-%d %H:%M UTC").to_string();
+    let locale = emails.locale(user.locale.as_deref(), Some(req));
+    emails.send(
+        &user.email,
+        event.template(),
+        &locale,
+        &[("ip", &client_ip(req)), ("user_agent", user_agent(req)), ("time", &time), ("link", &link)],
+    ).await?;
+    info!("Sent {} alert to user {}", event.template(), username);
+
+    Ok(())
+}
+
+// Called on every completed login. Devices are told apart by their User-Agent;
+// the first device of an account is remembered without an alert.
+pub async fn check_login_device(store: &dyn UserStore, emails: &Emails, config: &AppConfig, req: &HttpRequest, username: &str) -> Result<(), ServiceError> {
+    let known_devices = store.count_known_devices(username).await?;
+    let is_new = store.remember_device(username, &tokens::hash_token(user_agent(req))).await?;
+
+    if is_new && known_devices > 0 {
+        notify(store, emails, config, req, username, SecurityEvent::NewDeviceLogin).await;
+    }
+
+    Ok(())
+}
+
+// Target of the "this wasn't me" link: signs the account out everywhere, blocks password
+// logins and sends a reset link to the account's address. Without password reset the
+// password keeps working, since the user would have no way to replace it.
+// Mail scanners and link prefetchers open every link, so the GET only asks for confirmation
+#[get("/not_me")]
+async fn confirm_not_me(query: Query<VerifyQuery>) -> HttpResponse {
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html><body>
+<p>Wasn't you? This signs your account out everywhere and asks you to choose a new password.</p>
+<form method="post" action="not_me"><input type="hidden" name="token" value="{}"><button type="submit">Secure my account</button></form>
+</body></html>"#,
+        templates::escape_html(&query.token)
+    );
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page)
+}
+
+// Shuts password logins until a reset; false when password reset is off and the user is only told to change it
+#[cfg(feature = "password-reset")]
+async fn require_new_password(store: &dyn UserStore, emails: &Emails, config: &AppConfig, req: &HttpRequest, username: &str) -> Result<bool, ServiceError> {
+    if !config.features.password_reset {
+        return Ok(false);
+    }
+    store.require_password_reset(username).await?;
+    let user = store.find_user(username).await?.ok_or(ServiceError::InternalServerError)?;
+    forgot::send_reset_link(store, emails, config, &user.email, req).await?;
+    Ok(true)
+}
+
+#[cfg(not(feature = "password-reset"))]
+async fn require_new_password(_store: &dyn UserStore, _emails: &Emails, _config: &AppConfig, _req: &HttpRequest, _username: &str) -> Result<bool, ServiceError> {
+    Ok(false)
+}
+
+// Takes the token as JSON or from the confirmation page's form
+#[post("/not_me")]
+async fn not_me(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: Either<web::Json<VerifyQuery>, web::Form<VerifyQuery>>,
+) -> Result<HttpResponse, ServiceError> {
+    let token = match info {
+        Either::Left(json) => json.into_inner().token,
+        Either::Right(form) => form.into_inner().token,
+    };
+    let username = store
+        .take_security_alert_token(&tokens::hash_token(&token))
+        .await?
+        .ok_or(ServiceError::BadRequest("Invalid or already used link".to_string()))?;
+
+    sessions::revoke_all_sessions(store.get_ref(), &username).await?;
+    info!("User {} reported unrecognized activity, sessions revoked", username);
+
+    if require_new_password(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &username).await? {
+        return Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "You have been signed out everywhere. Check your email to choose a new password."})));
+    }
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "You have been signed out everywhere. Change your password as soon as possible."})))
+}
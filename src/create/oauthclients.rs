@@ -0,0 +1,98 @@
+// oauthclients.rs
+
+use crate::create::common::*;
+use crate::create::authtokens;
+use crate::create::tokens;
+use crate::create::store::OAuthClientRecord;
+
+pub struct OAuthClient {
+    pub client_id: String,
+    pub client_secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+}
+
+impl OAuthClient {
+    // Public clients (token_endpoint_auth_method "none") rely on PKCE alone
+    pub fn authenticate(&self, presented_secret: Option<&str>) -> bool {
+        match (&self.client_secret_hash, presented_secret) {
+            (None, _) => true,
+            (Some(stored_hash), Some(secret)) => authtokens::same_secret(Some(stored_hash), &tokens::hash_token(secret)),
+            (Some(_), None) => false,
+        }
+    }
+
+    pub fn allows_redirect_uri(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris.iter().any(|registered| registered == redirect_uri)
+    }
+}
+
+pub async fn find_client(
+    store: &dyn UserStore,
+    client_id: &str,
+) -> Result<Option<OAuthClient>, ServiceError> {
+    match store.find_oauth_client(client_id).await? {
+        Some(OAuthClientRecord { client_id, client_secret_hash, redirect_uris }) => {
+            let redirect_uris: Vec<String> = serde_json::from_str(&redirect_uris).map_err(|e| {
+                error!("Corrupt redirect_uris for client {}: {:?}", client_id, e);
+                ServiceError::InternalServerError
+            })?;
+            Ok(Some(OAuthClient { client_id, client_secret_hash, redirect_uris }))
+        },
+        None => Ok(None),
+    }
+}
+
+// Registration is gated by OIDC_REGISTRATION_TOKEN and disabled when it is unset
+#[post("/oauth/register")]
+async fn register_client(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<ClientRegistrationRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let registration_token = config.oidc.registration_token.as_deref()
+        .ok_or(ServiceError::Unauthorized("Client registration is disabled".to_string()))?;
+
+    let presented = req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if tokens::hash_token(presented) != tokens::hash_token(registration_token) {
+        return Err(ServiceError::Unauthorized("Invalid registration token".to_string()));
+    }
+
+    if info.redirect_uris.is_empty() {
+        return Err(ServiceError::BadRequest("At least one redirect_uri is required".to_string()));
+    }
+    for redirect_uri in &info.redirect_uris {
+        match url::Url::parse(redirect_uri) {
+            Ok(parsed) if parsed.fragment().is_none() => {},
+            _ => return Err(ServiceError::BadRequest(format!("Invalid redirect_uri: {}", redirect_uri))),
+        }
+    }
+
+    let auth_method = info.token_endpoint_auth_method.clone().unwrap_or("client_secret_basic".to_string());
+    let client_secret: Option<String> = match auth_method.as_str() {
+        "none" => None,
+        "client_secret_basic" | "client_secret_post" => Some(
+            rand::thread_rng().sample_iter(&Alphanumeric).take(48).map(char::from).collect()
+        ),
+        _ => return Err(ServiceError::BadRequest("Unsupported token_endpoint_auth_method".to_string())),
+    };
+
+    let client_id = Uuid::new_v4().to_string();
+    let redirect_uris = serde_json::to_string(&info.redirect_uris).map_err(|_| ServiceError::InternalServerError)?;
+
+    let client_secret_hash = client_secret.as_deref().map(tokens::hash_token);
+    store.insert_oauth_client(&client_id, client_secret_hash.as_deref(), &info.client_name, &redirect_uris).await?;
+    info!("Registered OAuth client {} ({})", client_id, info.client_name);
+
+    Ok(HttpResponse::Created().json(json!({
+        "client_id": client_id,
+        "client_secret": client_secret,
+        "client_name": info.client_name,
+        "redirect_uris": info.redirect_uris,
+        "token_endpoint_auth_method": auth_method
+    })))
+}
@@ -0,0 +1,120 @@
+// oidc.rs
+
+use crate::create::common::*;
+use crate::create::jwks;
+
+const ID_TOKEN_TTL_MINUTES: i64 = 15;
+
+pub fn issuer(config: &AppConfig) -> Result<&str, ServiceError> {
+    config.oidc.issuer.as_deref().ok_or_else(|| {
+        error!("OIDC_ISSUER is not configured");
+        ServiceError::InternalServerError
+    })
+}
+
+pub fn has_scope(scope: &str, wanted: &str) -> bool {
+    scope.split_whitespace().any(|s| s == wanted)
+}
+
+#[derive(Serialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: usize,
+    iat: usize,
+    auth_time: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+}
+
+pub async fn generate_id_token(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    client_id: &str,
+    scope: &str,
+    nonce: Option<String>,
+    auth_time: i64,
+) -> Result<String, ServiceError> {
+    let (email, verified) = store
+        .find_user(username)
+        .await?
+        .map(|user| (user.email, user.verified))
+        .ok_or(ServiceError::BadRequest("User not found".to_string()))?;
+
+    let now = Utc::now();
+    let expiration = now
+        .checked_add_signed(Duration::minutes(ID_TOKEN_TTL_MINUTES))
+        .ok_or(ServiceError::InternalServerError)?;
+
+    let claims = IdTokenClaims {
+        iss: issuer(config)?.to_string(),
+        sub: username.to_string(),
+        aud: client_id.to_string(),
+        exp: expiration.timestamp() as usize,
+        iat: now.timestamp() as usize,
+        auth_time: auth_time as usize,
+        nonce,
+        preferred_username: has_scope(scope, "profile").then(|| username.to_string()),
+        email: has_scope(scope, "email").then(|| email.clone()),
+        email_verified: has_scope(scope, "email").then_some(verified),
+    };
+
+    jwks::sign(&config.jwt, &claims)
+}
+
+#[get("/.well-known/openid-configuration")]
+async fn discovery(config: Data<AppConfig>) -> Result<HttpResponse, ServiceError> {
+    let issuer = issuer(config.get_ref())?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "userinfo_endpoint": format!("{}/userinfo", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "registration_endpoint": format!("{}/oauth/register", issuer),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": [jwks::signing_algorithm(&config.jwt)],
+        "scopes_supported": ["openid", "profile", "email"],
+        "claims_supported": ["iss", "sub", "aud", "exp", "iat", "auth_time", "nonce", "preferred_username", "email", "email_verified"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"]
+    })))
+}
+
+#[actix_web::route("/userinfo", method = "GET", method = "POST")]
+async fn userinfo(
+    store: Data<dyn UserStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServiceError> {
+    let claims = extract_access_token_claims(&req, store.get_ref()).await?;
+
+    let (email, verified) = store
+        .find_user(&claims.sub)
+        .await?
+        .map(|user| (user.email, user.verified))
+        .ok_or(ServiceError::Unauthorized("User not found".to_string()))?;
+
+    // Client tokens only see what their scopes cover, the API's own tokens see everything
+    let granted = |wanted| claims.scope.as_deref().is_none_or(|scope| has_scope(scope, wanted));
+    let mut response = json!({"sub": claims.sub});
+    if granted("profile") {
+        response["preferred_username"] = json!(claims.sub);
+    }
+    if granted("email") {
+        response["email"] = json!(email);
+        response["email_verified"] = json!(verified);
+    }
+
+    Ok(HttpResponse::Ok().json(response))
+}
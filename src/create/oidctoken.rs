@@ -0,0 +1,154 @@
+// oidctoken.rs
+
+use crate::create::common::*;
+use crate::create::oauthclients;
+use crate::create::oidc;
+use crate::create::refresh;
+use crate::create::sessions;
+use crate::create::store::{AuthorizationCode, SessionClient};
+use crate::create::tokens;
+use base64::{Engine, engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}};
+use sha2::{Digest, Sha256};
+
+fn oauth_error(error: &str, description: &str) -> HttpResponse {
+    let body = json!({"error": error, "error_description": description});
+    match error {
+        "invalid_client" => HttpResponse::Unauthorized().json(body),
+        _ => HttpResponse::BadRequest().json(body),
+    }
+}
+
+// client_secret_basic takes precedence over client_secret_post
+fn client_credentials(req: &HttpRequest, info: &OAuthTokenRequest) -> (Option<String>, Option<String>) {
+    let basic = req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+
+    if let Some((id, secret)) = basic.as_deref().and_then(|decoded| decoded.split_once(':')) {
+        let id = urlencoding::decode(id).map(|id| id.into_owned()).unwrap_or_else(|_| id.to_string());
+        let secret = urlencoding::decode(secret).map(|s| s.into_owned()).unwrap_or_else(|_| secret.to_string());
+        return (Some(id), Some(secret));
+    }
+
+    (info.client_id.clone(), info.client_secret.clone())
+}
+
+// RFC 6749 §4.1.2: a code presented again revokes the session its first exchange issued
+async fn revoke_replayed_code(store: &dyn UserStore, code_hash: &str) -> Result<(), ServiceError> {
+    if let Some(jti) = store.find_authorization_code_session(code_hash).await? {
+        sessions::revoke_session(store, &jti).await?;
+        warn!("Authorization code presented again, revoked session {}", jti);
+    }
+    Ok(())
+}
+
+fn pkce_matches(code_verifier: &str, code_challenge: &str) -> bool {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+#[post("/token")]
+async fn token(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Form<OAuthTokenRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let (client_id, client_secret) = client_credentials(&req, &info);
+    let client = match &client_id {
+        Some(client_id) => oauthclients::find_client(store.get_ref(), client_id).await?,
+        None => None,
+    };
+    let client = match client {
+        Some(client) if client.authenticate(client_secret.as_deref()) => client,
+        _ => return Ok(oauth_error("invalid_client", "Client authentication failed.")),
+    };
+
+    match info.grant_type.as_str() {
+        "authorization_code" => {
+            let (code, redirect_uri, code_verifier) = match (&info.code, &info.redirect_uri, &info.code_verifier) {
+                (Some(code), Some(redirect_uri), Some(code_verifier)) => (code, redirect_uri, code_verifier),
+                _ => return Ok(oauth_error("invalid_request", "code, redirect_uri and code_verifier are required.")),
+            };
+
+            let code_hash = tokens::hash_token(code);
+            let row = store.find_authorization_code(&code_hash).await?;
+
+            let (authorization_code, has_2fa) = match row {
+                Some(row) => row,
+                None => {
+                    revoke_replayed_code(store.get_ref(), &code_hash).await?;
+                    return Ok(oauth_error("invalid_grant", "Authorization code is invalid, expired or already used."));
+                },
+            };
+            let AuthorizationCode {
+                client_id: code_client_id, redirect_uri: code_redirect_uri, scope, code_challenge, nonce, auth_time, username,
+            } = authorization_code;
+
+            let rejection = if code_client_id != client.client_id || code_redirect_uri != *redirect_uri {
+                Some("Authorization code was issued to another client or redirect_uri.")
+            } else if !pkce_matches(code_verifier, &code_challenge) {
+                Some("PKCE verification failed.")
+            } else {
+                None
+            };
+            // A failed exchange spends the code as well
+            if let Some(description) = rejection {
+                if !store.mark_authorization_code_used(&code_hash, None).await? {
+                    revoke_replayed_code(store.get_ref(), &code_hash).await?;
+                    return Ok(oauth_error("invalid_grant", "Authorization code is invalid, expired or already used."));
+                }
+                return Ok(oauth_error("invalid_grant", description));
+            }
+
+            let session_client = SessionClient { client_id: client.client_id.clone(), scope: scope.clone() };
+            let (access_token, refresh_token, jti) =
+                tokens::issue_client_token_pair(store.get_ref(), config.get_ref(), &username, has_2fa, &session_client).await?;
+
+            // Another request redeemed the code meanwhile, so neither of the two sessions is kept
+            if !store.mark_authorization_code_used(&code_hash, Some(&jti)).await? {
+                sessions::revoke_session(store.get_ref(), &jti).await?;
+                revoke_replayed_code(store.get_ref(), &code_hash).await?;
+                return Ok(oauth_error("invalid_grant", "Authorization code is invalid, expired or already used."));
+            }
+
+            let id_token = oidc::generate_id_token(store.get_ref(), config.get_ref(), &username, &client.client_id, &scope, nonce, auth_time).await?;
+            info!("Exchanged authorization code for user {} and client {}", username, client.client_id);
+
+            Ok(HttpResponse::Ok()
+                .insert_header((http::header::CACHE_CONTROL, "no-store"))
+                .json(json!({
+                    "access_token": access_token,
+                    "token_type": "Bearer",
+                    "expires_in": config.tokens.access_token_ttl().num_seconds(),
+                    "refresh_token": refresh_token,
+                    "id_token": id_token,
+                    "scope": scope
+                })))
+        },
+        "refresh_token" => {
+            let presented = match &info.refresh_token {
+                Some(presented) => presented,
+                None => return Ok(oauth_error("invalid_request", "refresh_token is required.")),
+            };
+
+            let (access_token, refresh_token) = match refresh::rotate_refresh_token(store.get_ref(), config.get_ref(), presented, Some(&client.client_id)).await {
+                Ok(pair) => pair,
+                Err(ServiceError::Unauthorized(message)) => return Ok(oauth_error("invalid_grant", &message)),
+                Err(e) => return Err(e),
+            };
+
+            Ok(HttpResponse::Ok()
+                .insert_header((http::header::CACHE_CONTROL, "no-store"))
+                .json(json!({
+                    "access_token": access_token,
+                    "token_type": "Bearer",
+                    "expires_in": config.tokens.access_token_ttl().num_seconds(),
+                    "refresh_token": refresh_token
+                })))
+        },
+        _ => Ok(oauth_error("unsupported_grant_type", "Only authorization_code and refresh_token are supported.")),
+    }
+}
@@ -0,0 +1,129 @@
+// outbox.rs
+
+use crate::create::common::*;
+use crate::create::crypto;
+use crate::create::store::OutboxEmail;
+use crate::create::templates::RenderedEmail;
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+// A claimed message is retried by another pass only if its delivery hangs longer than this
+const LEASE_SECONDS: i64 = 300;
+
+// The [outbox] section of the configuration
+#[derive(Clone)]
+pub struct OutboxSettings {
+    pub batch_size: usize,
+    pub max_attempts: i64,
+    pub base_delay_seconds: i64,
+    pub max_delay_seconds: i64,
+    pub poll_interval_seconds: u64,
+    pub encryption_key: Vec<u8>,
+}
+
+impl OutboxSettings {
+    // 30s, 1m, 2m, ... after the first, second, third failure, capped at max_delay_seconds
+    fn retry_delay(&self, attempts: i64) -> Duration {
+        let delay = self.base_delay_seconds.saturating_mul(1i64 << attempts.clamp(0, 30));
+        Duration::seconds(delay.min(self.max_delay_seconds))
+    }
+}
+
+// Emails are written to the email_outbox table and delivered by a background worker,
+// so a request never waits on SMTP and a failing server only delays delivery
+pub struct Outbox {
+    store: Arc<dyn UserStore>,
+    settings: OutboxSettings,
+    wake: Notify,
+}
+
+impl Outbox {
+    pub fn new(store: Arc<dyn UserStore>, settings: OutboxSettings) -> Self {
+        Outbox { store, settings, wake: Notify::new() }
+    }
+
+    pub async fn enqueue(&self, to: &str, email: &RenderedEmail) -> Result<i64, ServiceError> {
+        let text = crypto::encrypt(&self.settings.encryption_key, email.text.as_bytes())?;
+        let html = crypto::encrypt(&self.settings.encryption_key, email.html.as_bytes())?;
+        let id = self.store.enqueue_email(to, &email.subject, &text, &html).await?;
+        self.wake.notify_one();
+        Ok(id)
+    }
+
+    // One pass over the due messages; returns how many were claimed
+    pub async fn deliver_due(&self, mailer: &dyn Mailer) -> Result<usize, ServiceError> {
+        let lease_until = Utc::now().naive_utc() + Duration::seconds(LEASE_SECONDS);
+        let claimed = self.store.claim_due_emails(self.settings.batch_size, lease_until).await?;
+
+        for email in &claimed {
+            // Rows queued under another key, or before bodies were encrypted, can't be sent
+            let Some(rendered) = self.decrypt(email) else {
+                error!("Dead-lettering email {} to {}, its body could not be decrypted", email.id, email.recipient);
+                self.store.mark_email_failed(email.id, "The body could not be decrypted", None).await?;
+                continue;
+            };
+
+            match mailer.send(&email.recipient, &rendered).await {
+                Ok(()) => self.store.mark_email_sent(email.id).await?,
+                Err(e) if email.attempts + 1 >= self.settings.max_attempts => {
+                    error!("Giving up on email {} to {} after {} attempts", email.id, email.recipient, email.attempts + 1);
+                    self.store.mark_email_failed(email.id, &format!("{:?}", e), None).await?;
+                },
+                Err(e) => {
+                    let retry_at = Utc::now().naive_utc() + self.settings.retry_delay(email.attempts);
+                    warn!("Email {} to {} failed, retrying at {}", email.id, email.recipient, retry_at);
+                    self.store.mark_email_failed(email.id, &format!("{:?}", e), Some(retry_at)).await?;
+                },
+            }
+        }
+
+        Ok(claimed.len())
+    }
+
+    fn decrypt(&self, email: &OutboxEmail) -> Option<RenderedEmail> {
+        let body = |stored: &str| {
+            let plaintext = crypto::decrypt(&self.settings.encryption_key, stored).ok()?;
+            String::from_utf8(plaintext).ok()
+        };
+        Some(RenderedEmail { subject: email.subject.clone(), text: body(&email.text_body)?, html: body(&email.html_body)? })
+    }
+
+    // Runs until the server stops: drains the queue, then sleeps until the next enqueue or poll
+    pub fn spawn_worker(self: Arc<Self>, mailer: Arc<dyn Mailer>) {
+        let poll_interval = std::time::Duration::from_secs(self.settings.poll_interval_seconds);
+
+        actix_web::rt::spawn(async move {
+            loop {
+                match self.deliver_due(mailer.as_ref()).await {
+                    Ok(claimed) if claimed > 0 => continue,
+                    Ok(_) => {},
+                    Err(e) => error!("Outbox pass failed: {:?}", e),
+                }
+                let _ = tokio::time::timeout(poll_interval, self.wake.notified()).await;
+            }
+        });
+    }
+}
+
+// `outbox [status [ID]]`
+pub async fn run_command(store: &dyn UserStore, args: &[String]) -> Result<(), String> {
+    let failed = |e: ServiceError| format!("Outbox command failed: {:?}", e);
+
+    match args.first().map(String::as_str).unwrap_or("status") {
+        "status" => match args.get(1) {
+            Some(id) => {
+                let id = id.parse::<i64>().map_err(|_| format!("Not a number: {}", id))?;
+                let status = store.find_outbox_status(id).await.map_err(failed)?.ok_or(format!("No email {}", id))?;
+                println!("{} after {} attempt(s){}", status.status, status.attempts, status.last_error.map(|e| format!(": {}", e)).unwrap_or_default());
+            },
+            None => {
+                for (status, count) in store.count_outbox_emails().await.map_err(failed)? {
+                    println!("{:<8} {}", status, count);
+                }
+            },
+        },
+        other => return Err(format!("Unknown outbox command: {} (expected status)", other)),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,269 @@
+// passkeys.rs
+
+use crate::create::common::*;
+use crate::create::notifications;
+use crate::create::store::{NewWebAuthnCredential, WebAuthnCredentialRecord};
+use crate::create::tokens;
+#[cfg(feature = "two-factor")]
+use crate::create::twoauth;
+use crate::create::webauthn::{self, RegisteredCredential};
+
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+const CEREMONY_TIMEOUT_MS: i64 = 300_000;
+
+async fn store_challenge(
+    store: &dyn UserStore,
+    username: Option<&str>,
+    purpose: &str,
+) -> Result<(String, String), ServiceError> {
+    let challenge_id = Uuid::new_v4().to_string();
+    let challenge = webauthn::generate_challenge();
+
+    let expiry = Utc::now()
+        .checked_add_signed(Duration::minutes(CHALLENGE_TTL_MINUTES))
+        .ok_or(ServiceError::InternalServerError)?;
+
+    store.insert_webauthn_challenge(&challenge_id, username, purpose, &challenge, expiry.naive_utc()).await?;
+
+    Ok((challenge_id, challenge))
+}
+
+// Challenges are single use: they are deleted whether or not the ceremony succeeds
+async fn take_challenge(
+    store: &dyn UserStore,
+    challenge_id: &str,
+    purpose: &str,
+) -> Result<(String, Option<String>), ServiceError> {
+    store
+        .take_webauthn_challenge(challenge_id, purpose)
+        .await?
+        .ok_or(ServiceError::BadRequest("Unknown or expired challenge".to_string()))
+}
+
+async fn credential_descriptors(store: &dyn UserStore, username: &str) -> Result<Vec<serde_json::Value>, ServiceError> {
+    let credential_ids = store.list_webauthn_credential_ids(username).await?;
+
+    Ok(credential_ids.into_iter().map(|id| json!({"type": "public-key", "id": id})).collect())
+}
+
+// Checks an assertion against the stored credential and records its new signature counter
+async fn verify_user_assertion(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    credential: &WebAuthnCredential,
+    challenge: &str,
+    require_user_verification: bool,
+) -> Result<String, ServiceError> {
+    let (authenticator_data, signature) = match (&credential.authenticator_data, &credential.signature) {
+        (Some(authenticator_data), Some(signature)) => (authenticator_data, signature),
+        _ => return Err(ServiceError::BadRequest("authenticatorData and signature are required".to_string())),
+    };
+
+    let raw_id = webauthn::decode_b64url(&credential.id)?;
+    let credential_id = webauthn::encode_b64url(&raw_id);
+    let WebAuthnCredentialRecord { username, public_key, sign_count } = store
+        .find_webauthn_credential(&credential_id)
+        .await?
+        .ok_or(ServiceError::Unauthorized("Unknown passkey".to_string()))?;
+
+    if let Some(user_handle) = credential.user_handle.as_deref().filter(|handle| !handle.is_empty()) {
+        if webauthn::decode_b64url(user_handle)? != username.as_bytes() {
+            return Err(ServiceError::Unauthorized("Passkey does not belong to this user".to_string()));
+        }
+    }
+
+    let stored = RegisteredCredential { credential_id: raw_id, cose_key: webauthn::decode_b64url(&public_key)?, sign_count };
+    let new_sign_count = webauthn::verify_assertion(
+        &config.webauthn,
+        &credential.client_data_json,
+        authenticator_data,
+        signature,
+        challenge,
+        &stored,
+        require_user_verification,
+    )?;
+
+    store.update_webauthn_sign_count(&credential_id, new_sign_count).await?;
+
+    Ok(username)
+}
+
+#[post("/webauthn/register/start")]
+async fn register_start(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServiceError> {
+    let (email_addr, user_from_token) = extract_user_email_from_token(&req, store.get_ref()).await?;
+
+    let (challenge_id, challenge) = store_challenge(store.get_ref(), Some(&user_from_token), "register").await?;
+    let exclude_credentials = credential_descriptors(store.get_ref(), &user_from_token).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "challenge_id": challenge_id,
+        "publicKey": {
+            "challenge": challenge,
+            "rp": {"id": webauthn::rp_id(&config.webauthn)?, "name": config.webauthn.rp_name},
+            "user": {
+                "id": webauthn::encode_b64url(user_from_token.as_bytes()),
+                "name": email_addr,
+                "displayName": user_from_token
+            },
+            "pubKeyCredParams": [
+                {"type": "public-key", "alg": webauthn::COSE_ALG_ES256},
+                {"type": "public-key", "alg": webauthn::COSE_ALG_EDDSA},
+                {"type": "public-key", "alg": webauthn::COSE_ALG_RS256}
+            ],
+            "timeout": CEREMONY_TIMEOUT_MS,
+            "attestation": "direct",
+            "excludeCredentials": exclude_credentials,
+            "authenticatorSelection": {"residentKey": "preferred", "userVerification": "preferred"}
+        }
+    })))
+}
+
+#[post("/webauthn/register/finish")]
+async fn register_finish(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<WebAuthnRegisterFinishRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let user_from_token = extract_claims_from_token(&req, store.get_ref()).await?.sub;
+
+    let (challenge, challenge_user) = take_challenge(store.get_ref(), &info.challenge_id, "register").await?;
+    if challenge_user.as_deref() != Some(user_from_token.as_str()) {
+        return Err(ServiceError::BadRequest("Unknown or expired challenge".to_string()));
+    }
+
+    let attestation_object = info.credential.attestation_object.as_deref()
+        .ok_or(ServiceError::BadRequest("attestationObject is required".to_string()))?;
+    let registered = webauthn::verify_registration(&config.webauthn, &info.credential.client_data_json, attestation_object, &challenge)?;
+    let credential_id = webauthn::encode_b64url(&registered.credential_id);
+
+    let inserted = store.insert_webauthn_credential(NewWebAuthnCredential {
+        username: &user_from_token,
+        credential_id: &credential_id,
+        public_key: &webauthn::encode_b64url(&registered.cose_key),
+        alg: webauthn::cose_algorithm(&registered.cose_key)?,
+        sign_count: registered.sign_count,
+        name: info.name.as_deref(),
+    }).await?;
+    if !inserted {
+        return Err(ServiceError::BadRequest("This passkey is already registered".to_string()));
+    }
+    info!("Registered passkey for user {}", user_from_token);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "credential_id": credential_id})))
+}
+
+// Without a username the browser offers any discoverable passkey for this site
+#[post("/webauthn/login/start")]
+async fn login_start(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    info: web::Json<WebAuthnLoginStartRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let (challenge_id, challenge) = store_challenge(store.get_ref(), info.username.as_deref(), "login").await?;
+    let allow_credentials = match &info.username {
+        Some(username) => credential_descriptors(store.get_ref(), username).await?,
+        None => Vec::new(),
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "challenge_id": challenge_id,
+        "publicKey": {
+            "challenge": challenge,
+            "rpId": webauthn::rp_id(&config.webauthn)?,
+            "timeout": CEREMONY_TIMEOUT_MS,
+            "allowCredentials": allow_credentials,
+            "userVerification": "required"
+        }
+    })))
+}
+
+// A user-verified passkey is already two factors, so this never asks for 2FA
+#[post("/webauthn/login/finish")]
+async fn login_finish(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<WebAuthnLoginFinishRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let (challenge, challenge_user) = take_challenge(store.get_ref(), &info.challenge_id, "login").await?;
+    let username = verify_user_assertion(store.get_ref(), config.get_ref(), &info.credential, &challenge, true).await?;
+    if challenge_user.is_some_and(|challenge_user| challenge_user != username) {
+        return Err(ServiceError::Unauthorized("Passkey does not belong to this user".to_string()));
+    }
+
+    let (verified, has_2fa) = store
+        .find_user(&username)
+        .await?
+        .map(|user| (user.verified, user.has_2fa))
+        .ok_or(ServiceError::InternalServerError)?;
+    if !verified {
+        return Err(ServiceError::Unauthorized("Please verify your email before logging in".to_string()));
+    }
+
+    notifications::check_login_device(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &username).await?;
+    let (token, refresh_token) = tokens::issue_token_pair(store.get_ref(), config.get_ref(), &username, has_2fa).await?;
+    info!("Generated JWT for user {} via passkey", username);
+
+    Ok(HttpResponse::Ok().json(tokens::token_pair_response(config.get_ref(), &token, &refresh_token)))
+}
+
+#[cfg(feature = "two-factor")]
+#[post("/webauthn/2fa/start")]
+async fn two_factor_start(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    info: web::Json<WebAuthn2FAStartRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let username = twoauth::pending_login(store.get_ref(), &info.temp_token).await?.username;
+    let allow_credentials = credential_descriptors(store.get_ref(), &username).await?;
+    if allow_credentials.is_empty() {
+        return Err(ServiceError::BadRequest("No passkey registered for this account".to_string()));
+    }
+    let (challenge_id, challenge) = store_challenge(store.get_ref(), Some(&username), "2fa").await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "challenge_id": challenge_id,
+        "publicKey": {
+            "challenge": challenge,
+            "rpId": webauthn::rp_id(&config.webauthn)?,
+            "timeout": CEREMONY_TIMEOUT_MS,
+            "allowCredentials": allow_credentials,
+            "userVerification": "preferred"
+        }
+    })))
+}
+
+#[cfg(feature = "two-factor")]
+#[post("/webauthn/2fa/finish")]
+async fn two_factor_finish(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+    info: web::Json<WebAuthn2FAFinishRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let pending = twoauth::pending_login(store.get_ref(), &info.temp_token).await?;
+    let (challenge, challenge_user) = take_challenge(store.get_ref(), &info.challenge_id, "2fa").await?;
+    if challenge_user.as_deref() != Some(pending.username.as_str()) {
+        return Err(ServiceError::BadRequest("Unknown or expired challenge".to_string()));
+    }
+
+    let username = verify_user_assertion(store.get_ref(), config.get_ref(), &info.credential, &challenge, false).await?;
+    if username != pending.username {
+        return Err(ServiceError::Unauthorized("Passkey does not belong to this user".to_string()));
+    }
+
+    twoauth::finish_login(store.get_ref(), &pending).await?;
+
+    notifications::check_login_device(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &username).await?;
+    let (token, refresh_token) = tokens::issue_token_pair(store.get_ref(), config.get_ref(), &username, true).await?;
+    info!("Generated JWT for user {} after passkey 2FA", username);
+
+    Ok(HttpResponse::Ok().json(tokens::token_pair_response(config.get_ref(), &token, &refresh_token)))
+}
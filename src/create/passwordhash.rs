@@ -0,0 +1,125 @@
+// passwordhash.rs
+
+use crate::create::common::*;
+use crate::create::config::{HashAlgorithm, HashingConfig};
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+// Hashes new passwords with the configured algorithm and verifies both formats: Argon2id PHC strings
+// ($argon2id$v=19$m=...,t=...,p=...$salt$hash) and bcrypt ($2a$, $2b$ or $2y$ with the cost).
+// The work runs on actix's blocking thread pool so it never stalls a worker.
+#[derive(Clone, Copy)]
+pub struct PasswordHasher {
+    config: HashingConfig,
+}
+
+// A successful verification of a hash made with other settings carries the replacement hash
+pub struct Verification {
+    pub valid: bool,
+    pub rehashed: Option<String>,
+}
+
+impl PasswordHasher {
+    pub fn new(config: &HashingConfig) -> Self {
+        PasswordHasher { config: *config }
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, ServiceError> {
+        let hasher = *self;
+        let password = password.to_string();
+        web::block(move || hasher.hash_blocking(&password)).await.map_err(|e| {
+            error!("Password hashing was cancelled: {:?}", e);
+            ServiceError::InternalServerError
+        })?
+    }
+
+    pub async fn verify(&self, password: &str, stored: &str) -> Result<Verification, ServiceError> {
+        let hasher = *self;
+        let password = password.to_string();
+        let stored = stored.to_string();
+        web::block(move || {
+            if !verify_blocking(&password, &stored) {
+                return Ok(Verification { valid: false, rehashed: None });
+            }
+            let rehashed = match hasher.needs_rehash(&stored) {
+                true => Some(hasher.hash_blocking(&password)?),
+                false => None,
+            };
+            Ok(Verification { valid: true, rehashed })
+        })
+        .await
+        .map_err(|e| {
+            error!("Password verification was cancelled: {:?}", e);
+            ServiceError::InternalServerError
+        })?
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, ServiceError> {
+        let params = Params::new(self.config.argon2_memory_kib, self.config.argon2_iterations, self.config.argon2_parallelism, None)
+            .map_err(|e| {
+                error!("Invalid Argon2 parameters: {:?}", e);
+                ServiceError::InternalServerError
+            })?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    fn hash_blocking(&self, password: &str) -> Result<String, ServiceError> {
+        match self.config.algorithm {
+            HashAlgorithm::Argon2id => {
+                let salt = SaltString::encode_b64(&rand::thread_rng().gen::<[u8; 16]>()).map_err(|e| {
+                    error!("Failed to encode salt: {:?}", e);
+                    ServiceError::InternalServerError
+                })?;
+                let hash = self.argon2()?.hash_password(password.as_bytes(), &salt).map_err(|e| {
+                    error!("Hashing error: {:?}", e);
+                    ServiceError::InternalServerError
+                })?;
+                Ok(hash.to_string())
+            },
+            HashAlgorithm::Bcrypt => bcrypt::hash(password, self.config.bcrypt_cost).map_err(|e| {
+                error!("Hashing error: {:?}", e);
+                ServiceError::InternalServerError
+            }),
+        }
+    }
+
+    // True when the hash was not made with the current algorithm and parameters
+    fn needs_rehash(&self, stored: &str) -> bool {
+        match self.config.algorithm {
+            HashAlgorithm::Argon2id => {
+                let Ok(parsed) = PasswordHash::new(stored) else { return true };
+                let Ok(params) = Params::try_from(&parsed) else { return true };
+                parsed.algorithm != Algorithm::Argon2id.ident()
+                    || parsed.version != Some(Version::V0x13.into())
+                    || params.m_cost() != self.config.argon2_memory_kib
+                    || params.t_cost() != self.config.argon2_iterations
+                    || params.p_cost() != self.config.argon2_parallelism
+            },
+            HashAlgorithm::Bcrypt => bcrypt_cost(stored) != Some(self.config.bcrypt_cost),
+        }
+    }
+}
+
+fn is_bcrypt(stored: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| stored.starts_with(prefix))
+}
+
+fn bcrypt_cost(stored: &str) -> Option<u32> {
+    if !is_bcrypt(stored) {
+        return None;
+    }
+    stored.split('$').nth(2)?.parse().ok()
+}
+
+// The parameters come from the hash itself, so hashes made with older settings still verify
+fn verify_blocking(password: &str, stored: &str) -> bool {
+    if stored.starts_with("$argon2") {
+        PasswordHash::new(stored)
+            .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+            .unwrap_or(false)
+    } else if is_bcrypt(stored) {
+        bcrypt::verify(password, stored).unwrap_or(false)
+    } else {
+        false
+    }
+}
@@ -0,0 +1,206 @@
+// passwordpolicy.rs
+
+use crate::create::common::*;
+use crate::create::config::PasswordConfig;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Passwords and fragments tried first by every cracker, most common first
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "password", "12345678", "qwerty", "123456789", "12345", "1234", "111111", "1234567", "dragon",
+    "123123", "baseball", "abc123", "football", "monkey", "letmein", "696969", "shadow", "master", "666666",
+    "qwertyuiop", "123321", "mustang", "1234567890", "michael", "654321", "superman", "1qaz2wsx", "7777777", "121212",
+    "000000", "qazwsx", "123qwe", "killer", "trustno1", "jordan", "jennifer", "zxcvbnm", "asdfgh", "hunter",
+    "buster", "soccer", "harley", "batman", "andrew", "tigger", "sunshine", "iloveyou", "2000", "charlie",
+    "robert", "thomas", "hockey", "ranger", "daniel", "starwars", "klaster", "112233", "george", "computer",
+    "michelle", "jessica", "pepper", "1111", "zxcvbn", "555555", "11111111", "131313", "freedom", "777777",
+    "pass", "maggie", "159753", "aaaaaa", "ginger", "princess", "joshua", "cheese", "amanda", "summer",
+    "love", "ashley", "nicole", "chelsea", "biteme", "matthew", "access", "yankees", "987654321", "dallas",
+    "austin", "thunder", "taylor", "matrix", "welcome", "admin", "login", "passw0rd", "qwerty123", "secret",
+];
+
+// Why a password was refused, one entry per broken rule
+#[derive(Serialize, Debug)]
+pub struct PolicyFailure {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl PolicyFailure {
+    fn new(rule: &'static str, message: String) -> Self {
+        PolicyFailure { rule, message }
+    }
+}
+
+// PasswordConfig plus the breached password list, loaded once at startup and shared as app data
+pub struct PasswordPolicy {
+    config: PasswordConfig,
+    breached: Option<BreachedPasswords>,
+}
+
+impl PasswordPolicy {
+    pub fn from_config(config: &PasswordConfig) -> Result<Self, String> {
+        let breached = match &config.breached_passwords_file {
+            Some(path) => Some(BreachedPasswords::load(Path::new(path))?),
+            None => None,
+        };
+        Ok(PasswordPolicy { config: config.clone(), breached })
+    }
+
+    // Every rule is checked, so the user learns about all of them at once
+    pub fn failures(&self, password: &str, username: &str, email: &str) -> Vec<PolicyFailure> {
+        let config = &self.config;
+        let mut failures = Vec::new();
+
+        let length = password.chars().count();
+        if length < config.min_length {
+            failures.push(PolicyFailure::new("min_length", format!("Use at least {} characters.", config.min_length)));
+        }
+        if length > config.max_length {
+            failures.push(PolicyFailure::new("max_length", format!("Use at most {} characters.", config.max_length)));
+        }
+
+        let classes = [
+            (config.require_lowercase, "lowercase", "Add a lowercase letter.", password.chars().any(char::is_lowercase)),
+            (config.require_uppercase, "uppercase", "Add an uppercase letter.", password.chars().any(char::is_uppercase)),
+            (config.require_digit, "digit", "Add a digit.", password.chars().any(|c| c.is_ascii_digit())),
+            (config.require_symbol, "symbol", "Add a symbol.", password.chars().any(|c| !c.is_alphanumeric())),
+        ];
+        for (required, rule, message, present) in classes {
+            if required && !present {
+                failures.push(PolicyFailure::new(rule, message.to_string()));
+            }
+        }
+
+        if config.reject_personal_info && contains_personal_info(password, username, email) {
+            failures.push(PolicyFailure::new("personal_info", "Do not use your username or email address.".to_string()));
+        }
+
+        let score = strength(password);
+        if score < config.min_strength {
+            failures.push(PolicyFailure::new(
+                "strength",
+                format!("Too easy to guess (strength {} of 4, {} needed). Add more words or characters.", score, config.min_strength),
+            ));
+        }
+
+        if let Some(count) = self.breached.as_ref().map(|breached| breached.count(password)).filter(|count| *count > 0) {
+            failures.push(PolicyFailure::new(
+                "breached",
+                format!("This password appeared {} time(s) in known data breaches.", count),
+            ));
+        }
+
+        failures
+    }
+
+    pub fn check(&self, password: &str, username: &str, email: &str) -> Result<(), ServiceError> {
+        let failures = self.failures(password, username, email);
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(ServiceError::PasswordRejected(failures))
+        }
+    }
+}
+
+// Parts shorter than 3 characters would match too many passwords by chance
+fn contains_personal_info(password: &str, username: &str, email: &str) -> bool {
+    let password = password.to_lowercase();
+    let local_part = email.split('@').next().unwrap_or_default();
+    [username, local_part]
+        .iter()
+        .map(|part| part.trim().to_lowercase())
+        .any(|part| part.chars().count() >= 3 && password.contains(&part))
+}
+
+// A zxcvbn-style score from 0 to 4, from the cheapest way to guess the password: common passwords and their
+// fragments, repeated or sequential characters, and brute force over the character classes it uses
+pub fn strength(password: &str) -> u8 {
+    let bits = guess_bits(password);
+    // 10^3, 10^6, 10^8 and 10^10 guesses, zxcvbn's thresholds
+    match bits {
+        b if b < 10.0 => 0,
+        b if b < 20.0 => 1,
+        b if b < 26.6 => 2,
+        b if b < 33.2 => 3,
+        _ => 4,
+    }
+}
+
+fn guess_bits(password: &str) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+    let lower: Vec<char> = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+
+    let mut cardinality = 0;
+    if chars.iter().any(|c| c.is_lowercase()) { cardinality += 26; }
+    if chars.iter().any(|c| c.is_uppercase()) { cardinality += 26; }
+    if chars.iter().any(|c| c.is_ascii_digit()) { cardinality += 10; }
+    if chars.iter().any(|c| !c.is_alphanumeric()) { cardinality += 33; }
+    let char_bits = (cardinality.max(10) as f64).log2();
+
+    // best[i] is the fewest bits needed to guess the first i characters
+    let mut best = vec![f64::INFINITY; chars.len() + 1];
+    best[0] = 0.0;
+    for end in 1..=chars.len() {
+        let i = end - 1;
+        let predictable = i > 0 && (lower[i] as i64 - lower[i - 1] as i64).abs() <= 1;
+        best[end] = best[i] + if predictable { 1.0 } else { char_bits };
+
+        for (rank, word) in COMMON_PASSWORDS.iter().enumerate() {
+            let length = word.chars().count();
+            if length > end || lower[end - length..end].iter().copied().ne(word.chars()) {
+                continue;
+            }
+            let start = end - length;
+            let capitalized = chars[start..end].iter().any(|c| c.is_uppercase());
+            let bits = best[start] + ((rank + 1) as f64).log2() + 1.0 + if capitalized { 1.0 } else { 0.0 };
+            best[end] = best[end].min(bits);
+        }
+    }
+    best[chars.len()]
+}
+
+// The breached password list, grouped by the first 5 hex digits of the SHA-1 like the k-anonymity range API
+// of Have I Been Pwned, so a lookup only ever compares against one range
+pub struct BreachedPasswords {
+    ranges: HashMap<String, Vec<(String, u64)>>,
+}
+
+impl BreachedPasswords {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // SHA1 or SHA1:COUNT per line; blank lines are skipped
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut ranges: HashMap<String, Vec<(String, u64)>> = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (hash, count) = line.split_once(':').unwrap_or((line, "1"));
+            let count = count.trim().parse::<u64>().map_err(|_| format!("line {}: invalid count", number + 1))?;
+            if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("line {}: expected a SHA-1 hash in hex", number + 1));
+            }
+            let hash = hash.to_ascii_uppercase();
+            ranges.entry(hash[..5].to_string()).or_default().push((hash[5..].to_string(), count));
+        }
+        Ok(BreachedPasswords { ranges })
+    }
+
+    // How often the password was seen in breaches, 0 when it is not listed
+    pub fn count(&self, password: &str) -> u64 {
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(5);
+        self.ranges
+            .get(prefix)
+            .and_then(|range| range.iter().find(|(listed, _)| listed == suffix))
+            .map(|(_, count)| *count)
+            .unwrap_or(0)
+    }
+}
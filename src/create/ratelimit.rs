@@ -0,0 +1,305 @@
+// ratelimit.rs
+
+use crate::create::common::*;
+use crate::create::jwks;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const MEMORY_STORE_SWEEP_THRESHOLD: usize = 10_000;
+
+pub type StoreFuture<'a> = Pin<Box<dyn Future<Output = Result<(), u64>> + 'a>>;
+
+#[derive(Clone, Copy)]
+pub struct Limit {
+    pub capacity: u32,
+    pub period_seconds: u64,
+}
+
+impl Limit {
+    fn refill_per_second(&self) -> f64 {
+        self.capacity as f64 / self.period_seconds as f64
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum KeyBy {
+    Ip,
+    Email,
+    // The subject of a valid bearer token, else the "username" field of the JSON body
+    Username,
+}
+
+impl KeyBy {
+    fn name(&self) -> &'static str {
+        match self {
+            KeyBy::Ip => "ip",
+            KeyBy::Email => "email",
+            KeyBy::Username => "username",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [KeyBy::Ip, KeyBy::Email, KeyBy::Username].into_iter().find(|key| key.name() == name)
+    }
+}
+
+#[derive(Clone)]
+pub struct RouteRule {
+    pub path: String,
+    pub key: KeyBy,
+    pub limit: Limit,
+}
+
+impl RouteRule {
+    fn new(path: &str, key: KeyBy, capacity: u32, period_seconds: u64) -> Self {
+        RouteRule { path: path.to_string(), key, limit: Limit { capacity, period_seconds } }
+    }
+}
+
+// The default limits. "*" applies to all requests, on top of the rules of the route itself.
+pub fn route_limits() -> Vec<RouteRule> {
+    vec![
+        RouteRule::new("*", KeyBy::Ip, 300, 60),
+        RouteRule::new("/login", KeyBy::Ip, 30, 60),
+        RouteRule::new("/login", KeyBy::Username, 10, 60),
+        RouteRule::new("/authorize/login", KeyBy::Ip, 30, 60),
+        RouteRule::new("/authorize/login", KeyBy::Username, 10, 60),
+        RouteRule::new("/verify_2fa", KeyBy::Ip, 20, 60),
+        RouteRule::new("/authorize/verify_2fa", KeyBy::Ip, 20, 60),
+        RouteRule::new("/verify_2fa_activation", KeyBy::Ip, 20, 60),
+        RouteRule::new("/verify_2fa_activation", KeyBy::Username, 10, 60),
+        RouteRule::new("/verify_2fa_deactivation", KeyBy::Ip, 20, 60),
+        RouteRule::new("/verify_2fa_deactivation", KeyBy::Username, 10, 60),
+        RouteRule::new("/verify_totp_enrollment", KeyBy::Ip, 20, 60),
+        RouteRule::new("/verify_totp_enrollment", KeyBy::Username, 10, 60),
+        RouteRule::new("/create_account", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/forgot_password", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/forgot_password", KeyBy::Email, 3, 3600),
+        RouteRule::new("/resend_verification", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/resend_verification", KeyBy::Email, 3, 3600),
+        RouteRule::new("/login/magic_link", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/login/magic_link", KeyBy::Email, 3, 3600),
+        RouteRule::new("/activate_2fa", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/activate_2fa", KeyBy::Username, 3, 3600),
+        RouteRule::new("/request_deactivate_2fa", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/request_deactivate_2fa", KeyBy::Username, 3, 3600),
+        RouteRule::new("/reset_password", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/change_password", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/change_password", KeyBy::Username, 10, 3600),
+        RouteRule::new("/change_email", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/change_email", KeyBy::Username, 5, 3600),
+        RouteRule::new("/verify", KeyBy::Ip, 20, 3600),
+        RouteRule::new("/unlock_account", KeyBy::Ip, 20, 3600),
+        RouteRule::new("/confirm_email_change", KeyBy::Ip, 20, 3600),
+        RouteRule::new("/cancel_email_change", KeyBy::Ip, 20, 3600),
+        RouteRule::new("/not_me", KeyBy::Ip, 20, 3600),
+        RouteRule::new("/login/magic_link/verify", KeyBy::Ip, 30, 60),
+        RouteRule::new("/oauth/register", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/token", KeyBy::Ip, 60, 60),
+        RouteRule::new("/token/refresh", KeyBy::Ip, 60, 60),
+        RouteRule::new("/webauthn/login/finish", KeyBy::Ip, 30, 60),
+        RouteRule::new("/webauthn/2fa/finish", KeyBy::Ip, 20, 60),
+        RouteRule::new("/webauthn/register/start", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/webauthn/register/start", KeyBy::Username, 10, 3600),
+        RouteRule::new("/webauthn/register/finish", KeyBy::Ip, 10, 3600),
+        RouteRule::new("/webauthn/register/finish", KeyBy::Username, 10, 3600),
+    ]
+}
+
+// PATH:KEY:CAPACITY/SECONDS, e.g. /login:username:10/60
+fn parse_rule(entry: &str) -> Result<RouteRule, String> {
+    let invalid = || format!("{} is not PATH:KEY:CAPACITY/SECONDS", entry);
+    let [path, key, limit] = entry.split(':').collect::<Vec<_>>()[..] else {
+        return Err(invalid());
+    };
+    let key = KeyBy::from_name(key).ok_or_else(|| format!("{}: the key must be ip, email or username", entry))?;
+    let (capacity, period_seconds) = limit.split_once('/').ok_or_else(invalid)?;
+    let capacity = capacity.parse().map_err(|_| invalid())?;
+    let period_seconds = period_seconds.parse().map_err(|_| invalid())?;
+    if period_seconds == 0 {
+        return Err(format!("{}: the period must be at least one second", entry));
+    }
+    Ok(RouteRule::new(path, key, capacity, period_seconds))
+}
+
+// Each configured rule replaces the default for the same path and key; a capacity of 0 removes it
+pub fn load_rules(entries: &[String]) -> Result<Vec<RouteRule>, String> {
+    let mut rules = route_limits();
+    for entry in entries {
+        let rule = parse_rule(entry)?;
+        rules.retain(|existing| existing.path != rule.path || existing.key != rule.key);
+        if rule.limit.capacity > 0 {
+            rules.push(rule);
+        }
+    }
+    Ok(rules)
+}
+
+// Err carries the number of seconds until the next token is available
+pub trait RateLimitStore: Send + Sync {
+    fn take<'a>(&'a self, key: &'a str, limit: Limit) -> StoreFuture<'a>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+}
+
+// Per-process buckets; run several instances behind a shared store instead
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryStore {
+    pub fn take_token(&self, key: &str, limit: Limit) -> Result<(), u64> {
+        let now = Instant::now();
+        let rate = limit.refill_per_second();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Full buckets carry no information, drop them instead of growing forever
+        if buckets.len() > MEMORY_STORE_SWEEP_THRESHOLD {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit.capacity as f64,
+            updated_at: now,
+            full_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(limit.capacity as f64);
+        bucket.updated_at = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(((1.0 - bucket.tokens) / rate).ceil() as u64);
+        }
+
+        bucket.tokens -= 1.0;
+        bucket.full_at = now + std::time::Duration::from_secs_f64((limit.capacity as f64 - bucket.tokens) / rate);
+        Ok(())
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take<'a>(&'a self, key: &'a str, limit: Limit) -> StoreFuture<'a> {
+        Box::pin(ready(self.take_token(key, limit)))
+    }
+}
+
+pub struct RateLimiter {
+    rules: Rc<Vec<RouteRule>>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(rules: Vec<RouteRule>, store: Arc<dyn RateLimitStore>) -> Self {
+        RateLimiter { rules: Rc::new(rules), store }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            rules: self.rules.clone(),
+            store: self.store.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    rules: Rc<Vec<RouteRule>>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+fn bearer_subject(req: &ServiceRequest) -> Option<String> {
+    let header = req.headers().get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let token = header.strip_prefix("Bearer ")?;
+    let config = req.app_data::<Data<AppConfig>>()?;
+    jwks::verify::<Claims>(&config.jwt, token.trim()).ok().map(|claims| claims.sub)
+}
+
+// Reads the JSON body for the rules that need it and puts it back for the handler
+async fn buffer_json_body(req: &mut ServiceRequest) -> Result<Option<serde_json::Value>, actix_web::Error> {
+    let body = req.extract::<web::Bytes>().await?;
+    let parsed = serde_json::from_slice(&body).ok();
+
+    let (_, mut payload) = actix_http::h1::Payload::create(true);
+    payload.unread_data(body);
+    req.set_payload(payload.into());
+
+    Ok(parsed)
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let rules = self.rules.clone();
+        let store = self.store.clone();
+
+        Box::pin(async move {
+            let path = req.path().to_string();
+            let matching: Vec<&RouteRule> = rules.iter().filter(|rule| rule.path == "*" || rule.path == path).collect();
+
+            let body = if matching.iter().any(|rule| !matches!(rule.key, KeyBy::Ip)) {
+                buffer_json_body(&mut req).await?
+            } else {
+                None
+            };
+            let body_field = |field: &str| {
+                body.as_ref()
+                    .and_then(|body| body.get(field))
+                    .and_then(|value| value.as_str())
+                    .map(|value| value.trim().to_lowercase())
+            };
+
+            for rule in matching {
+                let subject = match rule.key {
+                    KeyBy::Ip => Some(client_ip(req.request())),
+                    KeyBy::Email => body_field("email"),
+                    KeyBy::Username => bearer_subject(&req).or_else(|| body_field("username")),
+                };
+                let Some(subject) = subject else { continue };
+
+                let key = format!("{}|{}|{}", rule.path, rule.key.name(), subject);
+                if let Err(retry_after) = store.take(&key, rule.limit).await {
+                    info!("Rate limit hit on {} for {} {}", path, rule.key.name(), subject);
+                    let response = ServiceError::TooManyRequests(retry_after as i64).error_response();
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
@@ -0,0 +1,94 @@
+// recoverycodes.rs
+
+use crate::create::common::*;
+use crate::create::config::TotpConfig;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_HALF_LEN: usize = 5;
+
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// Keyed with RECOVERY_CODE_KEY, so a leaked table can't be matched against the small code space offline
+fn hash_code(config: &TotpConfig, code: &str) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&config.recovery_code_key).expect("HMAC accepts keys of any size");
+    mac.update(normalize_code(code).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// What codes issued before the key was introduced were stored as
+fn legacy_hash_code(code: &str) -> String {
+    hex::encode(Sha256::digest(normalize_code(code).as_bytes()))
+}
+
+fn generate_code() -> String {
+    let raw: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(RECOVERY_CODE_HALF_LEN * 2)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect();
+    format!("{}-{}", &raw[..RECOVERY_CODE_HALF_LEN], &raw[RECOVERY_CODE_HALF_LEN..])
+}
+
+// Replaces any existing codes for the user and returns the new plaintext batch, which is never stored
+pub async fn generate_recovery_codes(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+) -> Result<Vec<String>, ServiceError> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_code()).collect();
+    let code_hashes: Vec<String> = codes.iter().map(|code| hash_code(&config.totp, code)).collect();
+
+    store.replace_recovery_codes(username, &code_hashes).await?;
+
+    Ok(codes)
+}
+
+pub async fn delete_recovery_codes(
+    store: &dyn UserStore,
+    username: &str,
+) -> Result<(), ServiceError> {
+    store.delete_recovery_codes(username).await
+}
+
+// Marks the code consumed and returns how many unused codes are left. Codes from before RECOVERY_CODE_KEY
+// keep working until used or regenerated
+pub async fn consume_recovery_code(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    code: &str,
+) -> Result<usize, ServiceError> {
+    if !store.consume_recovery_code(username, &hash_code(&config.totp, code)).await?
+        && !store.consume_recovery_code(username, &legacy_hash_code(code)).await?
+    {
+        return Err(ServiceError::BadRequest("Invalid recovery code.".to_string()));
+    }
+
+    store.count_recovery_codes(username).await
+}
+
+#[post("/regenerate_recovery_codes")]
+async fn regenerate_recovery_codes(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServiceError> {
+    let (_, user_from_token) = extract_user_email_from_token(&req, store.get_ref()).await?;
+
+    let has_2fa = store.find_user(&user_from_token).await?.map(|user| user.has_2fa);
+
+    if !has_2fa.unwrap_or(false) {
+        return Err(ServiceError::BadRequest("2FA is not activated for this account.".to_string()));
+    }
+
+    let codes = generate_recovery_codes(store.get_ref(), config.get_ref(), &user_from_token).await?;
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "New recovery codes generated. Previous codes no longer work.", "recovery_codes": codes })))
+}
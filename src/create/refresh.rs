@@ -0,0 +1,65 @@
+// refresh.rs
+
+use crate::create::common::*;
+use crate::create::tokens;
+use crate::create::sessions;
+use crate::create::store::RefreshTokenRecord;
+
+// Returns a new access token / refresh token pair in the same session. Sessions opened for an OAuth
+// client only rotate for that client at /token, and the API's own sessions only at /token/refresh.
+pub async fn rotate_refresh_token(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    presented_token: &str,
+    client_id: Option<&str>,
+) -> Result<(String, String), ServiceError> {
+    let token_hash = tokens::hash_token(presented_token);
+
+    let RefreshTokenRecord { id: token_id, family_id, expires_at, used, revoked, username, has_2fa, client } = store
+        .find_refresh_token(&token_hash)
+        .await?
+        .ok_or(ServiceError::Unauthorized("Invalid refresh token".to_string()))?;
+
+    if client.as_ref().map(|client| client.client_id.as_str()) != client_id {
+        error!("Refresh token of user {} presented by another client", username);
+        return Err(ServiceError::Unauthorized("Invalid refresh token".to_string()));
+    }
+
+    if used {
+        // A rotated token came back: someone holds a copy, so the whole family goes
+        error!("Refresh token reuse detected for user: {}", username);
+        sessions::revoke_session(store, &family_id).await?;
+        return Err(ServiceError::Unauthorized("Refresh token reuse detected. Please log in again.".to_string()));
+    }
+
+    if revoked || !sessions::is_session_active(store, &family_id).await? {
+        return Err(ServiceError::Unauthorized("Refresh token has been revoked".to_string()));
+    }
+
+    if Utc::now().naive_utc() > expires_at {
+        return Err(ServiceError::Unauthorized("Refresh token has expired".to_string()));
+    }
+
+    // Lost the race against a concurrent refresh with the same token
+    if !store.mark_refresh_token_used(token_id).await? {
+        return Err(ServiceError::Unauthorized("Invalid refresh token".to_string()));
+    }
+
+    let access_token = tokens::generate_access_token(config, &username, has_2fa, &family_id, client.as_ref())?;
+    let new_refresh_token = tokens::issue_refresh_token(store, config, &username, &family_id).await?;
+    sessions::extend_session(store, config, &family_id).await?;
+    info!("Rotated refresh token for user: {}", username);
+
+    Ok((access_token, new_refresh_token))
+}
+
+#[post("/token/refresh")]
+async fn refresh_token(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    info: web::Json<RefreshTokenRequest>,
+) -> Result<HttpResponse, ServiceError> {
+    let (access_token, new_refresh_token) = rotate_refresh_token(store.get_ref(), config.get_ref(), &info.refresh_token, None).await?;
+
+    Ok(HttpResponse::Ok().json(tokens::token_pair_response(config.get_ref(), &access_token, &new_refresh_token)))
+}
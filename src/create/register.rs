@@ -3,19 +3,27 @@
 use crate::create::common::*; 
 use crate::create::registertwo::handle_email_verification;
 use crate::create::registertwo::handle_database_and_token_generation;
+use crate::create::tokens;
 
 #[post("/create_account")]
 async fn create_account(
-    pool: Data<Pool>,
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    policy: Data<PasswordPolicy>,
+    req: HttpRequest,
     info: web::Json<RegisterRequest>,
 ) -> Result<HttpResponse, ServiceError> {
     info.validate().map_err(|e| {
         error!("Validation error: {:?}", e);
         ServiceError::BadRequest("Invalid input data.".to_string())
     })?;
+    policy.check(&info.password, &info.username, &info.email)?;
 
-    let verification_token = handle_email_verification(&info).await?;
-    let token = handle_database_and_token_generation(pool, &info, &verification_token).await?;
+    let locale = emails.locale(info.locale.as_deref(), Some(&req));
+    let verified = !config.features.email_verification;
+    let (token, refresh_token) = handle_database_and_token_generation(store.get_ref(), config.get_ref(), &info, verified, &locale).await?;
+    handle_email_verification(store.get_ref(), emails.get_ref(), config.get_ref(), &info, &locale).await?;
 
-    Ok(HttpResponse::Ok().json(json!({"status": "success", "token": token })))
+    Ok(HttpResponse::Ok().json(tokens::token_pair_response(config.get_ref(), &token, &refresh_token)))
 }
@@ -1,44 +1,48 @@
 // reset.rs
 
 use crate::create::common::*; 
+use crate::create::authtokens;
+use crate::create::store::TokenPurpose;
+use crate::create::notifications::{self, SecurityEvent};
+use crate::create::sessions;
+use crate::create::passwordhash::PasswordHasher;
 
 #[post("/reset_password")]
 async fn reset_password(
-    pool: Data<Pool>,
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    policy: Data<PasswordPolicy>,
+    req: HttpRequest,
     info: web::Json<ResetPasswordRequest>,
 ) -> Result<HttpResponse, ServiceError> {
-    let mut conn = pool.get_conn().await.map_err(|_| ServiceError::InternalServerError)?;
-
-    let result: Option<(String, String)> = conn.exec_first(
-        r"SELECT reset_password_token, token_expiry FROM users WHERE email = ?",
-        (&info.email,)
-    )
-    .await.map_err(|_| ServiceError::InternalServerError)?;
-
-    match result {
-        Some((db_token, token_expiry_string)) => {
-            let token_expiry = NaiveDateTime::parse_from_str(&token_expiry_string, "%Y-%m-%d %H:%M:%S")
-                .map_err(|_| ServiceError::InternalServerError)?;
-
-            if db_token != info.token {
-                return Err(ServiceError::BadRequest("Invalid reset token.".to_string()));
-            }
-
-            let current_time = Utc::now().naive_utc();
-            if current_time > token_expiry {
-                return Err(ServiceError::BadRequest("Reset token has expired.".to_string()));
-            }
-
-            let hashed_password = hash(&info.new_password, DEFAULT_COST).map_err(|_| ServiceError::InternalServerError)?;
+    let user = store
+        .find_user_by_email(&info.email)
+        .await?
+        .ok_or(ServiceError::BadRequest("Email not found.".to_string()))?;
+
+    let token = store
+        .find_user_auth_token(&user.username, TokenPurpose::PasswordReset)
+        .await?
+        .filter(|token| authtokens::matches(token, &info.token))
+        .ok_or(ServiceError::BadRequest("Invalid reset token.".to_string()))?;
+
+    if authtokens::is_expired(&token) {
+        return Err(ServiceError::BadRequest("Reset token has expired.".to_string()));
+    }
+    // Before consuming the token, so a rejected password can be retried with the same link
+    policy.check(&info.new_password, &user.username, &user.email)?;
+    if !authtokens::consume(store.get_ref(), &token).await? {
+        return Err(ServiceError::BadRequest("Invalid reset token.".to_string()));
+    }
 
-            conn.exec_drop(
-                r"UPDATE users SET password = ?, reset_password_token = NULL, token_expiry = NULL WHERE email = ?",
-                (&hashed_password, &info.email),
-            )
-            .await.map_err(|_| ServiceError::InternalServerError)?;
+    let hashed_password = PasswordHasher::new(&config.hashing).hash(&info.new_password).await?;
 
-            Ok(HttpResponse::Ok().json(json!({"status": "success"})))
-        },
-        None => Err(ServiceError::BadRequest("Email not found.".to_string())),
+    let username = store.reset_password(&info.email, &hashed_password).await?;
+    if let Some(username) = username {
+        sessions::revoke_all_sessions(store.get_ref(), &username).await?;
+        notifications::notify(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &username, SecurityEvent::PasswordReset).await;
     }
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success"})))
 }
@@ -0,0 +1,96 @@
+// sessions.rs
+
+use crate::create::common::*;
+use crate::create::store::SessionClient;
+
+// The session id doubles as the `jti` claim and as the refresh token family id
+pub async fn create_session(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    client: Option<&SessionClient>,
+) -> Result<String, ServiceError> {
+    let jti = Uuid::new_v4().to_string();
+
+    let expiry = Utc::now()
+        .checked_add_signed(config.tokens.refresh_token_ttl())
+        .ok_or_else(|| {
+            error!("Failed to calculate session expiry");
+            ServiceError::InternalServerError
+        })?;
+
+    store.create_session(&jti, username, client, expiry.naive_utc()).await?;
+
+    Ok(jti)
+}
+
+pub async fn is_session_active(
+    store: &dyn UserStore,
+    jti: &str,
+) -> Result<bool, ServiceError> {
+    store.is_session_active(jti).await
+}
+
+pub async fn extend_session(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    jti: &str,
+) -> Result<(), ServiceError> {
+    let expiry = Utc::now()
+        .checked_add_signed(config.tokens.refresh_token_ttl())
+        .ok_or_else(|| {
+            error!("Failed to calculate session expiry");
+            ServiceError::InternalServerError
+        })?;
+
+    store.extend_session(jti, expiry.naive_utc()).await
+}
+
+pub async fn revoke_session(
+    store: &dyn UserStore,
+    jti: &str,
+) -> Result<(), ServiceError> {
+    store.revoke_session(jti).await
+}
+
+pub async fn revoke_all_sessions(
+    store: &dyn UserStore,
+    username: &str,
+) -> Result<(), ServiceError> {
+    store.revoke_all_sessions(username).await
+}
+
+// Every session but the one making the request
+pub async fn revoke_other_sessions(
+    store: &dyn UserStore,
+    username: &str,
+    keep_jti: &str,
+) -> Result<(), ServiceError> {
+    store.revoke_other_sessions(username, keep_jti).await
+}
+
+#[post("/logout")]
+async fn logout(
+    store: Data<dyn UserStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServiceError> {
+    let claims = extract_claims_from_token(&req, store.get_ref()).await?;
+
+    revoke_session(store.get_ref(), &claims.jti).await?;
+    info!("Logged out session for user: {}", claims.sub);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "Logged out."})))
+}
+
+#[post("/logout_all")]
+async fn logout_all(
+    store: Data<dyn UserStore>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServiceError> {
+    let claims = extract_claims_from_token(&req, store.get_ref()).await?;
+
+    revoke_all_sessions(store.get_ref(), &claims.sub).await?;
+    info!("Logged out every session for user: {}", claims.sub);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "Logged out from every device."})))
+}
@@ -0,0 +1,192 @@
+// social.rs
+
+use crate::create::common::*;
+use crate::create::login;
+use crate::create::notifications;
+use crate::create::passwordhash::PasswordHasher;
+use crate::create::sessions;
+use crate::create::socialidp::{self, IdpClient};
+use crate::create::store::NewUser;
+use crate::create::tokens;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use sha2::{Digest, Sha256};
+
+const SOCIAL_STATE_TTL_MINUTES: i64 = 10;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn username_base(identity: &socialidp::ExternalIdentity, email: &str) -> String {
+    let candidate = identity.preferred_username.clone()
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+    let sanitized: String = candidate
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        .take(40)
+        .collect();
+
+    if sanitized.len() < 3 { format!("user{}", sanitized) } else { sanitized }
+}
+
+// Creates a verified account for an email the provider vouched for; the random password can be replaced via forgot_password
+async fn create_social_account(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    identity: &socialidp::ExternalIdentity,
+    email: &str,
+    locale: &str,
+) -> Result<String, ServiceError> {
+    let hashed_password = PasswordHasher::new(&config.hashing).hash(&random_string(32)).await?;
+    let base = username_base(identity, email);
+
+    for attempt in 0..5 {
+        let username = match attempt {
+            0 => base.clone(),
+            _ => format!("{}-{}", base, rand::thread_rng().gen_range(1000..10000)),
+        };
+
+        if store.find_user(&username).await?.is_some() {
+            continue;
+        }
+
+        store.create_user(NewUser {
+            username: &username,
+            email,
+            password_hash: &hashed_password,
+            verified: true,
+            locale: Some(locale),
+        }).await?;
+        info!("Created account {} from social login", username);
+
+        return Ok(username);
+    }
+
+    Err(ServiceError::InternalServerError)
+}
+
+// Known identity first, then an existing account with the same email, else a new account
+async fn resolve_user(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    provider: &str,
+    identity: &socialidp::ExternalIdentity,
+    locale: &str,
+) -> Result<String, ServiceError> {
+    let linked = store.find_social_identity(provider, &identity.sub).await?;
+    if let Some(username) = linked {
+        return Ok(username);
+    }
+
+    let email = match &identity.email {
+        Some(email) if identity.email_verified => email.clone(),
+        _ => return Err(ServiceError::BadRequest("The identity provider did not supply a verified email address".to_string())),
+    };
+
+    let username = match store.find_user_by_email(&email).await? {
+        Some(user) if user.verified => {
+            info!("Linking {} identity to existing account {}", provider, user.username);
+            user.username
+        },
+        // Whoever registered the unverified account never proved they own the address, so
+        // their password and sessions go before the owner the provider vouched for gets it
+        Some(user) => {
+            info!("Reclaiming unverified account {} for its {} identity", user.username, provider);
+            let hashed_password = PasswordHasher::new(&config.hashing).hash(&random_string(32)).await?;
+            store.reset_password(&email, &hashed_password).await?;
+            sessions::revoke_all_sessions(store, &user.username).await?;
+            store.mark_email_verified(&user.username).await?;
+            user.username
+        },
+        None => create_social_account(store, config, identity, &email, locale).await?,
+    };
+
+    store.link_social_identity(&username, provider, &identity.sub, &email).await?;
+
+    Ok(username)
+}
+
+#[get("/social/{provider}/authorize")]
+async fn social_authorize(
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
+    idp: Data<dyn IdpClient>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ServiceError> {
+    let provider = socialidp::provider(config.get_ref(), &path)?;
+    let metadata = socialidp::discover(idp.get_ref(), provider).await?;
+
+    let state = random_string(43);
+    let nonce = random_string(43);
+    let code_verifier = random_string(64);
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+    let expiry = Utc::now()
+        .checked_add_signed(Duration::minutes(SOCIAL_STATE_TTL_MINUTES))
+        .ok_or(ServiceError::InternalServerError)?;
+
+    store.insert_social_state(&state, &provider.name, &nonce, &code_verifier, expiry.naive_utc()).await?;
+
+    let mut location = url::Url::parse(&metadata.authorization_endpoint).map_err(|_| ServiceError::InternalServerError)?;
+    location.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &provider.redirect_uri)
+        .append_pair("scope", &provider.scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    Ok(HttpResponse::Found().insert_header((http::header::LOCATION, location.to_string())).finish())
+}
+
+// Answers like /login: a token pair, or the usual 2FA challenge for accounts with 2FA
+#[get("/social/{provider}/callback")]
+async fn social_callback(
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    idp: Data<dyn IdpClient>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: Query<SocialCallbackQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    let provider = socialidp::provider(config.get_ref(), &path)?;
+
+    if let Some(error) = &query.error {
+        return Err(ServiceError::Unauthorized(format!("The identity provider returned an error: {}", error)));
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(ServiceError::BadRequest("Missing code or state".to_string())),
+    };
+
+    let pending = store.take_social_state(state, &provider.name).await?;
+
+    let (nonce, code_verifier) = pending.ok_or(ServiceError::BadRequest("Unknown or expired login state".to_string()))?;
+
+    let metadata = socialidp::discover(idp.get_ref(), provider).await?;
+    let id_token = socialidp::exchange_code(idp.get_ref(), provider, &metadata, code, &code_verifier).await?;
+    let identity = socialidp::validate_id_token(idp.get_ref(), provider, &metadata, &id_token, &nonce).await?;
+
+    // New accounts get the browser's language
+    let locale = emails.locale(None, Some(&req));
+    let username = resolve_user(store.get_ref(), config.get_ref(), &provider.name, &identity, &locale).await?;
+
+    let has_2fa = store.find_user(&username).await?.is_some_and(|user| user.has_2fa);
+
+    if has_2fa {
+        return login::second_factor_challenge(store.get_ref(), emails.get_ref(), config.get_ref(), &username).await;
+    }
+
+    notifications::check_login_device(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &username).await?;
+    let (token, refresh_token) = tokens::issue_token_pair(store.get_ref(), config.get_ref(), &username, has_2fa).await?;
+    info!("Generated JWT for user {} via {}", username, provider.name);
+
+    Ok(HttpResponse::Ok().json(tokens::token_pair_response(config.get_ref(), &token, &refresh_token)))
+}
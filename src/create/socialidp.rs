@@ -0,0 +1,186 @@
+// socialidp.rs
+
+use crate::create::common::*;
+use jsonwebtoken::{Algorithm, jwk::JwkSet};
+use async_trait::async_trait;
+
+pub struct SocialProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+    pub redirect_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ExternalIdentity {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+}
+
+// Every request made to identity providers goes through this, registered as app data, so tests can stand in for a provider
+#[async_trait]
+pub trait IdpClient: Send + Sync {
+    async fn get_json(&self, url: &str) -> Result<serde_json::Value, String>;
+    // The status comes back with the body; error responses without a JSON body give Null
+    async fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<(u16, serde_json::Value), String>;
+}
+
+pub struct HttpIdpClient {
+    client: reqwest::Client,
+}
+
+impl Default for HttpIdpClient {
+    fn default() -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .expect("Failed to build HTTP client");
+        HttpIdpClient { client }
+    }
+}
+
+#[async_trait]
+impl IdpClient for HttpIdpClient {
+    async fn get_json(&self, url: &str) -> Result<serde_json::Value, String> {
+        let response = self.client.get(url).send().await.and_then(|response| response.error_for_status()).map_err(|e| e.to_string())?;
+        response.json().await.map_err(|e| e.to_string())
+    }
+
+    async fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<(u16, serde_json::Value), String> {
+        let response = self.client.post(url).form(form).send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        match response.json().await {
+            Ok(body) => Ok((status.as_u16(), body)),
+            Err(_) if !status.is_success() => Ok((status.as_u16(), serde_json::Value::Null)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+// Only providers listed in SOCIAL_PROVIDERS are reachable, whatever the URL says
+pub fn provider<'a>(config: &'a AppConfig, name: &str) -> Result<&'a SocialProvider, ServiceError> {
+    config.social.providers
+        .iter()
+        .find(|provider| provider.name == name)
+        .ok_or(ServiceError::BadRequest("Unknown identity provider".to_string()))
+}
+
+pub async fn discover(http: &dyn IdpClient, provider: &SocialProvider) -> Result<ProviderMetadata, ServiceError> {
+    let url = format!("{}/.well-known/openid-configuration", provider.issuer);
+    let document = http.get_json(&url).await.map_err(|e| {
+        error!("Discovery request to {} failed: {}", url, e);
+        ServiceError::InternalServerError
+    })?;
+    let metadata: ProviderMetadata = serde_json::from_value(document).map_err(|e| {
+        error!("Invalid discovery document from {}: {:?}", url, e);
+        ServiceError::InternalServerError
+    })?;
+
+    if metadata.issuer.trim_end_matches('/') != provider.issuer {
+        error!("Issuer mismatch for provider {}: {}", provider.name, metadata.issuer);
+        return Err(ServiceError::InternalServerError);
+    }
+
+    Ok(metadata)
+}
+
+pub async fn exchange_code(
+    http: &dyn IdpClient,
+    provider: &SocialProvider,
+    metadata: &ProviderMetadata,
+    code: &str,
+    code_verifier: &str,
+) -> Result<String, ServiceError> {
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &provider.redirect_uri),
+        ("client_id", &provider.client_id),
+        ("client_secret", &provider.client_secret),
+        ("code_verifier", code_verifier),
+    ];
+    let (status, body) = http.post_form(&metadata.token_endpoint, &form).await.map_err(|e| {
+        error!("Token request to {} failed: {}", provider.name, e);
+        ServiceError::InternalServerError
+    })?;
+
+    if !(200..300).contains(&status) {
+        error!("Provider {} rejected the authorization code: {}", provider.name, status);
+        return Err(ServiceError::Unauthorized("The identity provider rejected the login".to_string()));
+    }
+
+    let tokens: TokenResponse = serde_json::from_value(body).map_err(|e| {
+        error!("Invalid token response from {}: {:?}", provider.name, e);
+        ServiceError::InternalServerError
+    })?;
+
+    tokens.id_token.ok_or_else(|| {
+        error!("Provider {} returned no id_token", provider.name);
+        ServiceError::InternalServerError
+    })
+}
+
+pub async fn validate_id_token(
+    http: &dyn IdpClient,
+    provider: &SocialProvider,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+    expected_nonce: &str,
+) -> Result<ExternalIdentity, ServiceError> {
+    let invalid = |reason: &str| {
+        error!("Rejected id_token from {}: {}", provider.name, reason);
+        ServiceError::Unauthorized("Invalid identity token".to_string())
+    };
+
+    let header = jsonwebtoken::decode_header(id_token).map_err(|_| invalid("malformed header"))?;
+    if !matches!(header.alg, Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::ES256 | Algorithm::ES384 | Algorithm::EdDSA) {
+        return Err(invalid("unsupported algorithm"));
+    }
+
+    let jwks = http.get_json(&metadata.jwks_uri).await.map_err(|e| {
+        error!("JWKS request to {} failed: {}", metadata.jwks_uri, e);
+        ServiceError::InternalServerError
+    })?;
+    let jwks: JwkSet = serde_json::from_value(jwks).map_err(|_| invalid("malformed JWKS"))?;
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| invalid("no matching key"))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| invalid("unusable key"))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&provider.issuer, &metadata.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+
+    let identity = decode::<ExternalIdentity>(id_token, &key, &validation)
+        .map_err(|e| invalid(&e.to_string()))?
+        .claims;
+
+    if identity.nonce.as_deref() != Some(expected_nonce) {
+        return Err(invalid("nonce mismatch"));
+    }
+
+    Ok(identity)
+}
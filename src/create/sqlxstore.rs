@@ -0,0 +1,1309 @@
+// sqlxstore.rs
+
+use crate::create::common::*;
+use crate::create::migrations::{self, Dialect, Migration};
+use crate::create::store::*;
+use async_trait::async_trait;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::str::FromStr;
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+type UserRow = (String, String, String, bool, bool, String, Option<String>, bool);
+
+fn user_from_row((username, email, password_hash, verified, has_2fa, two_fa_method, locale, password_reset_required): UserRow) -> User {
+    User { username, email, password_hash, verified, has_2fa, two_fa_method, locale, password_reset_required }
+}
+
+type AuthTokenRow = (i64, String, String, NaiveDateTime, Option<NaiveDateTime>);
+
+fn auth_token_from_row((id, username, token_hash, expires_at, consumed_at): AuthTokenRow) -> AuthToken {
+    AuthToken { id, username, token_hash, expires_at, consumed_at }
+}
+
+// PostgreSQL and SQLite share every query: both take $N placeholders, ON CONFLICT and RETURNING
+macro_rules! sqlx_user_store {
+    ($store:ident, $pool:ty, $dialect:expr) => {
+        pub struct $store {
+            pool: $pool,
+        }
+
+        #[async_trait]
+        impl UserStore for $store {
+            fn dialect(&self) -> Dialect {
+                $dialect
+            }
+
+            async fn applied_migrations(&self) -> Result<Vec<i64>, ServiceError> {
+                sqlx::query(SCHEMA_MIGRATIONS_TABLE).execute(&self.pool).await.map_err(db_error)?;
+                let rows: Vec<(i64,)> = sqlx::query_as("SELECT version FROM schema_migrations ORDER BY version")
+                    .fetch_all(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(rows.into_iter().map(|(version,)| version).collect())
+            }
+
+            // Both backends have transactional DDL, so a failing script leaves nothing behind
+            async fn apply_migration(&self, migration: &Migration) -> Result<(), ServiceError> {
+                let mut tx = self.pool.begin().await.map_err(db_error)?;
+                for statement in migrations::statements(migration.up) {
+                    sqlx::query(statement).execute(&mut *tx).await.map_err(db_error)?;
+                }
+                sqlx::query("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)")
+                    .bind(migration.version)
+                    .bind(migration.name)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(db_error)?;
+                tx.commit().await.map_err(db_error)
+            }
+
+            async fn revert_migration(&self, migration: &Migration) -> Result<(), ServiceError> {
+                let mut tx = self.pool.begin().await.map_err(db_error)?;
+                for statement in migrations::statements(migration.down) {
+                    sqlx::query(statement).execute(&mut *tx).await.map_err(db_error)?;
+                }
+                sqlx::query("DELETE FROM schema_migrations WHERE version = $1")
+                    .bind(migration.version)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(db_error)?;
+                tx.commit().await.map_err(db_error)
+            }
+
+            async fn create_user(&self, user: NewUser<'_>) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"INSERT INTO users (username, email, password, verified, locale)
+                       VALUES ($1, $2, $3, $4, $5)",
+                )
+                .bind(user.username)
+                .bind(user.email)
+                .bind(user.password_hash)
+                .bind(user.verified)
+                .bind(user.locale)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_user(&self, username: &str) -> Result<Option<User>, ServiceError> {
+                let row: Option<UserRow> = sqlx::query_as(
+                    "SELECT username, email, password, verified, has_2fa, two_fa_method, locale, password_reset_required FROM users WHERE username = $1",
+                )
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(user_from_row))
+            }
+
+            async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, ServiceError> {
+                let row: Option<UserRow> = sqlx::query_as(
+                    "SELECT username, email, password, verified, has_2fa, two_fa_method, locale, password_reset_required FROM users WHERE email = $1",
+                )
+                .bind(email)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(user_from_row))
+            }
+
+            async fn insert_auth_token(&self, username: &str, purpose: TokenPurpose, token_hash: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"DELETE FROM auth_tokens WHERE purpose = $1 AND consumed_at IS NULL
+                       AND user_id = (SELECT id FROM users WHERE username = $2)",
+                )
+                .bind(purpose.as_str())
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                sqlx::query(
+                    r"INSERT INTO auth_tokens (user_id, purpose, token_hash, expires_at)
+                       SELECT id, $1, $2, $3 FROM users WHERE username = $4",
+                )
+                .bind(purpose.as_str())
+                .bind(token_hash)
+                .bind(expires_at)
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_auth_token(&self, purpose: TokenPurpose, token_hash: &str) -> Result<Option<AuthToken>, ServiceError> {
+                let row: Option<AuthTokenRow> = sqlx::query_as(
+                    r"SELECT t.id, u.username, t.token_hash, t.expires_at, t.consumed_at FROM auth_tokens t JOIN users u ON u.id = t.user_id
+                       WHERE t.purpose = $1 AND t.token_hash = $2",
+                )
+                .bind(purpose.as_str())
+                .bind(token_hash)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(auth_token_from_row))
+            }
+
+            #[cfg(any(feature = "two-factor", feature = "password-reset"))]
+            async fn find_user_auth_token(&self, username: &str, purpose: TokenPurpose) -> Result<Option<AuthToken>, ServiceError> {
+                let row: Option<AuthTokenRow> = sqlx::query_as(
+                    r"SELECT t.id, u.username, t.token_hash, t.expires_at, t.consumed_at FROM auth_tokens t JOIN users u ON u.id = t.user_id
+                       WHERE u.username = $1 AND t.purpose = $2 AND t.consumed_at IS NULL
+                       ORDER BY t.id DESC LIMIT 1",
+                )
+                .bind(username)
+                .bind(purpose.as_str())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(auth_token_from_row))
+            }
+
+            async fn consume_auth_token(&self, id: i64) -> Result<bool, ServiceError> {
+                let result = sqlx::query("UPDATE auth_tokens SET consumed_at = $1 WHERE id = $2 AND consumed_at IS NULL")
+                    .bind(now())
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn mark_email_verified(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET verified = TRUE WHERE username = $1")
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn update_password_hash(&self, username: &str, current_hash: &str, new_hash: &str) -> Result<bool, ServiceError> {
+                let result = sqlx::query("UPDATE users SET password = $1 WHERE username = $2 AND password = $3")
+                    .bind(new_hash)
+                    .bind(username)
+                    .bind(current_hash)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn reset_password(&self, email: &str, password_hash: &str) -> Result<Option<String>, ServiceError> {
+                let username: Option<(String,)> = sqlx::query_as(
+                    r"UPDATE users SET password = $1, failed_login_attempts = 0, locked_until = NULL, unlock_token_hash = NULL, password_reset_required = FALSE
+                       WHERE email = $2 RETURNING username",
+                )
+                .bind(password_hash)
+                .bind(email)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(username.map(|(username,)| username))
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn set_2fa_code(&self, username: &str, code: &str, expiry: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET two_fa_code = $1, two_fa_expiry = $2 WHERE username = $3")
+                    .bind(code)
+                    .bind(expiry)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn find_pending_2fa(&self, username: &str) -> Result<Option<PendingTwoFactor>, ServiceError> {
+                let row: Option<(String, bool, String, Option<String>, Option<NaiveDateTime>)> = sqlx::query_as(
+                    "SELECT username, has_2fa, two_fa_method, two_fa_code, two_fa_expiry FROM users WHERE username = $1",
+                )
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                Ok(row.map(|(username, has_2fa, two_fa_method, code, code_expiry)| PendingTwoFactor {
+                    username,
+                    has_2fa,
+                    two_fa_method,
+                    code,
+                    code_expiry,
+                }))
+            }
+
+            async fn start_email_change(&self, username: &str, new_email: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET pending_email = $1 WHERE username = $2")
+                    .bind(new_email)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_email_change(&self, username: &str) -> Result<Option<String>, ServiceError> {
+                let row: Option<(Option<String>,)> = sqlx::query_as("SELECT pending_email FROM users WHERE username = $1")
+                    .bind(username)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(row.and_then(|(email,)| email))
+            }
+
+            async fn apply_email_change(&self, username: &str, new_email: &str) -> Result<bool, ServiceError> {
+                let result = sqlx::query("UPDATE users SET email = $1, pending_email = NULL, verified = TRUE WHERE username = $2 AND pending_email = $1")
+                    .bind(new_email)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await;
+                match result {
+                    Ok(result) => Ok(result.rows_affected() > 0),
+                    Err(sqlx::Error::Database(ref e)) if e.is_unique_violation() => Ok(false),
+                    Err(e) => Err(db_error(e)),
+                }
+            }
+
+            async fn clear_email_change(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET pending_email = NULL WHERE username = $1")
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+
+                sqlx::query(
+                    r"DELETE FROM auth_tokens WHERE purpose IN ($1, $2) AND consumed_at IS NULL
+                       AND user_id = (SELECT id FROM users WHERE username = $3)",
+                )
+                .bind(TokenPurpose::EmailChange.as_str())
+                .bind(TokenPurpose::EmailChangeCancel.as_str())
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn start_2fa_change(&self, username: &str, code: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET temp_2fa_code = $1 WHERE username = $2")
+                    .bind(code)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn find_2fa_change(&self, username: &str) -> Result<Option<String>, ServiceError> {
+                let row: Option<(Option<String>,)> = sqlx::query_as("SELECT temp_2fa_code FROM users WHERE username = $1")
+                    .bind(username)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(row.and_then(|(code,)| code))
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn start_password_change(&self, username: &str, code: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET password_change_code = $1 WHERE username = $2")
+                    .bind(code)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn find_password_change(&self, username: &str) -> Result<Option<String>, ServiceError> {
+                let row: Option<(Option<String>,)> = sqlx::query_as("SELECT password_change_code FROM users WHERE username = $1")
+                    .bind(username)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(row.and_then(|(code,)| code))
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn enable_email_2fa(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"UPDATE users SET has_2fa = TRUE, two_fa_method = 'email', totp_secret = NULL, totp_last_step = NULL,
+                       temp_2fa_code = NULL WHERE username = $1",
+                )
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn count_2fa_users(&self) -> Result<i64, ServiceError> {
+                let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users WHERE has_2fa = TRUE")
+                    .fetch_one(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(count)
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn disable_2fa(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"UPDATE users SET has_2fa = FALSE, two_fa_method = 'email', totp_secret = NULL, totp_last_step = NULL,
+                       two_fa_code = NULL, two_fa_expiry = NULL, temp_2fa_code = NULL WHERE username = $1",
+                )
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn start_totp_enrollment(&self, username: &str, encrypted_secret: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET temp_totp_secret = $1 WHERE username = $2")
+                    .bind(encrypted_secret)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn find_totp_enrollment(&self, username: &str) -> Result<Option<String>, ServiceError> {
+                let row: Option<(Option<String>,)> = sqlx::query_as("SELECT temp_totp_secret FROM users WHERE username = $1")
+                    .bind(username)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(row.and_then(|(secret,)| secret))
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn enable_totp(&self, username: &str, last_step: i64) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"UPDATE users SET has_2fa = TRUE, two_fa_method = 'totp', totp_secret = temp_totp_secret, totp_last_step = $1,
+                       temp_totp_secret = NULL, two_fa_code = NULL, two_fa_expiry = NULL WHERE username = $2",
+                )
+                .bind(last_step)
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn find_totp_secret(&self, username: &str) -> Result<Option<(Option<String>, Option<i64>)>, ServiceError> {
+                sqlx::query_as("SELECT totp_secret, totp_last_step FROM users WHERE username = $1")
+                    .bind(username)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn set_totp_last_step(&self, username: &str, last_step: i64) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET totp_last_step = $1 WHERE username = $2")
+                    .bind(last_step)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn replace_recovery_codes(&self, username: &str, code_hashes: &[String]) -> Result<(), ServiceError> {
+                let mut tx = self.pool.begin().await.map_err(db_error)?;
+
+                sqlx::query("DELETE FROM recovery_codes WHERE user_id = (SELECT id FROM users WHERE username = $1)")
+                    .bind(username)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(db_error)?;
+
+                for code_hash in code_hashes {
+                    sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) SELECT id, $1 FROM users WHERE username = $2")
+                        .bind(code_hash)
+                        .bind(username)
+                        .execute(&mut *tx)
+                        .await
+                        .map_err(db_error)?;
+                }
+
+                tx.commit().await.map_err(db_error)
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn delete_recovery_codes(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query("DELETE FROM recovery_codes WHERE user_id = (SELECT id FROM users WHERE username = $1)")
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn consume_recovery_code(&self, username: &str, code_hash: &str) -> Result<bool, ServiceError> {
+                let result = sqlx::query(
+                    r"UPDATE recovery_codes SET used_at = $1
+                       WHERE user_id = (SELECT id FROM users WHERE username = $2) AND code_hash = $3 AND used_at IS NULL",
+                )
+                .bind(now())
+                .bind(username)
+                .bind(code_hash)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn count_recovery_codes(&self, username: &str) -> Result<usize, ServiceError> {
+                let (remaining,): (i64,) = sqlx::query_as(
+                    r"SELECT COUNT(*) FROM recovery_codes
+                       WHERE user_id = (SELECT id FROM users WHERE username = $1) AND used_at IS NULL",
+                )
+                .bind(username)
+                .fetch_one(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(remaining as usize)
+            }
+
+            async fn create_session(&self, jti: &str, username: &str, client: Option<&SessionClient>, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query("INSERT INTO sessions (jti, user_id, client_id, scope, expires_at) SELECT $1, id, $2, $3, $4 FROM users WHERE username = $5")
+                    .bind(jti)
+                    .bind(client.map(|client| client.client_id.as_str()))
+                    .bind(client.map(|client| client.scope.as_str()))
+                    .bind(expires_at)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn is_session_active(&self, jti: &str) -> Result<bool, ServiceError> {
+                let row: Option<(Option<NaiveDateTime>, Option<NaiveDateTime>)> = sqlx::query_as(
+                    "SELECT expires_at, revoked_at FROM sessions WHERE jti = $1",
+                )
+                .bind(jti)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                Ok(matches!(row, Some((Some(expires_at), None)) if expires_at > now()))
+            }
+
+            async fn extend_session(&self, jti: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE sessions SET expires_at = $1 WHERE jti = $2 AND revoked_at IS NULL")
+                    .bind(expires_at)
+                    .bind(jti)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn revoke_session(&self, jti: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE sessions SET revoked_at = $1 WHERE jti = $2 AND revoked_at IS NULL")
+                    .bind(now())
+                    .bind(jti)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+
+                sqlx::query("UPDATE refresh_tokens SET revoked_at = $1 WHERE family_id = $2 AND revoked_at IS NULL")
+                    .bind(now())
+                    .bind(jti)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn revoke_other_sessions(&self, username: &str, keep_jti: &str) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"UPDATE sessions SET revoked_at = $1
+                       WHERE user_id = (SELECT id FROM users WHERE username = $2) AND jti <> $3 AND revoked_at IS NULL",
+                )
+                .bind(now())
+                .bind(username)
+                .bind(keep_jti)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                sqlx::query(
+                    r"UPDATE refresh_tokens SET revoked_at = $1
+                       WHERE user_id = (SELECT id FROM users WHERE username = $2) AND family_id <> $3 AND revoked_at IS NULL",
+                )
+                .bind(now())
+                .bind(username)
+                .bind(keep_jti)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn revoke_all_sessions(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"UPDATE sessions SET revoked_at = $1
+                       WHERE user_id = (SELECT id FROM users WHERE username = $2) AND revoked_at IS NULL",
+                )
+                .bind(now())
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                sqlx::query(
+                    r"UPDATE refresh_tokens SET revoked_at = $1
+                       WHERE user_id = (SELECT id FROM users WHERE username = $2) AND revoked_at IS NULL",
+                )
+                .bind(now())
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn insert_refresh_token(&self, username: &str, token_hash: &str, family_id: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"INSERT INTO refresh_tokens (user_id, token_hash, family_id, expires_at)
+                       SELECT id, $1, $2, $3 FROM users WHERE username = $4",
+                )
+                .bind(token_hash)
+                .bind(family_id)
+                .bind(expires_at)
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRecord>, ServiceError> {
+                let row: Option<(i64, String, Option<NaiveDateTime>, Option<NaiveDateTime>, Option<NaiveDateTime>, String, bool, Option<String>, Option<String>)> = sqlx::query_as(
+                    r"SELECT rt.id, rt.family_id, rt.expires_at, rt.used_at, rt.revoked_at, u.username, u.has_2fa, s.client_id, s.scope
+                       FROM refresh_tokens rt JOIN users u ON u.id = rt.user_id
+                       LEFT JOIN sessions s ON s.jti = rt.family_id
+                       WHERE rt.token_hash = $1",
+                )
+                .bind(token_hash)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                Ok(row.map(|(id, family_id, expires_at, used_at, revoked_at, username, has_2fa, client_id, scope)| RefreshTokenRecord {
+                    id,
+                    family_id,
+                    expires_at: expires_at.unwrap_or_default(),
+                    used: used_at.is_some(),
+                    revoked: revoked_at.is_some(),
+                    username,
+                    has_2fa,
+                    client: client_id.map(|client_id| SessionClient { client_id, scope: scope.unwrap_or_default() }),
+                }))
+            }
+
+            async fn mark_refresh_token_used(&self, id: i64) -> Result<bool, ServiceError> {
+                let result = sqlx::query("UPDATE refresh_tokens SET used_at = $1 WHERE id = $2 AND used_at IS NULL")
+                    .bind(now())
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn insert_oauth_client(&self, client_id: &str, client_secret_hash: Option<&str>, client_name: &str, redirect_uris: &str) -> Result<(), ServiceError> {
+                sqlx::query("INSERT INTO oauth_clients (client_id, client_secret_hash, client_name, redirect_uris) VALUES ($1, $2, $3, $4)")
+                    .bind(client_id)
+                    .bind(client_secret_hash)
+                    .bind(client_name)
+                    .bind(redirect_uris)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClientRecord>, ServiceError> {
+                let row: Option<(String, Option<String>, String)> = sqlx::query_as(
+                    "SELECT client_id, client_secret_hash, redirect_uris FROM oauth_clients WHERE client_id = $1",
+                )
+                .bind(client_id)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(|(client_id, client_secret_hash, redirect_uris)| OAuthClientRecord { client_id, client_secret_hash, redirect_uris }))
+            }
+
+            async fn insert_authorization_request(&self, id: &str, request: &AuthorizationRequest, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"INSERT INTO oauth_authorization_requests (id, client_id, redirect_uri, scope, state, nonce, code_challenge, expires_at)
+                       VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                )
+                .bind(id)
+                .bind(&request.client_id)
+                .bind(&request.redirect_uri)
+                .bind(&request.scope)
+                .bind(&request.state)
+                .bind(&request.nonce)
+                .bind(&request.code_challenge)
+                .bind(expires_at)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_authorization_request(&self, id: &str) -> Result<Option<AuthorizationRequest>, ServiceError> {
+                let row: Option<(String, String, String, Option<String>, Option<String>, String, Option<String>)> = sqlx::query_as(
+                    r"SELECT ar.client_id, ar.redirect_uri, ar.scope, ar.state, ar.nonce, ar.code_challenge, u.username
+                       FROM oauth_authorization_requests ar LEFT JOIN users u ON u.id = ar.user_id
+                       WHERE ar.id = $1 AND ar.expires_at > $2",
+                )
+                .bind(id)
+                .bind(now())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                Ok(row.map(|(client_id, redirect_uri, scope, state, nonce, code_challenge, pending_username)| AuthorizationRequest {
+                    client_id,
+                    redirect_uri,
+                    scope,
+                    state,
+                    nonce,
+                    code_challenge,
+                    pending_username,
+                }))
+            }
+
+            async fn set_authorization_request_user(&self, id: &str, username: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE oauth_authorization_requests SET user_id = (SELECT id FROM users WHERE username = $1) WHERE id = $2")
+                    .bind(username)
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn delete_authorization_request(&self, id: &str) -> Result<(), ServiceError> {
+                sqlx::query("DELETE FROM oauth_authorization_requests WHERE id = $1")
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn insert_authorization_code(&self, code_hash: &str, code: &AuthorizationCode, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"INSERT INTO oauth_codes (code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time, expires_at)
+                       SELECT $1, $2, id, $3, $4, $5, $6, $7, $8 FROM users WHERE username = $9",
+                )
+                .bind(code_hash)
+                .bind(&code.client_id)
+                .bind(&code.redirect_uri)
+                .bind(&code.scope)
+                .bind(&code.nonce)
+                .bind(&code.code_challenge)
+                .bind(code.auth_time)
+                .bind(expires_at)
+                .bind(&code.username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_authorization_code(&self, code_hash: &str) -> Result<Option<(AuthorizationCode, bool)>, ServiceError> {
+                let row: Option<(String, String, String, String, Option<String>, i64, String, bool)> = sqlx::query_as(
+                    r"SELECT oc.client_id, oc.redirect_uri, oc.scope, oc.code_challenge, oc.nonce, oc.auth_time, u.username, u.has_2fa
+                       FROM oauth_codes oc JOIN users u ON u.id = oc.user_id
+                       WHERE oc.code_hash = $1 AND oc.used_at IS NULL AND oc.expires_at > $2",
+                )
+                .bind(code_hash)
+                .bind(now())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                Ok(row.map(|(client_id, redirect_uri, scope, code_challenge, nonce, auth_time, username, has_2fa)| {
+                    (AuthorizationCode { client_id, username, redirect_uri, scope, nonce, code_challenge, auth_time }, has_2fa)
+                }))
+            }
+
+            async fn mark_authorization_code_used(&self, code_hash: &str, session_jti: Option<&str>) -> Result<bool, ServiceError> {
+                let result = sqlx::query("UPDATE oauth_codes SET used_at = $1, session_jti = $2 WHERE code_hash = $3 AND used_at IS NULL")
+                    .bind(now())
+                    .bind(session_jti)
+                    .bind(code_hash)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn find_authorization_code_session(&self, code_hash: &str) -> Result<Option<String>, ServiceError> {
+                let row: Option<(Option<String>,)> = sqlx::query_as("SELECT session_jti FROM oauth_codes WHERE code_hash = $1 AND used_at IS NOT NULL")
+                    .bind(code_hash)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(row.and_then(|(jti,)| jti))
+            }
+
+            async fn insert_social_state(&self, state: &str, provider: &str, nonce: &str, code_verifier: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query("INSERT INTO social_login_states (state, provider, nonce, code_verifier, expires_at) VALUES ($1, $2, $3, $4, $5)")
+                    .bind(state)
+                    .bind(provider)
+                    .bind(nonce)
+                    .bind(code_verifier)
+                    .bind(expires_at)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn take_social_state(&self, state: &str, provider: &str) -> Result<Option<(String, String)>, ServiceError> {
+                let pending: Option<(String, String)> = sqlx::query_as(
+                    "SELECT nonce, code_verifier FROM social_login_states WHERE state = $1 AND provider = $2 AND expires_at > $3",
+                )
+                .bind(state)
+                .bind(provider)
+                .bind(now())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                sqlx::query("DELETE FROM social_login_states WHERE state = $1")
+                    .bind(state)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+
+                Ok(pending)
+            }
+
+            async fn find_social_identity(&self, provider: &str, subject: &str) -> Result<Option<String>, ServiceError> {
+                let row: Option<(String,)> = sqlx::query_as(
+                    r"SELECT u.username FROM social_identities si JOIN users u ON u.id = si.user_id
+                       WHERE si.provider = $1 AND si.subject = $2",
+                )
+                .bind(provider)
+                .bind(subject)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(|(username,)| username))
+            }
+
+            async fn link_social_identity(&self, username: &str, provider: &str, subject: &str, email: &str) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"INSERT INTO social_identities (user_id, provider, subject, email)
+                       SELECT id, $1, $2, $3 FROM users WHERE username = $4",
+                )
+                .bind(provider)
+                .bind(subject)
+                .bind(email)
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn insert_webauthn_challenge(&self, id: &str, username: Option<&str>, purpose: &str, challenge: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"INSERT INTO webauthn_challenges (id, user_id, purpose, challenge, expires_at)
+                       VALUES ($1, (SELECT id FROM users WHERE username = $2), $3, $4, $5)",
+                )
+                .bind(id)
+                .bind(username)
+                .bind(purpose)
+                .bind(challenge)
+                .bind(expires_at)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn take_webauthn_challenge(&self, id: &str, purpose: &str) -> Result<Option<(String, Option<String>)>, ServiceError> {
+                let row: Option<(String, Option<String>)> = sqlx::query_as(
+                    r"SELECT wc.challenge, u.username FROM webauthn_challenges wc LEFT JOIN users u ON u.id = wc.user_id
+                       WHERE wc.id = $1 AND wc.purpose = $2 AND wc.expires_at > $3",
+                )
+                .bind(id)
+                .bind(purpose)
+                .bind(now())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                sqlx::query("DELETE FROM webauthn_challenges WHERE id = $1")
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+
+                Ok(row)
+            }
+
+            async fn list_webauthn_credential_ids(&self, username: &str) -> Result<Vec<String>, ServiceError> {
+                let rows: Vec<(String,)> = sqlx::query_as(
+                    r"SELECT wc.credential_id FROM webauthn_credentials wc JOIN users u ON u.id = wc.user_id
+                       WHERE u.username = $1",
+                )
+                .bind(username)
+                .fetch_all(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(rows.into_iter().map(|(credential_id,)| credential_id).collect())
+            }
+
+            async fn find_webauthn_credential(&self, credential_id: &str) -> Result<Option<WebAuthnCredentialRecord>, ServiceError> {
+                let row: Option<(String, String, i64)> = sqlx::query_as(
+                    r"SELECT u.username, wc.public_key, wc.sign_count FROM webauthn_credentials wc JOIN users u ON u.id = wc.user_id
+                       WHERE wc.credential_id = $1",
+                )
+                .bind(credential_id)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(|(username, public_key, sign_count)| WebAuthnCredentialRecord { username, public_key, sign_count: sign_count as u32 }))
+            }
+
+            async fn insert_webauthn_credential(&self, credential: NewWebAuthnCredential<'_>) -> Result<bool, ServiceError> {
+                let inserted = sqlx::query(
+                    r"INSERT INTO webauthn_credentials (user_id, credential_id, public_key, alg, sign_count, name)
+                       SELECT id, $1, $2, $3, $4, $5 FROM users WHERE username = $6",
+                )
+                .bind(credential.credential_id)
+                .bind(credential.public_key)
+                .bind(credential.alg)
+                .bind(credential.sign_count as i64)
+                .bind(credential.name)
+                .bind(credential.username)
+                .execute(&self.pool)
+                .await;
+
+                match inserted {
+                    Ok(_) => Ok(true),
+                    Err(sqlx::Error::Database(ref e)) if e.is_unique_violation() => Ok(false),
+                    Err(e) => Err(db_error(e)),
+                }
+            }
+
+            async fn update_webauthn_sign_count(&self, credential_id: &str, sign_count: u32) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE webauthn_credentials SET sign_count = $1, last_used_at = $2 WHERE credential_id = $3")
+                    .bind(sign_count as i64)
+                    .bind(now())
+                    .bind(credential_id)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn replace_magic_link(&self, username: &str, token_hash: &str, fingerprint_hash: Option<&str>, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query("DELETE FROM magic_link_tokens WHERE used_at IS NULL AND user_id = (SELECT id FROM users WHERE username = $1)")
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+
+                sqlx::query(
+                    r"INSERT INTO magic_link_tokens (token_hash, user_id, fingerprint_hash, expires_at)
+                       SELECT $1, id, $2, $3 FROM users WHERE username = $4",
+                )
+                .bind(token_hash)
+                .bind(fingerprint_hash)
+                .bind(expires_at)
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_magic_link(&self, token_hash: &str) -> Result<Option<MagicLinkRecord>, ServiceError> {
+                let row: Option<(String, bool, Option<String>)> = sqlx::query_as(
+                    r"SELECT u.username, u.has_2fa, ml.fingerprint_hash FROM magic_link_tokens ml JOIN users u ON u.id = ml.user_id
+                       WHERE ml.token_hash = $1 AND ml.used_at IS NULL AND ml.expires_at > $2",
+                )
+                .bind(token_hash)
+                .bind(now())
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(|(username, has_2fa, fingerprint_hash)| MagicLinkRecord { username, has_2fa, fingerprint_hash }))
+            }
+
+            async fn mark_magic_link_used(&self, token_hash: &str) -> Result<bool, ServiceError> {
+                let result = sqlx::query("UPDATE magic_link_tokens SET used_at = $1 WHERE token_hash = $2 AND used_at IS NULL")
+                    .bind(now())
+                    .bind(token_hash)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            async fn ip_blocked_until(&self, ip: &str) -> Result<Option<NaiveDateTime>, ServiceError> {
+                let row: Option<(Option<NaiveDateTime>,)> = sqlx::query_as("SELECT blocked_until FROM ip_login_failures WHERE ip = $1")
+                    .bind(ip)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(row.and_then(|(until,)| until))
+            }
+
+            async fn account_locked_until(&self, username: &str) -> Result<Option<NaiveDateTime>, ServiceError> {
+                let row: Option<(Option<NaiveDateTime>,)> = sqlx::query_as("SELECT locked_until FROM users WHERE username = $1")
+                    .bind(username)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(row.and_then(|(until,)| until))
+            }
+
+            async fn record_ip_failure(&self, ip: &str) -> Result<i64, ServiceError> {
+                let (failures,): (i64,) = sqlx::query_as(
+                    r"INSERT INTO ip_login_failures (ip, failures, last_failure_at) VALUES ($1, 1, $2)
+                       ON CONFLICT (ip) DO UPDATE SET
+                         failures = CASE WHEN ip_login_failures.last_failure_at < $3 THEN 1 ELSE ip_login_failures.failures + 1 END,
+                         last_failure_at = $2
+                       RETURNING failures",
+                )
+                .bind(ip)
+                .bind(now())
+                .bind(now() - Duration::days(1))
+                .fetch_one(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(failures)
+            }
+
+            async fn block_ip(&self, ip: &str, until: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE ip_login_failures SET blocked_until = $1 WHERE ip = $2")
+                    .bind(until)
+                    .bind(ip)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn increment_failed_logins(&self, username: &str) -> Result<Option<(i64, String)>, ServiceError> {
+                sqlx::query_as(
+                    "UPDATE users SET failed_login_attempts = failed_login_attempts + 1 WHERE username = $1 RETURNING failed_login_attempts, email",
+                )
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)
+            }
+
+            async fn lock_account(&self, username: &str, until: NaiveDateTime, unlock_token_hash: Option<&str>) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET locked_until = $1, unlock_token_hash = COALESCE($2, unlock_token_hash) WHERE username = $3")
+                    .bind(until)
+                    .bind(unlock_token_hash)
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn reset_failed_logins(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET failed_login_attempts = 0, locked_until = NULL, unlock_token_hash = NULL WHERE username = $1")
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn unlock_account(&self, unlock_token_hash: &str) -> Result<bool, ServiceError> {
+                let result = sqlx::query(
+                    "UPDATE users SET failed_login_attempts = 0, locked_until = NULL, unlock_token_hash = NULL WHERE unlock_token_hash = $1",
+                )
+                .bind(unlock_token_hash)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(result.rows_affected() > 0)
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn increment_2fa_failures(&self, username: &str) -> Result<i64, ServiceError> {
+                let row: Option<(i64,)> = sqlx::query_as(
+                    "UPDATE users SET two_fa_failed_attempts = two_fa_failed_attempts + 1 WHERE username = $1 RETURNING two_fa_failed_attempts",
+                )
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(|(failures,)| failures).unwrap_or(0))
+            }
+
+            #[cfg(feature = "two-factor")]
+            async fn reset_2fa_challenge(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"DELETE FROM auth_tokens WHERE purpose IN ($1, $2, $3) AND consumed_at IS NULL
+                       AND user_id = (SELECT id FROM users WHERE username = $4)",
+                )
+                .bind(TokenPurpose::TwoFactorLogin.as_str())
+                .bind(TokenPurpose::TwoFactorChange.as_str())
+                .bind(TokenPurpose::PasswordChange.as_str())
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                sqlx::query("UPDATE users SET two_fa_code = NULL, temp_2fa_code = NULL, password_change_code = NULL, two_fa_failed_attempts = 0 WHERE username = $1")
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn enqueue_email(&self, recipient: &str, subject: &str, text_body: &str, html_body: &str) -> Result<i64, ServiceError> {
+                let (id,): (i64,) = sqlx::query_as(
+                    r"INSERT INTO email_outbox (recipient, subject, text_body, html_body, next_attempt_at)
+                       VALUES ($1, $2, $3, $4, $5) RETURNING id",
+                )
+                .bind(recipient)
+                .bind(subject)
+                .bind(text_body)
+                .bind(html_body)
+                .bind(now())
+                .fetch_one(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(id)
+            }
+
+            async fn claim_due_emails(&self, limit: usize, lease_until: NaiveDateTime) -> Result<Vec<OutboxEmail>, ServiceError> {
+                let claimed_at = now();
+                let rows: Vec<(i64, String, String, String, String, i32)> = sqlx::query_as(
+                    r"SELECT id, recipient, subject, text_body, html_body, attempts FROM email_outbox
+                       WHERE status = 'pending' AND next_attempt_at <= $1
+                       ORDER BY next_attempt_at LIMIT $2",
+                )
+                .bind(claimed_at)
+                .bind(limit as i64)
+                .fetch_all(&self.pool)
+                .await
+                .map_err(db_error)?;
+
+                let mut claimed = Vec::new();
+                for (id, recipient, subject, text_body, html_body, attempts) in rows {
+                    let result = sqlx::query(
+                        r"UPDATE email_outbox SET next_attempt_at = $1
+                           WHERE id = $2 AND status = 'pending' AND next_attempt_at <= $3",
+                    )
+                    .bind(lease_until)
+                    .bind(id)
+                    .bind(claimed_at)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                    if result.rows_affected() > 0 {
+                        claimed.push(OutboxEmail { id, recipient, subject, text_body, html_body, attempts: attempts as i64 });
+                    }
+                }
+                Ok(claimed)
+            }
+
+            async fn mark_email_sent(&self, id: i64) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"UPDATE email_outbox SET status = 'sent', attempts = attempts + 1, sent_at = $1,
+                           last_error = NULL, text_body = '', html_body = ''
+                       WHERE id = $2",
+                )
+                .bind(now())
+                .bind(id)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn mark_email_failed(&self, id: i64, error: &str, retry_at: Option<NaiveDateTime>) -> Result<(), ServiceError> {
+                let query = match retry_at {
+                    Some(retry_at) => sqlx::query("UPDATE email_outbox SET attempts = attempts + 1, last_error = $1, next_attempt_at = $2 WHERE id = $3")
+                        .bind(error)
+                        .bind(retry_at)
+                        .bind(id),
+                    None => sqlx::query("UPDATE email_outbox SET status = 'dead', attempts = attempts + 1, last_error = $1, text_body = '', html_body = '' WHERE id = $2")
+                        .bind(error)
+                        .bind(id),
+                };
+                query.execute(&self.pool).await.map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn find_outbox_status(&self, id: i64) -> Result<Option<OutboxStatus>, ServiceError> {
+                let row: Option<(String, i32, Option<String>)> = sqlx::query_as("SELECT status, attempts, last_error FROM email_outbox WHERE id = $1")
+                    .bind(id)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(row.map(|(status, attempts, last_error)| OutboxStatus { status, attempts: attempts as i64, last_error }))
+            }
+
+            async fn count_outbox_emails(&self) -> Result<Vec<(String, i64)>, ServiceError> {
+                sqlx::query_as("SELECT status, COUNT(*) FROM email_outbox GROUP BY status ORDER BY status")
+                    .fetch_all(&self.pool)
+                    .await
+                    .map_err(db_error)
+            }
+
+            async fn count_known_devices(&self, username: &str) -> Result<i64, ServiceError> {
+                let (count,): (i64,) = sqlx::query_as(
+                    "SELECT COUNT(*) FROM known_devices WHERE user_id = (SELECT id FROM users WHERE username = $1)",
+                )
+                .bind(username)
+                .fetch_one(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(count)
+            }
+
+            async fn remember_device(&self, username: &str, device_hash: &str) -> Result<bool, ServiceError> {
+                let inserted = sqlx::query(
+                    r"INSERT INTO known_devices (user_id, device_hash, first_seen_at, last_seen_at)
+                       SELECT id, $1, $2, $2 FROM users WHERE username = $3
+                       ON CONFLICT (user_id, device_hash) DO NOTHING",
+                )
+                .bind(device_hash)
+                .bind(now())
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                if inserted.rows_affected() > 0 {
+                    return Ok(true);
+                }
+
+                sqlx::query(
+                    r"UPDATE known_devices SET last_seen_at = $1
+                       WHERE device_hash = $2 AND user_id = (SELECT id FROM users WHERE username = $3)",
+                )
+                .bind(now())
+                .bind(device_hash)
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(false)
+            }
+
+            async fn insert_security_alert_token(&self, username: &str, token_hash: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError> {
+                sqlx::query(
+                    r"INSERT INTO security_alert_tokens (token_hash, user_id, expires_at)
+                       SELECT $1, id, $2 FROM users WHERE username = $3",
+                )
+                .bind(token_hash)
+                .bind(expires_at)
+                .bind(username)
+                .execute(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(())
+            }
+
+            async fn take_security_alert_token(&self, token_hash: &str) -> Result<Option<String>, ServiceError> {
+                let row: Option<(String,)> = sqlx::query_as(
+                    r"UPDATE security_alert_tokens SET used_at = $1
+                       WHERE token_hash = $2 AND used_at IS NULL AND expires_at > $1
+                       RETURNING (SELECT username FROM users WHERE users.id = security_alert_tokens.user_id)",
+                )
+                .bind(now())
+                .bind(token_hash)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(db_error)?;
+                Ok(row.map(|(username,)| username))
+            }
+
+            #[cfg(feature = "password-reset")]
+            async fn require_password_reset(&self, username: &str) -> Result<(), ServiceError> {
+                sqlx::query("UPDATE users SET password_reset_required = TRUE WHERE username = $1")
+                    .bind(username)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(db_error)?;
+                Ok(())
+            }
+        }
+    };
+}
+
+sqlx_user_store!(PostgresStore, PgPool, Dialect::Postgres);
+sqlx_user_store!(SqliteStore, SqlitePool, Dialect::Sqlite);
+
+impl PostgresStore {
+    pub async fn connect(database_url: &str, database_name: Option<&str>) -> Result<Self, ServiceError> {
+        let mut options = PgConnectOptions::from_str(database_url).map_err(|e| {
+            error!("Failed to parse database URL: {:?}", e);
+            ServiceError::InternalServerError
+        })?;
+        if let Some(db_name) = database_name {
+            options = options.database(db_name);
+        }
+
+        let pool = PgPoolOptions::new().connect_with(options).await.map_err(|e| {
+            error!("Failed to connect to PostgreSQL: {:?}", e);
+            ServiceError::InternalServerError
+        })?;
+        Ok(PostgresStore { pool })
+    }
+}
+
+impl SqliteStore {
+    pub async fn connect(database_url: &str) -> Result<Self, ServiceError> {
+        let options = SqliteConnectOptions::from_str(database_url)
+            .map_err(|e| {
+                error!("Failed to parse database URL: {:?}", e);
+                ServiceError::InternalServerError
+            })?
+            .create_if_missing(true);
+
+        // Every connection to an in-memory database gets its own empty database, so keep exactly one open
+        let pool_options = if database_url.contains(":memory:") || database_url.contains("mode=memory") {
+            SqlitePoolOptions::new().max_connections(1).idle_timeout(None).max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new()
+        };
+
+        let pool = pool_options.connect_with(options).await.map_err(|e| {
+            error!("Failed to open SQLite database: {:?}", e);
+            ServiceError::InternalServerError
+        })?;
+        Ok(SqliteStore { pool })
+    }
+}
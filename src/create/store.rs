@@ -0,0 +1,379 @@
+// store.rs
+
+use crate::create::common::*;
+use crate::create::migrations::{Dialect, Migration};
+use crate::create::mysqlstore::MySqlStore;
+use crate::create::sqlxstore::{PostgresStore, SqliteStore};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+pub struct User {
+    pub username: String,
+    pub email: String,
+    pub password_hash: String,
+    pub verified: bool,
+    pub has_2fa: bool,
+    // Rows carry every column; builds without the feature never read this one
+    #[cfg_attr(not(feature = "two-factor"), allow(dead_code))]
+    pub two_fa_method: String,
+    pub locale: Option<String>,
+    pub password_reset_required: bool,
+}
+
+pub struct NewUser<'a> {
+    pub username: &'a str,
+    pub email: &'a str,
+    pub password_hash: &'a str,
+    pub verified: bool,
+    pub locale: Option<&'a str>,
+}
+
+// What an auth_tokens row was issued for; each purpose has its own TTL. The variants follow the
+// compiled features, which can leave only the Email ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+pub enum TokenPurpose {
+    #[cfg(any(feature = "registration", feature = "email-verification"))]
+    EmailVerification,
+    #[cfg(feature = "password-reset")]
+    PasswordReset,
+    #[cfg(feature = "two-factor")]
+    TwoFactorLogin,
+    #[cfg(feature = "two-factor")]
+    TwoFactorChange,
+    #[cfg(feature = "two-factor")]
+    PasswordChange,
+    #[cfg(feature = "two-factor")]
+    TotpEnrollment,
+    EmailChange,
+    EmailChangeCancel,
+}
+
+impl TokenPurpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            #[cfg(any(feature = "registration", feature = "email-verification"))]
+            TokenPurpose::EmailVerification => "email_verification",
+            #[cfg(feature = "password-reset")]
+            TokenPurpose::PasswordReset => "password_reset",
+            #[cfg(feature = "two-factor")]
+            TokenPurpose::TwoFactorLogin => "two_factor_login",
+            #[cfg(feature = "two-factor")]
+            TokenPurpose::TwoFactorChange => "two_factor_change",
+            #[cfg(feature = "two-factor")]
+            TokenPurpose::PasswordChange => "password_change",
+            #[cfg(feature = "two-factor")]
+            TokenPurpose::TotpEnrollment => "totp_enrollment",
+            TokenPurpose::EmailChange => "email_change",
+            TokenPurpose::EmailChangeCancel => "email_change_cancel",
+        }
+    }
+}
+
+// Only the SHA-256 digest of a token is stored
+pub struct AuthToken {
+    pub id: i64,
+    pub username: String,
+    #[cfg_attr(not(any(feature = "two-factor", feature = "password-reset")), allow(dead_code))]
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub consumed_at: Option<NaiveDateTime>,
+}
+
+// The user waiting on a second factor at login
+    #[cfg(feature = "two-factor")]
+pub struct PendingTwoFactor {
+    pub username: String,
+    pub has_2fa: bool,
+    pub two_fa_method: String,
+    pub code: Option<String>,
+    pub code_expiry: Option<NaiveDateTime>,
+}
+
+pub struct RefreshTokenRecord {
+    pub id: i64,
+    pub family_id: String,
+    pub expires_at: NaiveDateTime,
+    pub used: bool,
+    pub revoked: bool,
+    pub username: String,
+    pub has_2fa: bool,
+    // The OAuth client the session was opened for, None for the API's own logins
+    pub client: Option<SessionClient>,
+}
+
+// An OAuth client session and the scopes it was granted
+#[derive(Clone)]
+pub struct SessionClient {
+    pub client_id: String,
+    pub scope: String,
+}
+
+pub struct OAuthClientRecord {
+    pub client_id: String,
+    pub client_secret_hash: Option<String>,
+    pub redirect_uris: String,
+}
+
+pub struct AuthorizationRequest {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    #[cfg_attr(not(feature = "two-factor"), allow(dead_code))]
+    pub pending_username: Option<String>,
+}
+
+pub struct AuthorizationCode {
+    pub client_id: String,
+    pub username: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub auth_time: i64,
+}
+
+pub struct WebAuthnCredentialRecord {
+    pub username: String,
+    pub public_key: String,
+    pub sign_count: u32,
+}
+
+pub struct NewWebAuthnCredential<'a> {
+    pub username: &'a str,
+    pub credential_id: &'a str,
+    pub public_key: &'a str,
+    pub alg: i64,
+    pub sign_count: u32,
+    pub name: Option<&'a str>,
+}
+
+pub struct MagicLinkRecord {
+    pub username: String,
+    pub has_2fa: bool,
+    pub fingerprint_hash: Option<String>,
+}
+
+pub struct OutboxEmail {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub text_body: String,
+    pub html_body: String,
+    pub attempts: i64,
+}
+
+// pending, sent or dead
+pub struct OutboxStatus {
+    pub status: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+}
+
+// Every read and write of user and token data goes through here; handlers never see SQL
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    // schema migrations, driven by the migrations module
+    fn dialect(&self) -> Dialect;
+    // Creates schema_migrations when missing
+    async fn applied_migrations(&self) -> Result<Vec<i64>, ServiceError>;
+    async fn apply_migration(&self, migration: &Migration) -> Result<(), ServiceError>;
+    async fn revert_migration(&self, migration: &Migration) -> Result<(), ServiceError>;
+
+    // users
+    async fn create_user(&self, user: NewUser<'_>) -> Result<(), ServiceError>;
+    async fn find_user(&self, username: &str) -> Result<Option<User>, ServiceError>;
+    async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, ServiceError>;
+
+    // verification, reset and 2FA tokens, driven by the authtokens module
+    // Replaces the unconsumed tokens of the user with the same purpose
+    async fn insert_auth_token(&self, username: &str, purpose: TokenPurpose, token_hash: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    // Consumed and expired tokens are returned too, so callers can tell them apart
+    async fn find_auth_token(&self, purpose: TokenPurpose, token_hash: &str) -> Result<Option<AuthToken>, ServiceError>;
+    // The latest unconsumed token of the user
+    #[cfg(any(feature = "two-factor", feature = "password-reset"))]
+    async fn find_user_auth_token(&self, username: &str, purpose: TokenPurpose) -> Result<Option<AuthToken>, ServiceError>;
+    // False when a concurrent request consumed it first
+    async fn consume_auth_token(&self, id: i64) -> Result<bool, ServiceError>;
+
+    // email verification
+    async fn mark_email_verified(&self, username: &str) -> Result<(), ServiceError>;
+
+    // password reset
+    // Also clears any login lockout and a required reset; returns the username
+    async fn reset_password(&self, email: &str, password_hash: &str) -> Result<Option<String>, ServiceError>;
+    // Only replaces current_hash, so a password changed in the meantime is kept; false in that case
+    async fn update_password_hash(&self, username: &str, current_hash: &str, new_hash: &str) -> Result<bool, ServiceError>;
+
+    // email change
+    async fn start_email_change(&self, username: &str, new_email: &str) -> Result<(), ServiceError>;
+    async fn find_email_change(&self, username: &str) -> Result<Option<String>, ServiceError>;
+    // False when another account holds the address by now, the UNIQUE constraint on users.email decides
+    async fn apply_email_change(&self, username: &str, new_email: &str) -> Result<bool, ServiceError>;
+    // Drops the pending address and its unused confirmation and cancel links
+    async fn clear_email_change(&self, username: &str) -> Result<(), ServiceError>;
+
+    // second factor at login
+    #[cfg(feature = "two-factor")]
+    async fn set_2fa_code(&self, username: &str, code: &str, expiry: NaiveDateTime) -> Result<(), ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn find_pending_2fa(&self, username: &str) -> Result<Option<PendingTwoFactor>, ServiceError>;
+
+    // 2FA activation and deactivation
+    #[cfg(feature = "two-factor")]
+    async fn start_2fa_change(&self, username: &str, code: &str) -> Result<(), ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn find_2fa_change(&self, username: &str) -> Result<Option<String>, ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn enable_email_2fa(&self, username: &str) -> Result<(), ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn disable_2fa(&self, username: &str) -> Result<(), ServiceError>;
+    async fn count_2fa_users(&self) -> Result<i64, ServiceError>;
+
+    // The emailed code confirming a password change
+    #[cfg(feature = "two-factor")]
+    async fn start_password_change(&self, username: &str, code: &str) -> Result<(), ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn find_password_change(&self, username: &str) -> Result<Option<String>, ServiceError>;
+
+    // TOTP
+    #[cfg(feature = "two-factor")]
+    async fn start_totp_enrollment(&self, username: &str, encrypted_secret: &str) -> Result<(), ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn find_totp_enrollment(&self, username: &str) -> Result<Option<String>, ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn enable_totp(&self, username: &str, last_step: i64) -> Result<(), ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn find_totp_secret(&self, username: &str) -> Result<Option<(Option<String>, Option<i64>)>, ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn set_totp_last_step(&self, username: &str, last_step: i64) -> Result<(), ServiceError>;
+
+    // recovery codes
+    #[cfg(feature = "two-factor")]
+    async fn replace_recovery_codes(&self, username: &str, code_hashes: &[String]) -> Result<(), ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn delete_recovery_codes(&self, username: &str) -> Result<(), ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn consume_recovery_code(&self, username: &str, code_hash: &str) -> Result<bool, ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn count_recovery_codes(&self, username: &str) -> Result<usize, ServiceError>;
+
+    // sessions and refresh tokens
+    async fn create_session(&self, jti: &str, username: &str, client: Option<&SessionClient>, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    async fn is_session_active(&self, jti: &str) -> Result<bool, ServiceError>;
+    async fn extend_session(&self, jti: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    // Revokes the session and its refresh token family
+    async fn revoke_session(&self, jti: &str) -> Result<(), ServiceError>;
+    async fn revoke_all_sessions(&self, username: &str) -> Result<(), ServiceError>;
+    async fn revoke_other_sessions(&self, username: &str, keep_jti: &str) -> Result<(), ServiceError>;
+    async fn insert_refresh_token(&self, username: &str, token_hash: &str, family_id: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    async fn find_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRecord>, ServiceError>;
+    // False when a concurrent request used it first
+    async fn mark_refresh_token_used(&self, id: i64) -> Result<bool, ServiceError>;
+
+    // OpenID Connect provider
+    async fn insert_oauth_client(&self, client_id: &str, client_secret_hash: Option<&str>, client_name: &str, redirect_uris: &str) -> Result<(), ServiceError>;
+    async fn find_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClientRecord>, ServiceError>;
+    async fn insert_authorization_request(&self, id: &str, request: &AuthorizationRequest, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    async fn find_authorization_request(&self, id: &str) -> Result<Option<AuthorizationRequest>, ServiceError>;
+    async fn set_authorization_request_user(&self, id: &str, username: &str) -> Result<(), ServiceError>;
+    async fn delete_authorization_request(&self, id: &str) -> Result<(), ServiceError>;
+    async fn insert_authorization_code(&self, code_hash: &str, code: &AuthorizationCode, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    // Returns the code with the has_2fa flag of its user
+    async fn find_authorization_code(&self, code_hash: &str) -> Result<Option<(AuthorizationCode, bool)>, ServiceError>;
+    // Records the session the code was exchanged for, if any; false when the code was already used
+    async fn mark_authorization_code_used(&self, code_hash: &str, session_jti: Option<&str>) -> Result<bool, ServiceError>;
+    // The session issued for a code that was already used
+    async fn find_authorization_code_session(&self, code_hash: &str) -> Result<Option<String>, ServiceError>;
+
+    // social login
+    async fn insert_social_state(&self, state: &str, provider: &str, nonce: &str, code_verifier: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    // Single use: the state is deleted whether or not it was still valid
+    async fn take_social_state(&self, state: &str, provider: &str) -> Result<Option<(String, String)>, ServiceError>;
+    async fn find_social_identity(&self, provider: &str, subject: &str) -> Result<Option<String>, ServiceError>;
+    async fn link_social_identity(&self, username: &str, provider: &str, subject: &str, email: &str) -> Result<(), ServiceError>;
+
+    // WebAuthn
+    async fn insert_webauthn_challenge(&self, id: &str, username: Option<&str>, purpose: &str, challenge: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    // Single use: returns the challenge and the user it was issued for
+    async fn take_webauthn_challenge(&self, id: &str, purpose: &str) -> Result<Option<(String, Option<String>)>, ServiceError>;
+    async fn list_webauthn_credential_ids(&self, username: &str) -> Result<Vec<String>, ServiceError>;
+    async fn find_webauthn_credential(&self, credential_id: &str) -> Result<Option<WebAuthnCredentialRecord>, ServiceError>;
+    // False when the credential is already registered
+    async fn insert_webauthn_credential(&self, credential: NewWebAuthnCredential<'_>) -> Result<bool, ServiceError>;
+    async fn update_webauthn_sign_count(&self, credential_id: &str, sign_count: u32) -> Result<(), ServiceError>;
+
+    // magic links
+    // Replaces any unused link of the user
+    async fn replace_magic_link(&self, username: &str, token_hash: &str, fingerprint_hash: Option<&str>, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    async fn find_magic_link(&self, token_hash: &str) -> Result<Option<MagicLinkRecord>, ServiceError>;
+    async fn mark_magic_link_used(&self, token_hash: &str) -> Result<bool, ServiceError>;
+
+    // brute-force protection
+    async fn ip_blocked_until(&self, ip: &str) -> Result<Option<NaiveDateTime>, ServiceError>;
+    async fn account_locked_until(&self, username: &str) -> Result<Option<NaiveDateTime>, ServiceError>;
+    // Failures older than a day are forgotten; returns the current count
+    async fn record_ip_failure(&self, ip: &str) -> Result<i64, ServiceError>;
+    async fn block_ip(&self, ip: &str, until: NaiveDateTime) -> Result<(), ServiceError>;
+    // Returns the new count and the email of the account, if it exists
+    async fn increment_failed_logins(&self, username: &str) -> Result<Option<(i64, String)>, ServiceError>;
+    async fn lock_account(&self, username: &str, until: NaiveDateTime, unlock_token_hash: Option<&str>) -> Result<(), ServiceError>;
+    async fn reset_failed_logins(&self, username: &str) -> Result<(), ServiceError>;
+    async fn unlock_account(&self, unlock_token_hash: &str) -> Result<bool, ServiceError>;
+    #[cfg(feature = "two-factor")]
+    async fn increment_2fa_failures(&self, username: &str) -> Result<i64, ServiceError>;
+    // Drops the pending 2FA login, 2FA change and password change tokens, the emailed codes and the failure count so the user has to start over
+    #[cfg(feature = "two-factor")]
+    async fn reset_2fa_challenge(&self, username: &str) -> Result<(), ServiceError>;
+
+    // email outbox, drained by the outbox worker
+    async fn enqueue_email(&self, recipient: &str, subject: &str, text_body: &str, html_body: &str) -> Result<i64, ServiceError>;
+    // Due messages are leased until `lease_until` so a slow delivery isn't picked up twice
+    async fn claim_due_emails(&self, limit: usize, lease_until: NaiveDateTime) -> Result<Vec<OutboxEmail>, ServiceError>;
+    // Also drops the bodies, which may hold codes and links
+    async fn mark_email_sent(&self, id: i64) -> Result<(), ServiceError>;
+    // Reschedules at `retry_at`, or dead-letters the message and drops its bodies when it is None
+    async fn mark_email_failed(&self, id: i64, error: &str, retry_at: Option<NaiveDateTime>) -> Result<(), ServiceError>;
+    async fn find_outbox_status(&self, id: i64) -> Result<Option<OutboxStatus>, ServiceError>;
+    async fn count_outbox_emails(&self) -> Result<Vec<(String, i64)>, ServiceError>;
+
+    // security notifications
+    async fn count_known_devices(&self, username: &str) -> Result<i64, ServiceError>;
+    // True when the device was not known yet
+    async fn remember_device(&self, username: &str, device_hash: &str) -> Result<bool, ServiceError>;
+    async fn insert_security_alert_token(&self, username: &str, token_hash: &str, expires_at: NaiveDateTime) -> Result<(), ServiceError>;
+    // Single use; returns the user the alert was sent to
+    async fn take_security_alert_token(&self, token_hash: &str) -> Result<Option<String>, ServiceError>;
+    // Password logins are refused until reset_password
+    #[cfg(feature = "password-reset")]
+    async fn require_password_reset(&self, username: &str) -> Result<(), ServiceError>;
+}
+
+// mysql://, postgres:// and sqlite: URLs pick the backend; `database_name` overrides the database of the URL
+pub async fn connect(database_url: &str, database_name: Option<&str>) -> Result<Arc<dyn UserStore>, ServiceError> {
+    let store: Arc<dyn UserStore> = match database_url.split(':').next().unwrap_or_default() {
+        "mysql" => Arc::new(MySqlStore::connect(database_url, database_name).await?),
+        "postgres" | "postgresql" => Arc::new(PostgresStore::connect(database_url, database_name).await?),
+        "sqlite" => Arc::new(SqliteStore::connect(database_url).await?),
+        scheme => {
+            error!("Unsupported DATABASE_URL scheme: {}", scheme);
+            return Err(ServiceError::InternalServerError);
+        },
+    };
+
+    Ok(store)
+}
+
+pub const SCHEMA_MIGRATIONS_TABLE: &str = r"CREATE TABLE IF NOT EXISTS schema_migrations (
+    version BIGINT PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+)";
+
+pub fn db_error(e: impl std::fmt::Debug) -> ServiceError {
+    error!("Error executing DB query: {:?}", e);
+    ServiceError::InternalServerError
+}
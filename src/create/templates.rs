@@ -0,0 +1,192 @@
+// templates.rs
+
+use crate::create::common::*;
+use crate::create::config::TemplatesConfig;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Every email the API sends; the default locale must provide all of them
+pub const TEMPLATE_NAMES: &[&str] = &[
+    "verification",
+    "password_reset",
+    "2fa_code",
+    "2fa_activation",
+    "2fa_deactivation",
+    "magic_link",
+    "account_locked",
+    "password_changed",
+    "2fa_disabled",
+    "new_device_login",
+    "email_change_confirm",
+    "email_change_notice",
+];
+
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+// EMAIL_TEMPLATE_DIR/<locale>/<name>.{subject,txt,html} plus layout.{txt,html}, loaded once at startup.
+// A locale may leave out files, they fall back to the default locale.
+pub struct EmailTemplates {
+    locales: HashMap<String, HashMap<String, String>>,
+    default_locale: String,
+    product_name: String,
+    logo_url: Option<String>,
+}
+
+impl EmailTemplates {
+    pub fn from_config(config: &TemplatesConfig) -> Result<Self, String> {
+        Self::load(Path::new(&config.dir), &config.default_locale, config.product_name.clone(), config.logo_url.clone())
+    }
+
+    pub fn load(dir: &Path, default_locale: &str, product_name: String, logo_url: Option<String>) -> Result<Self, String> {
+        let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+        let mut locales = HashMap::new();
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let locale = path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_lowercase();
+
+            let mut files = HashMap::new();
+            for file in std::fs::read_dir(&path).map_err(|e| format!("{}: {}", path.display(), e))? {
+                let file = file.map_err(|e| e.to_string())?.path();
+                if let Some(name) = file.file_name().and_then(|name| name.to_str()) {
+                    let content = std::fs::read_to_string(&file).map_err(|e| format!("{}: {}", file.display(), e))?;
+                    files.insert(name.to_string(), content);
+                }
+            }
+            locales.insert(locale, files);
+        }
+
+        let default_files = locales
+            .get(default_locale)
+            .ok_or_else(|| format!("no templates for the default locale {} in {}", default_locale, dir.display()))?;
+        for name in TEMPLATE_NAMES.iter().map(|name| name.to_string()).chain(["layout".to_string()]) {
+            let extensions: &[&str] = if name == "layout" { &["txt", "html"] } else { &["subject", "txt", "html"] };
+            for extension in extensions {
+                let file = format!("{}.{}", name, extension);
+                if !default_files.contains_key(&file) {
+                    return Err(format!("{}/{} is missing", default_locale, file));
+                }
+            }
+        }
+
+        Ok(EmailTemplates { locales, default_locale: default_locale.to_string(), product_name, logo_url })
+    }
+
+    // The stored locale of the user if we have templates for it, else the best match of Accept-Language
+    pub fn locale(&self, stored: Option<&str>, req: Option<&HttpRequest>) -> String {
+        if let Some(locale) = stored.and_then(|stored| self.supported(stored)) {
+            return locale;
+        }
+
+        let accept_language = req
+            .and_then(|req| req.headers().get(http::header::ACCEPT_LANGUAGE))
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.trim().split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && tag != "*" && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .into_iter()
+            .find_map(|(tag, _)| self.supported(tag))
+            .unwrap_or_else(|| self.default_locale.clone())
+    }
+
+    // "fr-CA" falls back to "fr"
+    fn supported(&self, tag: &str) -> Option<String> {
+        let tag = tag.to_lowercase();
+        let primary = tag.split('-').next().unwrap_or_default().to_string();
+        [tag, primary].into_iter().find(|candidate| self.locales.contains_key(candidate))
+    }
+
+    fn file(&self, locale: &str, file: &str) -> &str {
+        self.locales
+            .get(locale)
+            .and_then(|files| files.get(file))
+            .or_else(|| self.locales.get(&self.default_locale).and_then(|files| files.get(file)))
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    pub fn render(&self, name: &str, locale: &str, vars: &[(&str, &str)]) -> Result<RenderedEmail, ServiceError> {
+        let logo = match &self.logo_url {
+            Some(url) => format!("<img src=\"{}\" alt=\"{}\" height=\"40\" style=\"display:block;margin-bottom:16px;\">", escape_html(url), escape_html(&self.product_name)),
+            None => String::new(),
+        };
+        let mut vars: Vec<(&str, &str)> = vars.to_vec();
+        vars.push(("product_name", &self.product_name));
+
+        let subject = substitute(self.file(locale, &format!("{}.subject", name)).trim(), &vars, false)?;
+        let text = substitute(self.file(locale, &format!("{}.txt", name)).trim_end(), &vars, false)?;
+        let html = substitute(self.file(locale, &format!("{}.html", name)), &vars, true)?;
+
+        vars.push(("logo", &logo));
+        vars.push(("content", &text));
+        let text = substitute(self.file(locale, "layout.txt"), &vars, false)?;
+        vars.pop();
+        vars.push(("content", &html));
+        let html = substitute(self.file(locale, "layout.html"), &vars, true)?;
+
+        Ok(RenderedEmail { subject, text, html })
+    }
+}
+
+// These carry markup we built ourselves and are inserted as is
+const RAW_VARIABLES: &[&str] = &["logo", "content"];
+
+// Replaces {{name}}; an unknown name is a template bug, not something to send
+fn substitute(template: &str, vars: &[(&str, &str)], html: bool) -> Result<String, ServiceError> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let end = rest[start..].find("}}").ok_or_else(|| {
+            error!("Unclosed placeholder in email template");
+            ServiceError::InternalServerError
+        })? + start;
+
+        let name = rest[start + 2..end].trim();
+        let value = vars.iter().rev().find(|(var, _)| *var == name).map(|(_, value)| *value).ok_or_else(|| {
+            error!("Email template uses unknown variable {}", name);
+            ServiceError::InternalServerError
+        })?;
+
+        if html && !RAW_VARIABLES.contains(&name) {
+            output.push_str(&escape_html(value));
+        } else {
+            output.push_str(value);
+        }
+        rest = &rest[end + 2..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
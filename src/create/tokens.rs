@@ -0,0 +1,118 @@
+// tokens.rs
+
+use crate::create::common::*;
+use crate::create::sessions;
+use crate::create::jwks;
+use crate::create::store::SessionClient;
+use sha2::{Digest, Sha256};
+
+const REFRESH_TOKEN_LEN: usize = 48;
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn generate_access_token(
+    config: &AppConfig,
+    username: &str,
+    has_2fa: bool,
+    jti: &str,
+    client: Option<&SessionClient>,
+) -> Result<String, ServiceError> {
+    let expiration = Utc::now()
+        .checked_add_signed(config.tokens.access_token_ttl())
+        .ok_or_else(|| {
+            error!("Failed to calculate JWT expiration");
+            ServiceError::InternalServerError
+        })?
+        .timestamp() as usize;
+
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: expiration,
+        has_2fa,
+        jti: jti.to_string(),
+        aud: client.map(|client| client.client_id.clone()),
+        scope: client.map(|client| client.scope.clone()),
+    };
+
+    jwks::sign(&config.jwt, &claims)
+}
+
+// Stores only the SHA-256 digest; the plaintext is handed to the client once
+pub async fn issue_refresh_token(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    family_id: &str,
+) -> Result<String, ServiceError> {
+    let refresh_token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(REFRESH_TOKEN_LEN)
+        .map(char::from)
+        .collect();
+
+    let expiry = Utc::now()
+        .checked_add_signed(config.tokens.refresh_token_ttl())
+        .ok_or_else(|| {
+            error!("Failed to calculate refresh token expiry");
+            ServiceError::InternalServerError
+        })?;
+
+    store.insert_refresh_token(username, &hash_token(&refresh_token), family_id, expiry.naive_utc()).await?;
+
+    Ok(refresh_token)
+}
+
+// Opens a new session: access token plus a refresh token starting a new rotation family
+pub async fn issue_token_pair(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    has_2fa: bool,
+) -> Result<(String, String), ServiceError> {
+    let (access_token, refresh_token, _) = open_session(store, config, username, has_2fa, None).await?;
+    Ok((access_token, refresh_token))
+}
+
+// Same for an OAuth client; its tokens only work at /userinfo and at /token for that client.
+// Also returns the session id, which /token records on the authorization code
+pub async fn issue_client_token_pair(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    has_2fa: bool,
+    client: &SessionClient,
+) -> Result<(String, String, String), ServiceError> {
+    open_session(store, config, username, has_2fa, Some(client)).await
+}
+
+// Every way of logging in ends here, so an account flagged by "this wasn't me" stays shut until the password is reset
+async fn open_session(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    has_2fa: bool,
+    client: Option<&SessionClient>,
+) -> Result<(String, String, String), ServiceError> {
+    let user = store.find_user(username).await?.ok_or(ServiceError::BadRequest("User not found".to_string()))?;
+    if user.password_reset_required {
+        error!("Login refused until password reset for user: {}", username);
+        return Err(ServiceError::Unauthorized("This account needs a new password. Check your email for the reset link.".to_string()));
+    }
+
+    let jti = sessions::create_session(store, config, username, client).await?;
+    let access_token = generate_access_token(config, username, has_2fa, &jti, client)?;
+    let refresh_token = issue_refresh_token(store, config, username, &jti).await?;
+
+    Ok((access_token, refresh_token, jti))
+}
+
+pub fn token_pair_response(config: &AppConfig, access_token: &str, refresh_token: &str) -> serde_json::Value {
+    json!({
+        "status": "success",
+        "token": access_token,
+        "refresh_token": refresh_token,
+        "expires_in": config.tokens.access_token_ttl().num_seconds()
+    })
+}
@@ -0,0 +1,91 @@
+// totp.rs
+
+use crate::create::common::*;
+use crate::create::config::TotpConfig;
+use crate::create::crypto;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+const TOTP_DIGITS: u32 = 6;
+const TOTP_PERIOD: i64 = 30;
+const SECRET_LEN: usize = 20;
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    rand::thread_rng().fill(&mut secret[..]);
+    secret
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+pub fn provisioning_uri(config: &TotpConfig, secret: &[u8], account: &str) -> String {
+    let issuer = &config.issuer;
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        urlencoding::encode(issuer),
+        urlencoding::encode(account),
+        encode_secret(secret),
+        urlencoding::encode(issuer),
+        TOTP_DIGITS,
+        TOTP_PERIOD,
+    )
+}
+
+// RFC 4226 HOTP with dynamic truncation, used by TOTP with counter = time step
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = ((digest[offset] as u32 & 0x7f) << 24)
+        | ((digest[offset + 1] as u32) << 16)
+        | ((digest[offset + 2] as u32) << 8)
+        | (digest[offset + 3] as u32);
+
+    format!("{:0width$}", binary % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+// Returns the matched time step so callers can reject replays of the same code
+pub fn verify_code(config: &TotpConfig, secret: &[u8], code: &str, last_used_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let current_step = Utc::now().timestamp() / TOTP_PERIOD;
+    (current_step - config.skew_back..=current_step + config.skew_forward)
+        .filter(|step| *step >= 0 && last_used_step.is_none_or(|last| *step > last))
+        .find(|step| hotp(secret, *step as u64) == code)
+}
+
+pub fn encrypt_secret(config: &TotpConfig, secret: &[u8]) -> Result<String, ServiceError> {
+    crypto::encrypt(&config.encryption_key, secret)
+}
+
+pub fn decrypt_secret(config: &TotpConfig, stored: &str) -> Result<Vec<u8>, ServiceError> {
+    crypto::decrypt(&config.encryption_key, stored).inspect_err(|_| error!("Failed to decrypt TOTP secret"))
+}
+
+pub async fn verify_user_code(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    username: &str,
+    code: &str,
+) -> Result<(), ServiceError> {
+    let row = store.find_totp_secret(username).await?;
+
+    let (stored_secret, last_step) = match row {
+        Some((Some(stored_secret), last_step)) => (stored_secret, last_step),
+        _ => return Err(ServiceError::BadRequest("TOTP is not configured for this account.".to_string())),
+    };
+
+    let secret = decrypt_secret(&config.totp, &stored_secret)?;
+    let step = verify_code(&config.totp, &secret, code, last_step)
+        .ok_or(ServiceError::BadRequest("Invalid 2FA code.".to_string()))?;
+
+    store.set_totp_last_step(username, step).await
+}
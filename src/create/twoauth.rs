@@ -1,6 +1,13 @@
 // twoauth.rs
 
 use crate::create::common::*;
+use crate::create::authtokens;
+use crate::create::store::{AuthToken, TokenPurpose};
+use crate::create::lockout;
+use crate::create::notifications;
+use crate::create::totp;
+use crate::create::recoverycodes;
+use crate::create::tokens;
 
 pub fn generate_2fa_code() -> String {
     let code: String = rand::thread_rng()
@@ -11,74 +18,125 @@ pub fn generate_2fa_code() -> String {
     code
 }
 
+// The user behind a temp_token that is still usable
+pub async fn pending_login(store: &dyn UserStore, temp_token: &str) -> Result<AuthToken, ServiceError> {
+    let token = authtokens::find(store, TokenPurpose::TwoFactorLogin, temp_token)
+        .await?
+        .filter(|token| token.consumed_at.is_none())
+        .ok_or(ServiceError::BadRequest("Invalid temporary token.".to_string()))?;
+    if authtokens::is_expired(&token) {
+        return Err(ServiceError::BadRequest("Temporary token has expired.".to_string()));
+    }
+    Ok(token)
+}
+
+// Burns the temp_token; a request that lost the race to another one with the same token fails here
+pub async fn finish_login(store: &dyn UserStore, token: &AuthToken) -> Result<(), ServiceError> {
+    if !authtokens::consume(store, token).await? {
+        return Err(ServiceError::BadRequest("Invalid temporary token.".to_string()));
+    }
+    store.reset_2fa_challenge(&token.username).await?;
+    info!("Invalidated temp_token of user {}", token.username);
+    Ok(())
+}
+
+// The code and token of a pending 2FA activation or deactivation; burns the token when both match
+pub async fn confirm_2fa_change(
+    store: &dyn UserStore,
+    info: &TwoFAVerificationRequest,
+    no_pending_message: &str,
+) -> Result<(), ServiceError> {
+    let token = store
+        .find_user_auth_token(&info.username, TokenPurpose::TwoFactorChange)
+        .await?
+        .ok_or(ServiceError::BadRequest(no_pending_message.to_string()))?;
+    let code = store.find_2fa_change(&info.username).await?;
+
+    if !authtokens::matches(&token, &info.token) || !authtokens::same_secret(code.as_deref(), &info.code) {
+        return Err(ServiceError::BadRequest("Invalid code or token".to_string()));
+    }
+    if authtokens::is_expired(&token) {
+        return Err(ServiceError::BadRequest("Token has expired".to_string()));
+    }
+    if !authtokens::consume(store, &token).await? {
+        return Err(ServiceError::BadRequest("Invalid code or token".to_string()));
+    }
+    Ok(())
+}
+
+pub struct TwoFactorOutcome {
+    pub username: String,
+    pub has_2fa: bool,
+    pub recovery_codes_remaining: Option<usize>,
+}
+
+// Checks the second factor behind a temp_token and burns the temp_token on success
+pub async fn check_2fa(
+    store: &dyn UserStore,
+    config: &AppConfig,
+    info: &Verify2FARequest,
+) -> Result<TwoFactorOutcome, ServiceError> {
+    let token = pending_login(store, &info.temp_token).await?;
+    let pending = store
+        .find_pending_2fa(&token.username)
+        .await?
+        .ok_or(ServiceError::BadRequest("Invalid temporary token.".to_string()))?;
+
+    let username = pending.username;
+    let method = pending.two_fa_method;
+
+    let verification = if let Some(recovery_code) = &info.recovery_code {
+        recoverycodes::consume_recovery_code(store, config, &username, recovery_code).await.map(Some)
+    } else if method == "totp" {
+        totp::verify_user_code(store, config, &username, &info.code).await.map(|_| None)
+    } else {
+        if !authtokens::same_secret(pending.code.as_deref(), &info.code) {
+            Err(ServiceError::BadRequest("Invalid 2FA code.".to_string()))
+        } else {
+            let expiry = pending.code_expiry.ok_or(ServiceError::InternalServerError)?;
+            if Utc::now().naive_utc() > expiry {
+                return Err(ServiceError::BadRequest("2FA code has expired.".to_string()));
+            }
+            Ok(None)
+        }
+    };
+
+    // Wrong codes count towards login.two_fa_max_attempts
+    let recovery_codes_remaining = match verification {
+        Ok(remaining) => remaining,
+        Err(ServiceError::BadRequest(message)) => {
+            lockout::record_2fa_failure(store, config, &username).await?;
+            return Err(ServiceError::BadRequest(message));
+        },
+        Err(e) => return Err(e),
+    };
+
+    let has_2fa = pending.has_2fa;
+
+    finish_login(store, &token).await?;
+
+    Ok(TwoFactorOutcome { username, has_2fa, recovery_codes_remaining })
+}
+
 #[post("/verify_2fa")]
 async fn verify_2fa(
-    pool: Data<Pool>,
+    store: Data<dyn UserStore>,
+    emails: Data<Emails>,
+    config: Data<AppConfig>,
+    req: HttpRequest,
     info: web::Json<Verify2FARequest>,
 ) -> Result<HttpResponse, ServiceError> {
-    let mut conn = pool.get_conn().await.map_err(|e| {
-        error!("Error getting DB connection: {:?}", e);
-        ServiceError::InternalServerError
-    })?;
-
-    let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET is not set in .env");
-
-    let row: Option<Row> = conn
-    .exec_first(
-        "SELECT 2fa_code, DATE_FORMAT(2fa_expiry, '%Y-%m-%d %H:%i:%s') AS 2fa_expiry, username, has_2fa FROM users WHERE temp_token = ?",
-        (&info.temp_token,),
-    )
-    .await.map_err(|e| {
-        error!("Error executing DB query: {:?}", e);
-        ServiceError::InternalServerError
-    })?;
-
-
-    match row {
-        Some(mut row_data) => {
-            let db_code: String = row_data.take("2fa_code").unwrap();
-            let expiry_string: String = row_data.take("2fa_expiry").unwrap();
-            let expiry = NaiveDateTime::parse_from_str(&expiry_string, "%Y-%m-%d %H:%M:%S").map_err(|_| ServiceError::InternalServerError)?;
-            let username: String = row_data.take("username").unwrap();
-            if db_code != info.code {
-                return Err(ServiceError::BadRequest("Invalid 2FA code.".to_string()));
-            }
-            if Utc::now().naive_utc() > expiry {
-                return Err(ServiceError::BadRequest("2FA code has expired.".to_string()));
+    let outcome = check_2fa(store.get_ref(), config.get_ref(), &info).await?;
 
-            }
+    notifications::check_login_device(store.get_ref(), emails.get_ref(), config.get_ref(), &req, &outcome.username).await?;
 
-            let has_2fa: bool = row_data.take("has_2fa").unwrap_or(false);
-
-            let expiration = Utc::now()
-                .checked_add_signed(Duration::days(1))
-                .expect("Failed to calculate JWT expiration")
-                .timestamp() as usize;
-            let username_clone = username.clone();
-            let claims = Claims {
-                sub: username.clone(), 
-                exp: expiration,
-                has_2fa: has_2fa,
-            };
-            let secret = &jwt_secret;
-            let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref()))
-                .map_err(|e| {
-                    error!("Error encoding JWT: {:?}", e);
-                    ServiceError::InternalServerError
-                })?;
-            info!("Generated JWT for user: {}", username_clone);
-            info!("About to invalidate temp_token for {}", info.temp_token);
-            conn.exec_drop(
-                "UPDATE users SET temp_token = NULL, temp_token_expiry = NULL WHERE temp_token = ?",
-                (&info.temp_token,),
-            ).await.map_err(|e| {
-                error!("Error invalidating temp_token: {:?}", e);
-                ServiceError::InternalServerError
-            })?;
-            info!("Successfully invalidated temp_token for {}", info.temp_token);
-            
-            Ok(HttpResponse::Ok().json(json!({"status": "success", "token": token })))
-        },
-        None => Err(ServiceError::BadRequest("Invalid temporary token.".to_string()))
+    let (token, refresh_token) = tokens::issue_token_pair(store.get_ref(), config.get_ref(), &outcome.username, outcome.has_2fa).await?;
+    info!("Generated JWT for user: {}", outcome.username);
+
+    let mut response = tokens::token_pair_response(config.get_ref(), &token, &refresh_token);
+    if let Some(remaining) = outcome.recovery_codes_remaining {
+        response["recovery_codes_remaining"] = json!(remaining);
     }
-}
\ No newline at end of file
+
+    Ok(HttpResponse::Ok().json(response))
+}
@@ -1,36 +1,18 @@
 use crate::create::common::*;
+use crate::create::recoverycodes;
+use crate::create::twoauth;
 
 #[post("/verify_2fa_activation")]
 async fn verify_2fa_activation(
-    pool: Data<Pool>,
+    store: Data<dyn UserStore>,
+    config: Data<AppConfig>,
     verification_data: web::Json<TwoFAVerificationRequest>,
 ) -> Result<HttpResponse, ServiceError> {
-    let mut conn = pool.get_conn().await.map_err(|e| {
-        error!("Error getting DB connection: {:?}", e);
-        ServiceError::InternalServerError
-    })?;
-    
-    let result: Option<(String, String)> = conn
-        .exec_first("SELECT temp_2fa_code, temp_token FROM users WHERE username = ?", (&verification_data.0.username,))
-        .await
-        .map_err(|e| {
-            error!("Error executing DB query: {:?}", e);
-            ServiceError::InternalServerError
-        })?;
+    twoauth::confirm_2fa_change(store.get_ref(), &verification_data.0, "User not found or no pending 2FA activation").await?;
 
-    if let Some((stored_code, stored_token)) = result {
-        if stored_code == verification_data.0.code && stored_token == verification_data.0.token {
-            
-            conn.exec_drop(
-                "UPDATE users SET has_2fa = 1, temp_2fa_code = NULL, temp_token = NULL WHERE username = ?",
-                (&verification_data.0.username,),
-            ).await.map_err(|_| ServiceError::InternalServerError)?;
+    store.enable_email_2fa(&verification_data.0.username).await?;
 
-            Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "2FA activated." })))
-        } else {
-            Err(ServiceError::BadRequest("Invalid code or token".to_string()))
-        }
-    } else {
-        Err(ServiceError::BadRequest("User not found or no pending 2FA activation".to_string()))
-    }
+    let recovery_codes = recoverycodes::generate_recovery_codes(store.get_ref(), config.get_ref(), &verification_data.0.username).await?;
+
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "message": "2FA activated. Store your recovery codes somewhere safe.", "recovery_codes": recovery_codes })))
 }
\ No newline at end of file
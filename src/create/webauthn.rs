@@ -0,0 +1,280 @@
+// webauthn.rs
+
+use crate::create::common::*;
+use crate::create::config::WebAuthnConfig;
+use crate::create::der;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ciborium::value::Value;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use sha2::{Digest, Sha256};
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+pub const COSE_ALG_ES256: i64 = -7;
+pub const COSE_ALG_EDDSA: i64 = -8;
+pub const COSE_ALG_RS256: i64 = -257;
+
+pub struct RegisteredCredential {
+    pub credential_id: Vec<u8>,
+    pub cose_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    attested_credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+fn invalid(reason: &str) -> ServiceError {
+    error!("Rejected WebAuthn response: {}", reason);
+    ServiceError::BadRequest("Invalid WebAuthn response".to_string())
+}
+
+// AppConfig requires WEBAUTHN_ORIGINS along with the RP ID
+pub fn rp_id(config: &WebAuthnConfig) -> Result<&str, ServiceError> {
+    config.rp_id.as_deref().ok_or_else(|| {
+        error!("WEBAUTHN_RP_ID is not configured");
+        ServiceError::InternalServerError
+    })
+}
+
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill(&mut challenge);
+    URL_SAFE_NO_PAD.encode(challenge)
+}
+
+pub fn decode_b64url(value: &str) -> Result<Vec<u8>, ServiceError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| invalid("bad base64url"))
+}
+
+pub fn encode_b64url(value: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(value)
+}
+
+// Returns the SHA-256 of clientDataJSON, which authenticators sign together with authenticatorData
+fn verify_client_data(
+    config: &WebAuthnConfig,
+    client_data_json: &[u8],
+    expected_type: &str,
+    expected_challenge: &str,
+) -> Result<Vec<u8>, ServiceError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(|_| invalid("malformed clientDataJSON"))?;
+
+    if client_data.ceremony != expected_type {
+        return Err(invalid("wrong ceremony type"));
+    }
+    if client_data.challenge.trim_end_matches('=') != expected_challenge {
+        return Err(invalid("challenge mismatch"));
+    }
+    if !config.origins.contains(&client_data.origin) {
+        return Err(invalid("origin not allowed"));
+    }
+
+    Ok(Sha256::digest(client_data_json).to_vec())
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData<'_>, ServiceError> {
+    if data.len() < 37 {
+        return Err(invalid("authenticatorData too short"));
+    }
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        // aaguid (16) | credentialIdLength (2) | credentialId | COSE public key
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(invalid("attested credential data too short"));
+        }
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let credential_id = rest.get(18..18 + id_len).ok_or_else(|| invalid("credential id truncated"))?;
+
+        let mut key_bytes = &rest[18 + id_len..];
+        let before = key_bytes.len();
+        let _: Value = ciborium::de::from_reader(&mut key_bytes).map_err(|_| invalid("malformed COSE key"))?;
+        let consumed = before - key_bytes.len();
+
+        Some((credential_id.to_vec(), rest[18 + id_len..18 + id_len + consumed].to_vec()))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData { rp_id_hash: &data[..32], flags, sign_count, attested_credential })
+}
+
+fn check_rp_and_flags(config: &WebAuthnConfig, auth_data: &AuthenticatorData, require_user_verification: bool) -> Result<(), ServiceError> {
+    if auth_data.rp_id_hash != Sha256::digest(rp_id(config)?.as_bytes()).as_slice() {
+        return Err(invalid("rpIdHash mismatch"));
+    }
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(invalid("user not present"));
+    }
+    if require_user_verification && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err(invalid("user not verified"));
+    }
+    Ok(())
+}
+
+fn map_get(map: &[(Value, Value)], key: i64) -> Option<&Value> {
+    map.iter()
+        .find(|(k, _)| k.as_integer().map(i128::from) == Some(key as i128))
+        .map(|(_, v)| v)
+}
+
+fn text_map_get<'a>(map: &'a [(Value, Value)], key: &str) -> Option<&'a Value> {
+    map.iter().find(|(k, _)| k.as_text() == Some(key)).map(|(_, v)| v)
+}
+
+fn cose_int(map: &[(Value, Value)], key: i64) -> Option<i64> {
+    map_get(map, key)
+        .and_then(Value::as_integer)
+        .and_then(|value| i64::try_from(i128::from(value)).ok())
+}
+
+pub fn cose_algorithm(cose_key: &[u8]) -> Result<i64, ServiceError> {
+    let key: Value = ciborium::de::from_reader(cose_key).map_err(|_| invalid("malformed COSE key"))?;
+    let map = key.as_map().ok_or_else(|| invalid("COSE key is not a map"))?;
+    cose_int(map, 3).ok_or_else(|| invalid("COSE key without alg"))
+}
+
+fn verify_with_cose_key(cose_key: &[u8], message: &[u8], signature_bytes: &[u8]) -> Result<(), ServiceError> {
+    let key: Value = ciborium::de::from_reader(cose_key).map_err(|_| invalid("malformed COSE key"))?;
+    let map = key.as_map().ok_or_else(|| invalid("COSE key is not a map"))?;
+    let bytes = |label: i64| map_get(map, label).and_then(Value::as_bytes).ok_or_else(|| invalid("COSE key component missing"));
+
+    let verified = match (cose_int(map, 1), cose_int(map, 3)) {
+        // kty EC2, crv P-256
+        (Some(2), Some(COSE_ALG_ES256)) if cose_int(map, -1) == Some(1) => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(bytes(-2)?);
+            point.extend_from_slice(bytes(-3)?);
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point).verify(message, signature_bytes)
+        },
+        // kty OKP, crv Ed25519
+        (Some(1), Some(COSE_ALG_EDDSA)) if cose_int(map, -1) == Some(6) => {
+            UnparsedPublicKey::new(&signature::ED25519, bytes(-2)?).verify(message, signature_bytes)
+        },
+        (Some(3), Some(COSE_ALG_RS256)) => {
+            RsaPublicKeyComponents { n: bytes(-1)?, e: bytes(-2)? }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature_bytes)
+        },
+        _ => return Err(invalid("unsupported COSE key")),
+    };
+
+    verified.map_err(|_| invalid("bad signature"))
+}
+
+// Packed attestation with x5c: the attestation certificate signs; the chain itself is not pinned to a vendor root
+fn verify_with_certificate(certificate: &[u8], alg: i64, message: &[u8], signature_bytes: &[u8]) -> Result<(), ServiceError> {
+    let spki = der::certificate_spki(certificate).map_err(|e| invalid(&e))?;
+    let (oid, key_bytes) = der::parse_spki(spki).map_err(|e| invalid(&e))?;
+
+    let verified = match (alg, oid) {
+        (COSE_ALG_ES256, der::OID_EC_PUBLIC_KEY) => {
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, key_bytes).verify(message, signature_bytes)
+        },
+        (COSE_ALG_RS256, der::OID_RSA_ENCRYPTION) => {
+            UnparsedPublicKey::new(&signature::RSA_PKCS1_2048_8192_SHA256, key_bytes).verify(message, signature_bytes)
+        },
+        (COSE_ALG_EDDSA, der::OID_ED25519) => {
+            UnparsedPublicKey::new(&signature::ED25519, key_bytes).verify(message, signature_bytes)
+        },
+        _ => return Err(invalid("unsupported attestation certificate")),
+    };
+
+    verified.map_err(|_| invalid("bad attestation signature"))
+}
+
+pub fn verify_registration(
+    config: &WebAuthnConfig,
+    client_data_json: &str,
+    attestation_object: &str,
+    expected_challenge: &str,
+) -> Result<RegisteredCredential, ServiceError> {
+    let client_data_hash = verify_client_data(config, &decode_b64url(client_data_json)?, "webauthn.create", expected_challenge)?;
+
+    let attestation: Value = ciborium::de::from_reader(decode_b64url(attestation_object)?.as_slice())
+        .map_err(|_| invalid("malformed attestationObject"))?;
+    let attestation = attestation.as_map().ok_or_else(|| invalid("attestationObject is not a map"))?;
+    let fmt = text_map_get(attestation, "fmt").and_then(Value::as_text).ok_or_else(|| invalid("missing fmt"))?;
+    let att_stmt = text_map_get(attestation, "attStmt").and_then(Value::as_map).ok_or_else(|| invalid("missing attStmt"))?;
+    let auth_data_bytes = text_map_get(attestation, "authData").and_then(Value::as_bytes).ok_or_else(|| invalid("missing authData"))?;
+
+    let auth_data = parse_authenticator_data(auth_data_bytes)?;
+    check_rp_and_flags(config, &auth_data, false)?;
+    let (credential_id, cose_key) = auth_data.attested_credential.clone()
+        .ok_or_else(|| invalid("no attested credential data"))?;
+
+    match fmt {
+        "none" => {},
+        "packed" => {
+            let alg = text_map_get(att_stmt, "alg")
+                .and_then(Value::as_integer)
+                .and_then(|alg| i64::try_from(i128::from(alg)).ok())
+                .ok_or_else(|| invalid("packed attStmt without alg"))?;
+            let sig = text_map_get(att_stmt, "sig").and_then(Value::as_bytes).ok_or_else(|| invalid("packed attStmt without sig"))?;
+
+            let mut signed = auth_data_bytes.to_vec();
+            signed.extend_from_slice(&client_data_hash);
+
+            match text_map_get(att_stmt, "x5c").and_then(Value::as_array) {
+                Some(chain) => {
+                    let leaf = chain.first().and_then(Value::as_bytes).ok_or_else(|| invalid("empty x5c"))?;
+                    verify_with_certificate(leaf, alg, &signed, sig)?;
+                },
+                None => {
+                    // Self attestation: signed by the credential key itself
+                    if cose_algorithm(&cose_key)? != alg {
+                        return Err(invalid("self attestation alg mismatch"));
+                    }
+                    verify_with_cose_key(&cose_key, &signed, sig)?;
+                },
+            }
+        },
+        _ => return Err(invalid("unsupported attestation format")),
+    }
+
+    Ok(RegisteredCredential { credential_id, cose_key, sign_count: auth_data.sign_count })
+}
+
+// Checks the assertion against the stored credential and returns the authenticator's new signature counter
+pub fn verify_assertion(
+    config: &WebAuthnConfig,
+    client_data_json: &str,
+    authenticator_data: &str,
+    signature_b64: &str,
+    expected_challenge: &str,
+    stored: &RegisteredCredential,
+    require_user_verification: bool,
+) -> Result<u32, ServiceError> {
+    let client_data_hash = verify_client_data(config, &decode_b64url(client_data_json)?, "webauthn.get", expected_challenge)?;
+    let auth_data_bytes = decode_b64url(authenticator_data)?;
+    let auth_data = parse_authenticator_data(&auth_data_bytes)?;
+    check_rp_and_flags(config, &auth_data, require_user_verification)?;
+
+    let mut signed = auth_data_bytes.clone();
+    signed.extend_from_slice(&client_data_hash);
+    verify_with_cose_key(&stored.cose_key, &signed, &decode_b64url(signature_b64)?)?;
+
+    // Counters only move forward; authenticators that don't count always report 0
+    if (auth_data.sign_count != 0 || stored.sign_count != 0) && auth_data.sign_count <= stored.sign_count {
+        return Err(invalid("signature counter did not increase, possible cloned authenticator"));
+    }
+
+    Ok(auth_data.sign_count)
+}
@@ -1,45 +1,153 @@
-// Main.rs with Rustls
+// Main.rs, serving over Rustls, OpenSSL or plain HTTP depending on TLS_BACKEND
 
-use std::{fs::File, io::BufReader};
-use actix_web::{App, HttpServer, middleware, web::Data};
-use mysql_async::{Pool, Opts};
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use rustls_pemfile::{certs, pkcs8_private_keys};
+use std::sync::Arc;
+use actix_web::{App, HttpServer, middleware, web::{Data, ServiceConfig}};
 use env_logger::Builder;
 use log::LevelFilter;
+use create::config::FeaturesConfig;
+#[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+use create::config::TlsBackend;
+use create::ratelimit::RateLimitStore;
+use create::mailer::Emails;
 
 mod create;
-mod func;
+#[cfg(test)]
+mod tests;
 
 extern crate mysql_async;
 #[macro_use]
 extern crate validator_derive;
 
-fn load_rustls_config() -> rustls::ServerConfig {
+#[cfg(feature = "tls-rustls")]
+fn load_rustls_config(server: &create::config::ServerConfig) -> Result<rustls::ServerConfig, String> {
+    use rustls::{Certificate, PrivateKey, ServerConfig};
+    use rustls_pemfile::{certs, pkcs8_private_keys};
+    use std::{fs::File, io::BufReader};
+
     let config = ServerConfig::builder()
         .with_safe_defaults()
         .with_no_client_auth();
 
-    let cert_file = &mut BufReader::new(File::open("cert.pem").unwrap());
-    let key_file = &mut BufReader::new(File::open("key.pem").unwrap());
+    let open = |path: &str| File::open(path).map(BufReader::new).map_err(|e| format!("{}: {}", path, e));
+    let cert_file = &mut open(&server.tls_cert_file)?;
+    let key_file = &mut open(&server.tls_key_file)?;
 
     let cert_chain = certs(cert_file)
-        .unwrap()
+        .map_err(|e| format!("{}: {}", server.tls_cert_file, e))?
         .into_iter()
         .map(Certificate)
         .collect();
     let mut keys: Vec<PrivateKey> = pkcs8_private_keys(key_file)
-        .unwrap()
+        .map_err(|e| format!("{}: {}", server.tls_key_file, e))?
         .into_iter()
         .map(PrivateKey)
         .collect();
 
     if keys.is_empty() {
-        eprintln!("Could not locate PKCS 8 private keys.");
-        std::process::exit(1);
+        return Err(format!("{}: could not locate PKCS 8 private keys", server.tls_key_file));
     }
 
-    config.with_single_cert(cert_chain, keys.remove(0)).unwrap()
+    config.with_single_cert(cert_chain, keys.remove(0)).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "tls-openssl")]
+fn load_openssl_config(server: &create::config::ServerConfig) -> Result<openssl::ssl::SslAcceptorBuilder, String> {
+    use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(|e| e.to_string())?;
+    builder
+        .set_private_key_file(&server.tls_key_file, SslFiletype::PEM)
+        .map_err(|e| format!("{}: {}", server.tls_key_file, e))?;
+    builder
+        .set_certificate_chain_file(&server.tls_cert_file)
+        .map_err(|e| format!("{}: {}", server.tls_cert_file, e))?;
+    Ok(builder)
+}
+
+// Like the rest of the configuration, unreadable certificates stop the server before it listens
+#[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+fn exit_on_tls_error<T>(message: String) -> T {
+    eprintln!("Invalid TLS configuration: {}", message);
+    std::process::exit(1);
+}
+
+fn exit_on_config_errors<T>(errors: Vec<String>) -> T {
+    eprintln!("Invalid configuration:");
+    for error in errors {
+        eprintln!("  - {}", error);
+    }
+    std::process::exit(1);
+}
+
+// Every route of the API; the tests mount the same list. Subsystems that are
+// compiled out or turned off in the configuration are not mounted at all.
+pub fn configure_services(cfg: &mut ServiceConfig, features: FeaturesConfig) {
+    #[cfg(feature = "registration")]
+    if features.registration {
+        cfg.service(create::register::create_account);
+    }
+
+    #[cfg(feature = "email-verification")]
+    if features.email_verification {
+        cfg.service(create::verify::handle_verification_link)
+            .service(create::sbverification::resend_verification);
+    }
+
+    #[cfg(feature = "password-reset")]
+    if features.password_reset {
+        cfg.service(create::reset::reset_password)
+            .service(create::forgot::forgot_password);
+    }
+
+    // Turning 2FA off only stops new enrolments; accounts that enrolled still log in and opt out through these
+    #[cfg(feature = "two-factor")]
+    {
+        cfg.service(create::twoauth::verify_2fa)
+            .service(create::authorize::authorize_verify_2fa)
+            .service(create::passkeys::two_factor_start)
+            .service(create::passkeys::two_factor_finish)
+            .service(create::recoverycodes::regenerate_recovery_codes)
+            .service(create::deactivatetwoauth::request_deactivate_2fa)
+            .service(create::deactivatetwoauth::verify_2fa_deactivation);
+    }
+
+    #[cfg(feature = "two-factor")]
+    if features.two_factor {
+        cfg.service(create::activatetwoauth::activate_2fa)
+            .service(create::verifyactivatetwoauth::verify_2fa_activation)
+            .service(create::enrolltotp::enroll_totp)
+            .service(create::enrolltotp::verify_totp_enrollment);
+    }
+
+    if features.magic_link {
+        cfg.service(create::magiclink::request_magic_link)
+            .service(create::magiclink::consume_magic_link);
+    }
+
+    cfg.service(create::login::login)
+        .service(create::refresh::refresh_token)
+        .service(create::sessions::logout)
+        .service(create::sessions::logout_all)
+        .service(create::changepassword::change_password)
+        .service(create::changeemail::change_email)
+        .service(create::changeemail::confirm_email_change)
+        .service(create::changeemail::cancel_email_change)
+        .service(create::jwks::jwks)
+        .service(create::oauthclients::register_client)
+        .service(create::oidc::discovery)
+        .service(create::oidc::userinfo)
+        .service(create::authorize::authorize)
+        .service(create::authorize::authorize_login)
+        .service(create::oidctoken::token)
+        .service(create::social::social_authorize)
+        .service(create::social::social_callback)
+        .service(create::passkeys::register_start)
+        .service(create::passkeys::register_finish)
+        .service(create::passkeys::login_start)
+        .service(create::passkeys::login_finish)
+        .service(create::lockout::unlock_account)
+        .service(create::notifications::confirm_not_me)
+        .service(create::notifications::not_me);
 }
 
 #[actix_web::main]
@@ -51,20 +159,96 @@ async fn main() -> std::io::Result<()> {
         .filter(None, LevelFilter::Info) // Modifiez ceci pour ajuster le niveau de filtrage des logs.
         .init();
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let opts = Opts::from_url(&database_url).expect("Failed to parse database URL");
-    let pool = Pool::new(opts);
+    // `opensourceapi migrate [up [VERSION] | down [STEPS] | status]` runs migrations and exits,
+    // `opensourceapi outbox [status [ID]]` inspects the email queue and exits.
+    // Both only read the database settings.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(command @ ("migrate" | "outbox")) = args.first().map(String::as_str) {
+        let database = create::config::DatabaseConfig::load().unwrap_or_else(exit_on_config_errors);
+        let store = create::store::connect(&database.url, database.name.as_deref()).await.expect("Failed to connect to the database");
+        return match command {
+            "migrate" => create::migrations::run_command(store.as_ref(), &args[1..]).await,
+            _ => create::outbox::run_command(store.as_ref(), &args[1..]).await,
+        }
+        .map_err(std::io::Error::other);
+    }
+
+    // Every setting is checked here, so a bad value stops the server before it takes requests
+    let app_config = create::config::AppConfig::load().unwrap_or_else(exit_on_config_errors);
+
+    let database = &app_config.database;
+    let store = create::store::connect(&database.url, database.name.as_deref()).await.expect("Failed to connect to the database");
+
+    // The handlers expect the latest schema, so the server does not start on an older one
+    if database.migrate_on_startup {
+        match create::migrations::migrate_up(store.as_ref(), None).await {
+            Ok(applied) => println!("Database ready, {} migration(s) applied", applied.len()),
+            Err(e) => {
+                eprintln!("Failed to migrate database: {:?}", e);
+                std::process::exit(1);
+            },
+        }
+    } else {
+        match create::migrations::pending(store.as_ref()).await {
+            Ok(pending) if !pending.is_empty() => {
+                eprintln!("{} pending migration(s), run `migrate up`", pending.len());
+                std::process::exit(1);
+            },
+            Ok(_) => println!("Database ready"),
+            Err(e) => {
+                eprintln!("Failed to read schema_migrations: {:?}", e);
+                std::process::exit(1);
+            },
+        }
+    }
 
-    match func::ensure_database_and_table_exists(&pool).await {
-        Ok(_) => println!("Database and table ready"),
-        Err(e) => eprintln!("Failed to create database or table: {}", e),
+    // A build without 2FA could not challenge the accounts that enrolled, so it refuses to let them in with less
+    if !cfg!(feature = "two-factor") {
+        match store.count_2fa_users().await {
+            Ok(0) => {},
+            Ok(enrolled) => {
+                eprintln!("{} account(s) use 2FA but the server was built without the two-factor feature", enrolled);
+                std::process::exit(1);
+            },
+            Err(e) => {
+                eprintln!("Failed to count 2FA accounts: {:?}", e);
+                std::process::exit(1);
+            },
+        }
     }
 
-    let config = load_rustls_config();
+    // Like the rest of the configuration, a bad mail configuration stops the server here instead of failing every email
+    let mailer = create::mailer::from_config(&app_config.mail).unwrap_or_else(|message| {
+        eprintln!("Invalid mail configuration: {}", message);
+        std::process::exit(1);
+    });
+    let templates = create::templates::EmailTemplates::from_config(&app_config.templates).unwrap_or_else(|message| {
+        eprintln!("Invalid email templates: {}", message);
+        std::process::exit(1);
+    });
+    // The breached password list is read here, once, rather than on every password change
+    let password_policy = Data::new(create::passwordpolicy::PasswordPolicy::from_config(&app_config.password).unwrap_or_else(|message| {
+        eprintln!("Invalid password policy: {}", message);
+        std::process::exit(1);
+    }));
+    let outbox = Arc::new(create::outbox::Outbox::new(store.clone(), app_config.outbox.clone()));
+    outbox.clone().spawn_worker(mailer);
+    let emails = Data::new(Emails::new(outbox, templates));
+
+    let idp_client: Arc<dyn create::socialidp::IdpClient> = Arc::new(create::socialidp::HttpIdpClient::default());
+
+    // Shared by all workers so limits hold per process, not per worker
+    let rate_limit_store: Arc<dyn RateLimitStore> = Arc::new(create::ratelimit::MemoryStore::default());
 
-    HttpServer::new(move || {
-        let cors = actix_cors::Cors::default()
-            .allowed_origin("https://192.168.0.39:8084")
+    let app_config = Data::new(app_config);
+    let shared_config = app_config.clone();
+
+    let server = HttpServer::new(move || {
+        let app_config = shared_config.clone();
+        let features = app_config.features;
+        let cors = app_config.server.cors_origins
+            .iter()
+            .fold(actix_cors::Cors::default(), |cors, origin| cors.allowed_origin(origin))
             .allowed_methods(vec!["GET", "POST"])
             .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
             .allowed_header(http::header::CONTENT_TYPE)
@@ -72,23 +256,26 @@ async fn main() -> std::io::Result<()> {
             .max_age(3600);
 
         App::new()
-            
+            .wrap(create::ratelimit::RateLimiter::new(app_config.rate_limits.rules.clone(), rate_limit_store.clone()))
             .wrap(middleware::Logger::new("%s %{User-Agent}i %m %U%q %H  %b %{Referer}i %{X-Forwarded-For}i %D"))
             .wrap(cors)
-            .app_data(Data::new(pool.clone()))
-            .service(create::register::create_account)
-            .service(create::verify::handle_verification_link)
-            .service(create::sbverification::resend_verification)
-            .service(create::reset::reset_password)
-            .service(create::forgot::forgot_password)
-            .service(create::login::login)
-            .service(create::activatetwoauth::activate_2fa)
-            .service(create::deactivatetwoauth::request_deactivate_2fa)
-            .service(create::twoauth::verify_2fa)
-            .service(create::verifyactivatetwoauth::verify_2fa_activation)
-            .service(create::deactivatetwoauth::verify_2fa_deactivation)
-    })
-    .bind_rustls_021("0.0.0.0:8084", config)?
-    .run()
-    .await
+            .app_data(Data::from(store.clone()))
+            .app_data(Data::from(idp_client.clone()))
+            .app_data(emails.clone())
+            .app_data(password_policy.clone())
+            .app_data(app_config)
+            .configure(|cfg| configure_services(cfg, features))
+    });
+
+    let bind_address = app_config.server.bind_address.as_str();
+    let server = match app_config.server.tls {
+        #[cfg(feature = "tls-rustls")]
+        TlsBackend::Rustls => server.bind_rustls_021(bind_address, load_rustls_config(&app_config.server).unwrap_or_else(exit_on_tls_error))?,
+        #[cfg(feature = "tls-openssl")]
+        TlsBackend::Openssl => server.bind_openssl(bind_address, load_openssl_config(&app_config.server).unwrap_or_else(exit_on_tls_error))?,
+        // AppConfig rejects the backends this binary was built without
+        _ => server.bind(bind_address)?,
+    };
+
+    server.run().await
 }
\ No newline at end of file
@@ -0,0 +1,231 @@
+// auth_flow.rs
+
+use super::*;
+use serde_json::json;
+
+const PASSWORD: &str = "correct horse";
+
+async fn register<S, B>(app: &S, ctx: &TestContext, username: &str) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let email = format!("{}@example.com", username);
+    let (status, _) = post_json(app, "/create_account", json!({"username": username, "email": email, "password": PASSWORD}), None).await;
+    assert_eq!(status, 200);
+
+    let verification = ctx.last_email_to(&email).await;
+    assert_eq!(verification.subject, "Please verify your email");
+    link_param(&verification.body, "token")
+}
+
+async fn login<S, B>(app: &S, username: &str, password: &str) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    post_json(app, "/login", json!({"username": username, "password": password}), None).await
+}
+
+#[actix_web::test]
+async fn register_verify_login_2fa_and_reset() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    let email = "alice@example.com";
+
+    // register, then log in only once the email is verified
+    let verification_token = register(&app, &ctx, "alice").await;
+    let (status, _) = login(&app, "alice", PASSWORD).await;
+    assert_eq!(status, 400);
+
+    let (status, body) = get(&app, &format!("/verify?token={}", verification_token)).await;
+    assert_eq!(status, 200, "{}", body);
+
+    let (status, body) = login(&app, "alice", PASSWORD).await;
+    assert_eq!(status, 200, "{}", body);
+    let access_token = body["token"].as_str().unwrap().to_string();
+    assert!(body["refresh_token"].is_string());
+
+    // turn on email 2FA
+    let (status, body) = post_json(&app, "/activate_2fa", json!({}), Some(&access_token)).await;
+    assert_eq!(status, 200, "{}", body);
+    let activation_code = code_in(&ctx.last_email_to(email).await.body);
+    let (status, body) = post_json(
+        &app,
+        "/verify_2fa_activation",
+        json!({"username": "alice", "code": activation_code, "token": body["token"]}),
+        None,
+    ).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["recovery_codes"].as_array().unwrap().len(), 10);
+
+    // the password alone is no longer enough
+    let (status, body) = login(&app, "alice", PASSWORD).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "2fa_required");
+    let code = code_in(&ctx.last_email_to(email).await.body);
+    let (status, body) = post_json(&app, "/verify_2fa", json!({"temp_token": body["temp_token"], "code": code}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(body["token"].is_string());
+
+    // reset the password through the emailed link
+    let (status, _) = post_json(&app, "/forgot_password", json!({"email": email}), None).await;
+    assert_eq!(status, 200);
+    let reset = ctx.last_email_to(email).await;
+    assert_eq!(reset.subject, "Reset Your Password");
+    let reset_token = link_param(&reset.body, "token");
+    let (status, body) = post_json(
+        &app,
+        "/reset_password",
+        json!({"email": email, "token": reset_token, "new_password": "battery staple"}),
+        None,
+    ).await;
+    assert_eq!(status, 200, "{}", body);
+
+    // the reset revoked the sessions opened before it
+    let (status, _) = post_json(&app, "/activate_2fa", json!({}), Some(&access_token)).await;
+    assert_eq!(status, 401);
+
+    let (status, _) = login(&app, "alice", PASSWORD).await;
+    assert_eq!(status, 400);
+    let (status, body) = login(&app, "alice", "battery staple").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "2fa_required");
+}
+
+#[actix_web::test]
+async fn verification_link_works_once() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+
+    let token = register(&app, &ctx, "bob").await;
+    let (status, _) = get(&app, &format!("/verify?token={}", token)).await;
+    assert_eq!(status, 200);
+    let (status, body) = get(&app, &format!("/verify?token={}", token)).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, "Email already verified");
+}
+
+#[actix_web::test]
+async fn wrong_2fa_code_is_rejected() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+
+    let token = register(&app, &ctx, "carol").await;
+    get(&app, &format!("/verify?token={}", token)).await;
+    ctx.store.enable_email_2fa("carol").await.unwrap();
+
+    let (_, body) = login(&app, "carol", PASSWORD).await;
+    let code = code_in(&ctx.last_email_to("carol@example.com").await.body);
+    let wrong = if code == "000000" { "111111" } else { "000000" };
+
+    let (status, _) = post_json(&app, "/verify_2fa", json!({"temp_token": body["temp_token"], "code": wrong}), None).await;
+    assert_eq!(status, 400);
+    let (status, _) = post_json(&app, "/verify_2fa", json!({"temp_token": body["temp_token"], "code": code}), None).await;
+    assert_eq!(status, 200);
+    // the temp token is burnt by the successful attempt
+    let (status, _) = post_json(&app, "/verify_2fa", json!({"temp_token": body["temp_token"], "code": code}), None).await;
+    assert_eq!(status, 400);
+}
+
+#[actix_web::test]
+async fn reset_needs_the_emailed_token() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+
+    let token = register(&app, &ctx, "dave").await;
+    get(&app, &format!("/verify?token={}", token)).await;
+    let (status, _) = post_json(&app, "/forgot_password", json!({"email": "dave@example.com"}), None).await;
+    assert_eq!(status, 200);
+    let sent = ctx.sent_count().await;
+
+    let (status, _) = post_json(
+        &app,
+        "/reset_password",
+        json!({"email": "dave@example.com", "token": "not-the-token", "new_password": "whatever"}),
+        None,
+    ).await;
+    assert_eq!(status, 400);
+    assert_eq!(ctx.sent_count().await, sent);
+
+    let (status, _) = login(&app, "dave", PASSWORD).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn reset_tokens_are_stored_hashed_and_work_once() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    let email = "erin@example.com";
+
+    let token = register(&app, &ctx, "erin").await;
+    get(&app, &format!("/verify?token={}", token)).await;
+    post_json(&app, "/forgot_password", json!({"email": email}), None).await;
+    let reset_token = link_param(&ctx.last_email_to(email).await.body, "token");
+
+    // only the digest is in the database
+    let purpose = crate::create::store::TokenPurpose::PasswordReset;
+    assert!(ctx.store.find_auth_token(purpose, &reset_token).await.unwrap().is_none());
+    let stored = ctx.store.find_auth_token(purpose, &crate::create::tokens::hash_token(&reset_token)).await.unwrap().unwrap();
+    assert_eq!(stored.username, "erin");
+
+    let reset = json!({"email": email, "token": reset_token, "new_password": "battery staple"});
+    let (status, body) = post_json(&app, "/reset_password", reset.clone(), None).await;
+    assert_eq!(status, 200, "{}", body);
+    let (status, _) = post_json(&app, "/reset_password", reset, None).await;
+    assert_eq!(status, 400);
+}
+
+#[actix_web::test]
+async fn email_changes_only_once_the_new_address_is_confirmed() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+
+    let token = register(&app, &ctx, "kim").await;
+    get(&app, &format!("/verify?token={}", token)).await;
+    register(&app, &ctx, "lee").await;
+    let (_, body) = login(&app, "kim", PASSWORD).await;
+    let access_token = body["token"].as_str().unwrap();
+    let email_of = |username: &'static str| {
+        let store = ctx.store.clone();
+        async move { store.find_user(username).await.unwrap().unwrap().email }
+    };
+
+    let (status, body) = post_json(&app, "/change_email", json!({"new_email": "lee@example.com", "current_password": PASSWORD}), Some(access_token)).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, "This email address is already in use.");
+
+    // the notice to the old address can cancel the change
+    let change = json!({"new_email": "kim@new.example", "current_password": PASSWORD});
+    let (status, body) = post_json(&app, "/change_email", change.clone(), Some(access_token)).await;
+    assert_eq!(status, 200, "{}", body);
+    let confirm = link_param(&ctx.last_email_to("kim@new.example").await.body, "token");
+    let notice = ctx.last_email_to("kim@example.com").await;
+    assert_eq!(notice.subject, "Your email address is being changed");
+    assert!(notice.body.contains("kim@new.example"));
+    let (status, _) = get(&app, &format!("/cancel_email_change?token={}", link_param(&notice.body, "token"))).await;
+    assert_eq!(status, 200);
+    let (status, _) = get(&app, &format!("/confirm_email_change?token={}", confirm)).await;
+    assert_eq!(status, 400);
+    assert_eq!(email_of("kim").await, "kim@example.com");
+
+    // the address was claimed by another account before the link was clicked
+    post_json(&app, "/change_email", change.clone(), Some(access_token)).await;
+    let confirm = link_param(&ctx.last_email_to("kim@new.example").await.body, "token");
+    let (status, _) = post_json(&app, "/create_account", json!({"username": "mallory", "email": "kim@new.example", "password": PASSWORD}), None).await;
+    assert_eq!(status, 200);
+    let (status, body) = get(&app, &format!("/confirm_email_change?token={}", confirm)).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, "This email address is already in use.");
+    assert_eq!(email_of("kim").await, "kim@example.com");
+
+    let change = json!({"new_email": "kim@other.example", "current_password": PASSWORD});
+    post_json(&app, "/change_email", change, Some(access_token)).await;
+    assert_eq!(email_of("kim").await, "kim@example.com");
+    let confirm = link_param(&ctx.last_email_to("kim@other.example").await.body, "token");
+    let (status, body) = get(&app, &format!("/confirm_email_change?token={}", confirm)).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(email_of("kim").await, "kim@other.example");
+    let (status, _) = get(&app, &format!("/confirm_email_change?token={}", confirm)).await;
+    assert_eq!(status, 400);
+}
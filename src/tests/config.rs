@@ -0,0 +1,89 @@
+// config.rs
+
+use super::*;
+use crate::create::config::AppConfig;
+
+#[actix_web::test]
+async fn env_overrides_the_file_and_errors_are_collected() {
+    init_env();
+    let file: toml::Table = r#"
+        [links]
+        verification_base_url = "https://file.test"
+
+        [login]
+        lockout_minutes = 45
+
+        [server]
+        cors_origins = ["https://one.test", "https://two.test"]
+    "#.parse().unwrap();
+
+    let config = AppConfig::from_sources(file).unwrap();
+    assert_eq!(config.links.verification_base_url, "https://app.test");
+    assert_eq!(config.login.lockout_minutes, 45);
+    assert_eq!(config.login.free_attempts, 3);
+    assert_eq!(config.server.cors_origins, vec!["https://one.test", "https://two.test"]);
+
+    let file: toml::Table = r#"
+        [server]
+        bind_address = "localhost"
+
+        [mail.smtp]
+        port = "not a port"
+    "#.parse().unwrap();
+
+    let errors = AppConfig::from_sources(file).err().unwrap();
+    assert_eq!(errors.len(), 2, "{:?}", errors);
+    assert!(errors[0].starts_with("BIND_ADDRESS"));
+    assert!(errors[1].starts_with("SMTP_PORT"));
+}
+
+#[actix_web::test]
+async fn security_sections_are_validated_at_load() {
+    init_env();
+    let file: toml::Table = r#"
+        [social]
+        providers = ["acme"]
+        redirect_base_url = "https://api.test/"
+
+        [social.acme]
+        issuer = "https://idp.acme.test/"
+        client_id = "client"
+        client_secret = "secret"
+
+        [tokens]
+        access_token_ttl_minutes = 5
+    "#.parse().unwrap();
+
+    let config = AppConfig::from_sources(file).unwrap();
+    assert_eq!(config.tokens.access_token_ttl().num_seconds(), 300);
+    assert_eq!(config.totp.encryption_key.len(), 32);
+    let acme = &config.social.providers[0];
+    assert_eq!(acme.issuer, "https://idp.acme.test");
+    assert_eq!(acme.redirect_uri, "https://api.test/social/acme/callback");
+    assert_eq!(acme.scopes, "openid email profile");
+
+    let file: toml::Table = r#"
+        [social]
+        providers = ["acme"]
+
+        [totp]
+        skew_back = -1
+
+        [webauthn]
+        rp_id = "app.test"
+
+        [oidc]
+        issuer = "https://api.test"
+    "#.parse().unwrap();
+
+    let errors = AppConfig::from_sources(file).err().unwrap();
+    assert_eq!(errors.len(), 8, "{:?}", errors);
+    assert!(errors[0].starts_with("TOTP_SKEW_BACK"));
+    assert!(errors[1].starts_with("WEBAUTHN_ORIGINS"));
+    assert!(errors[2].starts_with("OIDC_LOGIN_URL"));
+    assert!(errors[3].starts_with("OIDC_ISSUER (oidc.issuer) needs JWT_KEYS"));
+    assert!(errors[4].starts_with("SOCIAL_REDIRECT_BASE_URL"));
+    assert!(errors[5].starts_with("SOCIAL_ACME_ISSUER"));
+    assert!(errors[6].starts_with("SOCIAL_ACME_CLIENT_ID"));
+    assert!(errors[7].starts_with("SOCIAL_ACME_CLIENT_SECRET"));
+}
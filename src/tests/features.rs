@@ -0,0 +1,53 @@
+// features.rs
+
+use super::*;
+use crate::create::store::NewUser;
+use serde_json::json;
+
+#[actix_web::test]
+async fn disabled_subsystems_are_not_mounted() {
+    let ctx = context().await;
+    let file: toml::Table = r#"
+        [features]
+        registration = false
+        two_factor = false
+        magic_link = false
+    "#.parse().unwrap();
+    let config = AppConfig::from_sources(file).unwrap();
+    // its link is neither required nor read
+    assert_eq!(config.links.magic_link_base_url, "");
+    let app = app_with_config(&ctx, config).await;
+
+    let (status, _) = post_json(&app, "/create_account", json!({"username": "heidi", "email": "heidi@example.com", "password": "correct horse"}), None).await;
+    assert_eq!(status, 404);
+
+    // an account that enrolled in 2FA before it was turned off
+    let password_hash = bcrypt::hash("correct horse", 4).unwrap();
+    ctx.store.create_user(NewUser {
+        username: "heidi",
+        email: "heidi@example.com",
+        password_hash: &password_hash,
+        verified: true,
+        locale: None,
+    }).await.unwrap();
+    ctx.store.enable_email_2fa("heidi").await.unwrap();
+    assert_eq!(ctx.store.count_2fa_users().await.unwrap(), 1);
+
+    // it keeps its second factor, only new enrolments stop
+    let (status, body) = post_json(&app, "/login", json!({"username": "heidi", "password": "correct horse"}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["status"], "2fa_required");
+    let code = code_in(&ctx.last_email_to("heidi@example.com").await.body);
+    let (status, body) = post_json(&app, "/verify_2fa", json!({"temp_token": body["temp_token"], "code": code}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    let token = body["token"].as_str().unwrap();
+
+    let (status, _) = post_json(&app, "/activate_2fa", json!({}), Some(token)).await;
+    assert_eq!(status, 404);
+    let (status, _) = post_json(&app, "/enroll_totp", json!({}), Some(token)).await;
+    assert_eq!(status, 404);
+    let (status, _) = post_json(&app, "/login/magic_link", json!({"email": "heidi@example.com"}), None).await;
+    assert_eq!(status, 404);
+    let (status, _) = post_json(&app, "/forgot_password", json!({"email": "heidi@example.com"}), None).await;
+    assert_eq!(status, 200);
+}
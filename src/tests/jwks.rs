@@ -0,0 +1,66 @@
+// jwks.rs
+
+use super::*;
+use serde_json::json;
+
+fn keys_config(keys: &[&str]) -> AppConfig {
+    let file: toml::Table = format!("[jwt]\nkeys = {:?}", keys).parse().unwrap();
+    AppConfig::from_sources(file).unwrap()
+}
+
+fn kid(token: &str) -> String {
+    jsonwebtoken::decode_header(token).unwrap().kid.unwrap()
+}
+
+#[actix_web::test]
+async fn tokens_outlive_a_key_rotation_until_the_old_key_is_dropped() {
+    let ctx = context().await;
+    create_user(&ctx, "alice").await;
+    let (old_key, old_public) = ed25519_key("2024");
+    let (new_key, _) = ed25519_key("2025");
+
+    let before = app_with_config(&ctx, keys_config(&[&old_key])).await;
+    let old_token = login_token(&before, "alice").await;
+    assert_eq!(kid(&old_token), "2024");
+
+    // The new key signs; the old one only verifies and is still published
+    let during = app_with_config(&ctx, keys_config(&[&new_key, &old_public])).await;
+    let new_token = login_token(&during, "alice").await;
+    assert_eq!(kid(&new_token), "2025");
+    let (_, jwks) = get(&during, "/.well-known/jwks.json").await;
+    let kids: Vec<&str> = jwks["keys"].as_array().unwrap().iter().map(|key| key["kid"].as_str().unwrap()).collect();
+    assert_eq!(kids, ["2025", "2024"]);
+    let (status, _) = post_json(&during, "/logout_all", json!({}), Some(&old_token)).await;
+    assert_eq!(status, 200);
+
+    let after = app_with_config(&ctx, keys_config(&[&new_key])).await;
+    let fresh_token = login_token(&after, "alice").await;
+    let (status, body) = post_json(&after, "/logout", json!({}), Some(&old_token)).await;
+    assert_eq!(status, 401);
+    assert_eq!(body, "Unknown signing key");
+    let (status, _) = post_json(&after, "/logout", json!({}), Some(&fresh_token)).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn secret_tokens_stay_valid_after_switching_to_keys_while_the_secret_is_kept() {
+    let ctx = context().await;
+    create_user(&ctx, "alice").await;
+    let (key, _) = ed25519_key("2025");
+
+    let before = app(&ctx).await;
+    let first_token = login_token(&before, "alice").await;
+    let second_token = login_token(&before, "alice").await;
+    assert!(jsonwebtoken::decode_header(&first_token).unwrap().kid.is_none());
+
+    let with_secret = app_with_config(&ctx, keys_config(&[&key])).await;
+    let (status, _) = post_json(&with_secret, "/logout", json!({}), Some(&first_token)).await;
+    assert_eq!(status, 200);
+
+    let mut config = keys_config(&[&key]);
+    config.jwt.secret = None;
+    let without_secret = app_with_config(&ctx, config).await;
+    let (status, body) = post_json(&without_secret, "/logout", json!({}), Some(&second_token)).await;
+    assert_eq!(status, 401);
+    assert_eq!(body, "Invalid token");
+}
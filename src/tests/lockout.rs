@@ -0,0 +1,46 @@
+// lockout.rs
+
+use super::*;
+use serde_json::json;
+
+fn lockout_config() -> AppConfig {
+    let file: toml::Table = "[login]\nfree_attempts = 1\nlockout_threshold = 3".parse().unwrap();
+    AppConfig::from_sources(file).unwrap()
+}
+
+async fn login_with<S, B>(app: &S, password: &str) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    post_json(app, "/login", json!({"username": "alice", "password": password}), None).await
+}
+
+#[actix_web::test]
+async fn failed_logins_slow_down_then_lock_the_account_until_unlocked() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, lockout_config()).await;
+    create_user(&ctx, "alice").await;
+
+    // The free attempt, then a one second back-off that even the right password has to wait out
+    assert_eq!(login_with(&app, "wrong").await.0, 400);
+    assert_eq!(login_with(&app, "wrong").await.0, 400);
+    assert_eq!(login_with(&app, PASSWORD).await.0, 429);
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(1100)).await;
+
+    // Reaching the threshold locks the account and mails the owner an unlock link
+    assert_eq!(login_with(&app, "wrong").await.0, 400);
+    assert_eq!(login_with(&app, PASSWORD).await.0, 429);
+    let email = ctx.last_email_to("alice@example.com").await;
+    assert!(email.body.contains("/unlock_account?token="), "{}", email.body);
+    let unlock = format!("/unlock_account?token={}", link_param(&email.body, "token"));
+
+    let (status, body) = get(&app, &unlock).await;
+    assert_eq!(status, 200, "{}", body);
+    let (status, _) = login_with(&app, PASSWORD).await;
+    assert_eq!(status, 200);
+
+    // The link works once
+    let (status, _) = get(&app, &unlock).await;
+    assert_eq!(status, 400);
+}
@@ -0,0 +1,56 @@
+// magiclink.rs
+
+use super::*;
+use serde_json::json;
+
+async fn request_link<S, B>(app: &S, ctx: &TestContext, fingerprint: &str) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, _) = post_json(app, "/login/magic_link", json!({"email": "alice@example.com", "device_fingerprint": fingerprint}), None).await;
+    assert_eq!(status, 200);
+    link_param(&ctx.last_email_to("alice@example.com").await.body, "token")
+}
+
+#[actix_web::test]
+async fn magic_links_log_in_once_on_the_requesting_device() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+
+    // Unknown addresses get the same answer and no email
+    let (status, body) = post_json(&app, "/login/magic_link", json!({"email": "nobody@example.com"}), None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "success");
+    assert_eq!(ctx.sent_count().await, 0);
+
+    let token = request_link(&app, &ctx, "laptop").await;
+    let (status, body) = post_json(&app, "/login/magic_link/verify", json!({"token": token, "device_fingerprint": "phone"}), None).await;
+    assert_eq!(status, 401);
+    assert_eq!(body, "This login link was requested from another device");
+
+    let (status, body) = post_json(&app, "/login/magic_link/verify", json!({"token": token, "device_fingerprint": "laptop"}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(body["token"].is_string());
+
+    let (status, _) = post_json(&app, "/login/magic_link/verify", json!({"token": token, "device_fingerprint": "laptop"}), None).await;
+    assert_eq!(status, 401);
+}
+
+#[actix_web::test]
+async fn only_the_latest_magic_link_works() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+
+    let first = request_link(&app, &ctx, "laptop").await;
+    let second = request_link(&app, &ctx, "laptop").await;
+    assert_ne!(first, second);
+
+    let (status, body) = post_json(&app, "/login/magic_link/verify", json!({"token": first, "device_fingerprint": "laptop"}), None).await;
+    assert_eq!(status, 401);
+    assert_eq!(body, "Invalid or expired login link");
+    let (status, _) = post_json(&app, "/login/magic_link/verify", json!({"token": second, "device_fingerprint": "laptop"}), None).await;
+    assert_eq!(status, 200);
+}
@@ -0,0 +1,31 @@
+// mailer.rs
+
+use crate::create::mailer::{FileMailer, Mailer};
+use crate::create::templates::RenderedEmail;
+use uuid::Uuid;
+
+#[actix_web::test]
+async fn file_mailer_drops_an_eml_per_message() {
+    let dir = std::env::temp_dir().join(format!("mail-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mailer = FileMailer::new("API <no-reply@example.com>".parse().unwrap(), dir.to_str().unwrap());
+
+    let email = RenderedEmail {
+        subject: "Your 2FA code".to_string(),
+        text: "Here is your 2FA code: ABC123".to_string(),
+        html: "<p>Here is your 2FA code: <b>ABC123</b></p>".to_string(),
+    };
+    mailer.send("erin@example.com", &email).await.unwrap();
+
+    let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].extension().unwrap(), "eml");
+    let raw = std::fs::read_to_string(&files[0]).unwrap();
+    assert!(raw.contains("To: erin@example.com"));
+    assert!(raw.contains("Subject: Your 2FA code"));
+    assert!(raw.contains("ABC123"));
+    assert!(raw.contains("multipart/alternative"));
+    assert!(raw.contains("text/html"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,42 @@
+// migrations.rs
+
+use super::*;
+use crate::create::migrations::{self, Migration};
+
+// A row written by the API before it had migrations, into the users table it created back then
+const LEGACY_USER: Migration = Migration {
+    version: 0,
+    name: "legacy_user",
+    up: "INSERT INTO users (username, email, password, verification_token, verified) VALUES ('old', 'old@example.com', 'x', '', TRUE)",
+    down: "DELETE FROM users WHERE username = 'old'",
+};
+
+#[actix_web::test]
+async fn a_pre_migration_users_table_is_brought_up_to_date() {
+    init_env();
+    let store = crate::create::store::connect("sqlite::memory:", None).await.unwrap();
+    migrations::migrate_up(store.as_ref(), Some(1)).await.unwrap();
+    store.apply_migration(&LEGACY_USER).await.unwrap();
+
+    let applied = migrations::migrate_up(store.as_ref(), None).await.unwrap();
+    assert_eq!(applied.first(), Some(&2));
+    assert!(migrations::pending(store.as_ref()).await.unwrap().is_empty());
+
+    let user = store.find_user("old").await.unwrap().unwrap();
+    assert_eq!(user.two_fa_method, "email");
+    assert!(!user.password_reset_required);
+}
+
+#[actix_web::test]
+async fn every_migration_reverts_and_reapplies() {
+    let ctx = context().await;
+    let count = migrations::migrations(ctx.store.dialect()).len();
+
+    let reverted = migrations::migrate_down(ctx.store.as_ref(), count).await.unwrap();
+    assert_eq!(reverted.len(), count);
+    assert_eq!(migrations::pending(ctx.store.as_ref()).await.unwrap().len(), count);
+
+    migrations::migrate_up(ctx.store.as_ref(), None).await.unwrap();
+    create_user(&ctx, "alice").await;
+    assert!(ctx.store.find_user("alice").await.unwrap().is_some());
+}
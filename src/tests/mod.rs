@@ -0,0 +1,328 @@
+// tests/mod.rs
+
+mod auth_flow;
+mod config;
+mod features;
+mod jwks;
+mod lockout;
+mod magiclink;
+mod mailer;
+mod migrations;
+mod notifications;
+mod oidc;
+mod outbox;
+mod passwords;
+mod ratelimit;
+mod sessions;
+mod social;
+mod templates;
+mod totp;
+mod webauthn;
+
+use crate::create::common::*;
+use crate::create::outbox::{Outbox, OutboxSettings};
+use crate::create::passwordhash::PasswordHasher;
+use crate::create::ratelimit::{MemoryStore, RateLimiter};
+use crate::create::socialidp::IdpClient;
+use crate::create::store::NewUser;
+use crate::create::templates::{EmailTemplates, RenderedEmail};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{test, App};
+use async_trait::async_trait;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+
+pub struct SentEmail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub html: String,
+}
+
+// Keeps every message instead of sending it, or fails like a down SMTP server
+#[derive(Default)]
+pub struct CapturedMailer {
+    sent: Mutex<Vec<SentEmail>>,
+    pub failing: AtomicBool,
+}
+
+impl CapturedMailer {
+    pub fn count(&self) -> usize {
+        self.sent.lock().unwrap().len()
+    }
+
+    pub fn last_to(&self, to: &str) -> SentEmail {
+        let sent = self.sent.lock().unwrap();
+        let email = sent.iter().rev().find(|email| email.to == to).expect("no email sent to this address");
+        SentEmail { to: email.to.clone(), subject: email.subject.clone(), body: email.body.clone(), html: email.html.clone() }
+    }
+}
+
+#[async_trait]
+impl Mailer for CapturedMailer {
+    async fn send(&self, to: &str, email: &RenderedEmail) -> Result<(), ServiceError> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(ServiceError::InternalServerError);
+        }
+        self.sent.lock().unwrap().push(SentEmail {
+            to: to.to_string(),
+            subject: email.subject.clone(),
+            body: email.text.clone(),
+            html: email.html.clone(),
+        });
+        Ok(())
+    }
+}
+
+// An OpenID provider at MockIdp::ISSUER that signs an id_token for each code granted to it
+pub struct MockIdp {
+    pkcs8: Vec<u8>,
+    codes: Mutex<HashMap<String, serde_json::Value>>,
+}
+
+impl Default for MockIdp {
+    fn default() -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        MockIdp { pkcs8: pkcs8.as_ref().to_vec(), codes: Mutex::default() }
+    }
+}
+
+impl MockIdp {
+    pub const ISSUER: &'static str = "https://idp.test";
+
+    // Redeeming `code` at the token endpoint returns these claims, plus iss, aud and the times
+    pub fn grant(&self, code: &str, claims: serde_json::Value) {
+        self.codes.lock().unwrap().insert(code.to_string(), claims);
+    }
+}
+
+#[async_trait]
+impl IdpClient for MockIdp {
+    async fn get_json(&self, url: &str) -> Result<serde_json::Value, String> {
+        let public_key = Ed25519KeyPair::from_pkcs8(&self.pkcs8).unwrap().public_key().as_ref().to_vec();
+        match url.strip_prefix(Self::ISSUER) {
+            Some("/.well-known/openid-configuration") => Ok(json!({
+                "issuer": Self::ISSUER,
+                "authorization_endpoint": format!("{}/authorize", Self::ISSUER),
+                "token_endpoint": format!("{}/token", Self::ISSUER),
+                "jwks_uri": format!("{}/jwks", Self::ISSUER),
+            })),
+            Some("/jwks") => Ok(json!({"keys": [{
+                "kty": "OKP", "crv": "Ed25519", "kid": "idp", "alg": "EdDSA", "use": "sig",
+                "x": base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, public_key),
+            }]})),
+            _ => Err(format!("404 for {}", url)),
+        }
+    }
+
+    async fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<(u16, serde_json::Value), String> {
+        assert_eq!(url, format!("{}/token", Self::ISSUER));
+        let field = |name: &str| form.iter().find(|(key, _)| *key == name).map(|(_, value)| *value).unwrap_or_default();
+        let Some(mut claims) = self.codes.lock().unwrap().remove(field("code")) else {
+            return Ok((400, json!({"error": "invalid_grant"})));
+        };
+
+        let now = Utc::now().timestamp();
+        claims["iss"] = json!(Self::ISSUER);
+        claims["aud"] = json!(field("client_id"));
+        claims["iat"] = json!(now);
+        claims["exp"] = json!(now + 300);
+        let mut header = Header::new(jsonwebtoken::Algorithm::EdDSA);
+        header.kid = Some("idp".to_string());
+        let id_token = encode(&header, &claims, &EncodingKey::from_ed_der(&self.pkcs8)).unwrap();
+        Ok((200, json!({"access_token": "unused", "token_type": "Bearer", "id_token": id_token})))
+    }
+}
+
+// A fresh Ed25519 key pair in PEM files; returns the JWT_KEYS entries with and without the private key
+pub fn ed25519_key(kid: &str) -> (String, String) {
+    let dir = std::env::temp_dir().join(format!("jwt-keys-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+    let public_key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap().public_key().as_ref().to_vec();
+    let mut spki = vec![0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+    spki.extend_from_slice(&public_key);
+
+    let (public_pem, private_pem) = (dir.join(format!("{}.pub.pem", kid)), dir.join(format!("{}.key.pem", kid)));
+    std::fs::write(&public_pem, pem::encode(&pem::Pem::new("PUBLIC KEY", spki))).unwrap();
+    std::fs::write(&private_pem, pem::encode(&pem::Pem::new("PRIVATE KEY", pkcs8.as_ref().to_vec()))).unwrap();
+
+    let verify_only = format!("{}:EdDSA:{}", kid, public_pem.display());
+    (format!("{}:{}", verify_only, private_pem.display()), verify_only)
+}
+
+// The value of `param` in the first link of the text part
+pub fn link_param(body: &str, param: &str) -> String {
+    let link = body.split_whitespace().find(|word| word.starts_with("https://")).expect("no link in email");
+    url::Url::parse(link)
+        .unwrap()
+        .query_pairs()
+        .find(|(name, _)| name == param)
+        .map(|(_, value)| value.into_owned())
+        .expect("parameter missing from link")
+}
+
+// The last word of the "Here is your 2FA code: XXXXXX" line
+pub fn code_in(body: &str) -> String {
+    let line = body.lines().find(|line| line.contains("code:")).expect("no code in email");
+    line.split_whitespace().last().unwrap().to_string()
+}
+
+// The templates shipped in the repository
+pub fn templates() -> EmailTemplates {
+    EmailTemplates::load(Path::new("templates/email"), "en", "LoginAPI".to_string(), None).unwrap()
+}
+
+fn init_env() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        env::set_var("JWT_SECRET", "test_secret");
+        env::set_var("EMAIL_VERIFICATION_ENABLED", "true");
+        env::set_var("VERIFICATION_BASE_URL", "https://app.test");
+        env::set_var("RESET_PASSWORD_BASE_URL", "https://app.test");
+        env::set_var("UNLOCK_ACCOUNT_BASE_URL", "https://app.test");
+        env::set_var("MAGIC_LINK_BASE_URL", "https://app.test");
+        env::set_var("SECURITY_ALERT_BASE_URL", "https://app.test");
+        env::set_var("EMAIL_CHANGE_BASE_URL", "https://app.test");
+        env::set_var("DATABASE_URL", "sqlite::memory:");
+        env::set_var("MAIL_TRANSPORT", "stdout");
+        env::set_var("TOTP_ENCRYPTION_KEY", "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=");
+        env::set_var("RECOVERY_CODE_KEY", "QEFCQ0RFRkdISUpLTE1OT1BRUlNUVVZXWFlaW1xdXl8=");
+        env::set_var("OUTBOX_ENCRYPTION_KEY", "ICEiIyQlJicoKSorLC0uLzAxMjM0NTY3ODk6Ozw9Pj8=");
+        // Argon2id at its minimum cost keeps the tests fast
+        env::set_var("ARGON2_MEMORY_KIB", "8");
+        env::set_var("ARGON2_ITERATIONS", "1");
+    });
+}
+
+pub struct TestContext {
+    pub store: Arc<dyn UserStore>,
+    pub mailer: Arc<CapturedMailer>,
+    pub outbox: Arc<Outbox>,
+    pub idp: Arc<MockIdp>,
+}
+
+impl TestContext {
+    // One pass of the outbox worker
+    pub async fn deliver(&self) -> usize {
+        self.outbox.deliver_due(self.mailer.as_ref()).await.unwrap()
+    }
+
+    pub async fn last_email_to(&self, to: &str) -> SentEmail {
+        self.deliver().await;
+        self.mailer.last_to(to)
+    }
+
+    pub async fn sent_count(&self) -> usize {
+        self.deliver().await;
+        self.mailer.count()
+    }
+}
+
+// Retries right away so tests don't wait on the backoff
+pub fn outbox_settings() -> OutboxSettings {
+    OutboxSettings {
+        batch_size: 20,
+        max_attempts: 3,
+        base_delay_seconds: 0,
+        max_delay_seconds: 0,
+        poll_interval_seconds: 1,
+        encryption_key: AppConfig::load().unwrap().outbox.encryption_key,
+    }
+}
+
+// A fresh in-memory database per test, migrated like a real deployment
+pub async fn context() -> TestContext {
+    init_env();
+    let store = crate::create::store::connect("sqlite::memory:", None).await.unwrap();
+    crate::create::migrations::migrate_up(store.as_ref(), None).await.unwrap();
+    let outbox = Arc::new(Outbox::new(store.clone(), outbox_settings()));
+    TestContext { store, mailer: Arc::new(CapturedMailer::default()), outbox, idp: Arc::new(MockIdp::default()) }
+}
+
+pub const PASSWORD: &str = "correct horse";
+
+// A verified account with PASSWORD, made without the registration emails; returns its address
+pub async fn create_user(ctx: &TestContext, username: &str) -> String {
+    let email = format!("{}@example.com", username);
+    let hash = PasswordHasher::new(&AppConfig::load().unwrap().hashing).hash(PASSWORD).await.unwrap();
+    ctx.store
+        .create_user(NewUser { username, email: &email, password_hash: &hash, verified: true, locale: None })
+        .await
+        .unwrap();
+    email
+}
+
+// The App of main.rs, minus TLS, CORS and logging
+pub async fn app(
+    ctx: &TestContext,
+) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    app_with_config(ctx, AppConfig::load().unwrap()).await
+}
+
+pub async fn app_with_config(
+    ctx: &TestContext,
+    config: AppConfig,
+) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    let emails = Emails::new(ctx.outbox.clone(), templates());
+    let features = config.features;
+    let policy = PasswordPolicy::from_config(&config.password).unwrap();
+    test::init_service(
+        App::new()
+            .wrap(RateLimiter::new(config.rate_limits.rules.clone(), Arc::new(MemoryStore::default())))
+            .app_data(Data::from(ctx.store.clone()))
+            .app_data(Data::<dyn IdpClient>::from(ctx.idp.clone() as Arc<dyn IdpClient>))
+            .app_data(Data::new(emails))
+            .app_data(Data::new(policy))
+            .app_data(Data::new(config))
+            .configure(|cfg| crate::configure_services(cfg, features)),
+    )
+    .await
+}
+
+pub async fn post_json<S, B>(app: &S, path: &str, body: serde_json::Value, bearer: Option<&str>) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let mut req = test::TestRequest::post().uri(path).set_json(body);
+    if let Some(token) = bearer {
+        req = req.insert_header((http::header::AUTHORIZATION, format!("Bearer {}", token)));
+    }
+    send(app, req).await
+}
+
+pub async fn get<S, B>(app: &S, path: &str) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    send(app, test::TestRequest::get().uri(path)).await
+}
+
+pub async fn send<S, B>(app: &S, req: test::TestRequest) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let response = test::call_service(app, req.to_request()).await;
+    let status = response.status().as_u16();
+    let body = test::read_body(response).await;
+    (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+}
+
+// Password login for an account without 2FA; returns the access token
+pub async fn login_token<S, B>(app: &S, username: &str) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, body) = post_json(app, "/login", json!({"username": username, "password": PASSWORD}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    body["token"].as_str().unwrap().to_string()
+}
@@ -0,0 +1,91 @@
+// notifications.rs
+
+use super::*;
+use serde_json::json;
+
+const PASSWORD: &str = "correct horse";
+
+async fn login_from<S, B>(app: &S, user_agent: &str, password: &str) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .insert_header((http::header::USER_AGENT, user_agent))
+        .set_json(json!({"username": "grace", "password": password}));
+    send(app, req).await
+}
+
+#[actix_web::test]
+async fn new_device_alert_leads_to_a_forced_reset() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    let email = "grace@example.com";
+
+    let (status, _) = post_json(&app, "/create_account", json!({"username": "grace", "email": email, "password": PASSWORD}), None).await;
+    assert_eq!(status, 200);
+    let token = link_param(&ctx.last_email_to(email).await.body, "token");
+    get(&app, &format!("/verify?token={}", token)).await;
+
+    // the first device and known devices log in quietly
+    let (status, body) = login_from(&app, "Laptop/1.0", PASSWORD).await;
+    assert_eq!(status, 200);
+    let refresh_token = body["refresh_token"].as_str().unwrap().to_string();
+    login_from(&app, "Laptop/1.0", PASSWORD).await;
+    let sent = ctx.sent_count().await;
+
+    let (status, _) = login_from(&app, "Unknown Phone/2.0", PASSWORD).await;
+    assert_eq!(status, 200);
+    assert_eq!(ctx.sent_count().await, sent + 1);
+    let alert = ctx.last_email_to(email).await;
+    assert_eq!(alert.subject, "New sign-in to your account");
+    assert!(alert.body.contains("Device: Unknown Phone/2.0"));
+    assert!(alert.html.contains("This wasn't me"));
+
+    // "this wasn't me" signs out everywhere and blocks the password until it is reset
+    let not_me = link_param(&alert.body, "token");
+
+    // Opening the link, as a mail scanner would, only shows the confirmation form
+    let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/not_me?token={}", not_me)).to_request()).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let page = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(page.contains(&format!(r#"name="token" value="{}""#, not_me)), "{}", page);
+    let (status, body) = post_json(&app, "/token/refresh", json!({"refresh_token": refresh_token}), None).await;
+    assert_eq!(status, 200);
+    let refresh_token = body["refresh_token"].as_str().unwrap().to_string();
+
+    let form = test::TestRequest::post().uri("/not_me").set_form([("token", not_me.as_str())]);
+    let (status, _) = send(&app, form).await;
+    assert_eq!(status, 200);
+    let (status, _) = post_json(&app, "/not_me", json!({"token": not_me}), None).await;
+    assert_eq!(status, 400);
+
+    let (status, _) = post_json(&app, "/token/refresh", json!({"refresh_token": refresh_token}), None).await;
+    assert_eq!(status, 401);
+    let (status, _) = login_from(&app, "Laptop/1.0", PASSWORD).await;
+    assert_eq!(status, 401);
+
+    let reset = ctx.last_email_to(email).await;
+    assert_eq!(reset.subject, "Reset Your Password");
+
+    // Nor does any other way in
+    let (status, _) = post_json(&app, "/login/magic_link", json!({"email": email, "device_fingerprint": "laptop"}), None).await;
+    assert_eq!(status, 200);
+    let link = link_param(&ctx.last_email_to(email).await.body, "token");
+    let (status, body) = post_json(&app, "/login/magic_link/verify", json!({"token": link, "device_fingerprint": "laptop"}), None).await;
+    assert_eq!(status, 401);
+    assert_eq!(body, "This account needs a new password. Check your email for the reset link.");
+
+    let (status, _) = post_json(
+        &app,
+        "/reset_password",
+        json!({"email": email, "token": link_param(&reset.body, "token"), "new_password": "battery staple"}),
+        None,
+    ).await;
+    assert_eq!(status, 200);
+    assert_eq!(ctx.last_email_to(email).await.subject, "Your password was changed");
+
+    let (status, _) = login_from(&app, "Laptop/1.0", "battery staple").await;
+    assert_eq!(status, 200);
+}
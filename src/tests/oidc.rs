@@ -0,0 +1,260 @@
+// oidc.rs
+
+use super::*;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+const REDIRECT_URI: &str = "https://client.test/callback";
+const VERIFIER: &str = "a-code-verifier-long-enough-to-satisfy-rfc-7636-requirements";
+
+// ID tokens need an asymmetric key, so the provider signs with a fresh Ed25519 one
+fn oidc_config() -> AppConfig {
+    let file: toml::Table = format!(
+        r#"
+        [jwt]
+        keys = ["{}"]
+
+        [oidc]
+        issuer = "https://api.test"
+        login_url = "https://app.test/login"
+        registration_token = "registration-secret"
+        "#,
+        ed25519_key("test").0,
+    ).parse().unwrap();
+    AppConfig::from_sources(file).unwrap()
+}
+
+async fn register_client<S, B>(app: &S) -> (String, String)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, body) = post_json(
+        app,
+        "/oauth/register",
+        json!({"client_name": "Client", "redirect_uris": [REDIRECT_URI]}),
+        Some("registration-secret"),
+    ).await;
+    assert_eq!(status, 201, "{}", body);
+    (body["client_id"].as_str().unwrap().to_string(), body["client_secret"].as_str().unwrap().to_string())
+}
+
+// Runs /authorize and returns the request_id the login page receives
+async fn authorize<S, B>(app: &S, client_id: &str, scope: &str) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(VERIFIER.as_bytes()));
+    let uri = format!(
+        "/authorize?response_type=code&client_id={}&redirect_uri={}&scope={}&state=xyz&nonce=n-0S6&code_challenge={}&code_challenge_method=S256",
+        client_id,
+        urlencoding::encode(REDIRECT_URI),
+        urlencoding::encode(scope),
+        challenge,
+    );
+    let response = test::call_service(app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(response.status().as_u16(), 302);
+    let location = response.headers().get(http::header::LOCATION).unwrap().to_str().unwrap();
+    assert!(location.starts_with("https://app.test/login?"), "{}", location);
+    link_param(location, "request_id")
+}
+
+fn code_from(redirect_to: &serde_json::Value) -> String {
+    let redirect_to = redirect_to.as_str().unwrap();
+    assert!(redirect_to.starts_with(REDIRECT_URI), "{}", redirect_to);
+    assert_eq!(link_param(redirect_to, "state"), "xyz");
+    link_param(redirect_to, "code")
+}
+
+async fn login_code<S, B>(app: &S, client_id: &str, username: &str) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let request_id = authorize(app, client_id, "openid email").await;
+    let (status, body) = post_json(app, "/authorize/login", json!({"request_id": request_id, "username": username, "password": PASSWORD}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    code_from(&body["redirect_to"])
+}
+
+async fn token<S, B>(app: &S, form: &impl Serialize) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    send(app, test::TestRequest::post().uri("/token").set_form(form)).await
+}
+
+#[actix_web::test]
+async fn authorization_code_flow_with_pkce() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, oidc_config()).await;
+    create_user(&ctx, "alice").await;
+    let (client_id, client_secret) = register_client(&app).await;
+
+    // A wrong verifier fails, and the code is spent anyway
+    let code = login_code(&app, &client_id, "alice").await;
+    let exchange = |code: &str, verifier: &str| {
+        vec![
+            ("grant_type", "authorization_code".to_string()),
+            ("code", code.to_string()),
+            ("redirect_uri", REDIRECT_URI.to_string()),
+            ("code_verifier", verifier.to_string()),
+            ("client_id", client_id.clone()),
+            ("client_secret", client_secret.clone()),
+        ]
+    };
+    let form = exchange(&code, "not-the-verifier");
+    let (status, body) = token(&app, &form).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error_description"], "PKCE verification failed.");
+    let form = exchange(&code, VERIFIER);
+    let (status, body) = token(&app, &form).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"], "invalid_grant");
+
+    let code = login_code(&app, &client_id, "alice").await;
+    let form = exchange(&code, VERIFIER);
+    let (status, body) = token(&app, &form).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["scope"], "openid email");
+    let header = jsonwebtoken::decode_header(body["id_token"].as_str().unwrap()).unwrap();
+    assert_eq!(header.alg, jsonwebtoken::Algorithm::EdDSA);
+    assert_eq!(header.kid.as_deref(), Some("test"));
+
+    let (_, jwks) = get(&app, "/.well-known/jwks.json").await;
+    assert_eq!(jwks["keys"][0]["kid"], "test");
+}
+
+#[actix_web::test]
+async fn client_tokens_stay_with_their_client() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, oidc_config()).await;
+    create_user(&ctx, "alice").await;
+    let (client_id, client_secret) = register_client(&app).await;
+    let (other_id, other_secret) = register_client(&app).await;
+
+    let code = login_code(&app, &client_id, "alice").await;
+    let (status, body) = token(&app, &[
+        ("grant_type", "authorization_code"),
+        ("code", &code),
+        ("redirect_uri", REDIRECT_URI),
+        ("code_verifier", VERIFIER),
+        ("client_id", &client_id),
+        ("client_secret", &client_secret),
+    ]).await;
+    assert_eq!(status, 200, "{}", body);
+    let access_token = body["access_token"].as_str().unwrap().to_string();
+    let refresh_token = body["refresh_token"].as_str().unwrap().to_string();
+
+    // /userinfo answers within the granted scopes
+    let req = test::TestRequest::get().uri("/userinfo").insert_header((http::header::AUTHORIZATION, format!("Bearer {}", access_token)));
+    let (status, body) = send(&app, req).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["email"], "alice@example.com");
+    assert!(body.get("preferred_username").is_none());
+
+    // The API's own endpoints refuse the client's token
+    for path in ["/logout_all", "/enroll_totp", "/webauthn/register/start"] {
+        let (status, body) = post_json(&app, path, json!({}), Some(&access_token)).await;
+        assert_eq!(status, 401, "{}", path);
+        assert_eq!(body, "This token was issued to a client application");
+    }
+
+    // Its refresh token only rotates at /token, for the same client
+    let (status, _) = post_json(&app, "/token/refresh", json!({"refresh_token": refresh_token}), None).await;
+    assert_eq!(status, 401);
+    let refresh = |id: &str, secret: &str| {
+        vec![("grant_type", "refresh_token".to_string()), ("refresh_token", refresh_token.clone()), ("client_id", id.to_string()), ("client_secret", secret.to_string())]
+    };
+    let form = refresh(&other_id, &other_secret);
+    let (status, body) = token(&app, &form).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"], "invalid_grant");
+    let form = refresh(&client_id, &client_secret);
+    let (status, body) = token(&app, &form).await;
+    assert_eq!(status, 200, "{}", body);
+    let (status, _) = post_json(&app, "/logout", json!({}), Some(body["access_token"].as_str().unwrap())).await;
+    assert_eq!(status, 401);
+}
+
+#[actix_web::test]
+async fn a_replayed_code_revokes_the_session_it_was_exchanged_for() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, oidc_config()).await;
+    create_user(&ctx, "alice").await;
+    let (client_id, client_secret) = register_client(&app).await;
+
+    let code = login_code(&app, &client_id, "alice").await;
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", &code),
+        ("redirect_uri", REDIRECT_URI),
+        ("code_verifier", VERIFIER),
+        ("client_id", &client_id),
+        ("client_secret", &client_secret),
+    ];
+    let (status, body) = token(&app, &form).await;
+    assert_eq!(status, 200, "{}", body);
+    let access_token = body["access_token"].as_str().unwrap().to_string();
+    let refresh_token = body["refresh_token"].as_str().unwrap().to_string();
+
+    let (status, body) = token(&app, &form).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"], "invalid_grant");
+
+    let req = test::TestRequest::get().uri("/userinfo").insert_header((http::header::AUTHORIZATION, format!("Bearer {}", access_token)));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, 401);
+    let (status, body) = token(&app, &[
+        ("grant_type", "refresh_token"),
+        ("refresh_token", &refresh_token),
+        ("client_id", &client_id),
+        ("client_secret", &client_secret),
+    ]).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"], "invalid_grant");
+}
+
+#[actix_web::test]
+async fn another_users_temp_token_does_not_finish_an_authorization() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, oidc_config()).await;
+    create_user(&ctx, "alice").await;
+    create_user(&ctx, "mallory").await;
+    ctx.store.enable_email_2fa("alice").await.unwrap();
+    ctx.store.enable_email_2fa("mallory").await.unwrap();
+    let (client_id, _) = register_client(&app).await;
+
+    let request_id = authorize(&app, &client_id, "openid").await;
+    let (status, body) = post_json(&app, "/authorize/login", json!({"request_id": request_id, "username": "alice", "password": PASSWORD}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    let alice_temp_token = body["temp_token"].as_str().unwrap().to_string();
+    let alice_code = code_in(&ctx.last_email_to("alice@example.com").await.body);
+
+    let (_, body) = post_json(&app, "/login", json!({"username": "mallory", "password": PASSWORD}), None).await;
+    let mallory_temp_token = body["temp_token"].as_str().unwrap().to_string();
+    let mallory_code = code_in(&ctx.last_email_to("mallory@example.com").await.body);
+
+    let (status, _) = post_json(
+        &app,
+        "/authorize/verify_2fa",
+        json!({"request_id": request_id, "temp_token": mallory_temp_token, "code": mallory_code}),
+        None,
+    ).await;
+    assert_eq!(status, 400);
+
+    // Neither temp_token was spent by the rejected attempt
+    let (status, body) = post_json(&app, "/verify_2fa", json!({"temp_token": mallory_temp_token, "code": mallory_code}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    let (status, body) = post_json(
+        &app,
+        "/authorize/verify_2fa",
+        json!({"request_id": request_id, "temp_token": alice_temp_token, "code": alice_code}),
+        None,
+    ).await;
+    assert_eq!(status, 200, "{}", body);
+    code_from(&body["redirect_to"]);
+}
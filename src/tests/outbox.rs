@@ -0,0 +1,61 @@
+// outbox.rs
+
+use super::*;
+use serde_json::json;
+
+#[actix_web::test]
+async fn smtp_failures_are_retried_then_dead_lettered() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    ctx.mailer.failing.store(true, Ordering::SeqCst);
+
+    // the request no longer depends on the mail server
+    let (status, _) = post_json(&app, "/forgot_password", json!({"email": "frank@example.com"}), None).await;
+    assert_eq!(status, 200);
+
+    ctx.deliver().await;
+    let status = ctx.store.find_outbox_status(1).await.unwrap().unwrap();
+    assert_eq!((status.status.as_str(), status.attempts), ("pending", 1));
+    assert!(status.last_error.is_some());
+
+    // max_attempts is 3 in tests
+    ctx.deliver().await;
+    ctx.deliver().await;
+    let status = ctx.store.find_outbox_status(1).await.unwrap().unwrap();
+    assert_eq!((status.status.as_str(), status.attempts), ("dead", 3));
+    assert_eq!(ctx.deliver().await, 0);
+
+    ctx.mailer.failing.store(false, Ordering::SeqCst);
+    let (status, _) = post_json(&app, "/forgot_password", json!({"email": "frank@example.com"}), None).await;
+    assert_eq!(status, 200);
+    assert_eq!(ctx.last_email_to("frank@example.com").await.subject, "Reset Your Password");
+
+    let status = ctx.store.find_outbox_status(2).await.unwrap().unwrap();
+    assert_eq!((status.status.as_str(), status.last_error), ("sent", None));
+    assert_eq!(ctx.store.count_outbox_emails().await.unwrap(), vec![("dead".to_string(), 1), ("sent".to_string(), 1)]);
+}
+
+#[actix_web::test]
+async fn queued_bodies_are_encrypted_and_unreadable_rows_dead_lettered() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+
+    let (status, _) = post_json(&app, "/forgot_password", json!({"email": "alice@example.com"}), None).await;
+    assert_eq!(status, 200);
+    let queued = ctx.store.claim_due_emails(10, Utc::now().naive_utc()).await.unwrap();
+    assert_eq!(queued.len(), 1);
+    assert!(!queued[0].text_body.contains("reset_password"), "{}", queued[0].text_body);
+    assert!(!queued[0].html_body.contains("reset_password"));
+
+    // The worker still sends the plain message
+    let email = ctx.last_email_to("alice@example.com").await;
+    assert!(email.body.contains("/reset_password?token="), "{}", email.body);
+
+    // e.g. a row left over from before bodies were encrypted
+    let id = ctx.store.enqueue_email("alice@example.com", "Legacy", "plain text", "<p>plain</p>").await.unwrap();
+    assert_eq!(ctx.deliver().await, 1);
+    let status = ctx.store.find_outbox_status(id).await.unwrap().unwrap();
+    assert_eq!(status.status, "dead");
+    assert_eq!(ctx.mailer.last_to("alice@example.com").subject, email.subject);
+}
@@ -0,0 +1,185 @@
+// passwords.rs
+
+use super::*;
+use crate::create::store::NewUser;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+
+#[actix_web::test]
+async fn weak_and_breached_passwords_are_rejected_with_reasons() {
+    let ctx = context().await;
+    let breached = std::env::temp_dir().join(format!("breached-{}.txt", Uuid::new_v4()));
+    let digest = hex::encode_upper(Sha1::digest(b"purple elephant 42"));
+    std::fs::write(&breached, format!("{}:1234\n", digest)).unwrap();
+
+    let mut config = AppConfig::load().unwrap();
+    config.password.breached_passwords_file = Some(breached.to_string_lossy().into_owned());
+    let app = app_with_config(&ctx, config).await;
+
+    let (status, body) = post_json(&app, "/create_account", json!({"username": "ivan", "email": "ivan@example.com", "password": "Password1"}), None).await;
+    assert_eq!(status, 400);
+    let rules: Vec<&str> = body["reasons"].as_array().unwrap().iter().map(|reason| reason["rule"].as_str().unwrap()).collect();
+    assert_eq!(rules, ["strength"]);
+
+    let (status, body) = post_json(&app, "/create_account", json!({"username": "ivan", "email": "ivan@example.com", "password": "ivan"}), None).await;
+    assert_eq!(status, 400);
+    let rules: Vec<&str> = body["reasons"].as_array().unwrap().iter().map(|reason| reason["rule"].as_str().unwrap()).collect();
+    assert_eq!(rules, ["min_length", "personal_info", "strength"]);
+
+    let (status, body) = post_json(&app, "/create_account", json!({"username": "ivan", "email": "ivan@example.com", "password": "purple elephant 42"}), None).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["reasons"][0]["rule"], "breached");
+    assert!(body["reasons"][0]["message"].as_str().unwrap().contains("1234"));
+
+    let (status, body) = post_json(&app, "/create_account", json!({"username": "ivan", "email": "ivan@example.com", "password": "purple elephant 43"}), None).await;
+    assert_eq!(status, 200, "{}", body);
+
+    std::fs::remove_file(breached).ok();
+}
+
+#[actix_web::test]
+async fn bcrypt_hashes_are_upgraded_to_argon2id_on_login() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+
+    let legacy_hash = bcrypt::hash("correct horse battery", 4).unwrap();
+    ctx.store.create_user(NewUser {
+        username: "ivan",
+        email: "ivan@example.com",
+        password_hash: &legacy_hash,
+        verified: true,
+        locale: None,
+    }).await.unwrap();
+
+    let (status, _) = post_json(&app, "/login", json!({"username": "ivan", "password": "wrong horse battery"}), None).await;
+    assert_eq!(status, 400);
+    assert_eq!(ctx.store.find_user("ivan").await.unwrap().unwrap().password_hash, legacy_hash);
+
+    let (status, body) = post_json(&app, "/login", json!({"username": "ivan", "password": "correct horse battery"}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    let upgraded = ctx.store.find_user("ivan").await.unwrap().unwrap().password_hash;
+    assert!(upgraded.starts_with("$argon2id$v=19$m=8,t=1,p=1$"), "{}", upgraded);
+
+    let (status, body) = post_json(&app, "/login", json!({"username": "ivan", "password": "correct horse battery"}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(ctx.store.find_user("ivan").await.unwrap().unwrap().password_hash, upgraded);
+}
+
+#[actix_web::test]
+async fn change_password_needs_the_current_password_and_a_2fa_code() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    let email = "judy@example.com";
+
+    let password_hash = bcrypt::hash("correct horse battery", 4).unwrap();
+    ctx.store.create_user(NewUser {
+        username: "judy",
+        email,
+        password_hash: &password_hash,
+        verified: true,
+        locale: None,
+    }).await.unwrap();
+
+    let login = json!({"username": "judy", "password": "correct horse battery"});
+    let (_, current) = post_json(&app, "/login", login.clone(), None).await;
+    let (_, other) = post_json(&app, "/login", login.clone(), None).await;
+    let access_token = current["token"].as_str().unwrap();
+    ctx.store.enable_email_2fa("judy").await.unwrap();
+
+    let (status, body) = post_json(&app, "/change_password", json!({"current_password": "wrong horse battery", "new_password": "staple battery orbit"}), Some(access_token)).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, "Current password is incorrect.");
+    let (status, body) = post_json(&app, "/change_password", json!({"current_password": "correct horse battery", "new_password": "judy2024"}), Some(access_token)).await;
+    assert_eq!(status, 400);
+    assert!(body["reasons"].as_array().unwrap().iter().any(|reason| reason["rule"] == "personal_info"), "{}", body);
+
+    // the first request only emails a code
+    let change = json!({"current_password": "correct horse battery", "new_password": "staple battery orbit"});
+    let (status, body) = post_json(&app, "/change_password", change.clone(), Some(access_token)).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["status"], "2fa_required");
+    let code = code_in(&ctx.last_email_to(email).await.body);
+
+    let mut confirmed = change.clone();
+    confirmed["code"] = json!(code);
+    confirmed["token"] = body["token"].clone();
+    let (status, body) = post_json(&app, "/change_password", confirmed.clone(), Some(access_token)).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(ctx.last_email_to(email).await.subject, "Your password was changed");
+    let (status, _) = post_json(&app, "/change_password", confirmed, Some(access_token)).await;
+    assert_eq!(status, 400);
+
+    // only the session that made the change survives
+    let (status, _) = post_json(&app, "/token/refresh", json!({"refresh_token": other["refresh_token"]}), None).await;
+    assert_eq!(status, 401);
+    let (status, _) = post_json(&app, "/token/refresh", json!({"refresh_token": current["refresh_token"]}), None).await;
+    assert_eq!(status, 200);
+
+    let (status, _) = post_json(&app, "/login", login, None).await;
+    assert_eq!(status, 400);
+    let (status, body) = post_json(&app, "/login", json!({"username": "judy", "password": "staple battery orbit"}), None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "2fa_required");
+}
+
+#[actix_web::test]
+async fn wrong_2fa_codes_on_change_password_burn_the_pending_change() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+    let access_token = login_token(&app, "alice").await;
+    ctx.store.enable_email_2fa("alice").await.unwrap();
+
+    let change = json!({"current_password": PASSWORD, "new_password": "staple battery orbit"});
+    let (_, body) = post_json(&app, "/change_password", change.clone(), Some(&access_token)).await;
+    let mut confirmed = change.clone();
+    confirmed["code"] = json!(code_in(&ctx.last_email_to("alice@example.com").await.body));
+    confirmed["token"] = body["token"].clone();
+    let mut guess = confirmed.clone();
+    guess["code"] = json!("000000");
+
+    for _ in 1..5 {
+        let (status, body) = post_json(&app, "/change_password", guess.clone(), Some(&access_token)).await;
+        assert_eq!((status, body), (400, json!("Invalid code or token")));
+    }
+    let (status, body) = post_json(&app, "/change_password", guess, Some(&access_token)).await;
+    assert_eq!((status, body), (400, json!("Too many invalid codes. Please log in again.")));
+
+    // The right code came too late
+    let (status, _) = post_json(&app, "/change_password", confirmed, Some(&access_token)).await;
+    assert_eq!(status, 400);
+    let (status, _) = post_json(&app, "/login", json!({"username": "alice", "password": PASSWORD}), None).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn password_change_codes_are_kept_apart_from_2fa_changes() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+    let access_token = login_token(&app, "alice").await;
+    ctx.store.enable_email_2fa("alice").await.unwrap();
+
+    let (status, body) = post_json(&app, "/request_deactivate_2fa", json!({}), Some(&access_token)).await;
+    assert_eq!(status, 200, "{}", body);
+    let deactivation = json!({"username": "alice", "code": code_in(&ctx.last_email_to("alice@example.com").await.body), "token": body["token"]});
+
+    let change = json!({"current_password": PASSWORD, "new_password": "staple battery orbit"});
+    let (_, body) = post_json(&app, "/change_password", change.clone(), Some(&access_token)).await;
+    let code = code_in(&ctx.last_email_to("alice@example.com").await.body);
+
+    // The password change challenge can't turn 2FA off
+    let stolen = json!({"username": "alice", "code": code, "token": body["token"]});
+    let (status, _) = post_json(&app, "/verify_2fa_deactivation", stolen, None).await;
+    assert_eq!(status, 400);
+
+    let mut confirmed = change;
+    confirmed["code"] = json!(code);
+    confirmed["token"] = body["token"].clone();
+    let (status, body) = post_json(&app, "/change_password", confirmed, Some(&access_token)).await;
+    assert_eq!(status, 200, "{}", body);
+
+    // and asking for it left the pending deactivation alone
+    let (status, body) = post_json(&app, "/verify_2fa_deactivation", deactivation, None).await;
+    assert_eq!(status, 200, "{}", body);
+}
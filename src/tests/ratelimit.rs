@@ -0,0 +1,75 @@
+// ratelimit.rs
+
+use super::*;
+use serde_json::json;
+
+fn rules_config(rules: &[&str]) -> Result<AppConfig, Vec<String>> {
+    let file: toml::Table = format!("[rate_limits]\nrules = {:?}", rules).parse().unwrap();
+    AppConfig::from_sources(file)
+}
+
+#[actix_web::test]
+async fn configured_rules_replace_the_defaults() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, rules_config(&["/login:username:2/60"]).unwrap()).await;
+    create_user(&ctx, "alice").await;
+    create_user(&ctx, "bob").await;
+
+    for _ in 0..2 {
+        let (status, _) = post_json(&app, "/login", json!({"username": "alice", "password": PASSWORD}), None).await;
+        assert_eq!(status, 200);
+    }
+    let (status, _) = post_json(&app, "/login", json!({"username": "Alice", "password": PASSWORD}), None).await;
+    assert_eq!(status, 429);
+
+    // Other accounts have their own bucket
+    let (status, _) = post_json(&app, "/login", json!({"username": "bob", "password": PASSWORD}), None).await;
+    assert_eq!(status, 200);
+
+    let errors = rules_config(&["/login:password:2/60", "/login:ip:2/0"]).err().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("RATE_LIMITS (rate_limits.rules) is invalid"), "{}", errors[0]);
+}
+
+#[actix_web::test]
+async fn guessing_login_links_is_limited_per_ip() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+
+    for _ in 0..30 {
+        let (status, _) = post_json(&app, "/login/magic_link/verify", json!({"token": "guess", "device_fingerprint": "laptop"}), None).await;
+        assert_eq!(status, 401);
+    }
+    let (status, _) = post_json(&app, "/login/magic_link/verify", json!({"token": "guess", "device_fingerprint": "laptop"}), None).await;
+    assert_eq!(status, 429);
+
+    // A capacity of 0 lifts the limit
+    let app = app_with_config(&ctx, rules_config(&["/login/magic_link/verify:ip:0/60"]).unwrap()).await;
+    for _ in 0..31 {
+        let (status, _) = post_json(&app, "/login/magic_link/verify", json!({"token": "guess", "device_fingerprint": "laptop"}), None).await;
+        assert_eq!(status, 401);
+    }
+}
+
+#[actix_web::test]
+async fn email_changes_are_limited_per_account() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+    create_user(&ctx, "bob").await;
+    let alice = login_token(&app, "alice").await;
+    let bob = login_token(&app, "bob").await;
+
+    for attempt in 0..5 {
+        let change = json!({"current_password": PASSWORD, "new_email": format!("alice{}@example.com", attempt)});
+        let (status, body) = post_json(&app, "/change_email", change, Some(&alice)).await;
+        assert_eq!(status, 200, "{}", body);
+    }
+    let change = json!({"current_password": PASSWORD, "new_email": "alice5@example.com"});
+    let (status, _) = post_json(&app, "/change_email", change, Some(&alice)).await;
+    assert_eq!(status, 429);
+
+    let change = json!({"current_password": PASSWORD, "new_email": "bob2@example.com"});
+    let (status, _) = post_json(&app, "/change_email", change, Some(&bob)).await;
+    assert_eq!(status, 200);
+}
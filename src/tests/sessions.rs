@@ -0,0 +1,88 @@
+// sessions.rs
+
+use super::*;
+use serde_json::json;
+
+#[actix_web::test]
+async fn malformed_authorization_headers_are_refused() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+
+    let header = http::header::HeaderValue::from_bytes(b"Bearer \xff\xfe").unwrap();
+    let req = test::TestRequest::post().uri("/logout").insert_header((http::header::AUTHORIZATION, header));
+    let (status, body) = send(&app, req).await;
+    assert_eq!(status, 401);
+    assert_eq!(body, "Invalid authorization header format");
+
+    let (status, _) = post_json(&app, "/logout", json!({}), None).await;
+    assert_eq!(status, 401);
+}
+
+async fn login_pair<S, B>(app: &S) -> (String, String)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, body) = post_json(app, "/login", json!({"username": "alice", "password": PASSWORD}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    (body["token"].as_str().unwrap().to_string(), body["refresh_token"].as_str().unwrap().to_string())
+}
+
+#[actix_web::test]
+async fn a_reused_refresh_token_revokes_its_whole_family() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+    let (_, first_refresh) = login_pair(&app).await;
+    let (other_token, _) = login_pair(&app).await;
+
+    let (status, body) = post_json(&app, "/token/refresh", json!({"refresh_token": first_refresh}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    let rotated_token = body["token"].as_str().unwrap().to_string();
+    let rotated_refresh = body["refresh_token"].as_str().unwrap().to_string();
+    assert_ne!(rotated_refresh, first_refresh);
+
+    // The rotated-out token comes back, so whoever holds the current one loses it too
+    let (status, body) = post_json(&app, "/token/refresh", json!({"refresh_token": first_refresh}), None).await;
+    assert_eq!(status, 401);
+    assert_eq!(body, "Refresh token reuse detected. Please log in again.");
+    let (status, _) = post_json(&app, "/token/refresh", json!({"refresh_token": rotated_refresh}), None).await;
+    assert_eq!(status, 401);
+    let (status, _) = post_json(&app, "/logout", json!({}), Some(&rotated_token)).await;
+    assert_eq!(status, 401);
+
+    // Other sessions are another family
+    let (status, _) = post_json(&app, "/logout", json!({}), Some(&other_token)).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn logout_ends_one_session_and_logout_all_ends_every_one() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+    let (laptop, laptop_refresh) = login_pair(&app).await;
+    let (phone, phone_refresh) = login_pair(&app).await;
+    let (tablet, _) = login_pair(&app).await;
+
+    let (status, _) = post_json(&app, "/logout", json!({}), Some(&laptop)).await;
+    assert_eq!(status, 200);
+    let (status, _) = post_json(&app, "/logout", json!({}), Some(&laptop)).await;
+    assert_eq!(status, 401);
+    let (status, _) = post_json(&app, "/token/refresh", json!({"refresh_token": laptop_refresh}), None).await;
+    assert_eq!(status, 401);
+
+    // The phone keeps working until logout_all from the tablet
+    let (status, body) = post_json(&app, "/token/refresh", json!({"refresh_token": phone_refresh}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    let phone_refresh = body["refresh_token"].as_str().unwrap().to_string();
+    let (status, _) = post_json(&app, "/logout_all", json!({}), Some(&tablet)).await;
+    assert_eq!(status, 200);
+
+    for token in [&phone, &tablet] {
+        let (status, _) = post_json(&app, "/logout", json!({}), Some(token)).await;
+        assert_eq!(status, 401);
+    }
+    let (status, _) = post_json(&app, "/token/refresh", json!({"refresh_token": phone_refresh}), None).await;
+    assert_eq!(status, 401);
+}
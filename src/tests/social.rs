@@ -0,0 +1,126 @@
+// social.rs
+
+use super::*;
+use serde_json::json;
+
+fn social_config() -> AppConfig {
+    let file: toml::Table = format!(
+        r#"
+        [social]
+        providers = ["acme"]
+        redirect_base_url = "https://api.test"
+
+        [social.acme]
+        issuer = "{}"
+        client_id = "api"
+        client_secret = "api-secret"
+        "#,
+        MockIdp::ISSUER,
+    ).parse().unwrap();
+    AppConfig::from_sources(file).unwrap()
+}
+
+// Goes to the provider and back; `claims` are what the provider vouches for, the nonce is added here
+async fn social_login<S, B>(ctx: &TestContext, app: &S, claims: serde_json::Value) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let response = test::call_service(app, test::TestRequest::get().uri("/social/acme/authorize").to_request()).await;
+    assert_eq!(response.status().as_u16(), 302);
+    let location = response.headers().get(http::header::LOCATION).unwrap().to_str().unwrap().to_string();
+    assert!(location.starts_with("https://idp.test/authorize?"), "{}", location);
+    assert_eq!(link_param(&location, "redirect_uri"), "https://api.test/social/acme/callback");
+
+    let mut claims = claims;
+    claims["nonce"] = json!(link_param(&location, "nonce"));
+    let code = Uuid::new_v4().to_string();
+    ctx.idp.grant(&code, claims);
+
+    let uri = format!("/social/acme/callback?code={}&state={}", code, link_param(&location, "state"));
+    send(app, test::TestRequest::get().uri(&uri)).await
+}
+
+#[actix_web::test]
+async fn first_social_login_creates_an_account_and_later_ones_reuse_it() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, social_config()).await;
+
+    let identity = json!({"sub": "acme-1", "email": "new@example.com", "email_verified": true, "preferred_username": "newbie"});
+    let (status, body) = social_login(&ctx, &app, identity).await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(body["token"].is_string());
+
+    let user = ctx.store.find_user_by_email("new@example.com").await.unwrap().unwrap();
+    assert_eq!(user.username, "newbie");
+    assert!(user.verified);
+
+    // The same subject logs into the same account, even with another email at the provider
+    let (status, body) = social_login(&ctx, &app, json!({"sub": "acme-1", "email": "moved@example.com", "email_verified": true})).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(ctx.store.find_social_identity("acme", "acme-1").await.unwrap().as_deref(), Some("newbie"));
+    assert!(ctx.store.find_user_by_email("moved@example.com").await.unwrap().is_none());
+}
+
+#[actix_web::test]
+async fn verified_accounts_are_linked_by_email() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, social_config()).await;
+    create_user(&ctx, "alice").await;
+
+    let (status, body) = social_login(&ctx, &app, json!({"sub": "acme-2", "email": "alice@example.com", "email_verified": true})).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(ctx.store.find_social_identity("acme", "acme-2").await.unwrap().as_deref(), Some("alice"));
+
+    // Linking leaves the password alone
+    let (status, _) = post_json(&app, "/login", json!({"username": "alice", "password": PASSWORD}), None).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn unverified_accounts_are_reclaimed_before_linking() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, social_config()).await;
+
+    // Someone registered the address without ever confirming it
+    let hash = PasswordHasher::new(&social_config().hashing).hash(PASSWORD).await.unwrap();
+    ctx.store
+        .create_user(NewUser { username: "squatter", email: "victim@example.com", password_hash: &hash, verified: false, locale: None })
+        .await
+        .unwrap();
+
+    let (status, body) = social_login(&ctx, &app, json!({"sub": "acme-3", "email": "victim@example.com", "email_verified": true})).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(ctx.store.find_social_identity("acme", "acme-3").await.unwrap().as_deref(), Some("squatter"));
+
+    // The account is the provider user's now, and the squatter's password no longer opens it
+    assert!(ctx.store.find_user("squatter").await.unwrap().unwrap().verified);
+    let (status, _) = post_json(&app, "/login", json!({"username": "squatter", "password": PASSWORD}), None).await;
+    assert_eq!(status, 400);
+}
+
+#[actix_web::test]
+async fn unverified_emails_bad_nonces_and_replayed_states_are_refused() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, social_config()).await;
+    create_user(&ctx, "alice").await;
+
+    let (status, body) = social_login(&ctx, &app, json!({"sub": "acme-4", "email": "alice@example.com", "email_verified": false})).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, "The identity provider did not supply a verified email address");
+    assert!(ctx.store.find_social_identity("acme", "acme-4").await.unwrap().is_none());
+
+    // An id_token minted for another login attempt
+    let response = test::call_service(&app, test::TestRequest::get().uri("/social/acme/authorize").to_request()).await;
+    let location = response.headers().get(http::header::LOCATION).unwrap().to_str().unwrap().to_string();
+    ctx.idp.grant("stolen", json!({"sub": "acme-4", "email": "alice@example.com", "email_verified": true, "nonce": "another-nonce"}));
+    let callback = format!("/social/acme/callback?code=stolen&state={}", link_param(&location, "state"));
+    let (status, _) = send(&app, test::TestRequest::get().uri(&callback)).await;
+    assert_eq!(status, 401);
+
+    // The state was spent by the first callback
+    ctx.idp.grant("stolen", json!({"sub": "acme-4", "email": "alice@example.com", "email_verified": true, "nonce": link_param(&location, "nonce")}));
+    let (status, body) = send(&app, test::TestRequest::get().uri(&callback)).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, "Unknown or expired login state");
+}
@@ -0,0 +1,43 @@
+// templates.rs
+
+use super::*;
+use serde_json::json;
+
+#[actix_web::test]
+async fn accept_language_picks_and_stores_the_locale() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_account")
+        .insert_header((http::header::ACCEPT_LANGUAGE, "de;q=0.9, fr-CA, en;q=0.5"))
+        .set_json(json!({"username": "dora", "email": "dora@example.com", "password": "correct horse"}));
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, 200);
+    assert_eq!(ctx.last_email_to("dora@example.com").await.subject, "Veuillez vérifier votre adresse email");
+
+    // later emails follow the stored locale, whatever the request says
+    let (status, _) = post_json(&app, "/resend_verification", json!({"email": "dora@example.com"}), None).await;
+    assert_eq!(status, 200);
+    let resent = ctx.last_email_to("dora@example.com").await;
+    assert_eq!(resent.subject, "Veuillez vérifier votre adresse email");
+    assert!(resent.html.contains("<html lang=\"fr\">"));
+}
+
+#[actix_web::test]
+async fn unknown_locales_fall_back_to_the_default() {
+    let templates = templates();
+    let req = test::TestRequest::default().insert_header((http::header::ACCEPT_LANGUAGE, "es-ES, *;q=0.1")).to_http_request();
+
+    assert_eq!(templates.locale(Some("pt"), Some(&req)), "en");
+    assert_eq!(templates.locale(Some("FR"), None), "fr");
+}
+
+#[actix_web::test]
+async fn html_part_escapes_variables() {
+    let email = templates().render("2fa_code", "en", &[("code", "<b>&</b>")]).unwrap();
+
+    assert!(email.text.contains("Here is your 2FA code: <b>&</b>"));
+    assert!(email.html.contains("&lt;b&gt;&amp;&lt;/b&gt;"));
+    assert!(email.html.contains("<h1"));
+}
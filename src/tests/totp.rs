@@ -0,0 +1,134 @@
+// totp.rs
+
+use super::*;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use serde_json::json;
+
+// What an authenticator app shows for the time step `offset` steps from now
+fn authenticator_code(secret: &str, offset: i64) -> String {
+    let key = data_encoding::BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = (Utc::now().timestamp() / 30 + offset) as u64;
+    let mut mac = <Hmac<sha1::Sha1> as Mac>::new_from_slice(&key).unwrap();
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    format!("{:06}", binary % 1_000_000)
+}
+
+// Enrolls alice's authenticator app and returns its secret, the code it confirmed with and the recovery codes
+async fn enroll<S, B>(app: &S, token: &str) -> (String, String, Vec<String>)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, body) = post_json(app, "/enroll_totp", json!({}), Some(token)).await;
+    assert_eq!(status, 200, "{}", body);
+    let secret = body["secret"].as_str().unwrap().to_string();
+    assert!(body["otpauth_uri"].as_str().unwrap().contains(&secret));
+    let enrollment_token = body["token"].as_str().unwrap().to_string();
+
+    let (status, _) = post_json(app, "/verify_totp_enrollment", json!({"username": "alice", "code": "000000", "token": enrollment_token}), None).await;
+    assert_eq!(status, 400);
+
+    let code = authenticator_code(&secret, 0);
+    let (status, body) = post_json(app, "/verify_totp_enrollment", json!({"username": "alice", "code": code, "token": enrollment_token}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    let recovery_codes = body["recovery_codes"].as_array().unwrap().iter().map(|code| code.as_str().unwrap().to_string()).collect();
+    (secret, code, recovery_codes)
+}
+
+async fn totp_login<S, B>(app: &S) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, body) = post_json(app, "/login", json!({"username": "alice", "password": PASSWORD}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["method"], "totp");
+    body["temp_token"].as_str().unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn authenticator_app_codes_complete_the_login_once() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+    let token = login_token(&app, "alice").await;
+    let (secret, enrollment_code, _) = enroll(&app, &token).await;
+
+    // No code is emailed for TOTP accounts
+    let sent = ctx.sent_count().await;
+    let temp_token = totp_login(&app).await;
+    assert_eq!(ctx.sent_count().await, sent);
+
+    // The code that confirmed the enrollment was used up with it
+    let (status, _) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "code": enrollment_code}), None).await;
+    assert_eq!(status, 400);
+
+    let code = authenticator_code(&secret, 1);
+    let (status, body) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "code": code}), None).await;
+    assert_eq!(status, 200, "{}", body);
+
+    let temp_token = totp_login(&app).await;
+    let (status, _) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "code": code}), None).await;
+    assert_eq!(status, 400);
+}
+
+#[actix_web::test]
+async fn recovery_codes_stand_in_for_the_app_once_each() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+    let token = login_token(&app, "alice").await;
+    let (_, _, recovery_codes) = enroll(&app, &token).await;
+    assert_eq!(recovery_codes.len(), 10);
+
+    // Matching ignores case and separators
+    let temp_token = totp_login(&app).await;
+    let typed = recovery_codes[0].replace('-', " ").to_uppercase();
+    let (status, body) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "recovery_code": typed}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["recovery_codes_remaining"], 9);
+
+    let temp_token = totp_login(&app).await;
+    let (status, body) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "recovery_code": recovery_codes[0]}), None).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, "Invalid recovery code.");
+
+    // The failed attempt left the temp_token usable
+    let (status, body) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "recovery_code": recovery_codes[1]}), None).await;
+    assert_eq!(status, 200, "{}", body);
+
+    // A new batch replaces the old one
+    let (status, body) = post_json(&app, "/regenerate_recovery_codes", json!({}), body["token"].as_str()).await;
+    assert_eq!(status, 200, "{}", body);
+    let temp_token = totp_login(&app).await;
+    let (status, _) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "recovery_code": recovery_codes[2]}), None).await;
+    assert_eq!(status, 400);
+    let (status, _) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "recovery_code": body["recovery_codes"][0]}), None).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn recovery_codes_from_before_the_key_still_work_once() {
+    let ctx = context().await;
+    let app = app(&ctx).await;
+    create_user(&ctx, "alice").await;
+    let token = login_token(&app, "alice").await;
+    enroll(&app, &token).await;
+
+    // Stored the way codes were before RECOVERY_CODE_KEY: a bare SHA-256 of the normalized code
+    let legacy = hex::encode(Sha256::digest(b"abcde12345"));
+    ctx.store.replace_recovery_codes("alice", &[legacy]).await.unwrap();
+
+    let temp_token = totp_login(&app).await;
+    let (status, body) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "recovery_code": "abcde-12345"}), None).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["recovery_codes_remaining"], 0);
+
+    let temp_token = totp_login(&app).await;
+    let (status, _) = post_json(&app, "/verify_2fa", json!({"temp_token": temp_token, "recovery_code": "abcde-12345"}), None).await;
+    assert_eq!(status, 400);
+}
@@ -0,0 +1,226 @@
+// webauthn.rs
+
+use super::*;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ciborium::value::Value;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+fn webauthn_config() -> AppConfig {
+    let file: toml::Table = r#"
+        [webauthn]
+        rp_id = "app.test"
+        origins = ["https://app.test"]
+        "#.parse().unwrap();
+    AppConfig::from_sources(file).unwrap()
+}
+
+fn cbor(value: &Value) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(value, &mut bytes).unwrap();
+    bytes
+}
+
+// An Ed25519 platform authenticator that always verifies the user and counts its signatures
+struct SoftAuthenticator {
+    key: Ed25519KeyPair,
+    credential_id: Vec<u8>,
+    rp_id: String,
+    origin: String,
+    sign_count: u32,
+}
+
+impl SoftAuthenticator {
+    fn new() -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        SoftAuthenticator {
+            key: Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap(),
+            credential_id: Uuid::new_v4().as_bytes().to_vec(),
+            rp_id: "app.test".to_string(),
+            origin: "https://app.test".to_string(),
+            sign_count: 0,
+        }
+    }
+
+    fn client_data(&self, ceremony: &str, challenge: &str) -> Vec<u8> {
+        json!({"type": ceremony, "challenge": challenge, "origin": self.origin}).to_string().into_bytes()
+    }
+
+    // rpIdHash | flags (UP, UV and optionally AT) | signCount
+    fn authenticator_data(&self, flags: u8) -> Vec<u8> {
+        let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+        data.push(0x01 | 0x04 | flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        data
+    }
+
+    // navigator.credentials.create() with "none" attestation
+    fn register(&self, challenge: &str) -> serde_json::Value {
+        let cose_key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(1.into())),
+            (Value::Integer(3.into()), Value::Integer((-8).into())),
+            (Value::Integer((-1).into()), Value::Integer(6.into())),
+            (Value::Integer((-2).into()), Value::Bytes(self.key.public_key().as_ref().to_vec())),
+        ]);
+        let mut auth_data = self.authenticator_data(0x40);
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        auth_data.extend_from_slice(&cbor(&cose_key));
+
+        let attestation = Value::Map(vec![
+            (Value::Text("fmt".to_string()), Value::Text("none".to_string())),
+            (Value::Text("attStmt".to_string()), Value::Map(Vec::new())),
+            (Value::Text("authData".to_string()), Value::Bytes(auth_data)),
+        ]);
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "clientDataJSON": URL_SAFE_NO_PAD.encode(self.client_data("webauthn.create", challenge)),
+            "attestationObject": URL_SAFE_NO_PAD.encode(cbor(&attestation)),
+        })
+    }
+
+    // navigator.credentials.get(); every assertion moves the counter on
+    fn assert(&mut self, challenge: &str) -> serde_json::Value {
+        self.sign_count += 1;
+        let auth_data = self.authenticator_data(0);
+        let client_data = self.client_data("webauthn.get", challenge);
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data));
+
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+            "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+            "signature": URL_SAFE_NO_PAD.encode(self.key.sign(&signed)),
+        })
+    }
+}
+
+async fn register_passkey<S, B>(app: &S, token: &str, authenticator: &SoftAuthenticator) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, options) = post_json(app, "/webauthn/register/start", json!({}), Some(token)).await;
+    assert_eq!(status, 200, "{}", options);
+    assert_eq!(options["publicKey"]["rp"]["id"], "app.test");
+    let credential = authenticator.register(options["publicKey"]["challenge"].as_str().unwrap());
+    post_json(app, "/webauthn/register/finish", json!({"challenge_id": options["challenge_id"], "credential": credential}), Some(token)).await
+}
+
+async fn passkey_login<S, B>(app: &S, authenticator: &mut SoftAuthenticator) -> (u16, serde_json::Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, options) = post_json(app, "/webauthn/login/start", json!({"username": "alice"}), None).await;
+    assert_eq!(status, 200, "{}", options);
+    let credential = authenticator.assert(options["publicKey"]["challenge"].as_str().unwrap());
+    post_json(app, "/webauthn/login/finish", json!({"challenge_id": options["challenge_id"], "credential": credential}), None).await
+}
+
+#[actix_web::test]
+async fn registered_passkeys_log_in() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, webauthn_config()).await;
+    create_user(&ctx, "alice").await;
+    let token = login_token(&app, "alice").await;
+
+    let mut authenticator = SoftAuthenticator::new();
+    let (status, body) = register_passkey(&app, &token, &authenticator).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["credential_id"], URL_SAFE_NO_PAD.encode(&authenticator.credential_id));
+
+    // The same authenticator cannot be registered twice
+    let (status, _) = register_passkey(&app, &token, &authenticator).await;
+    assert_eq!(status, 400);
+
+    let (status, body) = passkey_login(&app, &mut authenticator).await;
+    assert_eq!(status, 200, "{}", body);
+    let (status, _) = post_json(&app, "/logout", json!({}), body["token"].as_str()).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_web::test]
+async fn passkeys_complete_a_second_factor() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, webauthn_config()).await;
+    create_user(&ctx, "alice").await;
+    let token = login_token(&app, "alice").await;
+    let mut authenticator = SoftAuthenticator::new();
+    let (status, _) = register_passkey(&app, &token, &authenticator).await;
+    assert_eq!(status, 200);
+    ctx.store.enable_email_2fa("alice").await.unwrap();
+
+    let (_, body) = post_json(&app, "/login", json!({"username": "alice", "password": PASSWORD}), None).await;
+    let temp_token = body["temp_token"].as_str().unwrap().to_string();
+
+    let (status, options) = post_json(&app, "/webauthn/2fa/start", json!({"temp_token": temp_token}), None).await;
+    assert_eq!(status, 200, "{}", options);
+    let credential = authenticator.assert(options["publicKey"]["challenge"].as_str().unwrap());
+    let finish = json!({"temp_token": temp_token, "challenge_id": options["challenge_id"], "credential": credential});
+    let (status, body) = post_json(&app, "/webauthn/2fa/finish", finish.clone(), None).await;
+    assert_eq!(status, 200, "{}", body);
+    assert!(body["token"].is_string());
+
+    // The temp_token and the challenge are both spent
+    let (status, _) = post_json(&app, "/webauthn/2fa/finish", finish, None).await;
+    assert_ne!(status, 200);
+}
+
+#[actix_web::test]
+async fn a_signature_counter_going_backwards_is_refused() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, webauthn_config()).await;
+    create_user(&ctx, "alice").await;
+    let token = login_token(&app, "alice").await;
+    let mut authenticator = SoftAuthenticator::new();
+    let (status, _) = register_passkey(&app, &token, &authenticator).await;
+    assert_eq!(status, 200);
+
+    authenticator.sign_count = 10;
+    let (status, _) = passkey_login(&app, &mut authenticator).await;
+    assert_eq!(status, 200);
+
+    // A clone of the authenticator taken before the last login
+    authenticator.sign_count = 9;
+    let (status, body) = passkey_login(&app, &mut authenticator).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, "Invalid WebAuthn response");
+}
+
+#[actix_web::test]
+async fn other_origins_and_relying_parties_are_refused() {
+    let ctx = context().await;
+    let app = app_with_config(&ctx, webauthn_config()).await;
+    create_user(&ctx, "alice").await;
+    let token = login_token(&app, "alice").await;
+
+    let mut phished = SoftAuthenticator::new();
+    phished.origin = "https://app.test.evil.example".to_string();
+    let (status, body) = register_passkey(&app, &token, &phished).await;
+    assert_eq!((status, body), (400, json!("Invalid WebAuthn response")));
+
+    let mut foreign = SoftAuthenticator::new();
+    foreign.rp_id = "evil.example".to_string();
+    let (status, body) = register_passkey(&app, &token, &foreign).await;
+    assert_eq!((status, body), (400, json!("Invalid WebAuthn response")));
+
+    // A registered passkey used from the wrong origin or for the wrong RP ID
+    let mut authenticator = SoftAuthenticator::new();
+    let (status, _) = register_passkey(&app, &token, &authenticator).await;
+    assert_eq!(status, 200);
+    authenticator.origin = "https://evil.example".to_string();
+    let (status, body) = passkey_login(&app, &mut authenticator).await;
+    assert_eq!((status, body), (400, json!("Invalid WebAuthn response")));
+    authenticator.origin = "https://app.test".to_string();
+    authenticator.rp_id = "evil.example".to_string();
+    let (status, body) = passkey_login(&app, &mut authenticator).await;
+    assert_eq!((status, body), (400, json!("Invalid WebAuthn response")));
+
+    authenticator.rp_id = "app.test".to_string();
+    let (status, body) = passkey_login(&app, &mut authenticator).await;
+    assert_eq!(status, 200, "{}", body);
+}